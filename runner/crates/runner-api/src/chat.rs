@@ -0,0 +1,277 @@
+//! Chat prompts, rendered with the model's own Jinja chat template the way
+//! Hugging Face's `apply_chat_template` does, or with a built-in one for models
+//! that do not ship a template.
+
+use std::ops::Range;
+use minijinja::value::Kwargs;
+use minijinja::{Environment, Error, ErrorKind};
+use serde::{Deserialize, Serialize};
+use runner_backend::tokenizer::Tokenizer;
+use runner_backend::ModelHandle;
+use runner_common::{Result, RunnerError};
+use crate::tools::{Tool, ToolCall, ToolFormat};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: String,
+    /// `null` on an assistant turn that only calls tools; text parts are joined.
+    #[serde(default, deserialize_with = "content")]
+    pub content: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// On a `tool` message, the call it answers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+fn content<'de, D: serde::Deserializer<'de>>(d: D) -> std::result::Result<String, D::Error> {
+    #[derive(Deserialize)]
+    struct Part { text: Option<String> }
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw { Text(String), Parts(Vec<Part>) }
+    Ok(match Option::<Raw>::deserialize(d)? {
+        None => String::new(),
+        Some(Raw::Text(text)) => text,
+        Some(Raw::Parts(parts)) => parts.into_iter().filter_map(|p| p.text).collect(),
+    })
+}
+
+impl ChatMessage {
+    /// The message as templates see it: tool call arguments as objects, as
+    /// `transformers` passes them, rather than OpenAI's JSON strings, and
+    /// `specials` in the content marked as text.
+    fn template_value(&self, specials: &[String]) -> serde_json::Value {
+        let mut v = serde_json::to_value(self).unwrap_or_default();
+        v["content"] = mark_specials(&self.content, specials).into();
+        for call in v.get_mut("tool_calls").and_then(serde_json::Value::as_array_mut).into_iter().flatten() {
+            if let Some(args) = call.pointer_mut("/function/arguments") {
+                if let Some(parsed) = args.as_str().and_then(|a| serde_json::from_str(a).ok()) { *args = parsed; }
+            }
+        }
+        v
+    }
+}
+
+/// Hermes-style calls, also used by Qwen.
+const CHATML: &str = concat!(
+    "{% if tools %}<|im_start|>system\n{% for m in messages if m.role == 'system' %}{{ m.content }}\n\n{% endfor %}",
+    "# Tools\n\nYou may call one or more functions to assist with the user query.\n\n",
+    "You are provided with function signatures within <tools></tools> XML tags:\n<tools>\n{% for t in tools %}{{ t | tojson }}\n{% endfor %}</tools>\n\n",
+    "For each function call, return a json object with function name and arguments within <tool_call></tool_call> XML tags:\n",
+    "<tool_call>\n{\"name\": <function-name>, \"arguments\": <args-json-object>}\n</tool_call><|im_end|>\n{% endif %}",
+    "{% for m in messages %}",
+    "{% if m.role == 'system' %}{% if not tools %}<|im_start|>system\n{{ m.content }}<|im_end|>\n{% endif %}",
+    "{% elif m.role == 'tool' %}<|im_start|>user\n<tool_response>\n{{ m.content }}\n</tool_response><|im_end|>\n",
+    "{% else %}<|im_start|>{{ m.role }}\n{{ m.content }}",
+    "{% for c in m.tool_calls %}{% if m.content or not loop.first %}{{ '\\n' }}{% endif %}",
+    "<tool_call>\n{\"name\": \"{{ c.function.name }}\", \"arguments\": {{ c.function.arguments | tojson }}}\n</tool_call>{% endfor %}",
+    "<|im_end|>\n{% endif %}{% endfor %}",
+    "{% if add_generation_prompt %}<|im_start|>assistant\n{% endif %}",
+);
+
+/// Llama 3.1's JSON tool calls: the functions lead the last user message and
+/// results come back as `ipython` turns.
+const LLAMA3: &str = concat!(
+    "{% set ns = namespace(last_user=-1) %}{% for m in messages %}{% if m.role == 'user' %}{% set ns.last_user = loop.index0 %}{% endif %}{% endfor %}",
+    "{{ bos_token }}{% for m in messages %}",
+    "{% if m.role == 'tool' %}<|start_header_id|>ipython<|end_header_id|>\n\n{{ m.content }}<|eot_id|>",
+    "{% elif m.tool_calls %}<|start_header_id|>assistant<|end_header_id|>\n\n",
+    "{% for c in m.tool_calls %}{% if not loop.first %}; {% endif %}{\"name\": \"{{ c.function.name }}\", \"parameters\": {{ c.function.arguments | tojson }}}{% endfor %}<|eot_id|>",
+    "{% else %}<|start_header_id|>{{ m.role }}<|end_header_id|>\n\n",
+    "{% if tools and loop.index0 == ns.last_user %}",
+    "Given the following functions, please respond with a JSON for a function call with its proper arguments that best answers the given prompt.\n\n",
+    "Respond in the format {\"name\": function name, \"parameters\": dictionary of argument name and its value}. Do not use variables.\n\n",
+    "{% for t in tools %}{{ t | tojson(indent=4) }}\n\n{% endfor %}{% endif %}",
+    "{{ m.content | trim }}<|eot_id|>{% endif %}{% endfor %}",
+    "{% if add_generation_prompt %}<|start_header_id|>assistant<|end_header_id|>\n\n{% endif %}",
+);
+
+/// Mistral has no system role; system messages lead the first user turn.
+const MISTRAL: &str = concat!(
+    "{% set ns = namespace(system='', last_user=-1) %}",
+    "{% for m in messages if m.role == 'system' %}{% set ns.system = ns.system ~ m.content ~ '\n\n' %}{% endfor %}",
+    "{% for m in messages if m.role != 'system' %}{% if m.role == 'user' %}{% set ns.last_user = loop.index0 %}{% endif %}{% endfor %}",
+    "{{ bos_token }}{% for m in messages if m.role != 'system' %}",
+    "{% if m.role == 'user' %}{% if tools and loop.index0 == ns.last_user %}[AVAILABLE_TOOLS] {{ tools | tojson }}[/AVAILABLE_TOOLS]{% endif %}",
+    "[INST] {% if loop.first %}{{ ns.system }}{% endif %}{{ m.content }} [/INST]",
+    "{% elif m.role == 'tool' %}[TOOL_RESULTS] {{ {'call_id': m.tool_call_id, 'content': m.content} | tojson }}[/TOOL_RESULTS]",
+    "{% elif m.tool_calls %}[TOOL_CALLS] [{% for c in m.tool_calls %}{% if not loop.first %}, {% endif %}",
+    "{{ {'name': c.function.name, 'arguments': c.function.arguments} | tojson }}{% endfor %}]{{ eos_token }}",
+    "{% else %}{{ m.content }}{{ eos_token }}{% endif %}",
+    "{% endfor %}",
+);
+
+/// Gemma has no system role either, calls the assistant `model`, and has no
+/// tool format of its own; it is asked for Hermes-style calls.
+const GEMMA: &str = concat!(
+    "{% set ns = namespace(system='') %}",
+    "{% for m in messages if m.role == 'system' %}{% set ns.system = ns.system ~ m.content ~ '\n\n' %}{% endfor %}",
+    "{% if tools %}{% set ns.system = ns.system ~ 'You can call these functions:\n' %}",
+    "{% for t in tools %}{% set ns.system = ns.system ~ (t | tojson) ~ '\n' %}{% endfor %}",
+    "{% set ns.system = ns.system ~ '\nTo call one, reply with <tool_call>{\"name\": <function-name>, \"arguments\": <args-json-object>}</tool_call>.\n\n' %}{% endif %}",
+    "{{ bos_token }}{% for m in messages if m.role != 'system' %}",
+    "<start_of_turn>{{ 'model' if m.role == 'assistant' else 'user' }}\n",
+    "{% if loop.first %}{{ ns.system }}{% endif %}",
+    "{% if m.role == 'tool' %}<tool_response>\n{{ m.content | trim }}\n</tool_response>{% else %}{{ m.content | trim }}{% endif %}",
+    "{% for c in m.tool_calls %}<tool_call>{\"name\": \"{{ c.function.name }}\", \"arguments\": {{ c.function.arguments | tojson }}}</tool_call>{% endfor %}",
+    "<end_of_turn>\n",
+    "{% endfor %}",
+    "{% if add_generation_prompt %}<start_of_turn>model\n{% endif %}",
+);
+
+/// Built-in templates by name, with a token only their vocabularies have.
+const BUILTIN: [(&str, &str, &str); 4] = [
+    ("llama3", LLAMA3, "<|eot_id|>"),
+    ("gemma", GEMMA, "<start_of_turn>"),
+    ("mistral", MISTRAL, "[INST]"),
+    ("chatml", CHATML, "<|im_start|>"),
+];
+
+pub struct ChatTemplate {
+    /// A built-in template's name, or `model` / `config` for the model's own.
+    pub name: String,
+    /// How this template's model writes tool calls.
+    pub tool_format: ToolFormat,
+    env: Environment<'static>,
+    bos: String,
+    eos: String,
+    /// What the tokenizer reads as special tokens, longest first.
+    specials: Vec<String>,
+}
+
+/// Follows the first character of special-token text that came from message
+/// content, through rendering, so it is tokenized as text. A noncharacter, so
+/// it is not in any vocabulary.
+const MARK: char = '\u{fdd0}';
+
+fn mark_specials(text: &str, specials: &[String]) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut i = 0;
+    while let Some(c) = text[i..].chars().next() {
+        match specials.iter().find(|s| text[i..].starts_with(s.as_str())) {
+            Some(s) => {
+                out.push(c);
+                out.push(MARK);
+                out.push_str(&s[c.len_utf8()..]);
+                i += s.len();
+            }
+            None => {
+                out.push(c);
+                i += c.len_utf8();
+            }
+        }
+    }
+    out
+}
+
+impl ChatTemplate {
+    /// Compile `source`, with `bos`/`eos` as the `bos_token`/`eos_token` it may use.
+    pub fn new(name: &str, source: &str, bos: &str, eos: &str) -> Result<Self> {
+        let mut env = Environment::new();
+        // as transformers renders chat templates
+        env.set_trim_blocks(true);
+        env.set_lstrip_blocks(true);
+        env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+        env.add_filter("tojson", tojson);
+        env.add_function("raise_exception", |msg: String| -> std::result::Result<String, Error> { Err(Error::new(ErrorKind::InvalidOperation, msg)) });
+        env.add_template_owned("chat", source.to_string()).map_err(template_error)?;
+        Ok(Self { name: name.to_string(), tool_format: ToolFormat::detect(source), env, bos: bos.to_string(), eos: eos.to_string(), specials: Vec::new() })
+    }
+
+    /// The template for a loaded model: the configured `override_` (a built-in
+    /// name or Jinja source), else the one in the model file, else the built-in
+    /// whose special tokens the vocabulary has, else ChatML.
+    pub fn for_model(override_: Option<&str>, model: &ModelHandle, tokenizer: &dyn Tokenizer) -> Result<Self> {
+        let mut template = Self::pick(override_, model, tokenizer)?;
+        template.specials = tokenizer.special_tokens();
+        template.specials.sort_by_key(|s| std::cmp::Reverse(s.len()));
+        Ok(template)
+    }
+
+    fn pick(override_: Option<&str>, model: &ModelHandle, tokenizer: &dyn Tokenizer) -> Result<Self> {
+        let token = |id: Option<u32>| id.and_then(|id| tokenizer.id_to_token(id)).unwrap_or_default();
+        let (bos, eos) = (token(model.special_tokens.bos), token(model.special_tokens.eos));
+        if let Some(source) = override_ {
+            return match BUILTIN.iter().find(|(name, ..)| *name == source) {
+                Some((name, source, _)) => Self::new(name, source, &bos, &eos),
+                None => Self::new("config", source, &bos, &eos),
+            };
+        }
+        if let Some(source) = &model.chat_template {
+            match Self::new("model", source, &bos, &eos) {
+                Ok(t) => return Ok(t),
+                Err(e) => tracing::warn!(target: "api", "{}: {}; using a built-in template", model.id, e),
+            }
+        }
+        let (name, source, _) = BUILTIN.iter().find(|(.., marker)| tokenizer.token_to_id(marker).is_some()).unwrap_or(&BUILTIN[3]);
+        Self::new(name, source, &bos, &eos)
+    }
+
+    /// Whether the template [`for_model`](Self::for_model) picks from these
+    /// sources shows the model the tools it is offered; the built-ins all do.
+    pub fn offers_tools(override_: Option<&str>, model_template: Option<&str>) -> bool {
+        match override_.or(model_template) {
+            Some(source) if !BUILTIN.iter().any(|(name, ..)| *name == source) => source.contains("tools"),
+            _ => true,
+        }
+    }
+
+    /// The conversation as prompt text, offering `tools` to the model;
+    /// `add_generation_prompt` opens the assistant's turn.
+    pub fn render(&self, messages: &[ChatMessage], tools: &[Tool], add_generation_prompt: bool) -> Result<String> {
+        self.render_marked(messages, tools, add_generation_prompt).map(|text| text.replace(MARK, ""))
+    }
+
+    fn render_marked(&self, messages: &[ChatMessage], tools: &[Tool], add_generation_prompt: bool) -> Result<String> {
+        let messages: Vec<serde_json::Value> = messages.iter().map(|m| m.template_value(&self.specials)).collect();
+        let tools = (!tools.is_empty()).then_some(tools);
+        let ctx = minijinja::context! { messages, tools, add_generation_prompt, bos_token => &self.bos, eos_token => &self.eos };
+        self.env.get_template("chat").and_then(|t| t.render(ctx)).map_err(template_error)
+    }
+
+    /// Tokens for the rendered conversation followed by `prefill`, adding BOS
+    /// only when the template did not. Special-token text is only read as
+    /// special tokens where the template wrote it, not in message content.
+    pub fn prompt(&self, tokenizer: &dyn Tokenizer, messages: &[ChatMessage], tools: &[Tool], add_generation_prompt: bool, prefill: &str) -> Result<Vec<u32>> {
+        let marked = self.render_marked(messages, tools, add_generation_prompt)? + prefill;
+        let (mut text, mut literal) = (String::with_capacity(marked.len()), Vec::<Range<usize>>::new());
+        for (n, piece) in marked.split(MARK).enumerate() {
+            // a mark follows the first character of the special token it covers
+            let start = text.char_indices().next_back().map(|(i, _)| i);
+            text.push_str(piece);
+            let Some(start) = start.filter(|&s| n > 0 && literal.last().is_none_or(|r| s >= r.end)) else { continue };
+            if let Some(s) = self.specials.iter().find(|s| text[start..].starts_with(s.as_str())) { literal.push(start..start + s.len()); }
+        }
+        tokenizer.encode_template(&text, self.bos.is_empty() || !text.starts_with(&self.bos), &literal)
+    }
+}
+
+/// Python's `json.dumps`, which templates are written against: `", "` and
+/// `": "` separators, or newlines and `indent` spaces.
+fn tojson(value: minijinja::Value, kwargs: Kwargs) -> std::result::Result<minijinja::Value, Error> {
+    let indent: Option<usize> = kwargs.get("indent")?;
+    kwargs.assert_all_used()?;
+    let mut out = Vec::new();
+    let written = match indent {
+        Some(n) => {
+            let indent = " ".repeat(n);
+            value.serialize(&mut serde_json::Serializer::with_formatter(&mut out, serde_json::ser::PrettyFormatter::with_indent(indent.as_bytes())))
+        }
+        None => value.serialize(&mut serde_json::Serializer::with_formatter(&mut out, PyFormatter)),
+    };
+    written.map_err(|e| Error::new(ErrorKind::InvalidOperation, e.to_string()))?;
+    Ok(minijinja::Value::from_safe_string(String::from_utf8(out).unwrap_or_default()))
+}
+
+struct PyFormatter;
+
+impl serde_json::ser::Formatter for PyFormatter {
+    fn begin_array_value<W: ?Sized + std::io::Write>(&mut self, w: &mut W, first: bool) -> std::io::Result<()> { if first { Ok(()) } else { w.write_all(b", ") } }
+    fn begin_object_key<W: ?Sized + std::io::Write>(&mut self, w: &mut W, first: bool) -> std::io::Result<()> { if first { Ok(()) } else { w.write_all(b", ") } }
+    fn begin_object_value<W: ?Sized + std::io::Write>(&mut self, w: &mut W) -> std::io::Result<()> { w.write_all(b": ") }
+}
+
+fn template_error(e: Error) -> RunnerError { RunnerError::Message(format!("chat template: {e}")) }
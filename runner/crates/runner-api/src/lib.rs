@@ -1,62 +1,106 @@
 //! HTTP API (skeleton -> minimal JSON + SSE)
 
+pub mod chat;
+pub mod registry;
+pub mod tools;
+
 use std::sync::Arc;
 
 use axum::{
-    extract::State,
+    extract::{Path, State},
+    http::StatusCode,
     response::{sse::{Event, Sse}, IntoResponse},
     routing::{get, post},
     Json, Router,
 };
-use axum::extract::ws::{WebSocketUpgrade, Message};
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
 use once_cell::sync::Lazy;
-use prometheus::{Encoder, IntCounter, Histogram, TextEncoder};
-use runner_backend::{mock::MockBackend, InferenceBackend};
-use runner_backend_llamacpp::LlamaCppBackend;
-use runner_core::decode::generate_once;
-use runner_core::scheduler::{SchedulerV1, Handle};
-use runner_core::kv::{PagedKvManager, PrefixCache};
-use runner_common::{Result, config::RunnerConfig};
-use tokio_stream::{wrappers::ReceiverStream, StreamExt as _};
+use prometheus::{Encoder, IntCounter, IntGaugeVec, Histogram, TextEncoder};
+use runner_backend::gguf::GgufFile;
+use runner_backend::tokenizer::Tokenizer;
+use runner_backend::{ContextOverflow, ContextPolicy, InferenceBackend, LoadParams, RopeParams};
+use runner_core::decode::{DecodeOptions, FinishReason, TokenLogprob, Usage};
+use runner_core::sampler::Sampling;
+use runner_core::scheduler::{EventStream, GenerationEvent, SchedulerConfig, SchedulerV1, Handle};
+use runner_core::kv::PagedKvManager;
+use runner_core::spill::{SpillConfig, Tier, TieredKvStore};
+use runner_core::session::{SessionError, SessionStore};
+use runner_common::{Result, RunnerError, cancel::CancelToken, config::{KeepAlive, RunnerConfig}};
+use tokio_stream::wrappers::ReceiverStream;
 use runner_obs::{init as obs_init, spawn_gpu_polling};
+use chat::ChatMessage;
+use tools::{Tool, ToolCall, ToolChoice};
+use registry::{Lease, ModelInfo, ModelRegistry, ModelSpec, Prepared, RegistryError};
 
 #[derive(Clone)]
 pub struct AppState {
-    backend: Arc<dyn InferenceBackend>,
     requests_total: IntCounter,
+    requests_cancelled_total: IntCounter,
     tokens_generated_total: IntCounter,
     ttft_seconds: Histogram,
-    scheduler: Handle,
+    registry: Arc<ModelRegistry>,
+    swap: Arc<std::sync::Mutex<SwapStatus>>,
+    readiness: Arc<std::sync::Mutex<Readiness>>,
+    models_loaded: prometheus::IntGauge,
+    model_resident: IntGaugeVec,
+    model_resident_bytes: IntGaugeVec,
     queue_depth_gauge: prometheus::IntGauge,
     batch_size_gauge: prometheus::IntGauge,
     kv_used_blocks: prometheus::IntGauge,
     kv_capacity_blocks: prometheus::IntGauge,
+    kv_used_bytes: prometheus::IntGauge,
+    kv_capacity_bytes: prometheus::IntGauge,
+    kv_tier_used_bytes: IntGaugeVec,
+    kv_tier_capacity_bytes: IntGaugeVec,
+    sessions_gauge: prometheus::IntGauge,
+    sessions: Arc<SessionStore>,
     limiter: RateLimiter,
     budgets: TokenBudgets,
-    model_path: std::sync::Arc<tokio::sync::RwLock<Option<String>>>,
+    config: Arc<RunnerConfig>,
 }
 
-static ENCODER: Lazy<TextEncoder> = Lazy::new(|| TextEncoder::new());
+static ENCODER: Lazy<TextEncoder> = Lazy::new(TextEncoder::new);
 
 pub fn app() -> Router {
-    let backend: Arc<dyn InferenceBackend> = select_backend();
+    let cfg = Arc::new(RunnerConfig::load());
     obs_init();
+    runner_core::metrics::init();
+    registry::init_metrics();
     spawn_gpu_polling();
-    let _cfg = RunnerConfig::load();
-    let kv = PagedKvManager::new(512 * 1024 * 1024);
-    let prefix = PrefixCache::new();
-    let scheduler = SchedulerV1::start(backend.clone(), kv.clone(), prefix.clone());
+    let sched_cfg = SchedulerConfig::from_runner(&cfg);
+    let registry = ModelRegistry::from_config(cfg.clone());
+    let names: Vec<String> = registry.specs().into_iter().map(|s| s.name).collect();
+    tracing::info!(target: "api", "models: {} (default {})", names.join(", "), registry.default_model().unwrap_or_default());
+    let default_path = registry.resolve(None).ok().and_then(|name| registry.spec(&name)).and_then(|s| s.path);
+    let params = load_params(&cfg, default_path.as_deref().unwrap_or_default());
+    tracing::info!(
+        target: "api",
+        "config: tick={}ms max_seqs={} max_batch_tokens={} queue_capacity={} kv={} MiB (k={} v={}) n_ctx={} gpu_layers={} rope={}",
+        sched_cfg.tick.as_millis(), sched_cfg.max_seqs, sched_cfg.max_batch_tokens, sched_cfg.queue_capacity,
+        cfg.kv_capacity_bytes() / (1024 * 1024), params.type_k.as_str(), params.type_v.as_str(), params.n_ctx, params.n_gpu_layers,
+        params.rope.scaling.map_or("model", |r| r.as_str()),
+    );
+    let sessions = SessionStore::open(cfg.session_dir.clone()).unwrap_or_else(|e| {
+        tracing::warn!(target: "api", "sessions will not persist: {}", e);
+        SessionStore::open(None).expect("in-memory session store")
+    });
     let queue_depth_gauge = prometheus::register_int_gauge!("runner_queue_depth", "Scheduler queue depth").expect("gauge");
     let batch_size_gauge = prometheus::register_int_gauge!("runner_batch_size", "Last batch size").expect("gauge");
     let kv_used_blocks = prometheus::register_int_gauge!("runner_kv_used_blocks", "KV used blocks").expect("gauge");
     let kv_capacity_blocks = prometheus::register_int_gauge!("runner_kv_capacity_blocks", "KV capacity blocks").expect("gauge");
+    let kv_used_bytes = prometheus::register_int_gauge!("runner_kv_used_bytes", "KV bytes held by allocated blocks").expect("gauge");
+    let kv_capacity_bytes = prometheus::register_int_gauge!("runner_kv_capacity_bytes", "KV pool size in bytes").expect("gauge");
     let state = AppState {
-        backend,
         requests_total: prometheus::register_int_counter!(
             "runner_requests_total",
             "Total number of /generate requests"
         )
         .expect("counter"),
+        requests_cancelled_total: prometheus::register_int_counter!(
+            "runner_requests_cancelled_total",
+            "Requests cancelled because the client disconnected"
+        )
+        .expect("counter"),
         tokens_generated_total: prometheus::register_int_counter!(
             "runner_tokens_generated_total",
             "Total output tokens (approx)"
@@ -67,15 +111,29 @@ pub fn app() -> Router {
             "Time to first token (approx for mock)"
         )
         .expect("histogram"),
-        scheduler,
+        registry,
+        swap: Arc::default(),
+        readiness: Arc::default(),
+        models_loaded: prometheus::register_int_gauge!("runner_models_loaded", "Models currently loaded").expect("gauge"),
+        model_resident: prometheus::register_int_gauge_vec!("runner_model_resident", "1 while the model is loaded", &["model"]).expect("gauge"),
+        model_resident_bytes: prometheus::register_int_gauge_vec!("runner_model_resident_bytes", "Memory a loaded model holds (weights plus KV pool)", &["model"]).expect("gauge"),
         queue_depth_gauge,
         batch_size_gauge,
         kv_used_blocks,
         kv_capacity_blocks,
+        kv_used_bytes,
+        kv_capacity_bytes,
+        kv_tier_used_bytes: prometheus::register_int_gauge_vec!("runner_kv_tier_used_bytes", "KV bytes held per tier", &["tier"]).expect("gauge"),
+        kv_tier_capacity_bytes: prometheus::register_int_gauge_vec!("runner_kv_tier_capacity_bytes", "KV capacity per tier", &["tier"]).expect("gauge"),
+        sessions_gauge: prometheus::register_int_gauge!("runner_sessions", "Open conversation sessions").expect("gauge"),
+        sessions,
         limiter: RateLimiter::new(),
         budgets: TokenBudgets::new(),
-        model_path: std::sync::Arc::new(tokio::sync::RwLock::new(std::env::var("RUNNER_MODEL").ok())),
+        config: cfg,
     };
+    spawn_session_parking(&state);
+    spawn_model_reaper(&state);
+    spawn_preload(&state);
 
     Router::new()
         .route("/healthz", get(|| async { "ok" }))
@@ -83,134 +141,591 @@ pub fn app() -> Router {
         .route("/metrics", get(metrics))
         .route("/generate", post(generate))
         .route("/v1/chat/completions", post(chat_completions))
-        .route("/sse/generate", get(generate_sse))
+        .route("/v1/completions", post(completions))
+        .route("/v1/models", get(list_models))
+        .route("/v1/models/*id", get(get_model))
+        .route("/tokenize", post(tokenize))
+        .route("/detokenize", post(detokenize))
+        .route("/sse/generate", post(generate_sse))
         .route("/ws/generate", get(ws_generate))
+        .route("/v1/sessions", post(create_session))
+        .route("/v1/sessions/:id", get(get_session).delete(delete_session))
+        .route("/v1/sessions/:id/messages", post(add_session_message))
+        .route("/v1/sessions/:id/generate", post(generate_session))
         .route("/admin/set_model", post(admin_set_model))
+        .route("/admin/model_status", get(admin_model_status))
         .route("/openapi.json", get(openapi))
         .with_state(state)
 }
 
-fn select_backend() -> Arc<dyn InferenceBackend> {
-    // Try llama backend first if model path is provided
-    if let Ok(model_path) = std::env::var("RUNNER_MODEL") {
-        let llama = LlamaCppBackend::new();
-        // ignore params for now
-        if llama.load_model(&model_path, runner_backend::LoadParams::default()).is_ok() {
-            tracing::info!(target: "api", "using llama.cpp backend with model {}", model_path);
-            return Arc::new(llama);
-        } else {
-            tracing::warn!(target: "api", "failed to init llama backend, falling back to mock");
+/// Load parameters for the model at `model`, from its `models` entry and the
+/// top-level settings. Values that do not parse are logged and left at their default.
+pub(crate) fn load_params(cfg: &RunnerConfig, model: &str) -> LoadParams {
+    let s = cfg.load_settings(model);
+    LoadParams {
+        n_ctx: s.context_size.unwrap_or(2048),
+        n_gpu_layers: s.gpu_layers.unwrap_or(0),
+        type_k: parse_setting("kv_cache_type_k", &s.kv_cache_type_k).unwrap_or_default(),
+        type_v: parse_setting("kv_cache_type_v", &s.kv_cache_type_v).unwrap_or_default(),
+        rope: RopeParams {
+            scaling: parse_setting("rope_scaling", &s.rope_scaling),
+            freq_base: s.rope_freq_base,
+            freq_scale: s.rope_freq_scale,
+            yarn_ext_factor: s.yarn_ext_factor,
+            yarn_attn_factor: s.yarn_attn_factor,
+            yarn_beta_fast: s.yarn_beta_fast,
+            yarn_beta_slow: s.yarn_beta_slow,
+            yarn_orig_ctx: s.yarn_orig_ctx,
+        },
+        flash_attn: s.flash_attn,
+        n_batch: s.batch_size,
+        n_ubatch: s.ubatch_size,
+        n_threads: s.threads,
+        n_threads_batch: s.threads_batch,
+        use_mmap: s.use_mmap,
+        use_mlock: s.use_mlock,
+        numa: parse_setting("numa", &s.numa).unwrap_or_default(),
+    }
+}
+
+fn parse_setting<T: std::str::FromStr<Err = runner_common::RunnerError>>(name: &str, value: &Option<String>) -> Option<T> {
+    value.as_deref()?.parse().map_err(|e| tracing::warn!(target: "api", "{}: {}; using the default", name, e)).ok()
+}
+
+/// KV pool with blocks sized from the loaded model's layout, read from the
+/// model file's GGUF header when the backend does not report one, or the
+/// default block size when neither does; plus any configured spill tiers, which
+/// a backend that does not keep its KV in the pool's blocks cannot use.
+pub(crate) fn kv_pool(cfg: &RunnerConfig, backend: &Arc<dyn InferenceBackend>, model: Option<&str>) -> Result<Arc<PagedKvManager>> {
+    let from_file = || {
+        let params = load_params(cfg, model?);
+        GgufFile::open(model?).ok()?.kv_layout(params.type_k, params.type_v)
+    };
+    let kv = match backend.kv_layout().or_else(from_file) {
+        Some(layout) => PagedKvManager::for_layout(cfg.kv_capacity_bytes(), &layout),
+        None => PagedKvManager::new(cfg.kv_capacity_bytes()),
+    };
+    let spill = SpillConfig::from_runner(cfg);
+    if spill.is_enabled() {
+        if !backend.paged_kv() {
+            return Err(RunnerError::Message("this backend cannot spill KV blocks; unset kv_host_spill_mb and kv_disk_spill_mb".into()));
+        }
+        match TieredKvStore::new(&spill, kv.block_bytes()) {
+            Ok(store) => kv.enable_spill(store, backend.clone()),
+            Err(e) => tracing::warn!(target: "api", "KV spill tiers disabled: {}", e),
         }
     }
-    Arc::new(MockBackend::new())
+    Ok(kv)
+}
+
+/// Periodically take sessions that went idle off the device so their KV blocks
+/// serve live traffic; they are restored on their next turn.
+fn spawn_session_parking(state: &AppState) {
+    let idle = std::time::Duration::from_secs(state.config.session_idle_secs.or(RunnerConfig::default().session_idle_secs).unwrap_or(300));
+    let state = state.clone();
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(idle.clamp(std::time::Duration::from_secs(1), std::time::Duration::from_secs(30)));
+        loop {
+            tick.tick().await;
+            // the sessions of an unloaded model have nothing left on the device
+            for (model, scheduler) in state.registry.loaded() {
+                let parked = state.sessions.park_idle(&model, scheduler.backend.as_ref(), idle);
+                if parked > 0 { tracing::info!(target: "api", "parked {} idle sessions of {}", parked, model); }
+            }
+        }
+    });
 }
 
-async fn metrics() -> impl IntoResponse {
+/// Where startup preloading stands, as `/readyz` reports it.
+#[derive(Clone, Default)]
+enum Readiness { #[default] Loading, Ready, Failed(String) }
+
+/// Load and warm up the `preload` models (by default the default model) one at
+/// a time; the server reports ready once all of them have passed warmup.
+fn spawn_preload(state: &AppState) {
+    let state = state.clone();
+    let models = state.config.preload.clone().unwrap_or_else(|| state.registry.default_model().into_iter().collect());
+    tokio::spawn(async move {
+        for model in &models {
+            let started = std::time::Instant::now();
+            if let Err(e) = state.registry.preload(model).await {
+                tracing::warn!(target: "api", "preloading {} failed: {}", model, e);
+                *state.readiness.lock().unwrap() = Readiness::Failed(e.to_string());
+                return;
+            }
+            tracing::info!(target: "api", "preloaded {} in {} ms", model, started.elapsed().as_millis());
+        }
+        *state.readiness.lock().unwrap() = Readiness::Ready;
+    });
+}
+
+/// Unload models once they have been idle past their keep-alive; the next
+/// request for one loads it again.
+fn spawn_model_reaper(state: &AppState) {
+    let registry = state.registry.clone();
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(std::time::Duration::from_secs(1));
+        loop {
+            tick.tick().await;
+            for name in registry.unload_expired() { tracing::info!(target: "api", "unloaded idle model {}", name); }
+        }
+    });
+}
+
+impl AppState {
+    /// Lease `requested` (a model name or alias) or the default model, loading it
+    /// if needed; `keep_alive` overrides how long it stays loaded after this request.
+    async fn model(&self, requested: Option<&str>, keep_alive: Option<KeepAlive>) -> std::result::Result<Lease, axum::response::Response> {
+        self.registry.lease_with(requested, keep_alive).await.map_err(model_error)
+    }
+
+    /// Lease the model session `id` runs on.
+    async fn session_model(&self, id: &str) -> std::result::Result<Lease, axum::response::Response> {
+        let info = self.sessions.info(id).ok_or_else(|| session_error(SessionError::NotFound))?;
+        self.model(Some(&info.model), None).await
+    }
+
+    /// Flip a request's cancel token after its client went away; counted once.
+    fn cancel(&self, token: &CancelToken) {
+        if !token.is_cancelled() {
+            token.cancel();
+            self.requests_cancelled_total.inc();
+        }
+    }
+
+    /// Copy scheduler and KV figures, summed over the loaded models, into
+    /// their prometheus series.
+    fn refresh_gauges(&self) {
+        use std::sync::atomic::Ordering::Relaxed;
+        let models = self.registry.loaded();
+        let sum = |f: &dyn Fn(&Handle) -> usize| models.iter().map(|(_, h)| f(h)).sum::<usize>() as i64;
+        self.models_loaded.set(models.len() as i64);
+        let resident = self.registry.resident();
+        for spec in self.registry.specs() {
+            let bytes = resident.iter().find(|(n, _)| *n == spec.name).map(|(_, b)| *b);
+            self.model_resident.with_label_values(&[&spec.name]).set(bytes.is_some() as i64);
+            self.model_resident_bytes.with_label_values(&[&spec.name]).set(bytes.unwrap_or(0) as i64);
+        }
+        self.queue_depth_gauge.set(sum(&|h| h.queue_depth()));
+        self.batch_size_gauge.set(sum(&|h| h.last_batch_size.load(Relaxed)));
+        self.kv_used_blocks.set(sum(&|h| h.kv.used_blocks()));
+        self.kv_capacity_blocks.set(sum(&|h| h.kv.capacity_blocks()));
+        self.kv_used_bytes.set(sum(&|h| h.kv.used_bytes()));
+        self.kv_capacity_bytes.set(sum(&|h| h.kv.capacity_bytes()));
+        self.sessions_gauge.set(self.sessions.len() as i64);
+        self.kv_tier_used_bytes.with_label_values(&["device"]).set(sum(&|h| h.kv.used_bytes()));
+        self.kv_tier_capacity_bytes.with_label_values(&["device"]).set(sum(&|h| h.kv.capacity_bytes()));
+        for tier in Tier::ALL {
+            let stats: Vec<_> = models.iter().filter_map(|(_, h)| h.kv.spill_store().map(|s| s.stats(tier))).collect();
+            if stats.is_empty() { continue; }
+            self.kv_tier_used_bytes.with_label_values(&[tier.as_str()]).set(stats.iter().map(|st| st.used_bytes).sum::<usize>() as i64);
+            self.kv_tier_capacity_bytes.with_label_values(&[tier.as_str()]).set(stats.iter().map(|st| st.capacity_bytes).sum::<usize>() as i64);
+        }
+    }
+}
+
+/// Cancels the request when the handler future is dropped before completing,
+/// which is how axum surfaces a plain HTTP client disconnect.
+struct CancelOnDrop { state: AppState, token: CancelToken, armed: bool }
+
+impl CancelOnDrop {
+    fn new(state: &AppState, token: &CancelToken) -> Self { Self { state: state.clone(), token: token.clone(), armed: true } }
+    fn disarm(mut self) { self.armed = false; }
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) { if self.armed { self.state.cancel(&self.token) } }
+}
+
+struct Generated { text: String, finish_reason: FinishReason, usage: Usage, logprobs: Vec<TokenLogprob> }
+
+/// Drain one request's scheduler events into the full completion, recording TTFT
+/// at the first token. Rejections come back as the scheduler's error text.
+async fn collect(state: &AppState, mut events: EventStream, start: std::time::Instant) -> std::result::Result<Generated, String> {
+    let (mut text, mut logprobs) = (String::new(), Vec::new());
+    while let Some(ev) = events.recv().await {
+        match ev {
+            GenerationEvent::Token(t) => {
+                if text.is_empty() { state.ttft_seconds.observe(start.elapsed().as_secs_f64()); }
+                text.push_str(&t);
+            }
+            GenerationEvent::Logprob(lp) => logprobs.push(lp),
+            GenerationEvent::Finished { finish_reason, usage } => return Ok(Generated { text, finish_reason, usage, logprobs }),
+            GenerationEvent::Error(e) => return Err(e),
+            GenerationEvent::Queued { .. } | GenerationEvent::Started => {}
+        }
+    }
+    Err(String::from("generation aborted"))
+}
+
+async fn metrics(State(state): State<AppState>) -> impl IntoResponse {
+    state.refresh_gauges();
     let metric_families = prometheus::gather();
     let mut buffer = Vec::new();
     ENCODER.encode(&metric_families, &mut buffer).unwrap();
     ([("content-type", ENCODER.format_type().to_string())], buffer)
 }
 
-async fn readyz(State(state): State<AppState>) -> impl IntoResponse {
-    // ready if scheduler is running and (if a model was requested) a path is set
-    let has_model = state.model_path.read().await.is_some();
-    let running = true;
-    if running { ([("content-type", "text/plain")], if has_model { "ready" } else { "ready-no-model" }) }
-    else { ([("content-type", "text/plain")], "not-ready") }
+/// 200 once the preloaded models have loaded and passed warmup; 503 while they
+/// are loading or after one failed, so probes hold traffic back until then.
+async fn readyz(State(state): State<AppState>) -> axum::response::Response {
+    let readiness = state.readiness.lock().unwrap().clone();
+    let (status, body) = match readiness {
+        Readiness::Ready => (StatusCode::OK, String::from("ready")),
+        Readiness::Loading => (StatusCode::SERVICE_UNAVAILABLE, String::from("loading")),
+        Readiness::Failed(e) => (StatusCode::SERVICE_UNAVAILABLE, format!("not-ready: {e}")),
+    };
+    (status, [("content-type", "text/plain")], body).into_response()
+}
+
+/// Sampling and stop settings, shared by the generation endpoints.
+#[derive(serde::Deserialize, Default)]
+struct SamplingParams {
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    top_k: Option<usize>,
+    seed: Option<u64>,
+    /// A string or a list of up to four strings that end the text.
+    #[serde(default, deserialize_with = "one_or_many")]
+    stop: Vec<String>,
+}
+
+impl SamplingParams {
+    fn options(&self) -> DecodeOptions {
+        let d = Sampling::default();
+        let sampling = Sampling { temperature: self.temperature.unwrap_or(d.temperature), top_p: self.top_p.unwrap_or(d.top_p), top_k: self.top_k.unwrap_or(d.top_k), seed: self.seed };
+        DecodeOptions { sampling, stop: self.stop.clone(), logprobs: None }
+    }
+
+    /// A setting out of range, with the parameter it came from.
+    fn error(&self) -> Option<(String, &'static str)> {
+        if let Some(t) = self.temperature.filter(|t| !(0.0..=2.0).contains(t)) { return Some((format!("temperature {t} is not between 0 and 2"), "temperature")); }
+        if let Some(p) = self.top_p.filter(|p| !(*p > 0.0 && *p <= 1.0)) { return Some((format!("top_p {p} is not in (0, 1]"), "top_p")); }
+        if self.stop.len() > 4 { return Some((format!("{} stop strings given, at most 4 are allowed", self.stop.len()), "stop")); }
+        None
+    }
+}
+
+fn one_or_many<'de, D: serde::Deserializer<'de>>(d: D) -> std::result::Result<Vec<String>, D::Error> {
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany { One(String), Many(Vec<String>) }
+    Ok(match <Option<OneOrMany> as serde::Deserialize>::deserialize(d)? {
+        None => Vec::new(),
+        Some(OneOrMany::One(s)) => vec![s],
+        Some(OneOrMany::Many(v)) => v,
+    })
+}
+
+/// What the text generation endpoints take besides their prompt.
+#[derive(serde::Deserialize)]
+struct GenerationParams {
+    /// Model name or alias; the default model when unset.
+    model: Option<String>,
+    max_tokens: Option<usize>,
+    #[serde(flatten)]
+    sampling: SamplingParams,
+    /// `reject` (default), `truncate` or `shift` when the prompt and `max_tokens` outgrow the context.
+    context_overflow: Option<ContextOverflow>,
+    /// How long the model stays loaded after this request, e.g. `"10m"`, `-1` or `0`.
+    keep_alive: Option<KeepAlive>,
+}
+
+impl GenerationParams {
+    /// Submit a tokenized prompt to `model` with these settings.
+    fn submit(&self, model: &Lease, tokens: Vec<u32>, options: DecodeOptions, cancel: &CancelToken) -> EventStream {
+        let context = ContextPolicy { overflow: self.context_overflow.unwrap_or_default(), keep: 0 };
+        SchedulerV1::submit_with(model, tokens, self.max_tokens.unwrap_or(128), context, options, cancel.clone())
+    }
 }
 
 #[derive(serde::Deserialize)]
 struct GenerateRequest {
     prompt: String,
-    #[allow(dead_code)]
-    max_tokens: Option<usize>,
+    #[serde(flatten)]
+    params: GenerationParams,
 }
 
 #[derive(serde::Serialize)]
 struct GenerateResponse { text: String }
 
-async fn generate(State(state): State<AppState>, Json(req): Json<GenerateRequest>) -> Json<GenerateResponse> {
+/// Tokenize a generation request and submit it to its model. The lease keeps
+/// the model loaded until the generation is done.
+async fn submit_generate(state: &AppState, req: &GenerateRequest, cancel: &CancelToken) -> std::result::Result<(Lease, EventStream), axum::response::Response> {
+    let params = &req.params;
+    if let Some((e, param)) = params.sampling.error() { return Err(invalid_request(e, param)); }
+    let model = state.model(params.model.as_deref(), params.keep_alive).await?;
+    state.refresh_gauges();
+    let tokens = model.tokenizer.encode(&req.prompt, true).map_err(tokenize_error)?;
+    let events = params.submit(&model, tokens, params.sampling.options(), cancel);
+    Ok((model, events))
+}
+
+async fn generate(State(state): State<AppState>, Json(req): Json<GenerateRequest>) -> axum::response::Response {
     state.requests_total.inc();
-    if !state.limiter.check_allow(&tenant_id()).await { return Json(GenerateResponse { text: String::from("RATE_LIMITED") }); }
+    if !state.limiter.check_allow(&tenant_id()).await { return rate_limited(); }
     tracing::info!(target: "api", "generate request");
     let start = std::time::Instant::now();
-    // update gauges from scheduler atomics
-    state.queue_depth_gauge.set(state.scheduler.queue_depth.load(std::sync::atomic::Ordering::Relaxed) as i64);
-    state.batch_size_gauge.set(state.scheduler.last_batch_size.load(std::sync::atomic::Ordering::Relaxed) as i64);
-    // KV metrics (approx)
-    state.kv_used_blocks.set(state.scheduler.kv.used_blocks() as i64);
-    state.kv_capacity_blocks.set(state.scheduler.kv.capacity_blocks() as i64);
-
-    let text = if let Some(model_path) = state.model_path.read().await.clone() {
-        // Try llama backend path with real decode if available
-        let llama = LlamaCppBackend::new();
-        if llama.load_model(&model_path, runner_backend::LoadParams::default()).is_ok() {
-            #[cfg(llama_ffi)]
-            {
-                let _ = model_path; // silence unused in cfg
-                // no streaming here; collect
-                llama.generate_with_callback(&req.prompt, req.max_tokens.unwrap_or(64), |_piece| {}).unwrap_or_default()
-            }
-            #[cfg(not(llama_ffi))]
-            { generate_once(state.backend.as_ref(), &req.prompt, req.max_tokens.unwrap_or(128)).unwrap_or_default() }
-        } else {
-            // Use scheduler (mock or other backend)
-            runner_core::scheduler::SchedulerV1::enqueue(&state.scheduler, req.prompt.clone(), req.max_tokens.unwrap_or(128)).await
+    let cancel = CancelToken::new();
+    let guard = CancelOnDrop::new(&state, &cancel);
+    let (_model, events) = match submit_generate(&state, &req, &cancel).await { Ok(s) => s, Err(resp) => return resp };
+    let result = collect(&state, events, start).await;
+    guard.disarm();
+    let text = match result {
+        Ok(done) => {
+            state.tokens_generated_total.inc_by(done.usage.completion_tokens as u64);
+            state.budgets.record(&tenant_id(), done.usage.completion_tokens as u64).await;
+            done.text
         }
-    } else {
-        runner_core::scheduler::SchedulerV1::enqueue(&state.scheduler, req.prompt.clone(), req.max_tokens.unwrap_or(128)).await
+        Err(e) => return generation_error(e),
     };
-    state.ttft_seconds.observe(start.elapsed().as_secs_f64());
-    // very rough tokenization proxy for mock: bytes → tokens
-    state.tokens_generated_total.inc_by(text.len() as u64);
-    state.budgets.record(&tenant_id(), text.len() as u64).await;
-    Json(GenerateResponse { text })
+    Json(GenerateResponse { text }).into_response()
+}
+
+/// What the streaming endpoints send about one generation: SSE events named
+/// after `type`, or WebSocket text messages.
+#[derive(serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StreamFrame {
+    Start { id: String },
+    Token { id: String, text: String },
+    Done { id: String, finish_reason: &'static str, usage: serde_json::Value },
+    /// `error` is the object an HTTP error response carries; `id` is unset
+    /// when the message that failed did not start a generation.
+    Error {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        error: serde_json::Value,
+    },
+}
+
+impl StreamFrame {
+    fn kind(&self) -> &'static str {
+        match self { Self::Start { .. } => "start", Self::Token { .. } => "token", Self::Done { .. } => "done", Self::Error { .. } => "error" }
+    }
+
+    async fn error(id: Option<String>, resp: axum::response::Response) -> Self {
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap_or_default();
+        let error = serde_json::from_slice::<serde_json::Value>(&body).ok().and_then(|mut v| v.get_mut("error").map(serde_json::Value::take));
+        Self::Error { id, error: error.unwrap_or_else(|| serde_json::json!({"message": String::from_utf8_lossy(&body), "type": "server_error"})) }
+    }
+}
+
+/// Id of a streamed generation the client did not name.
+fn generation_id() -> String {
+    static NEXT: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(1);
+    format!("gen-{}", NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed))
 }
 
-async fn generate_sse(State(state): State<AppState>) -> Sse<impl tokio_stream::Stream<Item = Result<Event>>> {
+/// One streamed generation's scheduler events as frames, recording TTFT and
+/// generated tokens on the way. Ends after `done` or `error`.
+struct Frames { id: String, events: EventStream, start: std::time::Instant, first: bool, ended: bool }
+
+impl Frames {
+    fn new(id: String, events: EventStream) -> Self { Self { id, events, start: std::time::Instant::now(), first: true, ended: false } }
+
+    async fn next(&mut self, state: &AppState) -> Option<StreamFrame> {
+        if self.ended { return None; }
+        let id = self.id.clone();
+        loop {
+            return Some(match self.events.recv().await {
+                Some(GenerationEvent::Queued { .. } | GenerationEvent::Logprob(_)) => continue,
+                Some(GenerationEvent::Started) => StreamFrame::Start { id },
+                Some(GenerationEvent::Token(text)) => {
+                    if self.first { state.ttft_seconds.observe(self.start.elapsed().as_secs_f64()); self.first = false; }
+                    StreamFrame::Token { id, text }
+                }
+                Some(GenerationEvent::Finished { finish_reason, usage }) => {
+                    self.ended = true;
+                    state.tokens_generated_total.inc_by(usage.completion_tokens as u64);
+                    StreamFrame::Done { id, finish_reason: finish_reason.as_str(), usage: usage_object(usage) }
+                }
+                Some(GenerationEvent::Error(e)) => { self.ended = true; StreamFrame::error(Some(id), generation_error(e)).await }
+                None => { self.ended = true; StreamFrame::error(Some(id), generation_error(String::from("generation aborted"))).await }
+            });
+        }
+    }
+}
+
+/// Wait until the scheduler admits a request, so that a rejection can still
+/// be answered with its status before a stream starts.
+async fn admitted(events: &mut EventStream) -> std::result::Result<(), axum::response::Response> {
+    loop {
+        match events.recv().await {
+            Some(GenerationEvent::Started) => return Ok(()),
+            Some(GenerationEvent::Error(e)) => return Err(generation_error(e)),
+            Some(_) => {}
+            None => return Err(generation_error(String::from("generation aborted"))),
+        }
+    }
+}
+
+/// `POST /sse/generate`: a generation request streamed as `start`, `token`
+/// and `done` (or `error`) events with JSON frames.
+async fn generate_sse(State(state): State<AppState>, Json(req): Json<GenerateRequest>) -> axum::response::Response {
     state.requests_total.inc();
-    let (tx, rx) = tokio::sync::mpsc::channel(16);
-    let start = std::time::Instant::now();
+    if !state.limiter.check_allow(&tenant_id()).await { return rate_limited(); }
+    let cancel = CancelToken::new();
+    let guard = CancelOnDrop::new(&state, &cancel);
+    let (model, mut events) = match submit_generate(&state, &req, &cancel).await { Ok(s) => s, Err(resp) => return resp };
+    if let Err(resp) = admitted(&mut events).await { return resp; }
+    guard.disarm();
+    let (tx, rx) = tokio::sync::mpsc::channel::<Result<Event>>(16);
     tokio::spawn(async move {
-        if let Ok(model_path) = std::env::var("RUNNER_MODEL") {
-            let llama = LlamaCppBackend::new();
-            if llama.load_model(&model_path, runner_backend::LoadParams::default()).is_ok() {
-                #[cfg(llama_ffi)]
-                {
-                    let mut emit = |piece: String| {
-                        let _ = tx.blocking_send(Ok(Event::default().data(piece)));
-                    };
-                    let _ = llama.generate_with_callback("", 0, |_| {}); // ensure symbols
-                    // Generate from a default prompt for SSE test
-                    let _ = llama.generate_with_callback("Hello", 64, &mut emit);
-                }
-                #[cfg(not(llama_ffi))]
-                {
-                    let _ = tx.send(Ok(Event::default().data("ffi disabled"))).await;
+        let _model = model;
+        let id = generation_id();
+        let mut frames = Frames::new(id.clone(), events);
+        let mut frame = Some(StreamFrame::Start { id });
+        while let Some(f) = frame {
+            let event = Event::default().event(f.kind()).data(serde_json::to_string(&f).unwrap_or_default());
+            if tx.send(Ok(event)).await.is_err() { state.cancel(&cancel); return; }
+            frame = frames.next(&state).await;
+        }
+    });
+    Sse::new(ReceiverStream::new(rx)).into_response()
+}
+
+/// Messages a WebSocket client sends.
+#[derive(serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientFrame {
+    /// Start a generation, named `id` in the frames about it; one is assigned when unset.
+    Generate {
+        id: Option<String>,
+        #[serde(flatten)]
+        request: GenerateRequest,
+    },
+    /// Stop the generation named `id`, or every running one without it.
+    Cancel { id: Option<String> },
+}
+
+async fn ws_generate(State(state): State<AppState>, ws: WebSocketUpgrade) -> impl IntoResponse {
+    ws.on_upgrade(|socket| ws_session(state, socket))
+}
+
+/// One WebSocket connection: any number of generations, overlapping or not,
+/// each streamed as frames tagged with its id. Closing the socket cancels
+/// whatever is still running.
+async fn ws_session(state: AppState, mut socket: WebSocket) {
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<StreamFrame>();
+    let mut running: HashMap<String, CancelToken> = HashMap::new();
+    loop {
+        tokio::select! {
+            Some(frame) = rx.recv() => {
+                if let StreamFrame::Done { id, .. } | StreamFrame::Error { id: Some(id), .. } = &frame { running.remove(id); }
+                if socket.send(Message::Text(serde_json::to_string(&frame).unwrap_or_default())).await.is_err() { break; }
+            }
+            msg = socket.recv() => {
+                let text = match msg {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                };
+                match serde_json::from_str::<ClientFrame>(&text) {
+                    Ok(ClientFrame::Generate { id, request }) => {
+                        let id = id.unwrap_or_else(generation_id);
+                        if running.contains_key(&id) {
+                            let _ = tx.send(StreamFrame::Error { id: None, error: serde_json::json!({"message": format!("generation `{id}` is already running"), "type": "invalid_request_error"}) });
+                            continue;
+                        }
+                        let cancel = CancelToken::new();
+                        running.insert(id.clone(), cancel.clone());
+                        tokio::spawn(ws_generation(state.clone(), id, request, cancel, tx.clone()));
+                    }
+                    Ok(ClientFrame::Cancel { id }) => {
+                        running.iter().filter(|(k, _)| id.as_ref().is_none_or(|id| id == *k)).for_each(|(_, token)| state.cancel(token));
+                    }
+                    Err(e) => { let _ = tx.send(StreamFrame::Error { id: None, error: serde_json::json!({"message": e.to_string(), "type": "invalid_request_error"}) }); }
                 }
-            } else {
-                let _ = tx.send(Ok(Event::default().data("model load failed"))).await;
             }
-        } else {
-            // fallback demo
-            let tokens = ["hello", " ", "world", "!\n"];
-            for t in tokens { let _ = tx.send(Ok(Event::default().data(t))).await; }
         }
-    });
-    let stream = ReceiverStream::new(rx).map(|e| e);
-    state.ttft_seconds.observe(start.elapsed().as_secs_f64());
-    Sse::new(stream)
-}
-
-async fn ws_generate(ws: WebSocketUpgrade) -> impl IntoResponse {
-    ws.on_upgrade(|mut socket| async move {
-        let _ = socket.send(Message::Text("hello".into())).await;
-        let _ = socket.send(Message::Text(" ".into())).await;
-        let _ = socket.send(Message::Text("world".into())).await;
-        let _ = socket.send(Message::Text("!".into())).await;
-        let _ = socket.close().await;
-    })
+    }
+    running.values().for_each(|token| state.cancel(token));
+}
+
+async fn ws_generation(state: AppState, id: String, req: GenerateRequest, cancel: CancelToken, tx: tokio::sync::mpsc::UnboundedSender<StreamFrame>) {
+    state.requests_total.inc();
+    if !state.limiter.check_allow(&tenant_id()).await { let _ = tx.send(StreamFrame::error(Some(id), rate_limited()).await); return; }
+    let (_model, events) = match submit_generate(&state, &req, &cancel).await {
+        Ok(s) => s,
+        Err(resp) => { let _ = tx.send(StreamFrame::error(Some(id), resp).await); return; }
+    };
+    let mut frames = Frames::new(id, events);
+    while let Some(frame) = frames.next(&state).await {
+        if tx.send(frame).is_err() { state.cancel(&cancel); return; }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct TokenizeRequest {
+    model: Option<String>,
+    content: String,
+    /// Add the tokens a sequence starts with, such as BOS.
+    #[serde(default)]
+    add_special: bool,
+}
+
+#[derive(serde::Deserialize)]
+struct DetokenizeRequest {
+    model: Option<String>,
+    tokens: Vec<u32>,
+}
+
+/// Token ids for `content` with the model's tokenizer; the model is not loaded.
+async fn tokenize(State(state): State<AppState>, Json(req): Json<TokenizeRequest>) -> axum::response::Response {
+    let tokenizer = match state.registry.tokenizer(req.model.as_deref()).await { Ok(t) => t, Err(e) => return model_error(e) };
+    match tokenizer.encode(&req.content, req.add_special) {
+        Ok(tokens) => Json(serde_json::json!({"tokens": tokens, "count": tokens.len()})).into_response(),
+        Err(e) => tokenize_error(e),
+    }
+}
+
+async fn detokenize(State(state): State<AppState>, Json(req): Json<DetokenizeRequest>) -> axum::response::Response {
+    let tokenizer = match state.registry.tokenizer(req.model.as_deref()).await { Ok(t) => t, Err(e) => return model_error(e) };
+    match tokenizer.validate(&req.tokens).and_then(|()| tokenizer.decode(&req.tokens, false)) {
+        Ok(content) => Json(serde_json::json!({"content": content})).into_response(),
+        Err(e) => tokenize_error(e),
+    }
+}
+
+/// A [`ModelInfo`] in the shape of OpenAI's model object.
+#[derive(serde::Serialize)]
+struct ModelObject {
+    object: &'static str,
+    owned_by: &'static str,
+    #[serde(flatten)]
+    info: ModelInfo,
+}
+
+impl From<ModelInfo> for ModelObject {
+    fn from(info: ModelInfo) -> Self { Self { object: "model", owned_by: "runner", info } }
+}
+
+/// Every model the server can route to, loaded or not, including model files
+/// added to `model_dir` since startup.
+async fn list_models(State(state): State<AppState>) -> axum::response::Response {
+    let registry = state.registry.clone();
+    let models = tokio::task::spawn_blocking(move || {
+        registry.discover();
+        registry.specs().iter().filter_map(|s| registry.describe(&s.name)).map(ModelObject::from).collect::<Vec<_>>()
+    }).await;
+    match models {
+        Ok(data) => Json(serde_json::json!({"object": "list", "data": data})).into_response(),
+        Err(e) => model_error(RegistryError::Load { model: String::new(), reason: e.to_string() }),
+    }
+}
+
+/// One model, by name or alias.
+async fn get_model(State(state): State<AppState>, Path(id): Path<String>) -> axum::response::Response {
+    let registry = state.registry.clone();
+    let model = tokio::task::spawn_blocking(move || {
+        let name = registry.resolve(Some(&id)).or_else(|_| { registry.discover(); registry.resolve(Some(&id)) })?;
+        registry.describe(&name).ok_or(RegistryError::NotFound(id))
+    }).await.unwrap_or_else(|e| Err(RegistryError::Load { model: String::new(), reason: e.to_string() }));
+    match model {
+        Ok(info) => Json(ModelObject::from(info)).into_response(),
+        Err(e) => model_error(e),
+    }
 }
 
 async fn openapi() -> impl IntoResponse {
@@ -220,39 +735,76 @@ async fn openapi() -> impl IntoResponse {
         "paths": {
             "/generate": {"post": {"summary": "Generate text"}},
             "/v1/chat/completions": {"post": {"summary": "OpenAI chat subset"}},
-            "/sse/generate": {"get": {"summary": "SSE stream demo"}},
-            "/ws/generate": {"get": {"summary": "WebSocket stream demo"}},
+            "/v1/completions": {"post": {"summary": "OpenAI text completions"}},
+            "/v1/models": {"get": {"summary": "Loaded and available models"}},
+            "/v1/models/{id}": {"get": {"summary": "One model, by name or alias"}},
+            "/tokenize": {"post": {"summary": "Token ids for text, with the model's tokenizer"}},
+            "/detokenize": {"post": {"summary": "Text for token ids"}},
+            "/sse/generate": {"post": {"summary": "Stream a generation as server-sent events"}},
+            "/ws/generate": {"get": {"summary": "Run and cancel generations over a WebSocket"}},
             "/metrics": {"get": {"summary": "Prometheus metrics"}},
             "/healthz": {"get": {"summary": "health"}},
-            "/readyz": {"get": {"summary": "readiness"}},
-            "/admin/set_model": {"post": {"summary": "Hot load model"}}
+            "/readyz": {"get": {"summary": "Ready once the preloaded models passed warmup"}},
+            "/v1/sessions": {"post": {"summary": "Open a conversation session"}},
+            "/v1/sessions/{id}": {"get": {"summary": "Session info"}, "delete": {"summary": "Close a session"}},
+            "/v1/sessions/{id}/messages": {"post": {"summary": "Append a message to a session"}},
+            "/v1/sessions/{id}/generate": {"post": {"summary": "Generate the assistant's next turn"}},
+            "/admin/set_model": {"post": {"summary": "Hot swap the default model"}},
+            "/admin/model_status": {"get": {"summary": "Progress of the last model swap"}}
         }
     });
     Json(spec)
 }
 
-#[derive(serde::Deserialize)]
-struct ChatMessage {
-    role: String,
-    content: String,
-}
-
 #[derive(serde::Deserialize)]
 struct ChatRequest {
-    #[allow(dead_code)]
+    /// Model name or alias; the default model when unset.
     model: Option<String>,
     messages: Vec<ChatMessage>,
     stream: Option<bool>,
-    #[allow(dead_code)]
+    stream_options: Option<StreamOptions>,
     max_tokens: Option<usize>,
-    #[allow(dead_code)]
-    temperature: Option<f32>,
-    #[allow(dead_code)]
-    top_p: Option<f32>,
+    #[serde(flatten)]
+    sampling: SamplingParams,
+    /// `reject` (default), `truncate` (drop the oldest messages) or `shift`.
+    context_overflow: Option<ContextOverflow>,
+    /// How long the model stays loaded after this request, e.g. `"10m"`, `-1` or `0`.
+    keep_alive: Option<KeepAlive>,
+    /// Functions the model may call.
+    #[serde(default)]
+    tools: Vec<Tool>,
+    #[serde(default)]
+    tool_choice: ToolChoice,
+}
+
+#[derive(serde::Deserialize)]
+struct StreamOptions {
+    /// Send a last chunk with the request's token usage.
+    #[serde(default)]
+    include_usage: bool,
+}
+
+impl ChatRequest {
+    /// The tools offered to the model, none under `tool_choice: none`.
+    fn offered_tools(&self) -> &[Tool] { if self.tool_choice == ToolChoice::None { &[] } else { &self.tools } }
+
+    /// A `tool_choice` naming a function that is not among `tools`, or forcing a call without any.
+    fn tool_choice_error(&self) -> Option<String> {
+        match &self.tool_choice {
+            ToolChoice::Function(name) if !self.tools.iter().any(|t| &t.function.name == name) => Some(format!("tool_choice names `{name}`, which is not in tools")),
+            ToolChoice::Required if self.tools.is_empty() => Some("tool_choice `required` needs tools".into()),
+            _ => None,
+        }
+    }
 }
 
 #[derive(serde::Serialize)]
-struct ChatChoiceMessage { role: String, content: String }
+struct ChatChoiceMessage {
+    role: String,
+    content: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<ToolCall>,
+}
 
 #[derive(serde::Serialize)]
 struct ChatChoice { index: u32, message: ChatChoiceMessage, finish_reason: String }
@@ -261,84 +813,616 @@ struct ChatChoice { index: u32, message: ChatChoiceMessage, finish_reason: Strin
 struct ChatResponse {
     id: String,
     object: String,
+    created: u64,
+    model: String,
     choices: Vec<ChatChoice>,
 }
 
+/// Id (`prefix-...`) and creation time of a completion, shared by all its stream chunks.
+fn completion_id(prefix: &str) -> (String, u64) {
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+    (format!("{prefix}-{:x}", now.as_nanos()), now.as_secs())
+}
+
+fn usage_object(usage: Usage) -> serde_json::Value {
+    serde_json::json!({"prompt_tokens": usage.prompt_tokens, "completion_tokens": usage.completion_tokens, "total_tokens": usage.prompt_tokens + usage.completion_tokens})
+}
+
 async fn chat_completions(State(state): State<AppState>, Json(req): Json<ChatRequest>) -> axum::response::Response {
     state.requests_total.inc();
-    if !state.limiter.check_allow(&tenant_id()).await {
-        let resp = ChatResponse { id: "rate-limited".into(), object: "chat.completion".into(), choices: vec![ChatChoice { index: 0, message: ChatChoiceMessage { role: "assistant".into(), content: String::from("RATE_LIMITED") }, finish_reason: "stop".into() }] };
-        return Json(resp).into_response();
-    }
+    if !state.limiter.check_allow(&tenant_id()).await { return rate_limited(); }
     tracing::info!(target: "api", "chat request: {} messages", req.messages.len());
-    // Build a simple chat-style prompt to avoid echoing behavior
-    let mut prompt = String::new();
-    prompt.push_str("System: You are a helpful assistant.\n");
-    for m in &req.messages {
-        if m.role == "system" {
-            prompt.push_str("System: "); prompt.push_str(&m.content); prompt.push('\n');
-        } else if m.role == "user" {
-            prompt.push_str("User: "); prompt.push_str(&m.content); prompt.push('\n');
+    if let Err(e) = state.registry.resolve(req.model.as_deref()) { return model_error(e); }
+    if let Some(e) = req.tool_choice_error() { return invalid_request(e, "tool_choice"); }
+    if let Some((e, param)) = req.sampling.error() { return invalid_request(e, param); }
+    let cancel = CancelToken::new();
+    let guard = CancelOnDrop::new(&state, &cancel);
+    let scheduler = match state.model(req.model.as_deref(), req.keep_alive).await { Ok(m) => m, Err(resp) => return resp };
+    let max_tokens = req.max_tokens.unwrap_or(128);
+    let overflow = req.context_overflow.unwrap_or_default();
+    let tools = req.offered_tools();
+    let format = scheduler.template.tool_format;
+    // a forced call is prefilled up to its arguments; output that still does not parse as one is an error
+    let prefill = match &req.tool_choice {
+        ToolChoice::Function(name) => format.call_prefix(Some(name)),
+        ToolChoice::Required => format.call_prefix(None),
+        ToolChoice::None | ToolChoice::Auto => String::new(),
+    };
+    let (prompt, keep) = match chat_prompt(&scheduler, &req.messages, tools, &prefill, max_tokens, overflow) {
+        Ok(p) => p,
+        Err(e) => return tokenize_error(e),
+    };
+    let events = SchedulerV1::submit_with(&scheduler, prompt, max_tokens, ContextPolicy { overflow, keep }, req.sampling.options(), cancel.clone());
+    if req.stream.unwrap_or(false) { return chat_completions_stream(state, req, scheduler, events, prefill, cancel, guard).await; }
+    let result = collect(&state, events, std::time::Instant::now()).await;
+    guard.disarm();
+    let (content, finish_reason) = match result {
+        Ok(done) => {
+            state.tokens_generated_total.inc_by(done.usage.completion_tokens as u64);
+            (prefill + &done.text, done.finish_reason.as_str())
         }
+        // as the streaming reply does when the request is not admitted
+        Err(e) => return generation_error(e),
+    };
+    let (content, tool_calls) = if tools.is_empty() { (Some(content), Vec::new()) } else { format.parse(&content, tools) };
+    if req.tool_choice.forces_call() && tool_calls.is_empty() { return forced_call_error(); }
+    let finish_reason = if tool_calls.is_empty() { finish_reason } else { "tool_calls" };
+    let message = ChatChoiceMessage { role: "assistant".into(), content, tool_calls };
+    let (id, created) = completion_id("chatcmpl");
+    let resp = ChatResponse { id, object: "chat.completion".into(), created, model: scheduler.model.clone(), choices: vec![ChatChoice { index: 0, message, finish_reason: finish_reason.into() }] };
+    Json(resp).into_response()
+}
+
+/// `stream=true`: `chat.completion.chunk` deltas as tokens arrive, opened by
+/// the assistant's role and closed by the finish reason, usage if asked for,
+/// and `[DONE]`. The response waits until the scheduler admits the request,
+/// so a rejection still gets its status. With tools offered the text is held
+/// back until it can be parsed, then sent as content or `tool_calls`.
+async fn chat_completions_stream(state: AppState, req: ChatRequest, model: Lease, mut events: EventStream, prefill: String, cancel: CancelToken, guard: CancelOnDrop) -> axum::response::Response {
+    let start = std::time::Instant::now();
+    if let Err(resp) = admitted(&mut events).await { return resp; }
+    guard.disarm();
+    let (tx, rx) = tokio::sync::mpsc::channel::<Result<Event>>(32);
+    tokio::spawn(async move {
+        let (id, created) = completion_id("chatcmpl");
+        let include_usage = req.stream_options.as_ref().is_some_and(|o| o.include_usage);
+        let chunk = |choices: serde_json::Value| {
+            let mut c = serde_json::json!({"id": id, "object": "chat.completion.chunk", "created": created, "model": model.model, "choices": choices});
+            if include_usage { c["usage"] = serde_json::Value::Null; }
+            c
+        };
+        let delta = |delta: serde_json::Value, finish_reason: Option<&str>| chunk(serde_json::json!([{"index": 0, "delta": delta, "finish_reason": finish_reason}]));
+        let tools = req.offered_tools();
+        let mut held = prefill;
+        let mut first = true;
+        let mut frames = vec![delta(serde_json::json!({"role": "assistant", "content": ""}), None)];
+        loop {
+            for frame in frames.drain(..) {
+                if tx.send(Ok(Event::default().data(frame.to_string()))).await.is_err() { state.cancel(&cancel); return; }
+            }
+            let Some(ev) = events.recv().await else { break };
+            match ev {
+                GenerationEvent::Token(t) => {
+                    if first { state.ttft_seconds.observe(start.elapsed().as_secs_f64()); first = false; }
+                    if tools.is_empty() { frames.push(delta(serde_json::json!({"content": t}), None)) } else { held.push_str(&t) }
+                }
+                GenerationEvent::Finished { finish_reason, usage } => {
+                    state.tokens_generated_total.inc_by(usage.completion_tokens as u64);
+                    let mut finish_reason = finish_reason.as_str();
+                    if !tools.is_empty() {
+                        let (content, calls) = model.template.tool_format.parse(&held, tools);
+                        if req.tool_choice.forces_call() && calls.is_empty() {
+                            frames.push(serde_json::json!({"error": {"message": FORCED_CALL_UNPARSED, "type": "server_error"}}));
+                            continue;
+                        }
+                        if let Some(content) = content { frames.push(delta(serde_json::json!({"content": content}), None)); }
+                        if !calls.is_empty() {
+                            finish_reason = "tool_calls";
+                            let calls: Vec<_> = calls.iter().enumerate().map(|(i, c)| {
+                                let mut v = serde_json::to_value(c).unwrap_or_default();
+                                v["index"] = i.into();
+                                v
+                            }).collect();
+                            frames.push(delta(serde_json::json!({"tool_calls": calls}), None));
+                        }
+                    }
+                    frames.push(delta(serde_json::json!({}), Some(finish_reason)));
+                    if include_usage {
+                        let mut last = chunk(serde_json::json!([]));
+                        last["usage"] = usage_object(usage);
+                        frames.push(last);
+                    }
+                }
+                GenerationEvent::Error(e) => frames.push(serde_json::json!({"error": {"message": e, "type": "server_error"}})),
+                GenerationEvent::Queued { .. } | GenerationEvent::Started | GenerationEvent::Logprob(_) => {}
+            }
+        }
+        let _ = tx.send(Ok(Event::default().data("[DONE]"))).await;
+    });
+    Sse::new(ReceiverStream::new(rx)).into_response()
+}
+
+/// A `prompt` as text or as token ids.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum Prompt {
+    Text(String),
+    Tokens(Vec<u32>),
+}
+
+/// One prompt or a batch of them, each completed on its own.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum Prompts {
+    One(Prompt),
+    Many(Vec<Prompt>),
+}
+
+/// Most completions one request may ask for, over all its prompts.
+const MAX_CHOICES: usize = 16;
+
+#[derive(serde::Deserialize)]
+struct CompletionRequest {
+    prompt: Prompts,
+    /// Text after the insertion point, for models trained to fill in the middle.
+    suffix: Option<String>,
+    /// Return the prompt ahead of its completion.
+    #[serde(default)]
+    echo: bool,
+    /// Log-probability of each token and of up to 5 likeliest alternatives.
+    logprobs: Option<usize>,
+    /// Completions per prompt.
+    n: Option<usize>,
+    stream: Option<bool>,
+    stream_options: Option<StreamOptions>,
+    #[serde(flatten)]
+    params: GenerationParams,
+}
+
+/// One prompt ready to complete: the tokens generation starts from, and the
+/// text and tokens `echo` returns.
+struct PreparedPrompt { tokens: Vec<u32>, echo: String, echo_tokens: Vec<TokenLogprob> }
+
+impl CompletionRequest {
+    fn prompts(&self) -> Vec<&Prompt> {
+        match &self.prompt { Prompts::One(p) => vec![p], Prompts::Many(ps) => ps.iter().collect() }
+    }
+
+    fn n(&self) -> usize { self.n.unwrap_or(1) }
+
+    /// A setting out of range, with the parameter it came from.
+    fn error(&self) -> Option<(String, &'static str)> {
+        let prompts = self.prompts();
+        if prompts.is_empty() || prompts.iter().any(|p| matches!(p, Prompt::Tokens(t) if t.is_empty())) { return Some(("prompt is empty".into(), "prompt")); }
+        if self.n() == 0 || self.n() * prompts.len() > MAX_CHOICES {
+            return Some((format!("{} prompts with n={} ask for more than {MAX_CHOICES} completions", prompts.len(), self.n()), "n"));
+        }
+        if self.logprobs.is_some_and(|k| k > 5) { return Some(("logprobs is at most 5".into(), "logprobs")); }
+        self.params.sampling.error()
+    }
+
+    /// Tokens for `prompt`, which a `suffix` wraps in the model's fill-in-the-middle markers.
+    fn prepare(&self, tokenizer: &dyn Tokenizer, prompt: &Prompt) -> Result<PreparedPrompt> {
+        let (echo, prefix) = match prompt {
+            Prompt::Text(text) => (text.clone(), tokenizer.encode(text, false)?),
+            Prompt::Tokens(tokens) => { tokenizer.validate(tokens)?; (tokenizer.decode(tokens, true)?, tokens.clone()) }
+        };
+        let echo_tokens = prefix.iter().map(|&token| TokenLogprob { token, logprob: None, top: Vec::new() }).collect();
+        let tokens = match (&self.suffix, prompt) {
+            (None, Prompt::Text(text)) => tokenizer.encode(text, true)?,
+            (None, Prompt::Tokens(tokens)) => tokens.clone(),
+            (Some(suffix), _) => {
+                let [pre, suf, mid] = tokenizer.fim_tokens().ok_or_else(|| RunnerError::Message("no fill-in-the-middle tokens".into()))?;
+                let suffix = tokenizer.encode(suffix, false)?;
+                let start = tokenizer.encode("", true)?;
+                [start, vec![pre], prefix, vec![suf], suffix, vec![mid]].concat()
+            }
+        };
+        Ok(PreparedPrompt { tokens, echo, echo_tokens })
+    }
+}
+
+/// OpenAI's `logprobs` object for `entries`, the first of which starts `offset`
+/// characters into the choice's text. Entries starting at or past `end` are left
+/// out; `offset` ends up past the ones kept.
+fn logprobs_object(tokenizer: &dyn Tokenizer, entries: &[TokenLogprob], offset: &mut usize, end: usize) -> serde_json::Value {
+    let text = |t: u32| tokenizer.decode(&[t], false).unwrap_or_default();
+    let (mut tokens, mut logprobs, mut top, mut offsets) = (Vec::new(), Vec::new(), Vec::new(), Vec::new());
+    for e in entries {
+        if *offset >= end { break; }
+        let token = text(e.token);
+        offsets.push(*offset);
+        *offset += token.chars().count();
+        tokens.push(token);
+        logprobs.push(e.logprob);
+        top.push(e.logprob.map(|_| e.top.iter().map(|&(t, lp)| (text(t), lp.into())).collect::<serde_json::Map<_, _>>()));
+    }
+    serde_json::json!({"tokens": tokens, "token_logprobs": logprobs, "top_logprobs": top, "text_offset": offsets})
+}
+
+#[derive(serde::Serialize)]
+struct CompletionChoice { text: String, index: usize, logprobs: Option<serde_json::Value>, finish_reason: &'static str }
+
+#[derive(serde::Serialize)]
+struct CompletionResponse { id: String, object: &'static str, created: u64, model: String, choices: Vec<CompletionChoice>, usage: serde_json::Value }
+
+/// OpenAI's legacy text completions, on the same pipeline as `/generate`.
+/// Choice `i` completes prompt `i / n`.
+async fn completions(State(state): State<AppState>, Json(req): Json<CompletionRequest>) -> axum::response::Response {
+    state.requests_total.inc();
+    if !state.limiter.check_allow(&tenant_id()).await { return rate_limited(); }
+    if let Some((e, param)) = req.error() { return invalid_request(e, param); }
+    tracing::info!(target: "api", "completion request: {} prompts, n={}", req.prompts().len(), req.n());
+    let cancel = CancelToken::new();
+    let guard = CancelOnDrop::new(&state, &cancel);
+    let model = match state.model(req.params.model.as_deref(), req.params.keep_alive).await { Ok(m) => m, Err(resp) => return resp };
+    state.refresh_gauges();
+    if req.suffix.is_some() && model.tokenizer.fim_tokens().is_none() {
+        return invalid_request(format!("{} has no fill-in-the-middle tokens for suffix", model.model), "suffix");
     }
-    prompt.push_str("Assistant: ");
+    let mut prompts = Vec::new();
+    for prompt in req.prompts() {
+        match req.prepare(model.tokenizer.as_ref(), prompt) { Ok(p) => prompts.push(p), Err(e) => return tokenize_error(e) }
+    }
+    let options = DecodeOptions { logprobs: req.logprobs, ..req.params.sampling.options() };
+    let mut streams: Vec<EventStream> = prompts.iter()
+        .flat_map(|p| std::iter::repeat_n(p, req.n()))
+        .map(|p| req.params.submit(&model, p.tokens.clone(), options.clone(), &cancel))
+        .collect();
     if req.stream.unwrap_or(false) {
-        return chat_completions_stream(axum::extract::State(state), Json(req)).await.into_response();
-    }
-    // Prefer llama.cpp backend when a model path is configured
-    let text = if let Some(model_path) = state.model_path.read().await.clone() {
-        let llama = LlamaCppBackend::new();
-        if llama.load_model(&model_path, runner_backend::LoadParams::default()).is_ok() {
-            #[cfg(llama_ffi)]
-            {
-                let mut acc = String::new();
-                let _ = llama.generate_with_callback(&prompt, req.max_tokens.unwrap_or(128), |piece| { acc.push_str(&piece); });
-                acc
+        for events in &mut streams {
+            if let Err(resp) = admitted(events).await {
+                // a rejection is not a cancellation; the other choices are stopped without counting one
+                guard.disarm();
+                cancel.cancel();
+                return resp;
             }
-            #[cfg(not(llama_ffi))]
-            { generate_once(state.backend.as_ref(), &prompt, req.max_tokens.unwrap_or(128)).unwrap_or_else(|_| String::new()) }
-        } else {
-            runner_core::scheduler::SchedulerV1::enqueue(&state.scheduler, prompt.clone(), req.max_tokens.unwrap_or(128)).await
         }
-    } else {
-        runner_core::scheduler::SchedulerV1::enqueue(&state.scheduler, prompt.clone(), req.max_tokens.unwrap_or(128)).await
-    };
-    let resp = ChatResponse { id: "chatcmpl-1".into(), object: "chat.completion".into(), choices: vec![ChatChoice { index: 0, message: ChatChoiceMessage { role: "assistant".into(), content: text }, finish_reason: "stop".into() }] };
-    Json(resp).into_response()
+        guard.disarm();
+        return completions_stream(state, req, model, prompts, streams, cancel);
+    }
+    let start = std::time::Instant::now();
+    let (mut choices, mut usage) = (Vec::new(), Usage::default());
+    for (index, events) in streams.into_iter().enumerate() {
+        let done = match collect(&state, events, start).await {
+            Ok(done) => done,
+            Err(e) => {
+                guard.disarm();
+                cancel.cancel();
+                return generation_error(e);
+            }
+        };
+        state.tokens_generated_total.inc_by(done.usage.completion_tokens as u64);
+        if index % req.n() == 0 { usage.prompt_tokens += done.usage.prompt_tokens; }
+        usage.completion_tokens += done.usage.completion_tokens;
+        let prompt = &prompts[index / req.n()];
+        let text = if req.echo { prompt.echo.clone() + &done.text } else { done.text };
+        let logprobs = req.logprobs.map(|_| {
+            let (end, mut offset) = (text.chars().count(), 0);
+            let echoed = if req.echo { &prompt.echo_tokens[..] } else { &[] };
+            logprobs_object(model.tokenizer.as_ref(), &[echoed, &done.logprobs].concat(), &mut offset, end)
+        });
+        choices.push(CompletionChoice { text, index, logprobs, finish_reason: done.finish_reason.as_str() });
+    }
+    guard.disarm();
+    let (id, created) = completion_id("cmpl");
+    Json(CompletionResponse { id, object: "text_completion", created, model: model.model.clone(), choices, usage: usage_object(usage) }).into_response()
 }
 
-// Streamed chat (OpenAI-style) when stream=true
-async fn chat_completions_stream(State(state): State<AppState>, Json(_req): Json<ChatRequest>) -> Sse<impl tokio_stream::Stream<Item = Result<Event>>> {
-    state.requests_total.inc();
-    let (tx, rx) = tokio::sync::mpsc::channel(32);
+/// `stream=true`: `text_completion` chunks as the choices' tokens arrive, each
+/// choice's finish reason in its last one, then usage if asked for and `[DONE]`.
+/// With logprobs, text is sent along with the log-probabilities of its tokens.
+fn completions_stream(state: AppState, req: CompletionRequest, model: Lease, prompts: Vec<PreparedPrompt>, streams: Vec<EventStream>, cancel: CancelToken) -> axum::response::Response {
+    let start = std::time::Instant::now();
+    let choices = streams.len();
+    let (merged_tx, mut merged) = tokio::sync::mpsc::unbounded_channel();
+    for (index, mut events) in streams.into_iter().enumerate() {
+        let merged_tx = merged_tx.clone();
+        tokio::spawn(async move {
+            while let Some(ev) = events.recv().await {
+                if merged_tx.send((index, ev)).is_err() { break; }
+            }
+        });
+    }
+    drop(merged_tx);
+    let (tx, rx) = tokio::sync::mpsc::channel::<Result<Event>>(32);
     tokio::spawn(async move {
-        let id = "chatcmpl-stream-1";
-        // Minimal prompt to delta frames demo
-        let tokens = ["H", "e", "l", "l", "o"];
-        for t in tokens {
-            let frame = serde_json::json!({
-                "id": id,
-                "object": "chat.completion.chunk",
-                "choices": [{
-                    "index": 0,
-                    "delta": {"content": t},
-                    "finish_reason": null
-                }]
-            });
-            let _ = tx.send(Ok(Event::default().data(frame.to_string()))).await;
+        let (id, created) = completion_id("cmpl");
+        let include_usage = req.stream_options.as_ref().is_some_and(|o| o.include_usage);
+        let tokenizer = model.tokenizer.as_ref();
+        let chunk = |choices: serde_json::Value| {
+            let mut c = serde_json::json!({"id": id, "object": "text_completion", "created": created, "model": model.model, "choices": choices});
+            if include_usage { c["usage"] = serde_json::Value::Null; }
+            c
+        };
+        let choice = |index: usize, text: &str, logprobs: Option<serde_json::Value>, finish_reason: Option<&str>| {
+            chunk(serde_json::json!([{"text": text, "index": index, "logprobs": logprobs, "finish_reason": finish_reason}]))
+        };
+        // text not yet sent, and where the next token starts, per choice
+        let (mut pending, mut offsets) = (vec![String::new(); choices], vec![0; choices]);
+        let mut frames = Vec::new();
+        if req.echo {
+            for (index, offset) in offsets.iter_mut().enumerate() {
+                let prompt = &prompts[index / req.n()];
+                let logprobs = req.logprobs.map(|_| logprobs_object(tokenizer, &prompt.echo_tokens, offset, usize::MAX));
+                frames.push(choice(index, &prompt.echo, logprobs, None));
+            }
+        }
+        let (mut usage, mut first) = (Usage::default(), true);
+        loop {
+            for frame in frames.drain(..) {
+                if tx.send(Ok(Event::default().data(frame.to_string()))).await.is_err() { state.cancel(&cancel); return; }
+            }
+            let Some((index, ev)) = merged.recv().await else { break };
+            match ev {
+                GenerationEvent::Token(t) => {
+                    if first { state.ttft_seconds.observe(start.elapsed().as_secs_f64()); first = false; }
+                    if req.logprobs.is_some() { pending[index].push_str(&t) } else { frames.push(choice(index, &t, None, None)) }
+                }
+                GenerationEvent::Logprob(lp) => {
+                    let logprobs = logprobs_object(tokenizer, &[lp], &mut offsets[index], usize::MAX);
+                    frames.push(choice(index, &std::mem::take(&mut pending[index]), Some(logprobs), None));
+                }
+                GenerationEvent::Finished { finish_reason, usage: u } => {
+                    state.tokens_generated_total.inc_by(u.completion_tokens as u64);
+                    if index % req.n() == 0 { usage.prompt_tokens += u.prompt_tokens; }
+                    usage.completion_tokens += u.completion_tokens;
+                    frames.push(choice(index, &std::mem::take(&mut pending[index]), None, Some(finish_reason.as_str())));
+                }
+                GenerationEvent::Error(e) => frames.push(serde_json::json!({"error": {"message": e, "type": "server_error"}})),
+                GenerationEvent::Queued { .. } | GenerationEvent::Started => {}
+            }
+        }
+        if include_usage {
+            let mut last = chunk(serde_json::json!([]));
+            last["usage"] = usage_object(usage);
+            let _ = tx.send(Ok(Event::default().data(last.to_string()))).await;
         }
         let _ = tx.send(Ok(Event::default().data("[DONE]"))).await;
     });
-    let stream = ReceiverStream::new(rx).map(|e| e);
-    Sse::new(stream)
+    Sse::new(ReceiverStream::new(rx)).into_response()
+}
+
+/// Tokenized chat prompt, rendered with the model's chat template and
+/// offering `tools`, with the assistant's turn opened by `prefill`. Under
+/// `Truncate` the oldest non-system messages are dropped, keeping at least the
+/// last one, until the prompt and `max_tokens` fit the model's window. Also
+/// returns how many leading tokens hold the system messages, which a `Shift` keeps.
+fn chat_prompt(model: &Lease, messages: &[ChatMessage], tools: &[Tool], prefill: &str, max_tokens: usize, overflow: ContextOverflow) -> Result<(Vec<u32>, usize)> {
+    let (template, tokenizer) = (&model.template, model.tokenizer.as_ref());
+    let build = |drop: usize| {
+        let mut dropped = 0;
+        let kept: Vec<ChatMessage> = messages.iter().filter(|m| m.role == "system" || { dropped += 1; dropped > drop }).cloned().collect();
+        template.prompt(tokenizer, &kept, tools, true, prefill)
+    };
+    let mut prompt = build(0)?;
+    if let (ContextOverflow::Truncate, Some(n_ctx)) = (overflow, model.backend.context_size()) {
+        let turns = messages.iter().filter(|m| m.role != "system").count();
+        let mut from = 0;
+        while prompt.len() + max_tokens > n_ctx && from + 1 < turns {
+            from += 1;
+            prompt = build(from)?;
+        }
+    }
+    let system: Vec<ChatMessage> = messages.iter().filter(|m| m.role == "system").cloned().collect();
+    if system.is_empty() { return Ok((prompt, 0)); }
+    let system = template.prompt(tokenizer, &system, tools, false, "")?;
+    let keep = system.iter().zip(&prompt).take_while(|(a, b)| a == b).count();
+    Ok((prompt, keep))
+}
+
+const FORCED_CALL_UNPARSED: &str = "the model's output did not parse as the tool call that tool_choice forced";
+
+/// The prefilled call prefix only starts a forced call; the model may still
+/// not finish it as one, and that is not a reply the client asked for.
+fn forced_call_error() -> axum::response::Response {
+    (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": {"message": FORCED_CALL_UNPARSED, "type": "server_error", "code": null}}))).into_response()
+}
+
+fn rate_limited() -> axum::response::Response {
+    (StatusCode::TOO_MANY_REQUESTS, Json(serde_json::json!({"error": {"message": "rate limit exceeded", "type": "rate_limit_error"}}))).into_response()
+}
+
+fn is_context_error(e: &str) -> bool { e.starts_with("context length exceeded") }
+
+/// A generation that failed before its first token: a context overflow is the
+/// client's to fix, anything else means the server could not run it.
+fn generation_error(e: String) -> axum::response::Response {
+    let (status, kind, code) = if is_context_error(&e) {
+        (StatusCode::BAD_REQUEST, "invalid_request_error", Some("context_length_exceeded"))
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "server_error", None)
+    };
+    (status, Json(serde_json::json!({"error": {"message": e, "type": kind, "code": code}}))).into_response()
+}
+
+/// An unknown model is the client's mistake (OpenAI's `model_not_found`); a model
+/// that exists but cannot be loaded right now is the server's.
+fn model_error(e: RegistryError) -> axum::response::Response {
+    let message = e.to_string();
+    let body = match e {
+        RegistryError::NotFound(_) => return (StatusCode::NOT_FOUND, Json(serde_json::json!({
+            "error": {"message": message, "type": "invalid_request_error", "param": "model", "code": "model_not_found"}
+        }))).into_response(),
+        RegistryError::Load { .. } | RegistryError::OverBudget { .. } | RegistryError::Warmup { .. } => serde_json::json!({"error": {"message": message, "type": "server_error", "code": null}}),
+    };
+    (StatusCode::SERVICE_UNAVAILABLE, Json(body)).into_response()
+}
+
+fn invalid_request(message: String, param: &str) -> axum::response::Response {
+    (StatusCode::BAD_REQUEST, Json(serde_json::json!({"error": {"message": message, "type": "invalid_request_error", "param": param, "code": null}}))).into_response()
+}
+
+fn session_error(e: SessionError) -> axum::response::Response {
+    let (status, message) = match e {
+        SessionError::NotFound => (StatusCode::NOT_FOUND, "session not found"),
+        SessionError::Busy => (StatusCode::CONFLICT, "session is already generating"),
+    };
+    (status, Json(serde_json::json!({"error": {"message": message, "type": "invalid_request_error"}}))).into_response()
+}
+
+fn tokenize_error(e: runner_common::RunnerError) -> axum::response::Response {
+    (StatusCode::UNPROCESSABLE_ENTITY, Json(serde_json::json!({"error": {"message": e.to_string(), "type": "invalid_request_error"}}))).into_response()
+}
+
+#[derive(serde::Deserialize, Default)]
+struct CreateSession {
+    /// Model name or alias the session runs on; the default model when unset.
+    model: Option<String>,
+    system: Option<String>,
+}
+
+async fn create_session(State(state): State<AppState>, body: Option<Json<CreateSession>>) -> axum::response::Response {
+    let Json(body) = body.unwrap_or_default();
+    let scheduler = match state.model(body.model.as_deref(), None).await { Ok(m) => m, Err(resp) => return resp };
+    let system = body.system.map(|content| ChatMessage { role: "system".into(), content, ..ChatMessage::default() });
+    let messages = system.iter().map(|m| serde_json::to_value(m).unwrap_or_default()).collect();
+    (StatusCode::CREATED, Json(state.sessions.create(&scheduler.model, messages))).into_response()
+}
+
+async fn get_session(State(state): State<AppState>, Path(id): Path<String>) -> axum::response::Response {
+    match state.sessions.info(&id) {
+        Some(info) => Json(info).into_response(),
+        None => session_error(SessionError::NotFound),
+    }
+}
+
+async fn delete_session(State(state): State<AppState>, Path(id): Path<String>) -> axum::response::Response {
+    // a session's sequence is only resident while its model is loaded
+    let scheduler = state.sessions.info(&id).and_then(|s| state.registry.lease_loaded(&s.model));
+    if state.sessions.delete(&id, scheduler.as_ref().map(|s| s.backend.as_ref())) { StatusCode::NO_CONTENT.into_response() } else { session_error(SessionError::NotFound) }
+}
+
+async fn add_session_message(State(state): State<AppState>, Path(id): Path<String>, Json(m): Json<ChatMessage>) -> axum::response::Response {
+    match state.sessions.append(&id, serde_json::to_value(&m).unwrap_or_default()) {
+        Ok(info) => Json(info).into_response(),
+        Err(e) => session_error(e),
+    }
+}
+
+#[derive(serde::Deserialize, Default)]
+struct SessionGenerate { max_tokens: Option<usize> }
+
+/// Generate the assistant's next turn. The conversation is rendered with the
+/// model's chat template, as for chat completions, and the session's sequence
+/// is resumed, so only the messages added since the previous turn are prefilled.
+async fn generate_session(State(state): State<AppState>, Path(id): Path<String>, body: Option<Json<SessionGenerate>>) -> axum::response::Response {
+    state.requests_total.inc();
+    let max_tokens = body.and_then(|Json(b)| b.max_tokens).unwrap_or(128);
+    let scheduler = match state.session_model(&id).await { Ok(m) => m, Err(resp) => return resp };
+    let mut turn = match state.sessions.begin_turn(&id, &scheduler) { Ok(t) => t, Err(e) => return session_error(e) };
+    let messages: Vec<ChatMessage> = turn.messages.iter().filter_map(|m| serde_json::from_value(m.clone()).ok()).collect();
+    let prompt = match scheduler.template.prompt(scheduler.tokenizer.as_ref(), &messages, &[], true, "") { Ok(p) => p, Err(e) => return tokenize_error(e) };
+    let resume = turn.resume_for(&prompt, scheduler.backend.as_ref());
+    let cached_tokens = resume.as_ref().map_or(0, |p| p.n_past);
+    let cancel = CancelToken::new();
+    let guard = CancelOnDrop::new(&state, &cancel);
+    let (events, slot) = SchedulerV1::submit_turn(&scheduler, resume, prompt, max_tokens, cancel.clone());
+    let result = collect(&state, events, std::time::Instant::now()).await;
+    guard.disarm();
+    let done = match result {
+        Ok(done) => done,
+        Err(e) => return generation_error(e),
+    };
+    state.tokens_generated_total.inc_by(done.usage.completion_tokens as u64);
+    if let Some(parked) = slot.lock().unwrap().take() {
+        let reply = ChatMessage { role: "assistant".into(), content: done.text.clone(), ..ChatMessage::default() };
+        turn.finish(parked, serde_json::to_value(&reply).unwrap_or_default());
+    }
+    Json(serde_json::json!({
+        "id": id,
+        "text": done.text,
+        "finish_reason": done.finish_reason.as_str(),
+        "usage": {"prompt_tokens": done.usage.prompt_tokens, "completion_tokens": done.usage.completion_tokens, "cached_tokens": cached_tokens},
+    })).into_response()
 }
 
 #[derive(serde::Deserialize)]
-struct SetModel { path: String }
+struct SetModel {
+    path: String,
+    /// Answer once the swap is done instead of as soon as it starts.
+    #[serde(default)]
+    wait: bool,
+}
+
+/// Phase of the last `/admin/set_model` swap, as reported by `/admin/model_status`.
+#[derive(Clone, Copy, Default, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+enum SwapPhase { #[default] Idle, Loading, WarmingUp, Ready, Failed }
 
-async fn admin_set_model(State(state): State<AppState>, Json(req): Json<SetModel>) -> impl IntoResponse {
-    state.model_path.write().await.replace(req.path);
-    ([("content-type", "text/plain")], "ok")
+#[derive(Clone, Default, serde::Serialize)]
+struct SwapStatus {
+    state: SwapPhase,
+    model: Option<String>,
+    path: Option<String>,
+    error: Option<String>,
+    /// Time spent in the swap so far, or in total once it ended.
+    elapsed_ms: u64,
+    #[serde(skip)]
+    started: Option<std::time::Instant>,
+}
+
+impl SwapStatus {
+    fn in_progress(&self) -> bool { matches!(self.state, SwapPhase::Loading | SwapPhase::WarmingUp) }
+
+    fn snapshot(&self) -> Self {
+        let mut s = self.clone();
+        if s.in_progress() { s.elapsed_ms = s.started.map_or(0, |t| t.elapsed().as_millis() as u64); }
+        s
+    }
+}
+
+impl AppState {
+    fn set_swap_phase(&self, state: SwapPhase, error: Option<String>) {
+        let mut st = self.swap.lock().unwrap();
+        st.state = state;
+        st.error = error;
+        if !st.in_progress() { st.elapsed_ms = st.started.map_or(0, |t| t.elapsed().as_millis() as u64); }
+    }
+}
+
+/// Load the model at `path` in the background, check it with a warmup
+/// generation, then make it the default model. New requests switch over at
+/// once; requests already running finish on the previous model, which is
+/// freed after them. A failed load or warmup leaves the current model serving.
+async fn admin_set_model(State(state): State<AppState>, Json(req): Json<SetModel>) -> axum::response::Response {
+    let spec = state.registry.spec_for_path(&req.path);
+    {
+        let mut st = state.swap.lock().unwrap();
+        if st.in_progress() { return (StatusCode::CONFLICT, Json(st.snapshot())).into_response(); }
+        *st = SwapStatus { state: SwapPhase::Loading, model: Some(spec.name.clone()), path: Some(req.path.clone()), started: Some(std::time::Instant::now()), ..SwapStatus::default() };
+    }
+    let task = tokio::spawn(swap_model(state.clone(), spec));
+    if !req.wait { return (StatusCode::ACCEPTED, Json(state.swap.lock().unwrap().snapshot())).into_response(); }
+    let _ = task.await;
+    let status = state.swap.lock().unwrap().snapshot();
+    let code = if status.state == SwapPhase::Ready { StatusCode::OK } else { StatusCode::UNPROCESSABLE_ENTITY };
+    (code, Json(status)).into_response()
+}
+
+async fn swap_model(state: AppState, spec: ModelSpec) {
+    let result: std::result::Result<Prepared, RegistryError> = async {
+        let prepared = state.registry.prepare_async(spec).await?;
+        state.set_swap_phase(SwapPhase::WarmingUp, None);
+        ModelRegistry::warmup(&prepared).await?;
+        Ok(prepared)
+    }.await;
+    match result {
+        Ok(prepared) => {
+            let name = prepared.spec.name.clone();
+            state.registry.install_default(prepared);
+            tracing::info!(target: "api", "now serving {} by default", name);
+            state.set_swap_phase(SwapPhase::Ready, None);
+        }
+        Err(e) => {
+            tracing::warn!(target: "api", "set_model failed: {}", e);
+            state.set_swap_phase(SwapPhase::Failed, Some(e.to_string()));
+        }
+    }
+}
+
+async fn admin_model_status(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.swap.lock().unwrap().snapshot())
 }
 
 fn tenant_id() -> String {
@@ -372,6 +1456,7 @@ impl TokenBudgets {
         let v = g.entry(key.to_string()).or_insert(0);
         *v += tokens;
     }
+    #[allow(dead_code)]
     async fn allowed(&self, key: &str, new_tokens: u64) -> bool {
         let budget: u64 = std::env::var("RUNNER_TOKEN_BUDGET").ok().and_then(|v| v.parse().ok()).unwrap_or(u64::MAX);
         let g = self.inner.lock().await;
@@ -0,0 +1,535 @@
+//! Models the server routes to by name or alias. Each loaded model has its own
+//! backend, KV pool and scheduler. Models load on first use and are unloaded
+//! once idle past their keep-alive, or least recently used first to stay within
+//! the memory budget. Pinned models are never unloaded.
+
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, UNIX_EPOCH};
+use once_cell::sync::Lazy;
+use prometheus::IntCounterVec;
+use runner_backend::{gguf::GgufFile, mock::MockBackend, InferenceBackend, ModelHandle};
+use runner_backend::tokenizer::{BackendTokenizer, GgufTokenizer, HfTokenizer, Tokenizer};
+use runner_backend_llamacpp::LlamaCppBackend;
+use runner_common::{cancel::CancelToken, config::{KeepAlive, RunnerConfig}};
+use runner_core::kv::PrefixCache;
+use runner_core::scheduler::{GenerationEvent, Handle, SchedulerConfig, SchedulerV1};
+use crate::{chat::ChatTemplate, kv_pool, load_params};
+
+/// The built-in echo model, served when no model files are configured.
+pub const MOCK_MODEL: &str = "mock";
+
+static MODEL_LOADS: Lazy<IntCounterVec> = Lazy::new(|| prometheus::register_int_counter_vec!("runner_model_loads_total", "Model loads", &["model"]).expect("counter"));
+static MODEL_UNLOADS: Lazy<IntCounterVec> = Lazy::new(|| prometheus::register_int_counter_vec!("runner_model_unloads_total", "Model unloads by reason (idle, memory, replaced, manual)", &["model", "reason"]).expect("counter"));
+
+/// Register the load and unload counters so they are exported before the first load.
+pub fn init_metrics() {
+    Lazy::force(&MODEL_LOADS);
+    Lazy::force(&MODEL_UNLOADS);
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ModelSpec {
+    pub name: String,
+    pub aliases: Vec<String>,
+    /// Model file; `None` for the built-in mock.
+    pub path: Option<String>,
+    /// `tokenizer.json` to use instead of the one next to the model file or in it.
+    pub tokenizer: Option<String>,
+    /// Built-in template name or Jinja source, instead of the model file's template.
+    pub chat_template: Option<String>,
+    /// Idle time before unloading; the configured `keep_alive` when unset.
+    pub keep_alive: Option<KeepAlive>,
+    pub pinned: bool,
+}
+
+impl ModelSpec {
+    /// Spec for a model file, named after its file stem.
+    pub fn from_path(path: &str) -> Self {
+        let name = Path::new(path).file_stem().and_then(|s| s.to_str()).unwrap_or(path).to_string();
+        Self { name, path: Some(path.to_string()), ..Self::default() }
+    }
+
+    fn answers_to(&self, name: &str) -> bool { self.name == name || self.aliases.iter().any(|a| a == name) }
+}
+
+/// A model as `/v1/models` lists it, loaded or not.
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct ModelInfo {
+    pub id: String,
+    pub aliases: Vec<String>,
+    /// Modification time of the model file, in Unix seconds; 0 for the mock.
+    pub created: u64,
+    pub loaded: bool,
+    pub default: bool,
+    pub pinned: bool,
+    pub architecture: Option<String>,
+    pub parameters: Option<u64>,
+    pub quantization: Option<&'static str>,
+    /// Size of the model file.
+    pub size_bytes: u64,
+    /// Weights plus KV pool, while loaded.
+    pub memory_bytes: Option<usize>,
+    /// Context window the model is loaded with, or would be.
+    pub context_length: Option<usize>,
+    pub trained_context_length: Option<usize>,
+    /// `completion`, `chat`, `tools` and `fill_in_the_middle`, as far as they apply.
+    pub capabilities: Vec<&'static str>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RegistryError {
+    #[error("The model `{0}` does not exist")]
+    NotFound(String),
+    #[error("failed to load model `{model}`: {reason}")]
+    Load { model: String, reason: String },
+    #[error("model `{model}` needs {needed} MiB but only {free} MiB of the model memory budget can be freed")]
+    OverBudget { model: String, needed: usize, free: usize },
+    #[error("model `{model}` failed its warmup generation: {reason}")]
+    Warmup { model: String, reason: String },
+}
+
+/// Shared by a loaded model and its leases.
+struct Activity { last_used: Mutex<Instant>, keep_alive: Mutex<KeepAlive> }
+
+impl Activity {
+    fn touch(&self) { *self.last_used.lock().unwrap() = Instant::now(); }
+    fn last_used(&self) -> Instant { *self.last_used.lock().unwrap() }
+}
+
+struct Loaded {
+    handle: Handle,
+    tokenizer: Arc<dyn Tokenizer>,
+    template: Arc<ChatTemplate>,
+    activity: Arc<Activity>,
+    /// Keep-alive for requests that do not set their own.
+    keep_alive: KeepAlive,
+    pinned: bool,
+    bytes: usize,
+}
+
+impl Loaded {
+    /// No request holds a lease on the model.
+    fn is_idle(&self) -> bool { Arc::strong_count(&self.activity) == 1 }
+
+    /// May be unloaded to make room for another model.
+    fn is_evictable(&self) -> bool { !self.pinned && self.is_idle() }
+
+    /// Idle for longer than the keep-alive of its last request.
+    fn is_expired(&self) -> bool {
+        match *self.activity.keep_alive.lock().unwrap() {
+            KeepAlive::For(ttl) => self.is_evictable() && self.activity.last_used().elapsed() >= ttl,
+            KeepAlive::Forever => false,
+        }
+    }
+}
+
+/// A loaded model checked out for one request; it is not unloaded while leased,
+/// and its keep-alive counts from when the last lease is dropped.
+pub struct Lease { pub model: String, pub tokenizer: Arc<dyn Tokenizer>, pub template: Arc<ChatTemplate>, handle: Handle, activity: Arc<Activity> }
+
+impl Deref for Lease {
+    type Target = Handle;
+    fn deref(&self) -> &Handle { &self.handle }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) { self.activity.touch(); }
+}
+
+/// Loads and unloads of one model, by unload reason: `idle` (keep-alive ran
+/// out), `memory` (made room for another model), `replaced` (hot swap) or `manual`.
+#[derive(Debug, Clone, Default)]
+pub struct ModelStats { pub loads: u64, pub unloads: HashMap<&'static str, u64> }
+
+/// A model loaded next to the registry's own, not yet serving traffic.
+pub struct Prepared { pub spec: ModelSpec, handle: Handle, tokenizer: Arc<dyn Tokenizer>, template: Arc<ChatTemplate>, bytes: usize }
+
+pub struct ModelRegistry {
+    cfg: Arc<RunnerConfig>,
+    specs: Mutex<Vec<ModelSpec>>,
+    default: Mutex<Option<String>>,
+    loaded: Mutex<HashMap<String, Loaded>>,
+    stats: Mutex<HashMap<String, ModelStats>>,
+    /// Serializes loads so concurrent first requests load a model once.
+    loading: tokio::sync::Mutex<()>,
+    budget_bytes: Option<usize>,
+}
+
+impl ModelRegistry {
+    /// Registry of the configured models, the `*.gguf` files in `model_dir` and
+    /// `RUNNER_MODEL`, or just the mock when there are none. Nothing is loaded yet.
+    pub fn from_config(cfg: Arc<RunnerConfig>) -> Arc<Self> {
+        let mut specs: Vec<ModelSpec> = cfg.models.iter().map(|(name, m)| {
+            let path = m.path.clone().unwrap_or_else(|| cfg.model_dir.join(format!("{name}.gguf")));
+            ModelSpec {
+                name: name.clone(),
+                aliases: m.aliases.clone(),
+                path: Some(path.to_string_lossy().into_owned()),
+                tokenizer: m.tokenizer.as_ref().map(|p| p.to_string_lossy().into_owned()),
+                chat_template: m.chat_template.clone(),
+                keep_alive: m.keep_alive,
+                pinned: m.pinned,
+            }
+        }).collect();
+        let mut files = gguf_files(&cfg.model_dir);
+        let env_model = std::env::var("RUNNER_MODEL").ok();
+        files.extend(env_model.clone());
+        for path in files { add_file(&mut specs, &path); }
+        if specs.is_empty() { specs.push(ModelSpec { name: MOCK_MODEL.into(), ..ModelSpec::default() }); }
+        specs.sort_by(|a, b| a.name.cmp(&b.name));
+        let default = cfg.default_model.clone()
+            .or_else(|| env_model.map(|p| ModelSpec::from_path(&p).name))
+            .or_else(|| specs.first().map(|s| s.name.clone()));
+        Arc::new(Self {
+            budget_bytes: cfg.model_memory_mb.map(|mb| mb * 1024 * 1024),
+            cfg,
+            specs: Mutex::new(specs),
+            default: Mutex::new(default),
+            loaded: Mutex::new(HashMap::new()),
+            stats: Mutex::new(HashMap::new()),
+            loading: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    pub fn specs(&self) -> Vec<ModelSpec> { self.specs.lock().unwrap().clone() }
+    pub fn spec(&self, name: &str) -> Option<ModelSpec> { self.specs.lock().unwrap().iter().find(|s| s.name == name).cloned() }
+
+    /// Spec to serve the model file at `path` with. A registered model with that
+    /// file, or else named after its stem, keeps its aliases and settings with
+    /// `path` swapped in; any other file gets a new spec.
+    pub fn spec_for_path(&self, path: &str) -> ModelSpec {
+        let fresh = ModelSpec::from_path(path);
+        let specs = self.specs.lock().unwrap();
+        let known = specs.iter().find(|s| s.path.as_deref().is_some_and(|p| same_file(p, path))).or_else(|| specs.iter().find(|s| s.name == fresh.name));
+        known.map_or(fresh, |s| ModelSpec { path: Some(path.to_string()), ..s.clone() })
+    }
+
+    /// Add a model, replacing one registered under the same name.
+    pub fn register(&self, spec: ModelSpec) {
+        let mut specs = self.specs.lock().unwrap();
+        specs.retain(|s| s.name != spec.name);
+        specs.push(spec);
+        specs.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+
+    /// Register the `*.gguf` files added to `model_dir` since startup; returns their names.
+    pub fn discover(&self) -> Vec<String> {
+        let mut specs = self.specs.lock().unwrap();
+        let added: Vec<String> = gguf_files(&self.cfg.model_dir).iter().filter_map(|path| add_file(&mut specs, path)).collect();
+        specs.sort_by(|a, b| a.name.cmp(&b.name));
+        added
+    }
+
+    /// What the registry and the model file say about `name`, loaded or not.
+    /// Reads the file's GGUF header, so call it off the async runtime.
+    pub fn describe(&self, name: &str) -> Option<ModelInfo> {
+        let spec = self.spec(name)?;
+        let loaded = self.loaded.lock().unwrap().get(name).map(|m| (m.handle.backend.clone(), m.tokenizer.clone(), m.bytes));
+        let gguf = spec.path.as_deref().and_then(|p| GgufFile::open(p).ok());
+        let file = spec.path.as_deref().and_then(|p| std::fs::metadata(p).ok());
+        let model = match (&loaded, &spec.path) {
+            (Some((backend, ..)), _) => backend.model(),
+            (None, Some(path)) => gguf.as_ref().map(|g| ModelHandle::from_gguf(path, g, &load_params(&self.cfg, path))),
+            (None, None) => Some(MockBackend::handle()),
+        }.unwrap_or_default();
+        let tokenizer = match &loaded {
+            Some((_, tokenizer, _)) => Some(tokenizer.clone()),
+            None => tokenizer(&spec, &(Arc::new(MockBackend::new()) as Arc<dyn InferenceBackend>)).ok(),
+        };
+        let mut capabilities = vec!["completion", "chat"];
+        if ChatTemplate::offers_tools(spec.chat_template.as_deref(), model.chat_template.as_deref()) { capabilities.push("tools"); }
+        if tokenizer.is_some_and(|t| t.fim_tokens().is_some()) { capabilities.push("fill_in_the_middle"); }
+        Some(ModelInfo {
+            created: file.as_ref().and_then(|m| m.modified().ok()).and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map_or(0, |d| d.as_secs()),
+            loaded: loaded.is_some(),
+            default: self.default_model().as_deref() == Some(name),
+            pinned: spec.pinned,
+            architecture: model.architecture,
+            parameters: (model.n_params > 0).then_some(model.n_params),
+            quantization: gguf.as_ref().and_then(GgufFile::quantization),
+            size_bytes: file.map_or(model.size_bytes, |m| m.len()),
+            memory_bytes: loaded.as_ref().map(|(.., bytes)| *bytes),
+            context_length: loaded.as_ref().map_or(model.n_ctx, |(backend, ..)| backend.context_size()),
+            trained_context_length: model.n_ctx_train,
+            capabilities,
+            id: spec.name,
+            aliases: spec.aliases,
+        })
+    }
+
+    pub fn default_model(&self) -> Option<String> { self.default.lock().unwrap().clone() }
+    pub fn set_default(&self, name: &str) { *self.default.lock().unwrap() = Some(name.to_string()); }
+
+    /// Canonical name for `requested` (a name or alias), or the default model when unset.
+    pub fn resolve(&self, requested: Option<&str>) -> Result<String, RegistryError> {
+        let Some(requested) = requested.filter(|r| !r.is_empty()).map(str::to_string).or_else(|| self.default_model()) else {
+            return Err(RegistryError::NotFound(String::new()));
+        };
+        let specs = self.specs.lock().unwrap();
+        specs.iter().find(|s| s.answers_to(&requested)).map(|s| s.name.clone()).ok_or(RegistryError::NotFound(requested))
+    }
+
+    /// Lease `requested` (or the default model), loading it first if needed.
+    pub async fn lease(self: &Arc<Self>, requested: Option<&str>) -> Result<Lease, RegistryError> { self.lease_with(requested, None).await }
+
+    /// Lease as [`lease`](Self::lease), keeping the model loaded for `keep_alive`
+    /// after this request instead of its configured keep-alive.
+    pub async fn lease_with(self: &Arc<Self>, requested: Option<&str>, keep_alive: Option<KeepAlive>) -> Result<Lease, RegistryError> {
+        let name = self.resolve(requested)?;
+        if let Some(lease) = self.checkout(&name, keep_alive) { return Ok(lease); }
+        let _loading = self.loading.lock().await;
+        let this = self.clone();
+        let lease = tokio::task::spawn_blocking(move || this.load(&name))
+            .await
+            .unwrap_or_else(|e| Err(RegistryError::Load { model: String::new(), reason: e.to_string() }))?;
+        if let Some(k) = keep_alive { *lease.activity.keep_alive.lock().unwrap() = k; }
+        Ok(lease)
+    }
+
+    /// Load `requested` unless it is loaded already, and check it with a warmup
+    /// generation before it takes traffic.
+    pub async fn preload(self: &Arc<Self>, requested: &str) -> Result<(), RegistryError> {
+        let name = self.resolve(Some(requested))?;
+        let _loading = self.loading.lock().await;
+        if self.lease_loaded(&name).is_some() { return Ok(()); }
+        let spec = self.spec(&name).ok_or_else(|| RegistryError::NotFound(name.clone()))?;
+        let this = self.clone();
+        let prepared = tokio::task::spawn_blocking(move || this.prepare(spec))
+            .await
+            .map_err(|e| RegistryError::Load { model: name.clone(), reason: e.to_string() })??;
+        Self::warmup(&prepared).await?;
+        self.install(prepared);
+        Ok(())
+    }
+
+    /// Tokenizer of `requested` (or the default model), without loading the model.
+    pub async fn tokenizer(&self, requested: Option<&str>) -> Result<Arc<dyn Tokenizer>, RegistryError> {
+        let name = self.resolve(requested)?;
+        if let Some(m) = self.loaded.lock().unwrap().get(&name) { return Ok(m.tokenizer.clone()); }
+        let spec = self.spec(&name).ok_or_else(|| RegistryError::NotFound(name.clone()))?;
+        tokio::task::spawn_blocking(move || tokenizer(&spec, &(Arc::new(MockBackend::new()) as Arc<dyn InferenceBackend>)))
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|r| r.map_err(|e| e.to_string()))
+            .map_err(|reason| RegistryError::Load { model: name, reason })
+    }
+
+    /// Lease `name` only if it is already loaded.
+    pub fn lease_loaded(&self, name: &str) -> Option<Lease> { self.checkout(name, None) }
+
+    fn checkout(&self, name: &str, keep_alive: Option<KeepAlive>) -> Option<Lease> {
+        let loaded = self.loaded.lock().unwrap();
+        let m = loaded.get(name)?;
+        m.activity.touch();
+        *m.activity.keep_alive.lock().unwrap() = keep_alive.unwrap_or(m.keep_alive);
+        Some(Lease { model: name.to_string(), tokenizer: m.tokenizer.clone(), template: m.template.clone(), handle: m.handle.clone(), activity: m.activity.clone() })
+    }
+
+    /// Load `name` on this thread (model loads block) and lease it.
+    pub fn load(&self, name: &str) -> Result<Lease, RegistryError> {
+        if let Some(lease) = self.lease_loaded(name) { return Ok(lease); }
+        let spec = self.spec(name).ok_or_else(|| RegistryError::NotFound(name.to_string()))?;
+        self.install(self.prepare(spec)?);
+        self.lease_loaded(name).ok_or_else(|| RegistryError::NotFound(name.to_string()))
+    }
+
+    /// [`prepare`](Self::prepare) `spec` on a blocking thread, one load at a time
+    /// with the registry's own loads so they do not evict for each other.
+    pub async fn prepare_async(self: &Arc<Self>, spec: ModelSpec) -> Result<Prepared, RegistryError> {
+        let _loading = self.loading.lock().await;
+        let (this, name) = (self.clone(), spec.name.clone());
+        tokio::task::spawn_blocking(move || this.prepare(spec))
+            .await
+            .map_err(|e| RegistryError::Load { model: name, reason: e.to_string() })?
+    }
+
+    /// Load `spec` on this thread without serving it yet. A loaded model of the
+    /// same name keeps serving and is not unloaded to make room.
+    pub fn prepare(&self, spec: ModelSpec) -> Result<Prepared, RegistryError> {
+        let handle = self.start(&spec)?;
+        let tokenizer = tokenizer(&spec, &handle.backend).map_err(|e| RegistryError::Load { model: spec.name.clone(), reason: e.to_string() })?;
+        // only evict once the load went through, by what the backend says the weights take
+        let weights = match handle.backend.model() {
+            Some(m) => m.size_bytes as usize,
+            None => spec.path.as_ref().and_then(|p| std::fs::metadata(p).ok()).map_or(0, |m| m.len() as usize),
+        };
+        let bytes = weights + self.cfg.kv_capacity_bytes();
+        self.make_room(&spec.name, bytes)?;
+        let model = handle.backend.model().unwrap_or_default();
+        let template = ChatTemplate::for_model(spec.chat_template.as_deref(), &model, tokenizer.as_ref())
+            .map_err(|e| RegistryError::Load { model: spec.name.clone(), reason: e.to_string() })?;
+        let or_unknown = |v: Option<usize>| v.map_or_else(|| String::from("?"), |v| v.to_string());
+        tracing::info!(
+            target: "api",
+            "loaded model {} ({} MiB; kv {} blocks of {} B): arch={} n_ctx={} (trained {}) vocab={} params={} chat_template={}",
+            spec.name, bytes / (1024 * 1024), handle.kv.capacity_blocks(), handle.kv.block_bytes(),
+            model.architecture.as_deref().unwrap_or("?"), or_unknown(model.n_ctx), or_unknown(model.n_ctx_train), model.vocab_size, model.n_params,
+            template.name,
+        );
+        Ok(Prepared { spec, handle, tokenizer, template: Arc::new(template), bytes })
+    }
+
+    /// Run a one-token generation on a prepared model before it takes traffic.
+    pub async fn warmup(prepared: &Prepared) -> Result<(), RegistryError> {
+        let failed = |reason: String| RegistryError::Warmup { model: prepared.spec.name.clone(), reason };
+        let mut events = SchedulerV1::submit(&prepared.handle, "Hello".into(), 1, CancelToken::new());
+        while let Some(ev) = events.recv().await {
+            match ev {
+                GenerationEvent::Finished { .. } => return Ok(()),
+                GenerationEvent::Error(e) => return Err(failed(e)),
+                GenerationEvent::Queued { .. } | GenerationEvent::Started | GenerationEvent::Token(_) | GenerationEvent::Logprob(_) => {}
+            }
+        }
+        Err(failed("scheduler stopped".into()))
+    }
+
+    /// Switch new requests for the prepared model's name over to it. Requests
+    /// leasing the model it replaces finish there, and it is freed after the last one.
+    pub fn install(&self, prepared: Prepared) {
+        let Prepared { spec, handle, tokenizer, template, bytes } = prepared;
+        let keep_alive = spec.keep_alive.or(self.cfg.keep_alive).unwrap_or(KeepAlive::DEFAULT);
+        let activity = Arc::new(Activity { last_used: Mutex::new(Instant::now()), keep_alive: Mutex::new(keep_alive) });
+        let m = Loaded { handle, tokenizer, template, activity, keep_alive, pinned: spec.pinned, bytes };
+        let mut loaded = self.loaded.lock().unwrap();
+        self.register(spec.clone());
+        self.stats.lock().unwrap().entry(spec.name.clone()).or_default().loads += 1;
+        MODEL_LOADS.with_label_values(&[&spec.name]).inc();
+        if loaded.insert(spec.name.clone(), m).is_some() {
+            tracing::info!(target: "api", "swapped model {}; the previous one drains and exits", spec.name);
+            self.count_unload(&spec.name, "replaced");
+        }
+    }
+
+    fn count_unload(&self, name: &str, reason: &'static str) {
+        *self.stats.lock().unwrap().entry(name.to_string()).or_default().unloads.entry(reason).or_default() += 1;
+        MODEL_UNLOADS.with_label_values(&[name, reason]).inc();
+    }
+
+    /// Serve the prepared model as the default. A previous default under another
+    /// name is unloaded too, and freed once the requests still leasing it finish.
+    pub fn install_default(&self, prepared: Prepared) {
+        let name = prepared.spec.name.clone();
+        let previous = self.default_model().filter(|p| *p != name);
+        self.install(prepared);
+        self.set_default(&name);
+        let Some(previous) = previous else { return };
+        if self.loaded.lock().unwrap().remove(&previous).is_some() {
+            tracing::info!(target: "api", "unloading {}, replaced by {} as the default; it drains and exits", previous, name);
+            self.count_unload(&previous, "replaced");
+        }
+    }
+
+    /// Unload idle models other than `model`, least recently used first, until
+    /// `bytes` more fit the budget.
+    fn make_room(&self, model: &str, bytes: usize) -> Result<(), RegistryError> {
+        let Some(budget) = self.budget_bytes else { return Ok(()) };
+        let mut loaded = self.loaded.lock().unwrap();
+        loop {
+            let used: usize = loaded.values().map(|m| m.bytes).sum();
+            if used + bytes <= budget { return Ok(()); }
+            let victim = loaded.iter().filter(|(n, m)| *n != model && m.is_evictable()).min_by_key(|(_, m)| m.activity.last_used()).map(|(n, _)| n.clone());
+            let Some(victim) = victim else {
+                let free = budget.saturating_sub(loaded.iter().filter(|(n, m)| *n == model || !m.is_evictable()).map(|(_, m)| m.bytes).sum());
+                return Err(RegistryError::OverBudget { model: model.to_string(), needed: bytes / (1024 * 1024), free: free / (1024 * 1024) });
+            };
+            tracing::info!(target: "api", "unloading idle model {} to make room for {}", victim, model);
+            loaded.remove(&victim);
+            self.count_unload(&victim, "memory");
+        }
+    }
+
+    /// Backend, KV pool and scheduler for `spec`. The scheduler stops once its
+    /// last `Handle` is gone, which frees the model.
+    fn start(&self, spec: &ModelSpec) -> Result<Handle, RegistryError> {
+        let backend: Arc<dyn InferenceBackend> = match &spec.path {
+            None => Arc::new(MockBackend::new()),
+            Some(path) => {
+                let llama = LlamaCppBackend::new();
+                llama.load_model(path, load_params(&self.cfg, path))
+                    .map_err(|e| RegistryError::Load { model: spec.name.clone(), reason: e.to_string() })?;
+                Arc::new(llama)
+            }
+        };
+        let kv = kv_pool(&self.cfg, &backend, spec.path.as_deref()).map_err(|e| RegistryError::Load { model: spec.name.clone(), reason: e.to_string() })?;
+        Ok(SchedulerV1::start_with(backend, kv.clone(), PrefixCache::new(&kv), SchedulerConfig::from_runner(&self.cfg)))
+    }
+
+    /// Unload `name`, pinned or not; requests still holding a lease finish first.
+    pub fn unload(&self, name: &str) -> bool {
+        let removed = self.loaded.lock().unwrap().remove(name).is_some();
+        if removed { self.count_unload(name, "manual"); }
+        removed
+    }
+
+    /// Unload the models left idle for longer than their keep-alive; returns their names.
+    pub fn unload_expired(&self) -> Vec<String> {
+        let mut loaded = self.loaded.lock().unwrap();
+        let mut expired: Vec<String> = loaded.iter().filter(|(_, m)| m.is_expired()).map(|(n, _)| n.clone()).collect();
+        expired.sort();
+        for name in &expired {
+            loaded.remove(name);
+            self.count_unload(name, "idle");
+        }
+        expired
+    }
+
+    /// Names and memory footprint of the loaded models.
+    pub fn resident(&self) -> Vec<(String, usize)> {
+        let loaded = self.loaded.lock().unwrap();
+        let mut models: Vec<_> = loaded.iter().map(|(n, m)| (n.clone(), m.bytes)).collect();
+        models.sort();
+        models
+    }
+
+    pub fn stats(&self) -> HashMap<String, ModelStats> { self.stats.lock().unwrap().clone() }
+
+    /// Names and schedulers of the loaded models.
+    pub fn loaded(&self) -> Vec<(String, Handle)> {
+        let loaded = self.loaded.lock().unwrap();
+        let mut models: Vec<_> = loaded.iter().map(|(n, m)| (n.clone(), m.handle.clone())).collect();
+        models.sort_by(|a, b| a.0.cmp(&b.0));
+        models
+    }
+}
+
+/// `*.gguf` files in `dir`, sorted.
+fn gguf_files(dir: &Path) -> Vec<String> {
+    let mut files: Vec<String> = std::fs::read_dir(dir).into_iter().flatten().flatten()
+        .map(|e| e.path())
+        .filter(|p| p.extension().is_some_and(|e| e == "gguf"))
+        .map(|p| p.to_string_lossy().into_owned())
+        .collect();
+    files.sort();
+    files
+}
+
+/// Add a spec for the model file at `path` unless a model already answers to
+/// its name or uses the file; returns the new model's name.
+/// Whether two paths name the same file, as written or once resolved.
+fn same_file(a: &str, b: &str) -> bool {
+    Path::new(a) == Path::new(b) || matches!((std::fs::canonicalize(a), std::fs::canonicalize(b)), (Ok(a), Ok(b)) if a == b)
+}
+
+fn add_file(specs: &mut Vec<ModelSpec>, path: &str) -> Option<String> {
+    let spec = ModelSpec::from_path(path);
+    if specs.iter().any(|s| s.answers_to(&spec.name) || s.path.as_deref() == Some(path)) { return None; }
+    let name = spec.name.clone();
+    specs.push(spec);
+    Some(name)
+}
+
+/// The backend's own tokenizer when it has the model's vocabulary loaded, so
+/// prompts use the ids it detokenizes with. Otherwise the spec's
+/// `tokenizer.json`, else one next to the model file, else the model file's
+/// own vocabulary; the mock without a file tokenizes through its backend.
+fn tokenizer(spec: &ModelSpec, backend: &Arc<dyn InferenceBackend>) -> runner_common::Result<Arc<dyn Tokenizer>> {
+    let Some(path) = spec.path.as_ref().filter(|_| !backend.has_tokenizer()) else { return Ok(Arc::new(BackendTokenizer(backend.clone()))) };
+    let sibling = Path::new(path).with_file_name("tokenizer.json");
+    if let Some(json) = spec.tokenizer.as_deref().map(Path::new).or(sibling.exists().then_some(sibling.as_path())) {
+        return Ok(Arc::new(HfTokenizer::from_file(json)?));
+    }
+    Ok(Arc::new(GgufTokenizer::open(path)?))
+}
@@ -0,0 +1,187 @@
+//! OpenAI tool calling: tool definitions in, `tool_calls` out. Each chat
+//! template family has its own call syntax, which `ToolFormat` parses back
+//! out of the generated text.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tool {
+    #[serde(rename = "type", default = "function_type")]
+    pub kind: String,
+    pub function: FunctionDef,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FunctionDef {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// JSON Schema of the arguments.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type", default = "function_type")]
+    pub kind: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FunctionCall {
+    pub name: String,
+    /// Arguments as a JSON string, as OpenAI sends them.
+    pub arguments: String,
+}
+
+fn function_type() -> String { "function".into() }
+
+/// `tool_choice`: `none`, `auto` (the default with tools), `required`, or
+/// `{"type": "function", "function": {"name": ...}}`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum ToolChoice {
+    None,
+    #[default]
+    Auto,
+    Required,
+    Function(String),
+}
+
+impl<'de> Deserialize<'de> for ToolChoice {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Name { name: String }
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw { Mode(String), Function { function: Name } }
+        match Raw::deserialize(d)? {
+            Raw::Mode(m) => match m.as_str() {
+                "none" => Ok(Self::None),
+                "auto" => Ok(Self::Auto),
+                "required" => Ok(Self::Required),
+                other => Err(serde::de::Error::custom(format!("unknown tool_choice `{other}`"))),
+            },
+            Raw::Function { function } => Ok(Self::Function(function.name)),
+        }
+    }
+}
+
+impl ToolChoice {
+    /// The model must call a tool.
+    pub fn forces_call(&self) -> bool { matches!(self, Self::Required | Self::Function(_)) }
+}
+
+/// How a template family writes tool calls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToolFormat {
+    /// `<tool_call>{"name": ..., "arguments": {...}}</tool_call>`, as Hermes and Qwen do.
+    Hermes,
+    /// A bare `{"name": ..., "parameters": {...}}` object, as Llama 3.1 does.
+    Llama3,
+    /// `[TOOL_CALLS] [{"name": ..., "arguments": {...}}, ...]`.
+    Mistral,
+}
+
+impl ToolFormat {
+    /// The format a template's source asks for; Hermes when it does not say.
+    pub fn detect(template: &str) -> Self {
+        if template.contains("[TOOL_CALLS]") { Self::Mistral }
+        else if template.contains("<|python_tag|>") || template.contains("ipython") { Self::Llama3 }
+        else { Self::Hermes }
+    }
+
+    /// Start of a call to `function` (any function when unset), which a forced
+    /// call prefills the assistant's turn with so the model can only continue it.
+    pub fn call_prefix(&self, function: Option<&str>) -> String {
+        let (open, args) = match self {
+            Self::Hermes => ("<tool_call>\n{\"name\": \"", "arguments"),
+            Self::Llama3 => ("{\"name\": \"", "parameters"),
+            Self::Mistral => ("[TOOL_CALLS] [{\"name\": \"", "arguments"),
+        };
+        match function {
+            Some(name) => format!("{open}{name}\", \"{args}\": "),
+            None => open.to_string(),
+        }
+    }
+
+    /// Split generated `text` into content and calls to the functions in `tools`.
+    /// Text that does not hold a well-formed call comes back as content only.
+    pub fn parse(&self, text: &str, tools: &[Tool]) -> (Option<String>, Vec<ToolCall>) {
+        let (content, calls) = match self {
+            Self::Hermes => parse_hermes(text),
+            Self::Llama3 => parse_llama3(text),
+            Self::Mistral => parse_mistral(text),
+        };
+        let known = |c: &(String, Value)| tools.iter().any(|t| t.function.name == c.0);
+        if calls.is_empty() || !calls.iter().all(known) { return (Some(text.to_string()), Vec::new()); }
+        let stamp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_nanos());
+        let calls = calls.into_iter().enumerate().map(|(i, (name, args))| ToolCall {
+            id: format!("call_{stamp:x}{i}"),
+            kind: function_type(),
+            function: FunctionCall { name, arguments: match args { Value::String(s) => s, v => v.to_string() } },
+        }).collect();
+        (Some(content.trim().to_string()).filter(|c| !c.is_empty()), calls)
+    }
+}
+
+type Calls = Vec<(String, Value)>;
+
+/// `name` and `arguments` (or `parameters`) of a call object.
+fn call(v: &Value) -> Option<(String, Value)> {
+    let name = v.get("name")?.as_str()?.to_string();
+    let args = v.get("arguments").or_else(|| v.get("parameters")).cloned().unwrap_or_else(|| Value::Object(Default::default()));
+    Some((name, args))
+}
+
+/// The JSON value at the start of `text` and the rest after it.
+fn leading_json(text: &str) -> Option<(Value, &str)> {
+    let mut values = serde_json::Deserializer::from_str(text).into_iter::<Value>();
+    let value = values.next()?.ok()?;
+    Some((value, &text[values.byte_offset()..]))
+}
+
+fn parse_hermes(text: &str) -> (String, Calls) {
+    let (mut content, mut calls) = (String::new(), Vec::new());
+    let mut rest = text;
+    while let Some(start) = rest.find("<tool_call>") {
+        content.push_str(&rest[..start]);
+        let body = &rest[start + "<tool_call>".len()..];
+        let (inner, after) = body.split_once("</tool_call>").unwrap_or((body, ""));
+        match leading_json(inner.trim_start()).and_then(|(v, _)| call(&v)) {
+            Some(c) => calls.push(c),
+            None => return (text.to_string(), Vec::new()),
+        }
+        rest = after;
+    }
+    content.push_str(rest);
+    (content, calls)
+}
+
+fn parse_llama3(text: &str) -> (String, Calls) {
+    let body = text.trim_start().trim_start_matches("<|python_tag|>").trim();
+    let mut calls = Vec::new();
+    let mut rest = body;
+    // several calls are separated by `;`
+    while !rest.is_empty() {
+        let Some((c, after)) = leading_json(rest).and_then(|(v, after)| Some((call(&v)?, after))) else { return (text.to_string(), Vec::new()) };
+        calls.push(c);
+        rest = after.trim_start().trim_start_matches(';').trim_start();
+    }
+    (String::new(), calls)
+}
+
+fn parse_mistral(text: &str) -> (String, Calls) {
+    let Some(start) = text.find("[TOOL_CALLS]") else { return (text.to_string(), Vec::new()) };
+    let calls = leading_json(text[start + "[TOOL_CALLS]".len()..].trim_start())
+        .and_then(|(v, _)| match v {
+            Value::Array(items) => items.iter().map(call).collect(),
+            v => call(&v).map(|c| vec![c]),
+        });
+    match calls {
+        Some(calls) => (text[..start].to_string(), calls),
+        None => (text.to_string(), Vec::new()),
+    }
+}
@@ -0,0 +1,110 @@
+use runner_api::chat::{ChatMessage, ChatTemplate};
+use runner_backend::tokenizer::{HfTokenizer, Tokenizer};
+use runner_backend::{ModelHandle, SpecialTokens};
+
+/// Whitespace tokenizer over a fixed vocabulary.
+struct Vocab(&'static [&'static str]);
+
+impl Tokenizer for Vocab {
+    fn encode(&self, text: &str, _add_special: bool) -> runner_common::Result<Vec<u32>> { Ok(text.split_whitespace().filter_map(|w| self.token_to_id(w)).collect()) }
+    fn encode_literal(&self, text: &str) -> runner_common::Result<Vec<u32>> { self.encode(text, false) }
+    fn decode(&self, tokens: &[u32], _skip_special: bool) -> runner_common::Result<String> { Ok(tokens.iter().filter_map(|&t| self.id_to_token(t)).collect::<Vec<_>>().join(" ")) }
+    fn vocab_size(&self) -> usize { self.0.len() }
+    fn token_to_id(&self, token: &str) -> Option<u32> { self.0.iter().position(|t| *t == token).map(|i| i as u32) }
+    fn id_to_token(&self, id: u32) -> Option<String> { self.0.get(id as usize).map(|t| t.to_string()) }
+}
+
+fn msg(role: &str, content: &str) -> ChatMessage { ChatMessage { role: role.into(), content: content.into(), ..ChatMessage::default() } }
+
+fn conversation() -> Vec<ChatMessage> { vec![msg("system", "Be brief."), msg("user", "Hi"), msg("assistant", "Hello!"), msg("user", "Bye")] }
+
+fn model(template: Option<&str>) -> ModelHandle {
+    let special_tokens = SpecialTokens { bos: Some(0), eos: Some(1), ..Default::default() };
+    ModelHandle { id: "test".into(), special_tokens, chat_template: template.map(String::from), ..Default::default() }
+}
+
+fn render(name: &str) -> String {
+    let vocab = Vocab(&["<s>", "</s>"]);
+    ChatTemplate::for_model(Some(name), &model(None), &vocab).unwrap().render(&conversation(), &[], true).unwrap()
+}
+
+#[test]
+fn builtin_templates_keep_every_turn() {
+    assert_eq!(render("chatml"), "<|im_start|>system\nBe brief.<|im_end|>\n<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\nHello!<|im_end|>\n<|im_start|>user\nBye<|im_end|>\n<|im_start|>assistant\n");
+    assert_eq!(
+        render("llama3"),
+        "<s><|start_header_id|>system<|end_header_id|>\n\nBe brief.<|eot_id|><|start_header_id|>user<|end_header_id|>\n\nHi<|eot_id|><|start_header_id|>assistant<|end_header_id|>\n\nHello!<|eot_id|><|start_header_id|>user<|end_header_id|>\n\nBye<|eot_id|><|start_header_id|>assistant<|end_header_id|>\n\n"
+    );
+    assert_eq!(render("mistral"), "<s>[INST] Be brief.\n\nHi [/INST]Hello!</s>[INST] Bye [/INST]");
+    assert_eq!(render("gemma"), "<s><start_of_turn>user\nBe brief.\n\nHi<end_of_turn>\n<start_of_turn>model\nHello!<end_of_turn>\n<start_of_turn>user\nBye<end_of_turn>\n<start_of_turn>model\n");
+}
+
+#[test]
+fn model_template_is_rendered_like_transformers() {
+    // trim_blocks/lstrip_blocks, Python string methods and raise_exception, as in Hub templates
+    let source = "{% if messages[0]['role'] != 'system' %}{{ raise_exception('system first') }}{% endif %}\n{% for message in messages %}\n    {% if loop.first %}{{ bos_token }}{% endif %}[{{ message['role'].upper() }}] {{ message['content'].strip() }}\n{% endfor %}\n";
+    let vocab = Vocab(&["<s>", "</s>"]);
+    let template = ChatTemplate::for_model(None, &model(Some(source)), &vocab).unwrap();
+    assert_eq!(template.name, "model");
+    let messages = [msg("system", " Be brief. "), msg("user", "Hi")];
+    assert_eq!(template.render(&messages, &[], true).unwrap(), "<s>[SYSTEM] Be brief.\n[USER] Hi\n");
+    let err = template.render(&messages[1..], &[], true).unwrap_err();
+    assert!(err.to_string().contains("system first"), "{err}");
+}
+
+#[test]
+fn fallback_follows_the_vocabulary() {
+    let pick = |vocab: &'static [&'static str], template: Option<&str>| ChatTemplate::for_model(None, &model(template), &Vocab(vocab)).unwrap().name;
+    assert_eq!(pick(&["<s>", "</s>", "<|eot_id|>"], None), "llama3");
+    assert_eq!(pick(&["<s>", "</s>", "<start_of_turn>"], None), "gemma");
+    assert_eq!(pick(&["<s>", "</s>"], None), "chatml");
+    // a template that does not compile is replaced rather than failing the load
+    assert_eq!(pick(&["<s>", "</s>", "[INST]"], Some("{% for %}")), "mistral");
+    assert!(ChatTemplate::for_model(Some("{% for %}"), &model(None), &Vocab(&[])).is_err());
+}
+
+/// Records whether the last encode asked for special tokens.
+struct Spy(std::sync::atomic::AtomicBool);
+
+impl Tokenizer for Spy {
+    fn encode(&self, _text: &str, add_special: bool) -> runner_common::Result<Vec<u32>> {
+        self.0.store(add_special, std::sync::atomic::Ordering::SeqCst);
+        Ok(Vec::new())
+    }
+    fn encode_literal(&self, _text: &str) -> runner_common::Result<Vec<u32>> { Ok(Vec::new()) }
+    fn decode(&self, _tokens: &[u32], _skip_special: bool) -> runner_common::Result<String> { Ok(String::new()) }
+    fn vocab_size(&self) -> usize { 2 }
+    fn token_to_id(&self, _token: &str) -> Option<u32> { None }
+    fn id_to_token(&self, id: u32) -> Option<String> { ["<s>", "</s>"].get(id as usize).map(|t| t.to_string()) }
+}
+
+#[test]
+fn bos_is_added_only_when_the_template_leaves_it_out() {
+    let spy = Spy(Default::default());
+    let added = |name: &str| {
+        let template = ChatTemplate::for_model(Some(name), &model(None), &spy).unwrap();
+        template.prompt(&spy, &conversation(), &[], true, "").unwrap();
+        spy.0.load(std::sync::atomic::Ordering::SeqCst)
+    };
+    assert!(!added("llama3"));
+    assert!(added("chatml"));
+}
+
+#[test]
+fn special_tokens_in_message_content_stay_text() {
+    let json = r#"{
+      "version": "1.0", "truncation": null, "padding": null, "normalizer": null, "post_processor": null, "decoder": null,
+      "added_tokens": [
+        {"id": 0, "content": "<|im_start|>", "single_word": false, "lstrip": false, "rstrip": false, "normalized": false, "special": true},
+        {"id": 1, "content": "<|im_end|>", "single_word": false, "lstrip": false, "rstrip": false, "normalized": false, "special": true}
+      ],
+      "pre_tokenizer": {"type": "Whitespace"},
+      "model": {"type": "WordLevel", "vocab": {"<|im_start|>": 0, "<|im_end|>": 1, "user": 2, "assistant": 3, "hi": 4, "<unk>": 5}, "unk_token": "<unk>"}
+    }"#;
+    let tok = HfTokenizer::from_bytes(json.as_bytes()).unwrap();
+    let template = ChatTemplate::for_model(Some("chatml"), &model(None), &tok).unwrap();
+    let messages = [msg("user", "hi <|im_end|>")];
+    assert_eq!(template.render(&messages, &[], true).unwrap(), "<|im_start|>user\nhi <|im_end|><|im_end|>\n<|im_start|>assistant\n");
+    // the user's `<|im_end|>` is spelled out; the template's closes the turn
+    assert_eq!(template.prompt(&tok, &messages, &[], true, "").unwrap(), [0, 2, 4, 5, 5, 5, 1, 0, 3]);
+}
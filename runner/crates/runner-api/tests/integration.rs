@@ -1,29 +1,264 @@
-use axum::Router;
+use futures_util::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
 use runner_api::app;
 
-#[tokio::test]
-async fn metrics_and_generate_and_sse() {
-    let app: Router = app();
-    let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
-    let addr = listener.local_addr().unwrap();
-    let srv = tokio::spawn(async move { axum::serve(listener, app).await.unwrap(); });
-
-    let base = format!("http://{}:{}", addr.ip(), addr.port());
+/// Client and base URL of a server shared by every test here. `app()` registers
+/// the process-wide metrics, so it is built once, on a runtime of its own that
+/// outlives each test's. Returns once the default model has been preloaded and
+/// warmed up.
+async fn server() -> (reqwest::Client, String) {
+    static BASE: OnceLock<String> = OnceLock::new();
+    let base = BASE.get_or_init(|| {
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+                tx.send(listener.local_addr().unwrap()).unwrap();
+                axum::serve(listener, app()).await.unwrap();
+            });
+        });
+        let addr = rx.recv().unwrap();
+        format!("http://{}:{}", addr.ip(), addr.port())
+    }).clone();
     let client = reqwest::Client::new();
+    for _ in 0..100 {
+        let r = client.get(format!("{}/readyz", base)).send().await.unwrap();
+        if r.status().is_success() { return (client, base); }
+        assert_eq!(r.status(), 503);
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    panic!("not ready");
+}
+
+fn chat(model: &str) -> serde_json::Value {
+    serde_json::json!({"model": model, "messages":[{"role":"user","content":"Hi"}], "max_tokens": 4})
+}
 
-    // metrics
+#[tokio::test]
+async fn exports_metrics() {
+    let (client, base) = server().await;
     let r = client.get(format!("{}/metrics", base)).send().await.unwrap();
     assert!(r.status().is_success());
+    let body = r.text().await.unwrap();
+    assert!(body.contains("runner_model_loads_total{model=\"mock\"}") && body.contains("runner_prefix_cache_hits_total"), "{body}");
+}
 
-    // generate
+#[tokio::test]
+async fn generate_returns_the_text_or_an_error_status() {
+    let (client, base) = server().await;
     let body = serde_json::json!({"prompt":"Hello"});
     let r = client.post(format!("{}/generate", base)).json(&body).send().await.unwrap();
     assert!(r.status().is_success());
+    // a generation that fails answers with an error status, not as its text
+    let r = client.post(format!("{}/generate", base)).json(&serde_json::json!({"prompt":"Hello", "max_tokens": 100_000_000})).send().await.unwrap();
+    assert_eq!(r.status(), 503);
+    assert!(r.json::<serde_json::Value>().await.unwrap()["error"]["message"].as_str().unwrap().starts_with("SERVER_BUSY"));
+}
 
-    // sse demo
-    let r = client.get(format!("{}/sse/generate", base)).send().await.unwrap();
+#[tokio::test]
+async fn tokenizes_and_detokenizes_with_the_model_tokenizer() {
+    let (client, base) = server().await;
+    let r = client.post(format!("{}/tokenize", base)).json(&serde_json::json!({"content":"Hi"})).send().await.unwrap();
+    let tokens = r.json::<serde_json::Value>().await.unwrap();
+    assert_eq!(tokens["count"], 2);
+    let r = client.post(format!("{}/detokenize", base)).json(&serde_json::json!({"tokens": tokens["tokens"]})).send().await.unwrap();
+    assert_eq!(r.json::<serde_json::Value>().await.unwrap()["content"], "Hi");
+    let r = client.post(format!("{}/detokenize", base)).json(&serde_json::json!({"tokens": [100000]})).send().await.unwrap();
+    assert_eq!(r.status(), 422);
+}
+
+#[tokio::test]
+async fn routes_and_lists_models_by_name() {
+    let (client, base) = server().await;
+    // models are routed by name; unknown ones get OpenAI's model_not_found
+    let r = client.post(format!("{}/v1/chat/completions", base)).json(&chat("mock")).send().await.unwrap();
     assert!(r.status().is_success());
+    let r = client.post(format!("{}/v1/chat/completions", base)).json(&chat("no-such-model")).send().await.unwrap();
+    assert_eq!(r.status(), 404);
+    assert_eq!(r.json::<serde_json::Value>().await.unwrap()["error"]["code"], "model_not_found");
 
-    drop(srv);
+    // clients list the models first, OpenAI-style, with what the registry knows
+    let models = client.get(format!("{}/v1/models", base)).send().await.unwrap().json::<serde_json::Value>().await.unwrap();
+    assert_eq!(models["object"], "list");
+    let mock = models["data"].as_array().unwrap().iter().find(|m| m["id"] == "mock").unwrap().clone();
+    assert_eq!((&mock["object"], &mock["owned_by"], &mock["loaded"], &mock["default"]), (&"model".into(), &"runner".into(), &true.into(), &true.into()));
+    assert!(mock["memory_bytes"].as_u64().is_some() && mock["capabilities"].as_array().unwrap().contains(&"chat".into()));
+    let r = client.get(format!("{}/v1/models/mock", base)).send().await.unwrap();
+    assert_eq!(r.json::<serde_json::Value>().await.unwrap()["id"], "mock");
+    let r = client.get(format!("{}/v1/models/no-such-model", base)).send().await.unwrap();
+    assert_eq!(r.status(), 404);
+    assert_eq!(r.json::<serde_json::Value>().await.unwrap()["error"]["code"], "model_not_found");
 }
 
+#[tokio::test]
+async fn streamed_chat_carries_the_completion_as_deltas() {
+    let (client, base) = server().await;
+    // streamed chat carries the same completion as chunk deltas
+    let full = client.post(format!("{}/v1/chat/completions", base)).json(&chat("mock")).send().await.unwrap().json::<serde_json::Value>().await.unwrap();
+    let mut req = chat("mock");
+    req["stream"] = true.into();
+    req["stream_options"] = serde_json::json!({"include_usage": true});
+    let r = client.post(format!("{}/v1/chat/completions", base)).json(&req).send().await.unwrap();
+    assert!(r.status().is_success());
+    let body = r.text().await.unwrap();
+    let data: Vec<&str> = body.lines().filter_map(|l| l.strip_prefix("data: ")).collect();
+    assert_eq!(data.last(), Some(&"[DONE]"));
+    let chunks: Vec<serde_json::Value> = data[..data.len() - 1].iter().map(|d| serde_json::from_str(d).unwrap()).collect();
+    assert!(chunks.iter().all(|c| c["id"] == chunks[0]["id"] && c["created"] == chunks[0]["created"] && c["model"] == "mock" && c["object"] == "chat.completion.chunk"));
+    assert_eq!(chunks[0]["choices"][0]["delta"]["role"], "assistant");
+    let content: String = chunks.iter().filter_map(|c| c["choices"][0]["delta"]["content"].as_str()).collect();
+    assert!(!content.is_empty());
+    assert_eq!(content, full["choices"][0]["message"]["content"].as_str().unwrap());
+    let (finish, usage) = (&chunks[chunks.len() - 2], &chunks[chunks.len() - 1]);
+    assert_eq!(finish["choices"][0]["finish_reason"], full["choices"][0]["finish_reason"]);
+    assert_eq!(usage["choices"], serde_json::json!([]));
+    assert_eq!(usage["usage"]["total_tokens"], usage["usage"]["prompt_tokens"].as_u64().unwrap() + usage["usage"]["completion_tokens"].as_u64().unwrap());
+    req["model"] = "no-such-model".into();
+    assert_eq!(client.post(format!("{}/v1/chat/completions", base)).json(&req).send().await.unwrap().status(), 404);
+}
+
+#[tokio::test]
+async fn tool_choice_must_name_an_offered_tool() {
+    let (client, base) = server().await;
+    // tool_choice must name one of the offered tools
+    let mut req = chat("mock");
+    req["tools"] = serde_json::json!([{"type":"function","function":{"name":"get_weather"}}]);
+    req["tool_choice"] = serde_json::json!({"type":"function","function":{"name":"get_time"}});
+    let r = client.post(format!("{}/v1/chat/completions", base)).json(&req).send().await.unwrap();
+    assert_eq!(r.status(), 400);
+    assert_eq!(r.json::<serde_json::Value>().await.unwrap()["error"]["param"], "tool_choice");
+    // the mock echoes its prompt, which never completes the call a forced tool_choice starts
+    req["tool_choice"] = "required".into();
+    let r = client.post(format!("{}/v1/chat/completions", base)).json(&req).send().await.unwrap();
+    assert_eq!(r.status(), 500);
+    req["stream"] = true.into();
+    let body = client.post(format!("{}/v1/chat/completions", base)).json(&req).send().await.unwrap().text().await.unwrap();
+    assert!(body.contains("did not parse as the tool call") && !body.contains("finish_reason\":\"stop"), "{body}");
+}
+
+#[tokio::test]
+async fn a_failed_swap_leaves_the_current_model_serving() {
+    let (client, base) = server().await;
+    // a model that fails to load leaves the current one serving
+    let r = client.post(format!("{}/admin/set_model", base)).json(&serde_json::json!({"path":"no-such-model.gguf","wait":true})).send().await.unwrap();
+    assert_eq!(r.status(), 422);
+    let status = client.get(format!("{}/admin/model_status", base)).send().await.unwrap().json::<serde_json::Value>().await.unwrap();
+    assert_eq!(status["state"], "failed");
+    assert_eq!(status["model"], "no-such-model");
+    let r = client.post(format!("{}/v1/chat/completions", base)).json(&chat("mock")).send().await.unwrap();
+    assert!(r.status().is_success());
+}
+
+#[tokio::test]
+async fn sse_streams_a_generation_as_named_events() {
+    let (client, base) = server().await;
+    // sse: a real generation request, streamed as named events
+    let r = client.post(format!("{}/sse/generate", base)).json(&serde_json::json!({"prompt": "Hello world", "stop": "wor", "temperature": 0.0})).send().await.unwrap();
+    assert!(r.status().is_success());
+    let body = r.text().await.unwrap();
+    let events: Vec<&str> = body.lines().filter_map(|l| l.strip_prefix("event: ")).collect();
+    assert_eq!((events.first(), events.last()), (Some(&"start"), Some(&"done")), "{body}");
+    let frames: Vec<serde_json::Value> = body.lines().filter_map(|l| l.strip_prefix("data: ")).map(|d| serde_json::from_str(d).unwrap()).collect();
+    let text: String = frames.iter().filter_map(|f| f["text"].as_str()).collect();
+    assert!(text.ends_with("Hello ") && !text.contains("wor"), "{text}");
+    assert_eq!(frames.last().unwrap()["finish_reason"], "stop");
+    let r = client.post(format!("{}/sse/generate", base)).json(&serde_json::json!({"prompt": "Hi", "top_p": 0})).send().await.unwrap();
+    assert_eq!(r.status(), 400);
+}
+
+#[tokio::test]
+async fn websocket_multiplexes_generations_with_cancel() {
+    let (_, base) = server().await;
+    // websocket: several generations over one connection, with cancel
+    let (mut ws, _) = tokio_tungstenite::connect_async(format!("{}/ws/generate", base.replace("http", "ws"))).await.unwrap();
+    for frame in [
+        serde_json::json!({"type": "generate", "id": "a", "prompt": "Hello", "max_tokens": 8}),
+        serde_json::json!({"type": "generate", "id": "b", "prompt": "Hi", "model": "no-such-model"}),
+        serde_json::json!({"type": "generate", "id": "c", "prompt": "Hello world"}),
+        serde_json::json!({"type": "cancel", "id": "c"}),
+        serde_json::json!({"type": "bogus"}),
+    ] {
+        ws.send(WsMessage::Text(frame.to_string())).await.unwrap();
+    }
+    let mut frames: HashMap<String, Vec<serde_json::Value>> = HashMap::new();
+    while !["a", "b", "c", ""].iter().all(|id| frames.get(*id).and_then(|f| f.last()).is_some_and(|f| f["type"] == "done" || f["type"] == "error")) {
+        let Some(Ok(WsMessage::Text(text))) = ws.next().await else { panic!("socket closed early: {frames:?}") };
+        let frame: serde_json::Value = serde_json::from_str(&text).unwrap();
+        frames.entry(frame["id"].as_str().unwrap_or_default().to_string()).or_default().push(frame);
+    }
+    let a = &frames["a"];
+    assert_eq!((a[0]["type"].as_str(), a.last().unwrap()["type"].as_str()), (Some("start"), Some("done")));
+    assert!(a.iter().filter_map(|f| f["text"].as_str()).collect::<String>().ends_with("Hello"));
+    assert_eq!(frames["b"][0]["error"]["code"], "model_not_found");
+    assert_eq!(frames[""][0]["type"], "error");
+    ws.close(None).await.unwrap();
+}
+
+#[tokio::test]
+async fn legacy_completions() {
+    let (client, base) = server().await;
+    // legacy completions: prompt batches, n, echo, stop and logprobs
+    let complete = |body: serde_json::Value| {
+        let (client, base) = (client.clone(), base.clone());
+        async move { client.post(format!("{}/v1/completions", base)).json(&body).send().await.unwrap() }
+    };
+    let r = complete(serde_json::json!({"prompt": ["Hello", [72, 105]], "n": 2, "stop": ["ll"]})).await;
+    assert!(r.status().is_success());
+    let body = r.json::<serde_json::Value>().await.unwrap();
+    assert_eq!(body["object"], "text_completion");
+    let texts: Vec<&str> = body["choices"].as_array().unwrap().iter().map(|c| c["text"].as_str().unwrap()).collect();
+    assert_eq!(texts, ["He", "He", "Hi", "Hi"]);
+    assert_eq!(body["choices"][3]["index"], 3);
+    assert_eq!(body["usage"]["prompt_tokens"], 7);
+    let body = complete(serde_json::json!({"prompt": "Hi", "echo": true, "logprobs": 2})).await.json::<serde_json::Value>().await.unwrap();
+    let choice = &body["choices"][0];
+    assert_eq!((choice["text"].as_str(), choice["finish_reason"].as_str()), (Some("HiHi"), Some("stop")));
+    // the mock picks tokens without logits, so there are no log-probabilities to give
+    assert_eq!(choice["logprobs"], serde_json::json!({"tokens": ["H", "i", "H", "i"], "token_logprobs": [null, null, null, null], "top_logprobs": [null, null, null, null], "text_offset": [0, 1, 2, 3]}));
+    for (body, param) in [
+        (serde_json::json!({"prompt": "Hi", "suffix": "!"}), "suffix"),
+        (serde_json::json!({"prompt": "Hi", "n": 0}), "n"),
+        (serde_json::json!({"prompt": "Hi", "logprobs": 6}), "logprobs"),
+        (serde_json::json!({"prompt": []}), "prompt"),
+    ] {
+        let r = complete(body).await;
+        assert_eq!(r.status(), 400);
+        assert_eq!(r.json::<serde_json::Value>().await.unwrap()["error"]["param"], param);
+    }
+    let r = complete(serde_json::json!({"prompt": "Hello", "n": 2, "echo": true, "stream": true, "stream_options": {"include_usage": true}})).await;
+    let body = r.text().await.unwrap();
+    let data: Vec<&str> = body.lines().filter_map(|l| l.strip_prefix("data: ")).collect();
+    assert_eq!(data.last(), Some(&"[DONE]"));
+    let chunks: Vec<serde_json::Value> = data[..data.len() - 1].iter().map(|d| serde_json::from_str(d).unwrap()).collect();
+    for index in 0..2 {
+        let mine: Vec<&serde_json::Value> = chunks.iter().flat_map(|c| c["choices"].as_array().unwrap()).filter(|c| c["index"] == index).collect();
+        assert_eq!(mine.iter().map(|c| c["text"].as_str().unwrap()).collect::<String>(), "HelloHello");
+        assert_eq!(mine.last().unwrap()["finish_reason"], "stop");
+    }
+    assert_eq!(chunks.last().unwrap()["usage"]["completion_tokens"], 10);
+}
+
+#[tokio::test]
+async fn sessions_resume_the_previous_turn() {
+    let (client, base) = server().await;
+    // session: two turns, the second resumes the first one's sequence
+    let r = client.post(format!("{}/v1/sessions", base)).send().await.unwrap();
+    assert_eq!(r.status(), 201);
+    let session = r.json::<serde_json::Value>().await.unwrap();
+    assert_eq!(session["model"], "mock");
+    let id = session["id"].as_str().unwrap().to_string();
+    for msg in ["Hi", "Again"] {
+        let r = client.post(format!("{}/v1/sessions/{}/messages", base, id)).json(&serde_json::json!({"role":"user","content":msg})).send().await.unwrap();
+        assert!(r.status().is_success());
+        let r = client.post(format!("{}/v1/sessions/{}/generate", base, id)).json(&serde_json::json!({"max_tokens":4})).send().await.unwrap();
+        assert!(r.status().is_success());
+        let cached = r.json::<serde_json::Value>().await.unwrap()["usage"]["cached_tokens"].as_u64().unwrap();
+        assert_eq!(cached > 0, msg == "Again");
+    }
+    let r = client.delete(format!("{}/v1/sessions/{}", base, id)).send().await.unwrap();
+    assert_eq!(r.status(), 204);
+    let r = client.get(format!("{}/v1/sessions/{}", base, id)).send().await.unwrap();
+    assert_eq!(r.status(), 404);
+}
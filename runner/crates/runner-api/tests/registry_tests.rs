@@ -0,0 +1,200 @@
+use std::sync::Arc;
+use runner_api::registry::{ModelRegistry, ModelSpec, RegistryError, MOCK_MODEL};
+use std::time::Duration;
+use runner_common::{cancel::CancelToken, config::{KeepAlive, RunnerConfig}};
+use runner_core::scheduler::{GenerationEvent, SchedulerV1};
+
+fn mock(name: &str) -> ModelSpec { ModelSpec { name: name.into(), aliases: vec![format!("{name}-alias")], ..ModelSpec::default() } }
+
+fn loaded(registry: &ModelRegistry) -> Vec<String> { registry.loaded().into_iter().map(|(n, _)| n).collect() }
+
+#[tokio::test]
+async fn routes_by_name_and_alias() {
+    let cfg = RunnerConfig { model_dir: "no-such-model-dir".into(), ..RunnerConfig::default() };
+    let registry = ModelRegistry::from_config(Arc::new(cfg));
+    assert_eq!(registry.default_model().as_deref(), Some(MOCK_MODEL));
+    registry.register(mock("a"));
+    assert_eq!(registry.resolve(None).unwrap(), MOCK_MODEL);
+    assert_eq!(registry.resolve(Some("a-alias")).unwrap(), "a");
+    assert!(matches!(registry.resolve(Some("gpt-4")), Err(RegistryError::NotFound(m)) if m == "gpt-4"));
+    let lease = registry.lease(Some("a-alias")).await.unwrap();
+    assert_eq!(lease.model, "a");
+    assert_eq!(loaded(&registry), ["a"]);
+}
+
+#[tokio::test]
+async fn unloads_least_recently_used_idle_models_under_budget() {
+    // every mock model costs its 1 MiB KV pool; two fit the budget
+    let cfg = RunnerConfig { model_dir: "no-such-model-dir".into(), kv_capacity_mb: Some(1), model_memory_mb: Some(2), ..RunnerConfig::default() };
+    let registry = ModelRegistry::from_config(Arc::new(cfg));
+    for name in ["a", "b", "c", "d"] { registry.register(mock(name)); }
+    drop(registry.lease(Some("a")).await.unwrap());
+    drop(registry.lease(Some("b")).await.unwrap());
+    drop(registry.lease(Some("a")).await.unwrap());
+    let c = registry.lease(Some("c")).await.unwrap();
+    assert_eq!(loaded(&registry), ["a", "c"], "b was the least recently used");
+    let a = registry.lease(Some("a")).await.unwrap();
+    // both loaded models are in use, so neither can make way
+    assert!(matches!(registry.lease(Some("d")).await, Err(RegistryError::OverBudget { .. })));
+    drop(a);
+    let _d = registry.lease(Some("d")).await.unwrap();
+    assert_eq!(loaded(&registry), ["c", "d"]);
+    drop(c);
+}
+
+#[tokio::test]
+async fn a_model_that_fails_to_load_evicts_nothing() {
+    let cfg = RunnerConfig { model_dir: "no-such-model-dir".into(), kv_capacity_mb: Some(1), model_memory_mb: Some(2), ..RunnerConfig::default() };
+    let registry = ModelRegistry::from_config(Arc::new(cfg));
+    for name in ["a", "b"] { registry.register(mock(name)); }
+    registry.register(ModelSpec { path: Some("no-such-model.gguf".into()), ..mock("broken") });
+    drop(registry.lease(Some("a")).await.unwrap());
+    drop(registry.lease(Some("b")).await.unwrap());
+    assert!(matches!(registry.lease(Some("broken")).await, Err(RegistryError::Load { .. })));
+    assert_eq!(loaded(&registry), ["a", "b"]);
+}
+
+#[tokio::test]
+async fn swap_keeps_in_flight_requests_on_the_old_model() {
+    let cfg = RunnerConfig { model_dir: "no-such-model-dir".into(), ..RunnerConfig::default() };
+    let registry = ModelRegistry::from_config(Arc::new(cfg));
+    let old = registry.lease(None).await.unwrap();
+    let prepared = registry.prepare(mock(MOCK_MODEL)).unwrap();
+    ModelRegistry::warmup(&prepared).await.unwrap();
+    registry.install(prepared);
+    let new = registry.lease(None).await.unwrap();
+    assert!(!Arc::ptr_eq(&old.backend, &new.backend), "new requests go to the swapped-in model");
+    assert_eq!(registry.resolve(Some("mock-alias")).unwrap(), MOCK_MODEL);
+    // the old scheduler keeps serving its lease holder
+    let mut events = SchedulerV1::submit(&old, "Hello".into(), 2, CancelToken::new());
+    let mut finished = false;
+    while let Some(ev) = events.recv().await { if let GenerationEvent::Finished { .. } = ev { finished = true; } }
+    assert!(finished);
+}
+
+#[test]
+fn swapping_in_a_configured_model_file_keeps_its_settings() {
+    let cfg = RunnerConfig { model_dir: "no-such-model-dir".into(), ..RunnerConfig::default() };
+    let registry = ModelRegistry::from_config(Arc::new(cfg));
+    let configured = ModelSpec { path: Some("models/llama.gguf".into()), chat_template: Some("chatml".into()), keep_alive: Some(KeepAlive::Forever), pinned: true, ..mock("llama") };
+    registry.register(configured.clone());
+    assert_eq!(registry.spec_for_path("models/llama.gguf"), configured);
+    // a new file for the same name takes its place under the same settings
+    assert_eq!(registry.spec_for_path("/srv/llama.gguf"), ModelSpec { path: Some("/srv/llama.gguf".into()), ..configured });
+    assert_eq!(registry.spec_for_path("models/qwen.gguf"), ModelSpec::from_path("models/qwen.gguf"));
+}
+
+#[tokio::test]
+async fn swapping_the_default_unloads_the_previous_one() {
+    let cfg = RunnerConfig { model_dir: "no-such-model-dir".into(), ..RunnerConfig::default() };
+    let registry = ModelRegistry::from_config(Arc::new(cfg));
+    registry.register(ModelSpec { pinned: true, ..mock("a") });
+    registry.set_default("a");
+    let old = registry.lease(None).await.unwrap();
+    let prepared = registry.prepare(mock("b")).unwrap();
+    registry.install_default(prepared);
+    assert_eq!((loaded(&registry), registry.default_model().as_deref()), (vec!["b".to_string()], Some("b")));
+    assert_eq!(registry.stats()["a"].unloads["replaced"], 1);
+    // the lease taken before the swap still finishes on the old model
+    let mut events = SchedulerV1::submit(&old, "Hello".into(), 2, CancelToken::new());
+    let mut finished = false;
+    while let Some(ev) = events.recv().await { if let GenerationEvent::Finished { .. } = ev { finished = true; } }
+    assert!(finished);
+}
+
+#[test]
+fn keep_alive_parses_like_ollama() {
+    assert_eq!("5m".parse::<KeepAlive>().unwrap(), KeepAlive::For(Duration::from_secs(300)));
+    assert_eq!("1h30m".parse::<KeepAlive>().unwrap(), KeepAlive::For(Duration::from_secs(5400)));
+    assert_eq!("90".parse::<KeepAlive>().unwrap(), KeepAlive::For(Duration::from_secs(90)));
+    assert_eq!("-1".parse::<KeepAlive>().unwrap(), KeepAlive::Forever);
+    assert_eq!(serde_json::from_str::<KeepAlive>("0").unwrap(), KeepAlive::For(Duration::ZERO));
+    assert!("soon".parse::<KeepAlive>().is_err());
+}
+
+#[tokio::test]
+async fn idle_models_unload_after_keep_alive_unless_pinned() {
+    let cfg = RunnerConfig { model_dir: "no-such-model-dir".into(), kv_capacity_mb: Some(1), model_memory_mb: Some(2), keep_alive: Some(KeepAlive::Forever), ..RunnerConfig::default() };
+    let registry = ModelRegistry::from_config(Arc::new(cfg));
+    registry.register(ModelSpec { keep_alive: Some(KeepAlive::For(Duration::ZERO)), ..mock("short") });
+    registry.register(ModelSpec { keep_alive: Some(KeepAlive::For(Duration::ZERO)), pinned: true, ..mock("pinned") });
+    registry.register(mock("long"));
+    let short = registry.lease(Some("short")).await.unwrap();
+    drop(registry.lease(Some("pinned")).await.unwrap());
+    assert!(registry.unload_expired().is_empty(), "leased models stay loaded");
+    drop(short);
+    assert_eq!(registry.unload_expired(), ["short"]);
+    // a request's keep_alive overrides the model's until its next request
+    drop(registry.lease_with(Some("long"), Some(KeepAlive::For(Duration::ZERO))).await.unwrap());
+    assert_eq!(registry.unload_expired(), ["long"]);
+    // the pinned model is not unloaded to make room either
+    let long = registry.lease(Some("long")).await.unwrap();
+    assert!(matches!(registry.lease(Some("short")).await, Err(RegistryError::OverBudget { .. })));
+    drop(long);
+    assert_eq!(loaded(&registry), ["long", "pinned"]);
+    let stats = registry.stats();
+    assert_eq!(stats["long"].loads, 2);
+    assert_eq!(stats["short"].unloads["idle"], 1);
+}
+
+#[tokio::test]
+async fn preload_warms_up_a_model_once() {
+    let cfg = RunnerConfig { model_dir: "no-such-model-dir".into(), ..RunnerConfig::default() };
+    let registry = ModelRegistry::from_config(Arc::new(cfg));
+    registry.register(mock("a"));
+    registry.preload("a-alias").await.unwrap();
+    registry.preload("a").await.unwrap();
+    assert_eq!(loaded(&registry), ["a"]);
+    assert_eq!(registry.stats()["a"].loads, 1);
+    assert!(matches!(registry.preload("b").await, Err(RegistryError::NotFound(_))));
+}
+
+/// Just the GGUF header `describe` reads: a Qwen-style model with FIM tokens.
+fn write_gguf(path: &std::path::Path) {
+    fn string(buf: &mut Vec<u8>, s: &str) { buf.extend((s.len() as u64).to_le_bytes()); buf.extend(s.as_bytes()); }
+    let mut kv = Vec::new();
+    for (key, value) in [("general.architecture", "qwen2"), ("tokenizer.ggml.model", "gpt2"), ("tokenizer.chat_template", "{% if tools %}{{ tools }}{% endif %}")] {
+        string(&mut kv, key);
+        kv.extend(8u32.to_le_bytes());
+        string(&mut kv, value);
+    }
+    for (key, value) in [("general.file_type", 7u32), ("qwen2.context_length", 32768)] {
+        string(&mut kv, key);
+        kv.extend(4u32.to_le_bytes());
+        kv.extend(value.to_le_bytes());
+    }
+    let tokens = ["a", "b", "<|fim_prefix|>", "<|fim_suffix|>", "<|fim_middle|>"];
+    string(&mut kv, "tokenizer.ggml.tokens");
+    kv.extend(9u32.to_le_bytes());
+    kv.extend(8u32.to_le_bytes());
+    kv.extend((tokens.len() as u64).to_le_bytes());
+    for t in tokens { string(&mut kv, t); }
+    let mut out = b"GGUF".to_vec();
+    out.extend(3u32.to_le_bytes());
+    out.extend(0u64.to_le_bytes());
+    out.extend(6u64.to_le_bytes());
+    out.extend(kv);
+    std::fs::write(path, out).unwrap();
+}
+
+#[test]
+fn describes_models_from_the_registry_and_their_files() {
+    let dir = std::env::temp_dir().join(format!("runner-models-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let cfg = RunnerConfig { model_dir: dir.clone(), ..RunnerConfig::default() };
+    let registry = ModelRegistry::from_config(Arc::new(cfg));
+    let mock = registry.describe(MOCK_MODEL).unwrap();
+    assert_eq!((mock.loaded, mock.default, mock.size_bytes, mock.quantization), (false, true, 0, None));
+    assert_eq!(mock.capabilities, ["completion", "chat", "tools"]);
+    // files dropped into the model directory show up without a restart
+    write_gguf(&dir.join("coder.gguf"));
+    assert_eq!(registry.discover(), ["coder"]);
+    assert!(registry.discover().is_empty());
+    let coder = registry.describe("coder").unwrap();
+    assert_eq!((coder.architecture.as_deref(), coder.quantization, coder.loaded), (Some("qwen2"), Some("Q8_0"), false));
+    assert_eq!((coder.context_length, coder.trained_context_length), (Some(2048), Some(32768)));
+    assert_eq!(coder.capabilities, ["completion", "chat", "tools", "fill_in_the_middle"]);
+    assert!(coder.size_bytes > 0 && coder.created > 0);
+    assert!(registry.describe("nope").is_none());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,91 @@
+use runner_api::chat::{ChatMessage, ChatTemplate};
+use runner_api::tools::{FunctionCall, Tool, ToolCall, ToolChoice, ToolFormat};
+use runner_backend::mock::MockBackend;
+use runner_backend::tokenizer::BackendTokenizer;
+use std::sync::Arc;
+
+fn weather() -> Vec<Tool> {
+    serde_json::from_value(serde_json::json!([{"type": "function", "function": {"name": "get_weather", "description": "Weather in a city", "parameters": {"type": "object", "properties": {"city": {"type": "string"}}}}}])).unwrap()
+}
+
+fn calls(format: ToolFormat, text: &str) -> (Option<String>, Vec<(String, serde_json::Value)>) {
+    let (content, calls) = format.parse(text, &weather());
+    (content, calls.into_iter().map(|c| (c.function.name, serde_json::from_str(&c.function.arguments).unwrap())).collect())
+}
+
+#[test]
+fn parses_each_family_call_format() {
+    let paris = || ("get_weather".to_string(), serde_json::json!({"city": "Paris"}));
+    let (content, found) = calls(ToolFormat::Hermes, "Let me check.\n<tool_call>\n{\"name\": \"get_weather\", \"arguments\": {\"city\": \"Paris\"}}\n</tool_call>\n<tool_call>{\"name\": \"get_weather\", \"arguments\": {\"city\": \"Paris\"}}");
+    assert_eq!((content.as_deref(), found), (Some("Let me check."), vec![paris(), paris()]));
+    let (content, found) = calls(ToolFormat::Llama3, "<|python_tag|>{\"name\": \"get_weather\", \"parameters\": {\"city\": \"Paris\"}}");
+    assert_eq!((content, found), (None, vec![paris()]));
+    let (content, found) = calls(ToolFormat::Mistral, "[TOOL_CALLS] [{\"name\": \"get_weather\", \"arguments\": {\"city\": \"Paris\"}}]");
+    assert_eq!((content, found), (None, vec![paris()]));
+    // plain answers, malformed calls and unknown functions stay content
+    for (format, text) in [
+        (ToolFormat::Llama3, "It is sunny."),
+        (ToolFormat::Hermes, "<tool_call>{\"name\": \"get_weather\", \"argu"),
+        (ToolFormat::Mistral, "[TOOL_CALLS] [{\"name\": \"delete_everything\", \"arguments\": {}}]"),
+    ] {
+        assert_eq!(calls(format, text), (Some(text.to_string()), Vec::new()));
+    }
+}
+
+#[test]
+fn tool_choice_and_forced_call_prefixes() {
+    let choice = |v: serde_json::Value| serde_json::from_value::<ToolChoice>(v).unwrap();
+    assert_eq!(choice(serde_json::json!("required")), ToolChoice::Required);
+    assert_eq!(choice(serde_json::json!({"type": "function", "function": {"name": "get_weather"}})), ToolChoice::Function("get_weather".into()));
+    assert!(serde_json::from_value::<ToolChoice>(serde_json::json!("sometimes")).is_err());
+    assert!(choice(serde_json::json!("required")).forces_call() && !choice(serde_json::json!("auto")).forces_call());
+    // the model only writes the arguments of a forced call
+    let prefix = ToolFormat::Hermes.call_prefix(Some("get_weather"));
+    let (_, found) = calls(ToolFormat::Hermes, &format!("{prefix}{{\"city\": \"Paris\"}}}}\n</tool_call>"));
+    assert_eq!(found, [("get_weather".to_string(), serde_json::json!({"city": "Paris"}))]);
+    assert_eq!(ToolFormat::Mistral.call_prefix(None), "[TOOL_CALLS] [{\"name\": \"");
+}
+
+fn template(name: &str) -> ChatTemplate {
+    let model = MockBackend::handle();
+    ChatTemplate::for_model(Some(name), &model, &BackendTokenizer(Arc::new(MockBackend::new()))).unwrap()
+}
+
+fn tool_turns() -> Vec<ChatMessage> {
+    let call = ToolCall { id: "call_1".into(), kind: "function".into(), function: FunctionCall { name: "get_weather".into(), arguments: "{\"city\": \"Paris\"}".into() } };
+    vec![
+        ChatMessage { role: "user".into(), content: "Weather in Paris?".into(), ..ChatMessage::default() },
+        ChatMessage { role: "assistant".into(), tool_calls: vec![call], ..ChatMessage::default() },
+        ChatMessage { role: "tool".into(), content: "sunny".into(), tool_call_id: Some("call_1".into()), ..ChatMessage::default() },
+    ]
+}
+
+#[test]
+fn builtin_templates_render_tools_calls_and_results() {
+    let chatml = template("chatml");
+    assert_eq!(chatml.tool_format, ToolFormat::Hermes);
+    let text = chatml.render(&tool_turns(), &weather(), true).unwrap();
+    assert!(text.starts_with("<|im_start|>system\n# Tools\n"), "{text}");
+    assert!(text.contains("<tools>\n{\"function\": {\"description\": \"Weather in a city\", \"name\": \"get_weather\""), "{text}");
+    assert!(text.contains("<|im_start|>assistant\n<tool_call>\n{\"name\": \"get_weather\", \"arguments\": {\"city\": \"Paris\"}}\n</tool_call><|im_end|>\n"), "{text}");
+    assert!(text.ends_with("<|im_start|>user\n<tool_response>\nsunny\n</tool_response><|im_end|>\n<|im_start|>assistant\n"), "{text}");
+    // the rendered call parses back into the same call
+    let rendered = text.split("<|im_start|>assistant\n").nth(1).unwrap().split("<|im_end|>").next().unwrap();
+    assert_eq!(chatml.tool_format.parse(rendered, &weather()).1[0].function.name, "get_weather");
+
+    let llama3 = template("llama3");
+    assert_eq!(llama3.tool_format, ToolFormat::Llama3);
+    let text = llama3.render(&tool_turns(), &weather(), true).unwrap();
+    assert!(text.contains("Do not use variables.\n\n{\n    \"function\": {"), "{text}");
+    assert!(text.contains("{\"name\": \"get_weather\", \"parameters\": {\"city\": \"Paris\"}}<|eot_id|><|start_header_id|>ipython<|end_header_id|>\n\nsunny<|eot_id|>"), "{text}");
+
+    let mistral = template("mistral");
+    assert_eq!(mistral.tool_format, ToolFormat::Mistral);
+    let text = mistral.render(&tool_turns(), &weather(), true).unwrap();
+    assert!(text.starts_with("[AVAILABLE_TOOLS] [{\"function\""), "{text}");
+    assert!(text.contains("[/INST][TOOL_CALLS] [{\"arguments\": {\"city\": \"Paris\"}, \"name\": \"get_weather\"}][TOOL_RESULTS] {\"call_id\": \"call_1\", \"content\": \"sunny\"}[/TOOL_RESULTS]"), "{text}");
+
+    // without tools nothing about them is rendered
+    assert!(!template("gemma").render(&tool_turns()[..1], &[], true).unwrap().contains("tool_call"));
+    assert!(template("gemma").render(&tool_turns(), &weather(), true).unwrap().contains("<tool_response>\nsunny\n</tool_response>"));
+}
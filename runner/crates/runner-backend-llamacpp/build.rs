@@ -26,7 +26,7 @@ fn main() {
     // Link search paths: explicit LLAMA_CPP_LIB + common build dirs under roots
     if let Ok(extra) = std::env::var("LLAMA_CPP_LIB") {
         // Allow multiple entries separated by ';' (Windows) or ':' (Unix)
-        let parts: Vec<&str> = extra.split(|c| c == ';' || c == ':').filter(|s| !s.is_empty()).collect();
+        let parts: Vec<&str> = extra.split([';', ':']).filter(|s| !s.is_empty()).collect();
         if parts.is_empty() {
             println!("cargo:rustc-link-search=native={}", canon(std::path::Path::new(&extra)).to_string_lossy());
         } else {
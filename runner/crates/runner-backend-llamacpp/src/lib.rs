@@ -1,5 +1,9 @@
 use runner_backend::{ForwardOutput, InferenceBackend, KvStats, LoadParams, ModelHandle, SequenceState};
+#[cfg(llama_ffi)]
+use runner_backend::{gguf::GgufFile, ContextOverflow, ContextPolicy, KvCacheType, KvLayout, NumaStrategy, RopeScaling};
 use runner_common::{Result, RunnerError};
+#[cfg(llama_ffi)]
+use runner_common::cancel::CancelToken;
 use std::sync::{Arc, Mutex};
 
 #[cfg(llama_ffi)]
@@ -9,7 +13,10 @@ mod ffi {
 }
 
 #[derive(Default, Clone)]
-pub struct LlamaCppBackend { state: Arc<Mutex<State>> }
+pub struct LlamaCppBackend {
+    #[cfg_attr(not(llama_ffi), allow(dead_code))]
+    state: Arc<Mutex<State>>,
+}
 
 #[cfg(llama_ffi)]
 #[derive(Default)]
@@ -17,10 +24,264 @@ struct State {
     model_loaded: bool,
     model_path: Option<String>,
     n_ctx: i32,
+    runtime: Option<Runtime>,
+    model: Option<ModelHandle>,
+    /// What the loaded model was set up with, reused by one-off generation.
+    params: LoadParams,
+}
+
+/// Number of llama sequence slots (`n_seq_max`) a context is created with.
+#[cfg(llama_ffi)]
+const MAX_SEQS: usize = 8;
+
+/// Model and context kept alive between steps so the scheduler can drive decoding.
+/// Only touched while holding the backend's state mutex.
+#[cfg(llama_ffi)]
+struct Runtime {
+    model: *mut ffi::llama_model,
+    ctx: *mut ffi::llama_context,
+    /// Scheduler sequence id occupying each llama seq slot, and when it was last stepped.
+    slots: [Option<u64>; MAX_SEQS],
+    last_used: [u64; MAX_SEQS],
+    steps: u64,
+}
+
+#[cfg(llama_ffi)]
+impl Runtime {
+    fn new(model: *mut ffi::llama_model, ctx: *mut ffi::llama_context) -> Self {
+        Self { model, ctx, slots: [None; MAX_SEQS], last_used: [0; MAX_SEQS], steps: 0 }
+    }
+
+    /// Slot holding `seq`'s KV, reclaiming the least recently stepped one if it has none.
+    /// A reclaimed slot is wiped and the sequence re-prefills from position 0; the
+    /// scheduler does not hand out cached prefixes, as `paged_kv` is false.
+    unsafe fn slot_for(&mut self, seq: &mut SequenceState) -> usize {
+        let slot = match self.slot_of(seq.id) {
+            Some(i) => i,
+            None => { seq.n_past = 0; self.claim(seq.id) }
+        };
+        self.steps += 1;
+        self.last_used[slot] = self.steps;
+        slot
+    }
+
+    fn slot_of(&self, seq_id: u64) -> Option<usize> { self.slots.iter().position(|s| *s == Some(seq_id)) }
+
+    /// Hand the least recently stepped slot (free ones first) to `seq_id`, wiping its cells.
+    unsafe fn claim(&mut self, seq_id: u64) -> usize {
+        let i = (0..MAX_SEQS).min_by_key(|&i| (self.slots[i].is_some(), self.last_used[i])).unwrap_or(0);
+        ffi::llama_kv_cache_seq_rm(self.ctx, i as i32, -1, -1);
+        self.slots[i] = Some(seq_id);
+        i
+    }
+
+    /// Evaluate every sequence's pending tokens in shared batches of at most `n_batch`
+    /// tokens, so long prompts prefill in chunks; the decoder samples from the logits.
+    /// A failed decode fails only the sequences that had tokens in that batch.
+    unsafe fn step(&mut self, seqs: &mut [SequenceState]) -> Vec<ForwardOutput> {
+        let mut outs = vec![ForwardOutput::default(); seqs.len()];
+        // (index into seqs, slot, next position to evaluate)
+        let mut pending = Vec::with_capacity(seqs.len());
+        for (i, seq) in seqs.iter_mut().enumerate() {
+            if seq.cancel.is_cancelled() || seq.tokens.is_empty() { continue; }
+            let slot = self.slot_for(seq);
+            // always evaluate at least the last token so fresh logits exist
+            let start = seq.n_past.min(seq.tokens.len() - 1);
+            if start < seq.n_past { ffi::llama_kv_cache_seq_rm(self.ctx, slot as i32, start as i32, -1); }
+            pending.push((i, slot, start));
+        }
+        let n_batch = (ffi::llama_n_batch(self.ctx) as usize).max(1);
+        let vocab = ffi::llama_n_vocab(self.model) as usize;
+        let mut batch = ffi::llama_batch_init(n_batch as i32, 0, 1);
+        while !pending.is_empty() {
+            // fill the batch in sequence order; a sequence finishing here gets logits on its last token
+            let mut n = 0;
+            let mut members = Vec::new();
+            for (k, (i, slot, pos)) in pending.iter_mut().enumerate() {
+                if n == n_batch { break; }
+                let tokens = &seqs[*i].tokens;
+                let take = (tokens.len() - *pos).min(n_batch - n);
+                for (j, &t) in tokens[*pos..*pos + take].iter().enumerate() {
+                    *batch.token.add(n) = t as ffi::llama_token;
+                    *batch.pos.add(n) = (*pos + j) as ffi::llama_pos;
+                    *batch.n_seq_id.add(n) = 1;
+                    **batch.seq_id.add(n) = *slot as ffi::llama_seq_id;
+                    *batch.logits.add(n) = 0;
+                    n += 1;
+                }
+                *pos += take;
+                let last = (*pos == tokens.len()).then_some(n - 1);
+                if let Some(j) = last { *batch.logits.add(j) = 1; }
+                members.push((k, last));
+            }
+            batch.n_tokens = n as i32;
+            let failed = ffi::llama_decode(self.ctx, batch) != 0;
+            let mut done = vec![false; pending.len()];
+            for (k, last) in members {
+                let (i, slot, _) = pending[k];
+                if failed {
+                    ffi::llama_kv_cache_seq_rm(self.ctx, slot as i32, -1, -1);
+                    self.slots[slot] = None;
+                    outs[i].error = Some("llama_decode failed".into());
+                } else if let Some(j) = last {
+                    seqs[i].n_past = seqs[i].tokens.len();
+                    let logits = ffi::llama_get_logits_ith(self.ctx, j as i32);
+                    // EOS/EOT are sampled like any token; the decoder stops on the model's stop tokens
+                    if !logits.is_null() { outs[i].logits = Some(std::slice::from_raw_parts(logits, vocab).to_vec()); }
+                } else {
+                    continue;
+                }
+                done[k] = true;
+            }
+            let mut k = 0;
+            pending.retain(|_| { k += 1; !done[k - 1] });
+        }
+        ffi::llama_batch_free(batch);
+        outs
+    }
+}
+
+#[cfg(llama_ffi)]
+unsafe impl Send for Runtime {}
+
+#[cfg(llama_ffi)]
+impl Drop for Runtime {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::llama_free(self.ctx);
+            ffi::llama_free_model(self.model);
+        }
+    }
+}
+
+/// Integer GGUF metadata value, e.g. `llama.block_count`.
+#[cfg(llama_ffi)]
+unsafe fn meta_usize(model: *const ffi::llama_model, key: &str) -> Option<usize> {
+    meta_str(model, key)?.trim().parse().ok()
+}
+
+#[cfg(llama_ffi)]
+unsafe fn meta_str(model: *const ffi::llama_model, key: &str) -> Option<String> {
+    let ckey = std::ffi::CString::new(key).ok()?;
+    let mut buf: Vec<i8> = vec![0; 256];
+    let n = ffi::llama_model_meta_val_str(model, ckey.as_ptr(), buf.as_mut_ptr(), buf.len());
+    if n < 0 { return None; }
+    let bytes = std::slice::from_raw_parts(buf.as_ptr() as *const u8, (n as usize).min(buf.len() - 1));
+    Some(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// KV shape from the model's `<arch>.*` GGUF keys. KV heads default to the
+/// attention head count (no GQA) and head dim to `embedding_length / heads`.
+#[cfg(llama_ffi)]
+unsafe fn kv_layout(model: *const ffi::llama_model, params: &LoadParams) -> Option<KvLayout> {
+    let arch = meta_str(model, "general.architecture")?;
+    let n_layers = meta_usize(model, &format!("{arch}.block_count"))?;
+    let n_heads = meta_usize(model, &format!("{arch}.attention.head_count"))?;
+    let n_kv_heads = meta_usize(model, &format!("{arch}.attention.head_count_kv")).unwrap_or(n_heads);
+    let head_dim = meta_usize(model, &format!("{arch}.attention.key_length"))
+        .or_else(|| Some(meta_usize(model, &format!("{arch}.embedding_length"))? / n_heads.max(1)))?;
+    Some(KvLayout { n_layers, n_kv_heads, head_dim, type_k: params.type_k, type_v: params.type_v })
+}
+
+/// Metadata of a freshly loaded model: its GGUF header (for the chat template
+/// and special tokens), with what llama.cpp itself reports taking precedence.
+#[cfg(llama_ffi)]
+unsafe fn model_handle(path: &str, model: *const ffi::llama_model, ctx: *const ffi::llama_context, params: &LoadParams) -> ModelHandle {
+    let mut m = GgufFile::open(path)
+        .map(|g| ModelHandle::from_gguf(path, &g, params))
+        .unwrap_or_else(|_| ModelHandle { id: path.to_string(), ..Default::default() });
+    // llama.cpp reports a missing special token as -1
+    let token = |t: ffi::llama_token| u32::try_from(t).ok();
+    m.vocab_size = ffi::llama_n_vocab(model) as usize;
+    m.special_tokens.bos = token(ffi::llama_token_bos(model));
+    m.special_tokens.eos = token(ffi::llama_token_eos(model));
+    m.special_tokens.eot = token(ffi::llama_token_eot(model));
+    m.n_ctx_train = Some(ffi::llama_n_ctx_train(model) as usize);
+    m.n_ctx = Some(ffi::llama_n_ctx(ctx) as usize);
+    m.n_embd = Some(ffi::llama_n_embd(model) as usize);
+    m.kv_layout = kv_layout(model, params).or(m.kv_layout);
+    m.n_params = ffi::llama_model_n_params(model);
+    m.size_bytes = ffi::llama_model_size(model);
+    m
+}
+
+#[cfg(llama_ffi)]
+fn ggml_type(t: KvCacheType) -> ffi::ggml_type {
+    match t {
+        KvCacheType::F32 => ffi::ggml_type_GGML_TYPE_F32,
+        KvCacheType::F16 => ffi::ggml_type_GGML_TYPE_F16,
+        KvCacheType::Q8_0 => ffi::ggml_type_GGML_TYPE_Q8_0,
+        KvCacheType::Q4_0 => ffi::ggml_type_GGML_TYPE_Q4_0,
+    }
+}
+
+#[cfg(llama_ffi)]
+fn model_params(params: &LoadParams) -> ffi::llama_model_params {
+    let mut m = unsafe { ffi::llama_model_default_params() };
+    m.n_gpu_layers = params.n_gpu_layers as i32;
+    if let Some(v) = params.use_mmap { m.use_mmap = v; }
+    if let Some(v) = params.use_mlock { m.use_mlock = v; }
+    m
+}
+
+/// Context parameters for `params`; RoPE fields left unset keep the model's values.
+#[cfg(llama_ffi)]
+fn context_params(params: &LoadParams) -> ffi::llama_context_params {
+    let mut c = unsafe { ffi::llama_context_default_params() };
+    c.n_ctx = params.n_ctx as u32;
+    c.n_seq_max = MAX_SEQS as u32;
+    c.type_k = ggml_type(params.type_k);
+    c.type_v = ggml_type(params.type_v);
+    // llama.cpp only supports a quantized V cache with flash attention
+    c.flash_attn = params.flash_attn.unwrap_or(params.type_v.is_quantized());
+    if let Some(n) = params.n_batch { c.n_batch = n as u32; }
+    if let Some(n) = params.n_ubatch { c.n_ubatch = n as u32; }
+    if let Some(n) = params.n_threads { c.n_threads = n as _; }
+    if let Some(n) = params.n_threads_batch { c.n_threads_batch = n as _; }
+    let rope = &params.rope;
+    if let Some(scaling) = rope.scaling {
+        c.rope_scaling_type = match scaling {
+            RopeScaling::None => ffi::llama_rope_scaling_type_LLAMA_ROPE_SCALING_TYPE_NONE,
+            RopeScaling::Linear => ffi::llama_rope_scaling_type_LLAMA_ROPE_SCALING_TYPE_LINEAR,
+            RopeScaling::Yarn => ffi::llama_rope_scaling_type_LLAMA_ROPE_SCALING_TYPE_YARN,
+        };
+    }
+    if let Some(v) = rope.freq_base { c.rope_freq_base = v; }
+    if let Some(v) = rope.freq_scale { c.rope_freq_scale = v; }
+    if let Some(v) = rope.yarn_ext_factor { c.yarn_ext_factor = v; }
+    if let Some(v) = rope.yarn_attn_factor { c.yarn_attn_factor = v; }
+    if let Some(v) = rope.yarn_beta_fast { c.yarn_beta_fast = v; }
+    if let Some(v) = rope.yarn_beta_slow { c.yarn_beta_slow = v; }
+    if let Some(v) = rope.yarn_orig_ctx { c.yarn_orig_ctx = v as u32; }
+    c
+}
+
+#[cfg(llama_ffi)]
+fn numa_strategy(numa: NumaStrategy) -> ffi::ggml_numa_strategy {
+    match numa {
+        NumaStrategy::Disabled => ffi::ggml_numa_strategy_GGML_NUMA_STRATEGY_DISABLED,
+        NumaStrategy::Distribute => ffi::ggml_numa_strategy_GGML_NUMA_STRATEGY_DISTRIBUTE,
+        NumaStrategy::Isolate => ffi::ggml_numa_strategy_GGML_NUMA_STRATEGY_ISOLATE,
+        NumaStrategy::Numactl => ffi::ggml_numa_strategy_GGML_NUMA_STRATEGY_NUMACTL,
+        NumaStrategy::Mirror => ffi::ggml_numa_strategy_GGML_NUMA_STRATEGY_MIRROR,
+    }
+}
+
+#[cfg(llama_ffi)]
+unsafe fn token_bytes(model: *const ffi::llama_model, token: ffi::llama_token) -> Vec<u8> {
+    let needed = ffi::llama_token_to_piece(model, token, std::ptr::null_mut(), 0);
+    // negative return is the required buffer size
+    let len = needed.unsigned_abs() as usize;
+    if len == 0 { return Vec::new(); }
+    let mut buf: Vec<i8> = vec![0; len + 1];
+    let written = ffi::llama_token_to_piece(model, token, buf.as_mut_ptr(), buf.len() as i32);
+    if written <= 0 { return Vec::new(); }
+    std::slice::from_raw_parts(buf.as_ptr() as *const u8, written as usize).to_vec()
 }
 
 #[cfg(not(llama_ffi))]
 #[derive(Default)]
+#[allow(dead_code)]
 struct State {
     model_loaded: bool,
     model_path: Option<String>,
@@ -35,12 +296,16 @@ impl LlamaCppBackend {
         &self,
         prompt: &str,
         max_tokens: usize,
+        cancel: &CancelToken,
         mut emit: F,
     ) -> Result<String> {
-        self.generate_with_callback_params(prompt, max_tokens, 1.0, 1.0, 0, &mut emit)
+        self.generate_with_callback_params(prompt, max_tokens, 1.0, 1.0, 0, ContextPolicy::default(), cancel, &mut emit)
     }
 
+    /// One-off generation in a fresh context set up like the loaded one. `context`
+    /// decides what happens when the prompt plus `max_tokens` outgrows it.
     #[cfg(llama_ffi)]
+    #[allow(clippy::too_many_arguments)]
     pub fn generate_with_callback_params<F: FnMut(String)>(
         &self,
         prompt: &str,
@@ -48,6 +313,8 @@ impl LlamaCppBackend {
         temperature: f32,
         top_p: f32,
         top_k: usize,
+        context: ContextPolicy,
+        cancel: &CancelToken,
         mut emit: F,
     ) -> Result<String> {
         unsafe {
@@ -56,15 +323,13 @@ impl LlamaCppBackend {
             let st = self.state.lock().unwrap();
             let Some(ref model_path) = st.model_path else { return Err(RunnerError::Message("model not loaded".into())) };
             let cpath = std::ffi::CString::new(model_path.as_str()).unwrap();
-            let mut mparams = ffi::llama_model_default_params();
-            // For generation we need full model; keep defaults
-            let model = ffi::llama_load_model_from_file(cpath.as_ptr(), mparams);
+            let model = ffi::llama_load_model_from_file(cpath.as_ptr(), model_params(&st.params));
             if model.is_null() { return Err(RunnerError::Message("llama_load_model_from_file failed".into())); }
-            let mut cparams = ffi::llama_context_default_params();
-            // reasonable default context
-            cparams.n_ctx = 2048u32;
+            let mut cparams = context_params(&st.params);
+            cparams.n_seq_max = 1;
             let ctx = ffi::llama_new_context_with_model(model, cparams);
             if ctx.is_null() { ffi::llama_free_model(model); return Err(RunnerError::Message("llama_new_context_with_model failed".into())) }
+            let n_ctx = ffi::llama_n_ctx(ctx) as usize;
 
             // tokenize prompt
             let cprompt = std::ffi::CString::new(prompt).unwrap();
@@ -73,16 +338,20 @@ impl LlamaCppBackend {
             let mut ptoks: Vec<i32> = vec![0; n as usize];
             let n2 = ffi::llama_tokenize(model, cprompt.as_ptr(), 0i32, ptoks.as_mut_ptr(), ptoks.len() as i32, true, false);
             let ptoks = &ptoks[..(n2 as usize)];
+            let max_tokens = match context.fit(ptoks.len(), max_tokens, n_ctx) {
+                Ok(n) => n,
+                Err(e) => { ffi::llama_free(ctx); ffi::llama_free_model(model); return Err(e); }
+            };
 
             let mut n_past: i32 = 0;
-            // evaluate all prompt tokens first using decode+batch
-            if !ptoks.is_empty() {
-                let mut toks: Vec<ffi::llama_token> = ptoks.iter().map(|&t| t as ffi::llama_token).collect();
-                let batch = ffi::llama_batch_get_one(toks.as_mut_ptr(), toks.len() as i32, 0, 0);
+            // evaluate the prompt in n_batch chunks; a larger batch is rejected by llama_decode
+            let mut toks: Vec<ffi::llama_token> = ptoks.iter().map(|&t| t as ffi::llama_token).collect();
+            let n_batch = (ffi::llama_n_batch(ctx) as usize).max(1);
+            for chunk in toks.chunks_mut(n_batch) {
+                let batch = ffi::llama_batch_get_one(chunk.as_mut_ptr(), chunk.len() as i32, n_past, 0);
                 let rc = ffi::llama_decode(ctx, batch);
-                ffi::llama_batch_free(batch);
                 if rc != 0 { ffi::llama_free(ctx); ffi::llama_free_model(model); return Err(RunnerError::Message("llama_decode prompt failed".into())) }
-                n_past += ptoks.len() as i32;
+                n_past += chunk.len() as i32;
             }
 
             let mut generated = String::new();
@@ -90,11 +359,22 @@ impl LlamaCppBackend {
             let eos = ffi::llama_token_eos(model);
             let mut cur: i32 = -1; // -1 indicates take logits after prompt
             for _step in 0..max_tokens {
+                if cancel.is_cancelled() {
+                    ffi::llama_free(ctx);
+                    ffi::llama_free_model(model);
+                    return Err(RunnerError::Cancelled);
+                }
                 if cur >= 0 {
+                    if n_past as usize >= n_ctx && context.overflow == ContextOverflow::Shift {
+                        let (keep, discard) = context.shift(n_past as usize, n_ctx);
+                        let (keep, discard) = (keep as i32, discard as i32);
+                        ffi::llama_kv_cache_seq_rm(ctx, 0, keep, keep + discard);
+                        ffi::llama_kv_cache_seq_add(ctx, 0, keep + discard, n_past, -discard);
+                        n_past -= discard;
+                    }
                     let mut one: [ffi::llama_token; 1] = [cur as ffi::llama_token];
                     let batch = ffi::llama_batch_get_one(one.as_mut_ptr(), 1, n_past, 0);
                     let rc = ffi::llama_decode(ctx, batch);
-                    ffi::llama_batch_free(batch);
                     if rc != 0 { break; }
                     n_past += 1;
                 }
@@ -135,40 +415,78 @@ impl InferenceBackend for LlamaCppBackend {
         unsafe {
             // Initialize backend (older APIs return void)
             ffi::llama_backend_init();
+            if params.numa != NumaStrategy::Disabled { ffi::llama_numa_init(numa_strategy(params.numa)); }
             let cpath = std::ffi::CString::new(path).unwrap();
-            let mut mparams = ffi::llama_model_default_params();
-            mparams.vocab_only = false;
-            let model = ffi::llama_load_model_from_file(cpath.as_ptr(), mparams);
+            let model = ffi::llama_load_model_from_file(cpath.as_ptr(), model_params(&params));
             if model.is_null() { return Err(RunnerError::Message("llama_load_model_from_file failed".into())); }
-            let mut cparams = ffi::llama_context_default_params();
-            cparams.n_ctx = params.n_ctx as u32;
-            let ctx = ffi::llama_new_context_with_model(model, cparams);
+            let ctx = ffi::llama_new_context_with_model(model, context_params(&params));
             if ctx.is_null() { ffi::llama_free_model(model); return Err(RunnerError::Message("llama_new_context_with_model failed".into())); }
-            // Do not retain raw pointers across threads; free immediately, store only path
-            ffi::llama_free(ctx);
-            ffi::llama_free_model(model);
-            if let Ok(mut st) = self.state.lock() { st.model_loaded = true; st.model_path = Some(path.to_string()); st.n_ctx = cparams.n_ctx as i32; }
-            return Ok(ModelHandle::default());
+            // Keep model + context for step decoding; the previous runtime (if any) is freed on replace
+            if let Ok(mut st) = self.state.lock() {
+                st.model_loaded = true;
+                st.model_path = Some(path.to_string());
+                st.n_ctx = ffi::llama_n_ctx(ctx) as i32;
+                let handle = model_handle(path, model, ctx, &params);
+                st.model = Some(handle.clone());
+                st.params = params;
+                st.runtime = Some(Runtime::new(model, ctx));
+                return Ok(handle);
+            }
+            return Err(RunnerError::Message("backend state poisoned".into()));
         }
         #[allow(unreachable_code)]
-        Err(RunnerError::NotImplemented)
+        {
+            let _ = (path, params);
+            Err(RunnerError::NotImplemented)
+        }
     }
 
-    fn tokenize(&self, text: &str) -> Result<Vec<u32>> {
+    fn tokenize(&self, text: &str) -> Result<Vec<u32>> { self.tokenize_with(text, true, false) }
+
+    fn tokenize_with(&self, text: &str, add_special: bool, parse_special: bool) -> Result<Vec<u32>> {
         #[cfg(llama_ffi)]
-        {
-            // Without persistent model context, fall back to naive tokenization for this method
-            return Ok(text.as_bytes().iter().map(|b| *b as u32).collect());
+        if let Some(rt) = self.state.lock().unwrap().runtime.as_ref() {
+            unsafe {
+                let ctext = std::ffi::CString::new(text).map_err(|e| RunnerError::Message(e.to_string()))?;
+                let len = text.len() as i32;
+                let n = ffi::llama_tokenize(rt.model, ctext.as_ptr(), len, std::ptr::null_mut(), 0, add_special, parse_special);
+                let mut toks: Vec<ffi::llama_token> = vec![0; n.unsigned_abs() as usize];
+                let n = ffi::llama_tokenize(rt.model, ctext.as_ptr(), len, toks.as_mut_ptr(), toks.len() as i32, add_special, parse_special);
+                if n < 0 { return Err(RunnerError::Message("llama_tokenize failed".into())); }
+                return Ok(toks[..n as usize].iter().map(|&t| t as u32).collect());
+            }
         }
         #[allow(unreachable_code)]
-        Ok(text.as_bytes().iter().map(|b| *b as u32).collect())
+        { let _ = (add_special, parse_special); Ok(text.as_bytes().iter().map(|b| *b as u32).collect()) }
+    }
+
+    fn has_tokenizer(&self) -> bool {
+        #[cfg(llama_ffi)]
+        return self.state.lock().unwrap().runtime.is_some();
+        #[allow(unreachable_code)]
+        false
+    }
+
+    /// Control and user-defined tokens, which llama.cpp matches when parsing specials.
+    fn special_tokens(&self) -> Vec<String> {
+        #[cfg(llama_ffi)]
+        if let Some(rt) = self.state.lock().unwrap().runtime.as_ref() {
+            return unsafe {
+                (0..ffi::llama_n_vocab(rt.model))
+                    .filter(|&t| matches!(ffi::llama_token_get_type(rt.model, t), ffi::llama_token_type_LLAMA_TOKEN_TYPE_CONTROL | ffi::llama_token_type_LLAMA_TOKEN_TYPE_USER_DEFINED))
+                    .map(|t| std::ffi::CStr::from_ptr(ffi::llama_token_get_text(rt.model, t)).to_string_lossy().into_owned())
+                    .filter(|t| !t.is_empty())
+                    .collect();
+            };
+        }
+        Vec::new()
     }
 
     fn detokenize(&self, tokens: &[u32]) -> Result<String> {
         #[cfg(llama_ffi)]
-        {
-            // Naive fallback
-            let bytes: Vec<u8> = tokens.iter().map(|t| *t as u8).collect();
+        if let Some(rt) = self.state.lock().unwrap().runtime.as_ref() {
+            let mut bytes = Vec::new();
+            for &t in tokens { bytes.extend(unsafe { token_bytes(rt.model, t as ffi::llama_token) }); }
             return Ok(String::from_utf8_lossy(&bytes).to_string());
         }
         #[allow(unreachable_code)]
@@ -178,16 +496,99 @@ impl InferenceBackend for LlamaCppBackend {
         }
     }
 
-    fn forward(&self, _requests: &mut [SequenceState]) -> Result<ForwardOutput> {
+    fn forward(&self, requests: &mut [SequenceState]) -> Result<Vec<ForwardOutput>> {
         #[cfg(llama_ffi)]
         {
-            // Not used in current flow; return default
-            return Ok(ForwardOutput::default());
+            let mut st = self.state.lock().unwrap();
+            let Some(rt) = st.runtime.as_mut() else { return Err(RunnerError::Message("model not loaded".into())) };
+            return Ok(unsafe { rt.step(requests) });
         }
         #[allow(unreachable_code)]
-        { Ok(ForwardOutput::default()) }
+        { Ok(requests.iter().map(|_| ForwardOutput::default()).collect()) }
     }
 
-    fn kv_usage(&self) -> KvStats { KvStats::default() }
+    fn kv_usage(&self) -> KvStats { KvStats }
+
+    fn save_sequence(&self, seq_id: u64) -> Option<Vec<u8>> {
+        #[cfg(llama_ffi)]
+        {
+            let st = self.state.lock().unwrap();
+            let rt = st.runtime.as_ref()?;
+            let slot = rt.slot_of(seq_id)? as i32;
+            unsafe {
+                let mut buf = vec![0u8; ffi::llama_state_seq_get_size(rt.ctx, slot)];
+                let n = ffi::llama_state_seq_get_data(rt.ctx, buf.as_mut_ptr(), slot);
+                buf.truncate(n);
+                return Some(buf);
+            }
+        }
+        #[allow(unreachable_code)]
+        { let _ = seq_id; None }
+    }
+
+    fn restore_sequence(&self, seq_id: u64, data: &[u8]) -> Result<()> {
+        #[cfg(llama_ffi)]
+        {
+            let mut st = self.state.lock().unwrap();
+            let Some(rt) = st.runtime.as_mut() else { return Err(RunnerError::Message("model not loaded".into())) };
+            unsafe {
+                let slot = rt.claim(seq_id);
+                if ffi::llama_state_seq_set_data(rt.ctx, data.as_ptr(), slot as i32) == 0 {
+                    rt.slots[slot] = None;
+                    return Err(RunnerError::Message("llama_state_seq_set_data failed".into()));
+                }
+            }
+            return Ok(());
+        }
+        #[allow(unreachable_code)]
+        { let _ = (seq_id, data); Err(RunnerError::NotImplemented) }
+    }
+
+    fn release_sequence(&self, seq_id: u64) {
+        #[cfg(llama_ffi)]
+        if let Some(rt) = self.state.lock().unwrap().runtime.as_mut() {
+            if let Some(slot) = rt.slot_of(seq_id) {
+                unsafe { ffi::llama_kv_cache_seq_rm(rt.ctx, slot as i32, -1, -1); }
+                rt.slots[slot] = None;
+            }
+        }
+        let _ = seq_id;
+    }
+
+    fn model(&self) -> Option<ModelHandle> {
+        #[cfg(llama_ffi)]
+        return self.state.lock().unwrap().model.clone();
+        #[allow(unreachable_code)]
+        None
+    }
+
+    fn shift_context(&self, seq_id: u64, keep: usize, discard: usize) -> Result<()> {
+        #[cfg(llama_ffi)]
+        {
+            let mut st = self.state.lock().unwrap();
+            let Some(rt) = st.runtime.as_mut() else { return Err(RunnerError::Message("model not loaded".into())) };
+            // a sequence that lost its slot re-prefills the shifted tokens anyway
+            let Some(slot) = rt.slot_of(seq_id) else { return Ok(()) };
+            let (keep, discard) = (keep as i32, discard as i32);
+            unsafe {
+                ffi::llama_kv_cache_seq_rm(rt.ctx, slot as i32, keep, keep + discard);
+                ffi::llama_kv_cache_seq_add(rt.ctx, slot as i32, keep + discard, -1, -discard);
+            }
+            return Ok(());
+        }
+        #[allow(unreachable_code)]
+        { let _ = (seq_id, keep, discard); Err(RunnerError::NotImplemented) }
+    }
+
+    /// llama.cpp owns its KV cells; paged blocks are only the scheduler's accounting.
+    fn paged_kv(&self) -> bool { false }
+
+    /// One llama seq slot per sequence; more would keep reclaiming each other's.
+    fn max_sequences(&self) -> Option<usize> {
+        #[cfg(llama_ffi)]
+        return Some(MAX_SEQS);
+        #[allow(unreachable_code)]
+        None
+    }
 }
 
@@ -0,0 +1,242 @@
+//! GGUF reader: header, metadata and tensor infos, without llama.cpp. Tensor
+//! data is not read; it starts at `data_offset`.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+use runner_common::{Result, RunnerError};
+use crate::{KvCacheType, KvLayout};
+
+const MAGIC: [u8; 4] = *b"GGUF";
+const DEFAULT_ALIGNMENT: u64 = 32;
+
+/// One metadata value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    U64(u64),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    Bool(bool),
+    String(String),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    /// Integer values of any width, if non-negative.
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Value::U8(v) => Some(v.into()),
+            Value::U16(v) => Some(v.into()),
+            Value::U32(v) => Some(v.into()),
+            Value::U64(v) => Some(v),
+            Value::I8(v) => u64::try_from(v).ok(),
+            Value::I16(v) => u64::try_from(v).ok(),
+            Value::I32(v) => u64::try_from(v).ok(),
+            Value::I64(v) => u64::try_from(v).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Value::F32(v) => Some(v.into()),
+            Value::F64(v) => Some(v),
+            _ => self.as_u64().map(|v| v as f64),
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> { if let Value::String(s) = self { Some(s) } else { None } }
+    pub fn as_array(&self) -> Option<&[Value]> { if let Value::Array(a) = self { Some(a) } else { None } }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TensorInfo {
+    pub name: String,
+    pub dims: Vec<u64>,
+    /// ggml type id (0 = F32, 1 = F16, 2 = Q4_0, 8 = Q8_0, ...).
+    pub ggml_type: u32,
+    /// Offset of the tensor's data from `GgufFile::data_offset`.
+    pub offset: u64,
+}
+
+impl TensorInfo {
+    pub fn n_elements(&self) -> u64 { self.dims.iter().product() }
+}
+
+#[derive(Debug, Clone)]
+pub struct GgufFile {
+    pub version: u32,
+    pub metadata: HashMap<String, Value>,
+    pub tensors: Vec<TensorInfo>,
+    /// Where tensor data starts in the file.
+    pub data_offset: u64,
+}
+
+impl GgufFile {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|e| RunnerError::Message(format!("{}: {e}", path.display())))?;
+        let len = file.metadata().map(|m| m.len()).unwrap_or(u64::MAX);
+        Self::read(BufReader::new(file), len).map_err(|e| RunnerError::Message(format!("{}: {e}", path.display())))
+    }
+
+    /// Parse from `r`, which holds at most `len` bytes; lengths past that are rejected
+    /// as corrupt rather than allocated.
+    pub fn read(r: impl Read, len: u64) -> Result<Self> {
+        let mut r = Reader { inner: r, pos: 0, len };
+        if r.bytes::<4>()? != MAGIC { return Err(bad("not a GGUF file")); }
+        let version = r.u32()?;
+        if !(2..=3).contains(&version) { return Err(bad(&format!("unsupported GGUF version {version}"))); }
+        let n_tensors = r.u64()?;
+        let n_kv = r.u64()?;
+        let mut metadata = HashMap::new();
+        for _ in 0..n_kv {
+            let key = r.string()?;
+            let ty = r.u32()?;
+            metadata.insert(key, r.value(ty)?);
+        }
+        let mut tensors = Vec::new();
+        for _ in 0..n_tensors {
+            let name = r.string()?;
+            let n_dims = r.u32()?;
+            if n_dims > 8 { return Err(bad(&format!("tensor {name} has {n_dims} dimensions"))); }
+            let dims = (0..n_dims).map(|_| r.u64()).collect::<Result<_>>()?;
+            tensors.push(TensorInfo { name, dims, ggml_type: r.u32()?, offset: r.u64()? });
+        }
+        let alignment = metadata.get("general.alignment").and_then(Value::as_u64).filter(|a| *a > 0).unwrap_or(DEFAULT_ALIGNMENT);
+        let data_offset = r.pos.div_ceil(alignment) * alignment;
+        Ok(Self { version, metadata, tensors, data_offset })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> { self.metadata.get(key) }
+    pub fn get_str(&self, key: &str) -> Option<&str> { self.get(key).and_then(Value::as_str) }
+    pub fn get_u64(&self, key: &str) -> Option<u64> { self.get(key).and_then(Value::as_u64) }
+
+    /// `general.architecture`, e.g. `llama`; the prefix of the model's own keys.
+    pub fn architecture(&self) -> Option<&str> { self.get_str("general.architecture") }
+    pub fn name(&self) -> Option<&str> { self.get_str("general.name") }
+
+    /// `<arch>.<key>` for the file's architecture.
+    fn arch_u64(&self, key: &str) -> Option<u64> { self.get_u64(&format!("{}.{key}", self.architecture()?)) }
+
+    /// Context length the model was trained with.
+    pub fn context_length(&self) -> Option<usize> { self.arch_u64("context_length").map(|v| v as usize) }
+
+    pub fn vocab_size(&self) -> Option<usize> {
+        self.get("tokenizer.ggml.tokens").and_then(Value::as_array).map(<[Value]>::len)
+            .or_else(|| self.arch_u64("vocab_size").map(|v| v as usize))
+    }
+
+    pub fn chat_template(&self) -> Option<&str> { self.get_str("tokenizer.chat_template") }
+
+    /// Total weights across all tensors.
+    pub fn parameter_count(&self) -> u64 { self.tensors.iter().map(TensorInfo::n_elements).sum() }
+
+    /// Quantization, from `general.file_type` or else the most common tensor type.
+    pub fn quantization(&self) -> Option<&'static str> {
+        if let Some(ft) = self.get_u64("general.file_type") { return file_type_name(ft); }
+        let mut counts: HashMap<u32, u64> = HashMap::new();
+        for t in &self.tensors { *counts.entry(t.ggml_type).or_default() += t.n_elements(); }
+        counts.into_iter().max_by_key(|(_, n)| *n).and_then(|(ty, _)| ggml_type_name(ty))
+    }
+
+    /// KV shape from the attention hyperparameters, with the given cache types.
+    pub fn kv_layout(&self, type_k: KvCacheType, type_v: KvCacheType) -> Option<KvLayout> {
+        let n_layers = self.arch_u64("block_count")? as usize;
+        let n_heads = self.arch_u64("attention.head_count")? as usize;
+        let n_kv_heads = self.arch_u64("attention.head_count_kv").map_or(n_heads, |v| v as usize);
+        let head_dim = match self.arch_u64("attention.key_length") {
+            Some(k) => k as usize,
+            None => self.arch_u64("embedding_length")? as usize / n_heads.max(1),
+        };
+        Some(KvLayout { n_layers, n_kv_heads, head_dim, type_k, type_v })
+    }
+}
+
+/// llama.cpp's `llama_ftype` names.
+fn file_type_name(ft: u64) -> Option<&'static str> {
+    Some(match ft {
+        0 => "F32", 1 => "F16", 2 => "Q4_0", 3 => "Q4_1", 7 => "Q8_0", 8 => "Q5_0", 9 => "Q5_1",
+        10 => "Q2_K", 11 => "Q3_K_S", 12 => "Q3_K_M", 13 => "Q3_K_L", 14 => "Q4_K_S", 15 => "Q4_K_M",
+        16 => "Q5_K_S", 17 => "Q5_K_M", 18 => "Q6_K", 19 => "IQ2_XXS", 20 => "IQ2_XS", 21 => "Q2_K_S",
+        22 => "IQ3_XS", 23 => "IQ3_XXS", 24 => "IQ1_S", 25 => "IQ4_NL", 26 => "IQ3_S", 27 => "IQ3_M",
+        28 => "IQ2_S", 29 => "IQ2_M", 30 => "IQ4_XS", 31 => "IQ1_M", 32 => "BF16",
+        _ => return None,
+    })
+}
+
+/// ggml's tensor type names.
+fn ggml_type_name(ty: u32) -> Option<&'static str> {
+    Some(match ty {
+        0 => "F32", 1 => "F16", 2 => "Q4_0", 3 => "Q4_1", 6 => "Q5_0", 7 => "Q5_1", 8 => "Q8_0", 9 => "Q8_1",
+        10 => "Q2_K", 11 => "Q3_K", 12 => "Q4_K", 13 => "Q5_K", 14 => "Q6_K", 15 => "Q8_K",
+        16 => "IQ2_XXS", 17 => "IQ2_XS", 18 => "IQ3_XXS", 19 => "IQ1_S", 20 => "IQ4_NL", 21 => "IQ3_S",
+        22 => "IQ2_S", 23 => "IQ4_XS", 24 => "I8", 25 => "I16", 26 => "I32", 27 => "I64", 28 => "F64",
+        29 => "IQ1_M", 30 => "BF16",
+        _ => return None,
+    })
+}
+
+fn bad(msg: &str) -> RunnerError { RunnerError::Message(format!("gguf: {msg}")) }
+
+struct Reader<R> { inner: R, pos: u64, len: u64 }
+
+impl<R: Read> Reader<R> {
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut buf = [0; N];
+        self.inner.read_exact(&mut buf).map_err(|_| bad("unexpected end of file"))?;
+        self.pos += N as u64;
+        Ok(buf)
+    }
+
+    fn u32(&mut self) -> Result<u32> { self.bytes().map(u32::from_le_bytes) }
+    fn u64(&mut self) -> Result<u64> { self.bytes().map(u64::from_le_bytes) }
+
+    /// Fail on a count of `size`-byte items that could not fit in the rest of the file.
+    fn check_len(&self, n: u64, size: u64) -> Result<usize> {
+        if n.saturating_mul(size) > self.len.saturating_sub(self.pos) { return Err(bad("length past end of file")); }
+        Ok(n as usize)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let n = self.u64()?;
+        let n = self.check_len(n, 1)?;
+        let mut buf = vec![0; n];
+        self.inner.read_exact(&mut buf).map_err(|_| bad("unexpected end of file"))?;
+        self.pos += n as u64;
+        String::from_utf8(buf).map_err(|_| bad("string is not UTF-8"))
+    }
+
+    fn value(&mut self, ty: u32) -> Result<Value> {
+        Ok(match ty {
+            0 => Value::U8(self.bytes::<1>()?[0]),
+            1 => Value::I8(i8::from_le_bytes(self.bytes()?)),
+            2 => Value::U16(u16::from_le_bytes(self.bytes()?)),
+            3 => Value::I16(i16::from_le_bytes(self.bytes()?)),
+            4 => Value::U32(self.u32()?),
+            5 => Value::I32(i32::from_le_bytes(self.bytes()?)),
+            6 => Value::F32(f32::from_le_bytes(self.bytes()?)),
+            7 => Value::Bool(self.bytes::<1>()?[0] != 0),
+            8 => Value::String(self.string()?),
+            9 => {
+                let item = self.u32()?;
+                if item == 9 { return Err(bad("nested arrays are not supported")); }
+                let n = self.u64()?;
+                let n = self.check_len(n, 1)?;
+                Value::Array((0..n).map(|_| self.value(item)).collect::<Result<_>>()?)
+            }
+            10 => Value::U64(self.u64()?),
+            11 => Value::I64(i64::from_le_bytes(self.bytes()?)),
+            12 => Value::F64(f64::from_le_bytes(self.bytes()?)),
+            other => return Err(bad(&format!("unknown metadata type {other}"))),
+        })
+    }
+}
@@ -1,29 +1,325 @@
-use runner_common::Result;
+use runner_common::{cancel::CancelToken, Result, RunnerError};
 
+pub mod gguf;
+pub mod tokenizer;
+
+/// How a model and its context are set up. `None` fields keep the backend's
+/// (or the model file's) own defaults.
 #[derive(Debug, Clone, Default)]
 pub struct LoadParams {
     pub n_ctx: usize,
     pub n_gpu_layers: usize,
+    /// Storage type of the K and V caches; quantized types trade accuracy for context.
+    pub type_k: KvCacheType,
+    pub type_v: KvCacheType,
+    pub rope: RopeParams,
+    /// Unset turns flash attention on only when the KV types require it.
+    pub flash_attn: Option<bool>,
+    /// Logical and physical batch sizes: tokens submitted per decode call and per compute pass.
+    pub n_batch: Option<usize>,
+    pub n_ubatch: Option<usize>,
+    /// Threads for single-token decoding and for batch (prompt) processing.
+    pub n_threads: Option<usize>,
+    pub n_threads_batch: Option<usize>,
+    pub use_mmap: Option<bool>,
+    pub use_mlock: Option<bool>,
+    pub numa: NumaStrategy,
 }
 
-#[derive(Debug, Clone, Default)]
-pub struct ModelHandle;
+/// RoPE overrides, for running a model past the context it was trained with.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RopeParams {
+    pub scaling: Option<RopeScaling>,
+    pub freq_base: Option<f32>,
+    pub freq_scale: Option<f32>,
+    pub yarn_ext_factor: Option<f32>,
+    pub yarn_attn_factor: Option<f32>,
+    pub yarn_beta_fast: Option<f32>,
+    pub yarn_beta_slow: Option<f32>,
+    /// Context length the model was trained with.
+    pub yarn_orig_ctx: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RopeScaling { None, Linear, Yarn }
+
+impl RopeScaling {
+    pub fn as_str(&self) -> &'static str {
+        match self { RopeScaling::None => "none", RopeScaling::Linear => "linear", RopeScaling::Yarn => "yarn" }
+    }
+}
+
+impl std::str::FromStr for RopeScaling {
+    type Err = RunnerError;
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(RopeScaling::None),
+            "linear" => Ok(RopeScaling::Linear),
+            "yarn" => Ok(RopeScaling::Yarn),
+            other => Err(RunnerError::Message(format!("unknown RoPE scaling '{other}' (expected none, linear or yarn)"))),
+        }
+    }
+}
+
+/// How model weights and threads are spread across NUMA nodes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NumaStrategy { #[default] Disabled, Distribute, Isolate, Numactl, Mirror }
+
+impl NumaStrategy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NumaStrategy::Disabled => "disabled",
+            NumaStrategy::Distribute => "distribute",
+            NumaStrategy::Isolate => "isolate",
+            NumaStrategy::Numactl => "numactl",
+            NumaStrategy::Mirror => "mirror",
+        }
+    }
+}
 
+impl std::str::FromStr for NumaStrategy {
+    type Err = RunnerError;
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "disabled" => Ok(NumaStrategy::Disabled),
+            "distribute" => Ok(NumaStrategy::Distribute),
+            "isolate" => Ok(NumaStrategy::Isolate),
+            "numactl" => Ok(NumaStrategy::Numactl),
+            "mirror" => Ok(NumaStrategy::Mirror),
+            other => Err(RunnerError::Message(format!("unknown NUMA strategy '{other}' (expected disabled, distribute, isolate, numactl or mirror)"))),
+        }
+    }
+}
+
+/// Special token ids of a vocabulary; `None` where it has no such token.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SpecialTokens {
+    pub bos: Option<u32>,
+    pub eos: Option<u32>,
+    /// End of turn, for chat models whose turns do not end with EOS.
+    pub eot: Option<u32>,
+    pub pad: Option<u32>,
+}
+
+impl SpecialTokens {
+    /// Tokens that end a generation.
+    pub fn stop(&self) -> Vec<u32> {
+        let mut stop: Vec<u32> = self.eos.into_iter().chain(self.eot).collect();
+        stop.dedup();
+        stop
+    }
+}
+
+/// What a backend loaded: its identity and the facts callers size things by.
+/// Fields the backend cannot tell are left unset.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ModelHandle {
+    /// Model file, or the backend's name for a built-in model.
+    pub id: String,
+    /// `general.name` from the model file.
+    pub name: Option<String>,
+    pub architecture: Option<String>,
+    pub vocab_size: usize,
+    pub special_tokens: SpecialTokens,
+    /// Context the model was trained with.
+    pub n_ctx_train: Option<usize>,
+    /// Context it was loaded with; bounds every sequence.
+    pub n_ctx: Option<usize>,
+    pub n_embd: Option<usize>,
+    pub chat_template: Option<String>,
+    pub kv_layout: Option<KvLayout>,
+    pub n_params: u64,
+    /// Memory the weights take once loaded.
+    pub size_bytes: u64,
+}
+
+impl ModelHandle {
+    /// What the GGUF header of the file at `path` says, for a backend to start from.
+    pub fn from_gguf(path: &str, gguf: &gguf::GgufFile, params: &LoadParams) -> Self {
+        let token = |key: &str| gguf.get_u64(&format!("tokenizer.ggml.{key}_token_id")).map(|t| t as u32);
+        let arch_usize = |key: &str| gguf.architecture().and_then(|a| gguf.get_u64(&format!("{a}.{key}"))).map(|v| v as usize);
+        Self {
+            id: path.to_string(),
+            name: gguf.name().map(String::from),
+            architecture: gguf.architecture().map(String::from),
+            vocab_size: gguf.vocab_size().unwrap_or(0),
+            special_tokens: SpecialTokens { bos: token("bos"), eos: token("eos"), eot: token("eot"), pad: token("padding") },
+            n_ctx_train: gguf.context_length(),
+            n_ctx: (params.n_ctx > 0).then_some(params.n_ctx).or(gguf.context_length()),
+            n_embd: arch_usize("embedding_length"),
+            chat_template: gguf.chat_template().map(String::from),
+            kv_layout: gguf.kv_layout(params.type_k, params.type_v),
+            n_params: gguf.parameter_count(),
+            size_bytes: std::fs::metadata(path).map_or(0, |m| m.len()),
+        }
+    }
+}
+
+/// One sequence being decoded: `tokens` holds the prompt followed by everything
+/// generated so far, `n_past` how many of them the backend already holds in its
+/// KV cache. Backends must stop stepping a sequence once `cancel` fires.
 #[derive(Debug, Clone, Default)]
-pub struct SequenceState { pub tokens: Vec<u32>, pub max_new_tokens: usize }
+pub struct SequenceState {
+    pub id: u64,
+    pub tokens: Vec<u32>,
+    pub n_past: usize,
+    pub prompt_len: usize,
+    pub max_new_tokens: usize,
+    pub cancel: CancelToken,
+}
 
+/// Result of one step for one sequence: either a picked `token`, raw `logits`
+/// for the caller to sample, or neither once the sequence hit end-of-sequence.
+/// `error` fails this sequence alone while the rest of the batch carries on.
 #[derive(Debug, Clone, Default)]
-pub struct ForwardOutput { pub logits: Option<Vec<f32>>, pub token: Option<u32> }
+pub struct ForwardOutput { pub logits: Option<Vec<f32>>, pub token: Option<u32>, pub error: Option<String> }
 
 #[derive(Debug, Clone, Default)]
 pub struct KvStats;
 
+/// Element type the KV cache is stored in. The quantized types pack 32
+/// values per block with an f16 scale, as ggml does.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KvCacheType { F32, #[default] F16, Q8_0, Q4_0 }
+
+impl KvCacheType {
+    pub fn as_str(&self) -> &'static str {
+        match self { KvCacheType::F32 => "f32", KvCacheType::F16 => "f16", KvCacheType::Q8_0 => "q8_0", KvCacheType::Q4_0 => "q4_0" }
+    }
+
+    /// Bytes needed to store a row of `n` values.
+    pub fn row_bytes(&self, n: usize) -> usize {
+        match self {
+            KvCacheType::F32 => n * 4,
+            KvCacheType::F16 => n * 2,
+            KvCacheType::Q8_0 => n.div_ceil(32) * 34,
+            KvCacheType::Q4_0 => n.div_ceil(32) * 18,
+        }
+    }
+
+    pub fn is_quantized(&self) -> bool { matches!(self, KvCacheType::Q8_0 | KvCacheType::Q4_0) }
+}
+
+impl std::str::FromStr for KvCacheType {
+    type Err = RunnerError;
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "f32" => Ok(KvCacheType::F32),
+            "f16" => Ok(KvCacheType::F16),
+            "q8_0" => Ok(KvCacheType::Q8_0),
+            "q4_0" => Ok(KvCacheType::Q4_0),
+            other => Err(RunnerError::Message(format!("unknown KV cache type '{other}' (expected f32, f16, q8_0 or q4_0)"))),
+        }
+    }
+}
+
+/// What happens when a prompt plus its generation outgrows the context window.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContextOverflow {
+    /// Refuse the request up front.
+    #[default]
+    Reject,
+    /// Cap generation at the room left; chat endpoints first drop the oldest messages.
+    Truncate,
+    /// Keep generating by discarding older tokens once the window is full.
+    Shift,
+}
+
+impl ContextOverflow {
+    pub fn as_str(&self) -> &'static str {
+        match self { ContextOverflow::Reject => "reject", ContextOverflow::Truncate => "truncate", ContextOverflow::Shift => "shift" }
+    }
+}
+
+/// Per-request context handling; the first `keep` tokens (e.g. the system prompt) survive a shift.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ContextPolicy { pub overflow: ContextOverflow, pub keep: usize }
+
+impl ContextPolicy {
+    /// `max_tokens` to run a `prompt`-token request with in an `n_ctx` window:
+    /// capped at the room left under `Truncate`, unchanged otherwise. The prompt
+    /// itself must leave room for at least one token.
+    pub fn fit(&self, prompt: usize, max_tokens: usize, n_ctx: usize) -> Result<usize> {
+        let exceeded = RunnerError::ContextLengthExceeded { prompt, max_tokens, n_ctx };
+        if prompt >= n_ctx { return Err(exceeded); }
+        match self.overflow {
+            ContextOverflow::Reject if prompt + max_tokens > n_ctx => Err(exceeded),
+            ContextOverflow::Truncate => Ok(max_tokens.min(n_ctx - prompt)),
+            _ => Ok(max_tokens),
+        }
+    }
+
+    /// `(keep, discard)` for a sequence of `len` tokens that outgrew `n_ctx`: the
+    /// prefix is kept (at most half the window) and half of what follows it is
+    /// dropped, as llama.cpp's context shift does.
+    pub fn shift(&self, len: usize, n_ctx: usize) -> (usize, usize) {
+        let keep = self.keep.min(n_ctx / 2);
+        (keep, len.saturating_sub(keep) / 2)
+    }
+}
+
+/// Shape of a loaded model's KV cache, enough to size it in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KvLayout {
+    pub n_layers: usize,
+    pub n_kv_heads: usize,
+    pub head_dim: usize,
+    pub type_k: KvCacheType,
+    pub type_v: KvCacheType,
+}
+
+impl KvLayout {
+    /// One K row and one V row (all KV heads) for every layer.
+    pub fn bytes_per_token(&self) -> usize {
+        let row = self.n_kv_heads * self.head_dim;
+        self.n_layers * (self.type_k.row_bytes(row) + self.type_v.row_bytes(row))
+    }
+}
+
 pub trait InferenceBackend: Send + Sync {
     fn load_model(&self, path: &str, params: LoadParams) -> Result<ModelHandle>;
     fn tokenize(&self, text: &str) -> Result<Vec<u32>>;
     fn detokenize(&self, tokens: &[u32]) -> Result<String>;
-    fn forward(&self, requests: &mut [SequenceState]) -> Result<ForwardOutput>;
+    /// `tokenize` with llama.cpp's switches: `add_special` adds BOS and the like,
+    /// `parse_special` reads special-token text such as `<|im_start|>` as that token.
+    fn tokenize_with(&self, text: &str, _add_special: bool, _parse_special: bool) -> Result<Vec<u32>> { self.tokenize(text) }
+    /// Whether `tokenize` uses the loaded model's own vocabulary, so its ids are
+    /// the ones the model was trained with. The mock's bytes are not.
+    fn has_tokenizer(&self) -> bool { false }
+    /// Text of the tokens `parse_special` reads out of text.
+    fn special_tokens(&self) -> Vec<String> { Vec::new() }
+    /// Advance every sequence in the batch by one step; one output per sequence, in order.
+    fn forward(&self, requests: &mut [SequenceState]) -> Result<Vec<ForwardOutput>>;
     fn kv_usage(&self) -> KvStats;
+    /// Metadata of the loaded model, as `load_model` returned it; `None` before a load.
+    fn model(&self) -> Option<ModelHandle> { None }
+    /// KV shape of the loaded model; `None` when unknown or nothing is loaded.
+    fn kv_layout(&self) -> Option<KvLayout> { self.model()?.kv_layout }
+    /// Whether the backend's KV lives in the scheduler's paged blocks, so a new
+    /// sequence can start at `n_past` on a cached prefix's blocks and blocks can
+    /// be spilled. Backends that keep their own KV cells always prefill in full.
+    fn paged_kv(&self) -> bool { true }
+    /// Contents of paged KV block `block`, so it can be spilled to a slower tier.
+    /// `None` when the backend's cache is not addressable by block.
+    fn read_kv_block(&self, _block: u32) -> Option<Vec<u8>> { None }
+    /// Load a block previously returned by `read_kv_block` into `block`.
+    fn write_kv_block(&self, _block: u32, _data: &[u8]) -> Result<()> { Err(RunnerError::NotImplemented) }
+    /// Serialized KV state of sequence `seq_id`, so an idle session can be parked
+    /// off the device. `None` when unsupported or the sequence is no longer held.
+    fn save_sequence(&self, _seq_id: u64) -> Option<Vec<u8>> { None }
+    /// Load state from `save_sequence` as sequence `seq_id`.
+    fn restore_sequence(&self, _seq_id: u64, _data: &[u8]) -> Result<()> { Err(RunnerError::NotImplemented) }
+    /// Drop whatever KV the backend still holds for `seq_id`.
+    fn release_sequence(&self, _seq_id: u64) {}
+    /// Tokens a single sequence can attend to; `None` when unbounded.
+    fn context_size(&self) -> Option<usize> { self.model()?.n_ctx }
+    /// Sequences the backend can hold KV for at once; `None` when unbounded.
+    /// The scheduler batches no more than this.
+    fn max_sequences(&self) -> Option<usize> { None }
+    /// Remove `discard` positions after the first `keep` of sequence `seq_id`
+    /// and move the later ones back to close the gap.
+    fn shift_context(&self, _seq_id: u64, _keep: usize, _discard: usize) -> Result<()> { Err(RunnerError::NotImplemented) }
 }
 
 #[cfg(feature = "mock")]
@@ -33,12 +329,18 @@ pub mod mock {
     #[derive(Default)]
     pub struct MockBackend;
 
-    impl MockBackend { pub fn new() -> Self { Self } }
+    impl MockBackend {
+        pub fn new() -> Self { Self }
+
+        /// Byte-level vocabulary with no special tokens and no context limit.
+        pub fn handle() -> ModelHandle { ModelHandle { id: "mock".into(), vocab_size: 256, ..Default::default() } }
+    }
 
     impl InferenceBackend for MockBackend {
         fn load_model(&self, _path: &str, _params: LoadParams) -> Result<ModelHandle> {
-            Ok(ModelHandle::default())
+            Ok(Self::handle())
         }
+        fn model(&self) -> Option<ModelHandle> { Some(Self::handle()) }
         fn tokenize(&self, text: &str) -> Result<Vec<u32>> {
             // very naive: bytes as tokens
             Ok(text.as_bytes().iter().map(|b| *b as u32).collect())
@@ -47,10 +349,19 @@ pub mod mock {
             let bytes: Vec<u8> = tokens.iter().map(|t| *t as u8).collect();
             Ok(String::from_utf8_lossy(&bytes).to_string())
         }
-        fn forward(&self, _requests: &mut [SequenceState]) -> Result<ForwardOutput> {
-            Ok(ForwardOutput::default())
+        fn forward(&self, requests: &mut [SequenceState]) -> Result<Vec<ForwardOutput>> {
+            // echo each prompt back one token per step, then stop
+            Ok(requests.iter_mut().map(|seq| {
+                if seq.cancel.is_cancelled() { return ForwardOutput::default(); }
+                seq.n_past = seq.tokens.len();
+                let generated = seq.tokens.len().saturating_sub(seq.prompt_len);
+                let token = (generated < seq.prompt_len).then(|| seq.tokens[generated]);
+                ForwardOutput { token, ..Default::default() }
+            }).collect())
         }
-        fn kv_usage(&self) -> KvStats { KvStats::default() }
+        fn kv_usage(&self) -> KvStats { KvStats }
+        // nothing is cached: the shifted tokens are simply what the next step sees
+        fn shift_context(&self, _seq_id: u64, _keep: usize, _discard: usize) -> Result<()> { Ok(()) }
     }
 }
 
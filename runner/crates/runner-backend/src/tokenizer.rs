@@ -0,0 +1,378 @@
+//! Tokenizers that run without a compute backend: Hugging Face `tokenizer.json`
+//! files and the vocabulary embedded in a GGUF file. Ids must match the ones the
+//! model was trained with, so pick the tokenizer that shipped with the weights.
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+use runner_common::{Result, RunnerError};
+use crate::gguf::{GgufFile, Value};
+use crate::InferenceBackend;
+use tokenizers::pre_tokenizers::byte_level::ByteLevel;
+use tokenizers::pre_tokenizers::sequence::Sequence;
+use tokenizers::pre_tokenizers::split::{Split, SplitPattern};
+use tokenizers::pre_tokenizers::PreTokenizerWrapper;
+use tokenizers::SplitDelimiterBehavior;
+
+pub trait Tokenizer: Send + Sync {
+    /// Token ids for `text`. `add_special` adds what the model expects at the start
+    /// of a sequence (usually BOS); pass `false` for text appended to one.
+    fn encode(&self, text: &str, add_special: bool) -> Result<Vec<u32>>;
+    /// Tokens for text as a user wrote it: special-token text such as
+    /// `<|im_start|>` stays text instead of becoming that token.
+    fn encode_literal(&self, text: &str) -> Result<Vec<u32>>;
+    /// Text for `tokens`; `skip_special` leaves out control tokens such as BOS/EOS.
+    fn decode(&self, tokens: &[u32], skip_special: bool) -> Result<String>;
+    fn vocab_size(&self) -> usize;
+    fn token_to_id(&self, token: &str) -> Option<u32>;
+    fn id_to_token(&self, id: u32) -> Option<String>;
+
+    /// `encode` for a rendered chat template, reading special-token text as
+    /// special tokens except in the `literal` byte ranges that message content
+    /// put there, so a user cannot type a control token.
+    fn encode_template(&self, text: &str, add_special: bool, literal: &[Range<usize>]) -> Result<Vec<u32>> {
+        let (mut out, mut at) = (Vec::new(), 0);
+        for r in literal {
+            out.extend(self.encode(&text[at..r.start], add_special && at == 0)?);
+            out.extend(self.encode_literal(&text[r.clone()])?);
+            at = r.end;
+        }
+        out.extend(self.encode(&text[at..], add_special && at == 0)?);
+        Ok(out)
+    }
+
+    /// Text of the tokens `encode` reads out of text as special tokens.
+    fn special_tokens(&self) -> Vec<String> { Vec::new() }
+
+    /// Tokens `text` takes, without special tokens.
+    fn count(&self, text: &str) -> Result<usize> { self.encode(text, false).map(|t| t.len()) }
+
+    /// Fill-in-the-middle markers as `[prefix, suffix, middle]`, when the
+    /// vocabulary has one of the code model families' spellings of them.
+    fn fim_tokens(&self) -> Option<[u32; 3]> {
+        FIM_MARKERS.iter().find_map(|m| Some([self.token_to_id(m[0])?, self.token_to_id(m[1])?, self.token_to_id(m[2])?]))
+    }
+
+    /// Fail on ids outside the vocabulary.
+    fn validate(&self, tokens: &[u32]) -> Result<()> {
+        let vocab = self.vocab_size();
+        match tokens.iter().find(|&&t| t as usize >= vocab) {
+            Some(t) => Err(RunnerError::Message(format!("token {t} is out of range for a vocabulary of {vocab}"))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Qwen and StarCoder 2, StarCoder, DeepSeek Coder and Code Llama.
+const FIM_MARKERS: [[&str; 3]; 4] = [
+    ["<|fim_prefix|>", "<|fim_suffix|>", "<|fim_middle|>"],
+    ["<fim_prefix>", "<fim_suffix>", "<fim_middle>"],
+    ["<\u{ff5c}fim\u{2581}begin\u{ff5c}>", "<\u{ff5c}fim\u{2581}hole\u{ff5c}>", "<\u{ff5c}fim\u{2581}end\u{ff5c}>"],
+    ["\u{2581}<PRE>", "\u{2581}<SUF>", "\u{2581}<MID>"],
+];
+
+/// A Hugging Face `tokenizer.json`.
+pub struct HfTokenizer {
+    inner: tokenizers::Tokenizer,
+    /// The same tokenizer with special tokens left as text.
+    literal: tokenizers::Tokenizer,
+}
+
+impl HfTokenizer {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        tokenizers::Tokenizer::from_file(path).map(Self::new).map_err(|e| RunnerError::Message(format!("{}: {e}", path.display())))
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> { tokenizers::Tokenizer::from_bytes(bytes).map(Self::new).map_err(hf_error) }
+
+    fn new(inner: tokenizers::Tokenizer) -> Self {
+        let mut literal = inner.clone();
+        literal.set_encode_special_tokens(true);
+        Self { inner, literal }
+    }
+}
+
+fn hf_error(e: tokenizers::Error) -> RunnerError { RunnerError::Message(format!("tokenizer: {e}")) }
+
+impl Tokenizer for HfTokenizer {
+    fn encode(&self, text: &str, add_special: bool) -> Result<Vec<u32>> {
+        self.inner.encode(text, add_special).map(|e| e.get_ids().to_vec()).map_err(hf_error)
+    }
+    fn encode_literal(&self, text: &str) -> Result<Vec<u32>> {
+        self.literal.encode(text, false).map(|e| e.get_ids().to_vec()).map_err(hf_error)
+    }
+    fn special_tokens(&self) -> Vec<String> {
+        self.inner.get_added_tokens_decoder().into_values().filter(|t| t.special).map(|t| t.content).collect()
+    }
+    fn decode(&self, tokens: &[u32], skip_special: bool) -> Result<String> { self.inner.decode(tokens, skip_special).map_err(hf_error) }
+    fn vocab_size(&self) -> usize { self.inner.get_vocab_size(true) }
+    fn token_to_id(&self, token: &str) -> Option<u32> { self.inner.token_to_id(token) }
+    fn id_to_token(&self, id: u32) -> Option<String> { self.inner.id_to_token(id) }
+}
+
+/// Delegates to a backend's own tokenizer: the loaded model's vocabulary on
+/// backends that have one, else what the backend does without (the mock's bytes).
+pub struct BackendTokenizer(pub Arc<dyn InferenceBackend>);
+
+impl Tokenizer for BackendTokenizer {
+    fn encode(&self, text: &str, add_special: bool) -> Result<Vec<u32>> { self.0.tokenize_with(text, add_special, true) }
+    fn encode_literal(&self, text: &str) -> Result<Vec<u32>> { self.0.tokenize_with(text, false, false) }
+    fn special_tokens(&self) -> Vec<String> { self.0.special_tokens() }
+    fn decode(&self, tokens: &[u32], _skip_special: bool) -> Result<String> { self.0.detokenize(tokens) }
+    fn vocab_size(&self) -> usize { self.0.model().map_or(0, |m| m.vocab_size) }
+    fn token_to_id(&self, token: &str) -> Option<u32> { self.0.tokenize_with(token, false, true).ok().filter(|t| t.len() == 1).map(|t| t[0]) }
+    fn id_to_token(&self, id: u32) -> Option<String> { self.0.detokenize(&[id]).ok() }
+}
+
+// llama.cpp's token types
+const TOKEN_UNKNOWN: i64 = 2;
+const TOKEN_CONTROL: i64 = 3;
+const TOKEN_USER_DEFINED: i64 = 4;
+const TOKEN_BYTE: i64 = 6;
+
+/// SentencePiece's stand-in for a space.
+const SPM_SPACE: &str = "\u{2581}";
+
+// llama.cpp's pre-tokenizer splits, applied in order before byte-level BPE
+const GPT2_SPLIT: &str = r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)";
+const LLAMA3_SPLIT: &str = r"(?:'[sS]|'[tT]|'[rR][eE]|'[vV][eE]|'[mM]|'[lL][lL]|'[dD])|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+const QWEN2_SPLIT: &str = r"(?:'[sS]|'[tT]|'[rR][eE]|'[vV][eE]|'[mM]|'[lL][lL]|'[dD])|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+
+/// Splits for a `tokenizer.ggml.pre` value; files without one get llama.cpp's `default`.
+fn pre_tokenizer_splits(pre: &str) -> Result<&'static [&'static str]> {
+    Ok(match pre {
+        "default" => &[r"[\p{P}\$\+<=>\^~\|]+", GPT2_SPLIT, r"\p{N}+", "[0-9][0-9][0-9]"],
+        "gpt-2" | "phi-2" => &[GPT2_SPLIT],
+        "llama3" | "llama-v3" | "llama-bpe" | "falcon3" => &[LLAMA3_SPLIT],
+        "qwen2" | "deepseek-r1-qwen" => &[QWEN2_SPLIT],
+        "starcoder" | "refact" | "command-r" | "smollm" | "codeshell" => &[r"\p{N}", GPT2_SPLIT],
+        other => return Err(RunnerError::Message(format!("gguf: unsupported pre-tokenizer `{other}`"))),
+    })
+}
+
+enum Model {
+    /// `llama`: SentencePiece, merging the highest-scoring pairs, with byte fallback.
+    Spm { scores: Vec<f32> },
+    /// `gpt2`: byte-level BPE with ranked merges.
+    Bpe(Box<tokenizers::Tokenizer>),
+}
+
+enum Fragment<'t> { Text(&'t str), Special(u32) }
+
+/// The vocabulary stored under `tokenizer.ggml.*` in a GGUF file.
+pub struct GgufTokenizer {
+    model: Model,
+    tokens: Vec<String>,
+    ids: HashMap<String, u32>,
+    types: Vec<i64>,
+    /// Control and user-defined tokens, longest first; matched verbatim in text.
+    special: Vec<(String, u32)>,
+    bos: Option<u32>,
+    unk: Option<u32>,
+    add_bos: bool,
+    add_space_prefix: bool,
+}
+
+impl GgufTokenizer {
+    pub fn from_gguf(gguf: &GgufFile) -> Result<Self> {
+        let strings = |key: &str| -> Vec<String> {
+            gguf.get(key).and_then(Value::as_array).unwrap_or_default().iter().filter_map(Value::as_str).map(String::from).collect()
+        };
+        let tokens = strings("tokenizer.ggml.tokens");
+        if tokens.is_empty() { return Err(RunnerError::Message("gguf: no tokenizer.ggml.tokens".into())); }
+        let ids: HashMap<String, u32> = tokens.iter().enumerate().map(|(i, t)| (t.clone(), i as u32)).collect();
+        let types: Vec<i64> = gguf.get("tokenizer.ggml.token_type").and_then(Value::as_array).unwrap_or_default()
+            .iter().map(|v| v.as_f64().map_or(1, |v| v as i64)).collect();
+        let kind = gguf.get_str("tokenizer.ggml.model").unwrap_or("llama");
+        let model = match kind {
+            "llama" => {
+                let scores = gguf.get("tokenizer.ggml.scores").and_then(Value::as_array).unwrap_or_default()
+                    .iter().map(|v| v.as_f64().unwrap_or(0.0) as f32).collect();
+                Model::Spm { scores }
+            }
+            "gpt2" => {
+                let merges = strings("tokenizer.ggml.merges").into_iter()
+                    .filter_map(|m| m.split_once(' ').map(|(a, b)| (a.to_string(), b.to_string())))
+                    .collect();
+                let bpe = tokenizers::models::bpe::BPE::builder().vocab_and_merges(ids.clone(), merges).build().map_err(hf_error)?;
+                let mut steps = pre_tokenizer_splits(gguf.get_str("tokenizer.ggml.pre").unwrap_or("default"))?.iter()
+                    .map(|re| Split::new(SplitPattern::Regex(re.to_string()), SplitDelimiterBehavior::Isolated, false).map(PreTokenizerWrapper::from))
+                    .collect::<tokenizers::Result<Vec<_>>>()
+                    .map_err(hf_error)?;
+                steps.push(ByteLevel::new(false, false, false).into());
+                let mut hf = tokenizers::Tokenizer::new(bpe);
+                hf.with_pre_tokenizer(Sequence::new(steps));
+                Model::Bpe(Box::new(hf))
+            }
+            other => return Err(RunnerError::Message(format!("gguf: unsupported tokenizer model `{other}`"))),
+        };
+        let mut special: Vec<(String, u32)> = types.iter().enumerate()
+            .filter(|(_, &t)| t == TOKEN_CONTROL || t == TOKEN_USER_DEFINED)
+            .map(|(i, _)| (tokens[i].clone(), i as u32))
+            .filter(|(t, _)| !t.is_empty())
+            .collect();
+        special.sort_by_key(|(s, _)| std::cmp::Reverse(s.len()));
+        let flag = |key: &str| gguf.get(key).and_then(|v| if let Value::Bool(b) = v { Some(*b) } else { None });
+        let token = |key: &str| gguf.get_u64(&format!("tokenizer.ggml.{key}_token_id")).map(|t| t as u32);
+        let spm = matches!(model, Model::Spm { .. });
+        Ok(Self {
+            model,
+            tokens,
+            ids,
+            types,
+            special,
+            bos: token("bos"),
+            unk: token("unknown"),
+            add_bos: flag("tokenizer.ggml.add_bos_token").unwrap_or(spm),
+            add_space_prefix: flag("tokenizer.ggml.add_space_prefix").unwrap_or(spm),
+        })
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> { Self::from_gguf(&GgufFile::open(path)?) }
+
+    fn token_type(&self, id: u32) -> i64 { self.types.get(id as usize).copied().unwrap_or(1) }
+
+    /// `text` split at the special tokens in it, leaving those that start in
+    /// a `literal` range as text.
+    fn fragments<'t>(&self, text: &'t str, literal: &[Range<usize>]) -> Vec<Fragment<'t>> {
+        let mut out = Vec::new();
+        let (mut start, mut i) = (0, 0);
+        while i < text.len() {
+            let special = if literal.iter().any(|r| r.contains(&i)) { None } else { self.special.iter().find(|(s, _)| text[i..].starts_with(s.as_str())) };
+            match special {
+                Some((s, id)) => {
+                    if start < i { out.push(Fragment::Text(&text[start..i])); }
+                    out.push(Fragment::Special(*id));
+                    i += s.len();
+                    start = i;
+                }
+                None => i += text[i..].chars().next().map_or(1, char::len_utf8),
+            }
+        }
+        if start < text.len() { out.push(Fragment::Text(&text[start..])); }
+        out
+    }
+
+    /// SentencePiece BPE as llama.cpp's `llm_tokenizer_spm` does it: a queue of
+    /// adjacent pairs that are in the vocabulary, best score first, over a
+    /// linked list of symbols, so each merge only looks at its new neighbours.
+    fn encode_spm(&self, scores: &[f32], text: &str, out: &mut Vec<u32>) {
+        let text = text.replace(' ', SPM_SPACE);
+        let mut symbols: Vec<Symbol> = text.char_indices().enumerate()
+            .map(|(i, (start, c))| Symbol { start, len: c.len_utf8(), prev: i.checked_sub(1), next: Some(i + 1) })
+            .collect();
+        if let Some(last) = symbols.last_mut() { last.next = None; }
+        let mut queue = BinaryHeap::new();
+        let pair = |symbols: &[Symbol], queue: &mut BinaryHeap<Bigram>, left: Option<usize>, right: Option<usize>| {
+            let (Some(left), Some(right)) = (left, right) else { return };
+            let (start, len) = (symbols[left].start, symbols[left].len + symbols[right].len);
+            if let Some(&id) = self.ids.get(&text[start..start + len]) {
+                queue.push(Bigram { score: scores.get(id as usize).copied().unwrap_or(0.0), left, right, len });
+            }
+        };
+        for i in 1..symbols.len() { pair(&symbols, &mut queue, Some(i - 1), Some(i)); }
+        while let Some(Bigram { left, right, len, .. }) = queue.pop() {
+            // one side was merged into something else since this pair was queued
+            if symbols[left].len == 0 || symbols[right].len == 0 || symbols[left].len + symbols[right].len != len { continue; }
+            symbols[left].len = len;
+            symbols[right].len = 0;
+            symbols[left].next = symbols[right].next;
+            if let Some(next) = symbols[right].next { symbols[next].prev = Some(left); }
+            pair(&symbols, &mut queue, symbols[left].prev, Some(left));
+            pair(&symbols, &mut queue, Some(left), symbols[left].next);
+        }
+        let mut at = (!symbols.is_empty()).then_some(0);
+        while let Some(i) = at {
+            let piece = &text[symbols[i].start..symbols[i].start + symbols[i].len];
+            match self.ids.get(piece) {
+                Some(&id) => out.push(id),
+                None => out.extend(piece.bytes().filter_map(|b| self.ids.get(&format!("<0x{b:02X}>")).copied().or(self.unk))),
+            }
+            at = symbols[i].next;
+        }
+    }
+
+    fn encode_fragments(&self, fragments: Vec<Fragment>, add_special: bool) -> Result<Vec<u32>> {
+        let mut out = Vec::new();
+        if add_special && self.add_bos { out.extend(self.bos); }
+        for (n, fragment) in fragments.into_iter().enumerate() {
+            match (fragment, &self.model) {
+                (Fragment::Special(id), _) => out.push(id),
+                (Fragment::Text(text), Model::Spm { scores }) => {
+                    let text = if n == 0 && self.add_space_prefix { format!(" {text}") } else { text.to_string() };
+                    self.encode_spm(scores, &text, &mut out);
+                }
+                (Fragment::Text(text), Model::Bpe(hf)) => out.extend_from_slice(hf.encode(text, false).map_err(hf_error)?.get_ids()),
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// A run of `text` in the SPM merge list; merged-away symbols have `len` 0.
+struct Symbol { start: usize, len: usize, prev: Option<usize>, next: Option<usize> }
+
+/// Adjacent symbols whose concatenation, `len` bytes, is in the vocabulary.
+struct Bigram { score: f32, left: usize, right: usize, len: usize }
+
+impl Ord for Bigram {
+    // best score first, then leftmost
+    fn cmp(&self, other: &Self) -> Ordering { self.score.total_cmp(&other.score).then(other.left.cmp(&self.left)) }
+}
+impl PartialOrd for Bigram {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> { Some(self.cmp(other)) }
+}
+impl PartialEq for Bigram {
+    fn eq(&self, other: &Self) -> bool { self.cmp(other) == Ordering::Equal }
+}
+impl Eq for Bigram {}
+
+impl Tokenizer for GgufTokenizer {
+    fn encode(&self, text: &str, add_special: bool) -> Result<Vec<u32>> { self.encode_fragments(self.fragments(text, &[]), add_special) }
+    fn encode_literal(&self, text: &str) -> Result<Vec<u32>> { self.encode_fragments(vec![Fragment::Text(text)], false) }
+    fn encode_template(&self, text: &str, add_special: bool, literal: &[Range<usize>]) -> Result<Vec<u32>> {
+        self.encode_fragments(self.fragments(text, literal), add_special)
+    }
+    fn special_tokens(&self) -> Vec<String> { self.special.iter().map(|(s, _)| s.clone()).collect() }
+
+    fn decode(&self, tokens: &[u32], skip_special: bool) -> Result<String> {
+        self.validate(tokens)?;
+        let mut bytes = Vec::new();
+        // the space the encoder put in front of the text is not part of it
+        let mut strip_space = self.add_space_prefix;
+        for &id in tokens {
+            let piece = &self.tokens[id as usize];
+            match self.token_type(id) {
+                TOKEN_CONTROL | TOKEN_UNKNOWN if skip_special => {}
+                TOKEN_CONTROL | TOKEN_USER_DEFINED | TOKEN_UNKNOWN => bytes.extend(piece.as_bytes()),
+                TOKEN_BYTE => bytes.extend(u8::from_str_radix(piece.trim_start_matches("<0x").trim_end_matches('>'), 16).ok()),
+                _ => match self.model {
+                    Model::Spm { .. } => {
+                        let piece = piece.replace(SPM_SPACE, " ");
+                        let piece = if strip_space { piece.strip_prefix(' ').unwrap_or(&piece) } else { &piece };
+                        bytes.extend(piece.as_bytes());
+                    }
+                    Model::Bpe(_) => bytes.extend(piece.chars().map(|c| byte_of_char(c).unwrap_or(b'?'))),
+                },
+            }
+            strip_space &= self.token_type(id) == TOKEN_CONTROL;
+        }
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    fn vocab_size(&self) -> usize { self.tokens.len() }
+    fn token_to_id(&self, token: &str) -> Option<u32> { self.ids.get(token).copied() }
+    fn id_to_token(&self, id: u32) -> Option<String> { self.tokens.get(id as usize).cloned() }
+}
+
+/// Inverse of GPT-2's byte-to-unicode table: printable Latin-1 bytes stand for
+/// themselves, the rest were shifted to U+0100 and up in byte order.
+fn byte_of_char(c: char) -> Option<u8> {
+    let printable = |b: u32| (0x21..=0x7E).contains(&b) || (0xA1..=0xAC).contains(&b) || (0xAE..=0xFF).contains(&b);
+    let c = c as u32;
+    if c < 0x100 { return printable(c).then_some(c as u8); }
+    (0u32..=0xFF).filter(|&b| !printable(b)).nth((c - 0x100) as usize).map(|b| b as u8)
+}
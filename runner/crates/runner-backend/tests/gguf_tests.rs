@@ -0,0 +1,198 @@
+use runner_backend::gguf::{GgufFile, Value};
+use runner_backend::tokenizer::{GgufTokenizer, Tokenizer};
+use runner_backend::{KvCacheType, KvLayout};
+
+/// Minimal GGUF v3 writer for the reader's tests.
+#[derive(Default)]
+struct Writer { kv: Vec<u8>, n_kv: u64, tensors: Vec<u8>, n_tensors: u64 }
+
+impl Writer {
+    fn string(buf: &mut Vec<u8>, s: &str) {
+        buf.extend((s.len() as u64).to_le_bytes());
+        buf.extend(s.as_bytes());
+    }
+
+    fn key(&mut self, key: &str, ty: u32) -> &mut Vec<u8> {
+        Self::string(&mut self.kv, key);
+        self.kv.extend(ty.to_le_bytes());
+        self.n_kv += 1;
+        &mut self.kv
+    }
+
+    fn str(mut self, key: &str, v: &str) -> Self { Self::string(self.key(key, 8), v); self }
+    fn u32(mut self, key: &str, v: u32) -> Self { self.key(key, 4).extend(v.to_le_bytes()); self }
+    fn bool(mut self, key: &str, v: bool) -> Self { self.key(key, 7).push(v.into()); self }
+
+    fn array<T: Copy, const N: usize>(mut self, key: &str, ty: u32, items: &[T], bytes: fn(T) -> [u8; N]) -> Self {
+        let buf = self.key(key, 9);
+        buf.extend(ty.to_le_bytes());
+        buf.extend((items.len() as u64).to_le_bytes());
+        for &v in items { buf.extend(bytes(v)); }
+        self
+    }
+
+    fn strings(mut self, key: &str, items: &[&str]) -> Self {
+        let buf = self.key(key, 9);
+        buf.extend(8u32.to_le_bytes());
+        buf.extend((items.len() as u64).to_le_bytes());
+        for s in items { Self::string(buf, s); }
+        self
+    }
+
+    fn tensor(mut self, name: &str, dims: &[u64], ggml_type: u32) -> Self {
+        Self::string(&mut self.tensors, name);
+        self.tensors.extend((dims.len() as u32).to_le_bytes());
+        for d in dims { self.tensors.extend(d.to_le_bytes()); }
+        self.tensors.extend(ggml_type.to_le_bytes());
+        self.tensors.extend(0u64.to_le_bytes());
+        self.n_tensors += 1;
+        self
+    }
+
+    fn finish(self) -> Vec<u8> {
+        let mut out = b"GGUF".to_vec();
+        out.extend(3u32.to_le_bytes());
+        out.extend(self.n_tensors.to_le_bytes());
+        out.extend(self.n_kv.to_le_bytes());
+        out.extend(self.kv);
+        out.extend(self.tensors);
+        out
+    }
+}
+
+fn llama() -> Vec<u8> {
+    Writer::default()
+        .str("general.architecture", "llama")
+        .str("general.name", "tiny")
+        .u32("general.file_type", 15)
+        .u32("llama.context_length", 4096)
+        .u32("llama.embedding_length", 256)
+        .u32("llama.block_count", 4)
+        .u32("llama.attention.head_count", 8)
+        .u32("llama.attention.head_count_kv", 2)
+        .strings("tokenizer.ggml.tokens", &["<s>", "</s>", "a", "b"])
+        .str("tokenizer.chat_template", "{{ messages }}")
+        .tensor("token_embd.weight", &[256, 4], 12)
+        .tensor("output.weight", &[256, 4], 14)
+        .finish()
+}
+
+#[test]
+fn reads_model_facts_from_metadata_and_tensors() {
+    let bytes = llama();
+    let gguf = GgufFile::read(&bytes[..], bytes.len() as u64).unwrap();
+    assert_eq!(gguf.version, 3);
+    assert_eq!(gguf.architecture(), Some("llama"));
+    assert_eq!(gguf.name(), Some("tiny"));
+    assert_eq!(gguf.context_length(), Some(4096));
+    assert_eq!(gguf.vocab_size(), Some(4));
+    assert_eq!(gguf.chat_template(), Some("{{ messages }}"));
+    assert_eq!(gguf.quantization(), Some("Q4_K_M"));
+    assert_eq!(gguf.parameter_count(), 2048);
+    assert_eq!(gguf.get("llama.block_count"), Some(&Value::U32(4)));
+    assert_eq!(gguf.data_offset % 32, 0);
+    assert!(gguf.data_offset >= bytes.len() as u64);
+    let layout = gguf.kv_layout(KvCacheType::F16, KvCacheType::Q8_0).unwrap();
+    assert_eq!(layout, KvLayout { n_layers: 4, n_kv_heads: 2, head_dim: 32, type_k: KvCacheType::F16, type_v: KvCacheType::Q8_0 });
+}
+
+#[test]
+fn quantization_falls_back_to_the_dominant_tensor_type() {
+    let bytes = Writer::default().tensor("a", &[64, 64], 8).tensor("b", &[64], 0).finish();
+    let gguf = GgufFile::read(&bytes[..], bytes.len() as u64).unwrap();
+    assert_eq!(gguf.quantization(), Some("Q8_0"));
+    assert_eq!(gguf.kv_layout(KvCacheType::F16, KvCacheType::F16), None);
+}
+
+#[test]
+fn rejects_truncated_and_foreign_files() {
+    let bytes = llama();
+    assert!(GgufFile::read(&bytes[..bytes.len() - 3], bytes.len() as u64 - 3).is_err());
+    assert!(GgufFile::read(&b"GGML\x03\0\0\0"[..], 8).is_err());
+    // a string length past the end of the file is not allocated
+    let mut huge = Writer::default().str("k", "v").finish();
+    let at = 4 + 4 + 8 + 8 + 8 + 1 + 4;
+    huge[at..at + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+    assert!(GgufFile::read(&huge[..], huge.len() as u64).is_err());
+}
+
+#[test]
+fn model_handle_starts_from_the_header() {
+    let bytes = Writer::default()
+        .str("general.architecture", "llama")
+        .u32("llama.context_length", 4096)
+        .u32("llama.embedding_length", 256)
+        .u32("tokenizer.ggml.bos_token_id", 0)
+        .u32("tokenizer.ggml.eos_token_id", 1)
+        .strings("tokenizer.ggml.tokens", &["<s>", "</s>", "a", "b"])
+        .finish();
+    let gguf = GgufFile::read(&bytes[..], bytes.len() as u64).unwrap();
+    let params = runner_backend::LoadParams { n_ctx: 1024, ..Default::default() };
+    let model = runner_backend::ModelHandle::from_gguf("missing.gguf", &gguf, &params);
+    assert_eq!((model.special_tokens.bos, model.special_tokens.eos, model.special_tokens.eot), (Some(0), Some(1), None));
+    assert_eq!(model.special_tokens.stop(), [1]);
+    assert_eq!((model.n_ctx_train, model.n_ctx, model.n_embd, model.vocab_size), (Some(4096), Some(1024), Some(256), 4));
+    assert_eq!(model.size_bytes, 0);
+}
+
+fn read(bytes: &[u8]) -> GgufFile { GgufFile::read(bytes, bytes.len() as u64).unwrap() }
+
+#[test]
+fn sentencepiece_vocab_merges_by_score_with_byte_fallback() {
+    let tokens = ["<unk>", "<s>", "</s>", "\u{2581}", "h", "i", "\u{2581}h", "hi", "\u{2581}hi", "<0x21>", "<|im_end|>"];
+    let bytes = Writer::default()
+        .str("tokenizer.ggml.model", "llama")
+        .strings("tokenizer.ggml.tokens", &tokens)
+        .array("tokenizer.ggml.scores", 6, &[0.0, 0.0, 0.0, -1.0, -2.0, -2.0, -3.0, -1.0, -0.5, 0.0, 0.0], f32::to_le_bytes)
+        .array("tokenizer.ggml.token_type", 5, &[2, 3, 3, 1, 1, 1, 1, 1, 1, 6, 3], i32::to_le_bytes)
+        .u32("tokenizer.ggml.bos_token_id", 1)
+        .u32("tokenizer.ggml.unknown_token_id", 0)
+        .finish();
+    let tok = GgufTokenizer::from_gguf(&read(&bytes)).unwrap();
+    assert_eq!(tok.encode("hi", true).unwrap(), [1, 8]);
+    // `!` is not in the vocabulary but its byte is; `?` is neither
+    assert_eq!(tok.encode("hi!?", false).unwrap(), [8, 9, 0]);
+    // control tokens in the text are matched whole, not spelled out, unless they are literal text
+    assert_eq!(tok.encode("hi<|im_end|>", false).unwrap(), [8, 10]);
+    assert!(!tok.encode_template("hi<|im_end|>", false, std::slice::from_ref(&(2..12))).unwrap().contains(&10));
+    assert!(!tok.encode_literal("<|im_end|>").unwrap().contains(&10));
+    assert_eq!(tok.decode(&[1, 8, 9, 10], true).unwrap(), "hi!");
+    assert_eq!(tok.decode(&[1, 8, 9, 10], false).unwrap(), "<s>hi!<|im_end|>");
+    assert_eq!((tok.vocab_size(), tok.token_to_id("hi"), tok.count("hi").unwrap()), (11, Some(7), 1));
+    assert!(tok.decode(&[11], false).is_err());
+}
+
+#[test]
+fn gpt2_vocab_is_byte_level_bpe() {
+    let bytes = Writer::default()
+        .str("tokenizer.ggml.model", "gpt2")
+        .strings("tokenizer.ggml.tokens", &["a", "b", "ab", "\u{120}", "\u{120}a", "<|endoftext|>"])
+        .strings("tokenizer.ggml.merges", &["a b", "\u{120} a"])
+        .array("tokenizer.ggml.token_type", 5, &[1, 1, 1, 1, 1, 3], i32::to_le_bytes)
+        .u32("tokenizer.ggml.bos_token_id", 5)
+        .bool("tokenizer.ggml.add_bos_token", false)
+        .finish();
+    let tok = GgufTokenizer::from_gguf(&read(&bytes)).unwrap();
+    assert_eq!(tok.encode("ab a", true).unwrap(), [2, 4]);
+    assert_eq!(tok.encode("ab<|endoftext|>", false).unwrap(), [2, 5]);
+    assert_eq!(tok.decode(&[2, 4, 5], true).unwrap(), "ab a");
+}
+
+fn digits(pre: &str) -> runner_common::Result<GgufTokenizer> {
+    let bytes = Writer::default()
+        .str("tokenizer.ggml.model", "gpt2")
+        .str("tokenizer.ggml.pre", pre)
+        .strings("tokenizer.ggml.tokens", &["1", "2", "3", "4", "12", "123", "1234"])
+        .strings("tokenizer.ggml.merges", &["1 2", "12 3", "123 4"])
+        .finish();
+    GgufTokenizer::from_gguf(&read(&bytes))
+}
+
+#[test]
+fn gpt2_vocab_splits_like_its_pre_tokenizer() {
+    assert_eq!(digits("gpt-2").unwrap().encode("1234", false).unwrap(), [6]);
+    // Llama 3 splits digits into runs of at most three
+    assert_eq!(digits("llama-bpe").unwrap().encode("1234", false).unwrap(), [5, 3]);
+    assert_eq!(digits("qwen2").unwrap().encode("1234", false).unwrap(), [0, 1, 2, 3]);
+    assert!(digits("no-such-pre").is_err());
+}
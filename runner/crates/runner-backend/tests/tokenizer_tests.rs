@@ -0,0 +1,36 @@
+use runner_backend::tokenizer::{HfTokenizer, Tokenizer};
+
+const TOKENIZER_JSON: &str = r#"{
+  "version": "1.0",
+  "truncation": null,
+  "padding": null,
+  "added_tokens": [{"id": 0, "content": "<s>", "single_word": false, "lstrip": false, "rstrip": false, "normalized": false, "special": true}],
+  "normalizer": null,
+  "pre_tokenizer": {"type": "Whitespace"},
+  "post_processor": {
+    "type": "TemplateProcessing",
+    "single": [{"SpecialToken": {"id": "<s>", "type_id": 0}}, {"Sequence": {"id": "A", "type_id": 0}}],
+    "pair": [{"SpecialToken": {"id": "<s>", "type_id": 0}}, {"Sequence": {"id": "A", "type_id": 0}}, {"Sequence": {"id": "B", "type_id": 1}}],
+    "special_tokens": {"<s>": {"id": "<s>", "ids": [0], "tokens": ["<s>"]}}
+  },
+  "decoder": null,
+  "model": {"type": "WordLevel", "vocab": {"<s>": 0, "hello": 1, "world": 2, "<unk>": 3}, "unk_token": "<unk>"}
+}"#;
+
+#[test]
+fn hugging_face_tokenizer_json() {
+    let tok = HfTokenizer::from_bytes(TOKENIZER_JSON.as_bytes()).unwrap();
+    assert_eq!(tok.encode("hello world", true).unwrap(), [0, 1, 2]);
+    assert_eq!(tok.encode("hello there", false).unwrap(), [1, 3]);
+    assert_eq!(tok.decode(&[0, 1, 2], true).unwrap(), "hello world");
+    assert_eq!((tok.vocab_size(), tok.token_to_id("world"), tok.id_to_token(1).as_deref()), (4, Some(2), Some("hello")));
+    assert!(tok.validate(&[4]).is_err());
+    assert!(HfTokenizer::from_bytes(b"{}").is_err());
+}
+
+#[test]
+fn fim_markers_from_the_vocabulary() {
+    let fim = TOKENIZER_JSON.replace(r#""<unk>": 3}"#, r#""<unk>": 3, "<fim_prefix>": 4, "<fim_suffix>": 5, "<fim_middle>": 6}"#);
+    assert_eq!(HfTokenizer::from_bytes(fim.as_bytes()).unwrap().fim_tokens(), Some([4, 5, 6]));
+    assert_eq!(HfTokenizer::from_bytes(TOKENIZER_JSON.as_bytes()).unwrap().fim_tokens(), None);
+}
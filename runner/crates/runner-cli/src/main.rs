@@ -1,6 +1,7 @@
 use axum::Router;
 use clap::{Parser, Subcommand, Args};
 use runner_api::app;
+use runner_backend::gguf::GgufFile;
 use runner_backend::mock::MockBackend;
 use runner_core::decode::generate_once;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
@@ -83,13 +84,33 @@ async fn list_models() {
         Ok(read_dir) => {
             println!("models dir: {}", path.display());
             for entry in read_dir.flatten() {
-                println!("- {}", entry.path().display());
+                let path = entry.path();
+                if path.extension().is_none_or(|e| e != "gguf") {
+                    println!("- {}", path.display());
+                    continue;
+                }
+                match GgufFile::open(&path) {
+                    Ok(gguf) => println!("- {} ({})", path.display(), describe(&gguf)),
+                    Err(e) => println!("- {} (unreadable: {})", path.display(), e),
+                }
             }
         }
         Err(_) => println!("no models directory at {}", path.display()),
     }
 }
 
+/// One-line summary of a model file's GGUF header.
+fn describe(gguf: &GgufFile) -> String {
+    let mut parts = vec![gguf.architecture().unwrap_or("unknown architecture").to_string()];
+    let params = gguf.parameter_count();
+    if params > 0 { parts.push(format!("{:.1}B params", params as f64 / 1e9)); }
+    parts.extend(gguf.quantization().map(String::from));
+    parts.extend(gguf.context_length().map(|n| format!("ctx {n}")));
+    parts.extend(gguf.vocab_size().map(|n| format!("vocab {n}")));
+    if gguf.chat_template().is_some() { parts.push("chat template".into()); }
+    parts.join(", ")
+}
+
 async fn stats() {
     use sysinfo::System;
     let mut sys = System::new_all();
@@ -146,7 +167,7 @@ async fn pull_model(args: PullArgs) {
         let file = parts[2..].join("/");
         (format!("https://huggingface.co/{}/{}/resolve/main/{}", org, repo, file), file)
     } else {
-        let fname = args.source.split('/').last().unwrap_or("model.gguf").to_string();
+        let fname = args.source.split('/').next_back().unwrap_or("model.gguf").to_string();
         (args.source, fname)
     };
 
@@ -4,52 +4,303 @@ pub type Result<T> = core::result::Result<T, RunnerError>;
 pub enum RunnerError {
     #[error("not implemented")] 
     NotImplemented,
+    #[error("cancelled")]
+    Cancelled,
+    #[error("KV cache exhausted")]
+    KvExhausted,
+    #[error("context length exceeded: {prompt} prompt tokens and max_tokens {max_tokens} do not fit a {n_ctx}-token context")]
+    ContextLengthExceeded { prompt: usize, max_tokens: usize, n_ctx: usize },
     #[error("{0}")]
     Message(String),
 }
 
+pub mod cancel {
+    use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+
+    /// Shared flag flipped when the client that owns a request goes away.
+    /// Cloned from the API layer into the scheduler, decode loop and backend.
+    #[derive(Debug, Clone, Default)]
+    pub struct CancelToken(Arc<AtomicBool>);
+
+    impl CancelToken {
+        pub fn new() -> Self { Self::default() }
+        pub fn cancel(&self) { self.0.store(true, Ordering::SeqCst) }
+        pub fn is_cancelled(&self) -> bool { self.0.load(Ordering::SeqCst) }
+    }
+}
+
 pub mod config {
     use serde::Deserialize;
+    use std::collections::HashMap;
     use std::env;
-    use std::path::PathBuf;
+    use std::path::{Path, PathBuf};
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    /// How long a model stays loaded after its last request, as in Ollama: a
+    /// duration such as `"5m"`, `"1h30m"` or `"45s"`, or a number of seconds.
+    /// A negative value keeps the model loaded; zero unloads it once it is idle.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum KeepAlive { Forever, For(Duration) }
+
+    impl KeepAlive {
+        /// Ollama's default.
+        pub const DEFAULT: KeepAlive = KeepAlive::For(Duration::from_secs(300));
+
+        fn from_secs(secs: f64) -> Self { if secs < 0.0 { Self::Forever } else { Self::For(Duration::from_secs_f64(secs)) } }
+    }
+
+    impl FromStr for KeepAlive {
+        type Err = crate::RunnerError;
+        fn from_str(s: &str) -> crate::Result<Self> {
+            let bad = || crate::RunnerError::Message(format!("invalid keep_alive `{s}`"));
+            let s = s.trim();
+            if s.is_empty() { return Err(bad()); }
+            if let Ok(secs) = s.parse::<f64>() { return Ok(Self::from_secs(secs)); }
+            if s.starts_with('-') { return Ok(Self::Forever); }
+            let mut total = Duration::ZERO;
+            let mut rest = s;
+            while !rest.is_empty() {
+                let split = rest.find(|c: char| !(c.is_ascii_digit() || c == '.')).ok_or_else(bad)?;
+                let value: f64 = rest[..split].parse().map_err(|_| bad())?;
+                let unit_len = rest[split..].find(|c: char| c.is_ascii_digit()).unwrap_or(rest.len() - split);
+                let unit = match &rest[split..split + unit_len] { "ms" => 0.001, "s" => 1.0, "m" => 60.0, "h" => 3600.0, _ => return Err(bad()) };
+                total += Duration::from_secs_f64(value * unit);
+                rest = &rest[split + unit_len..];
+            }
+            Ok(Self::For(total))
+        }
+    }
+
+    impl<'de> Deserialize<'de> for KeepAlive {
+        fn deserialize<D: serde::Deserializer<'de>>(d: D) -> std::result::Result<Self, D::Error> {
+            #[derive(Deserialize)]
+            #[serde(untagged)]
+            enum Raw { Secs(f64), Text(String) }
+            match Raw::deserialize(d)? {
+                Raw::Secs(secs) => Ok(Self::from_secs(secs)),
+                Raw::Text(s) => s.parse().map_err(serde::de::Error::custom),
+            }
+        }
+    }
+
+    /// How a model is loaded. Top-level values apply to every model; an entry in
+    /// `RunnerConfig::models` overrides them field by field. Unset fields keep the
+    /// backend's (or the model file's) own default.
+    #[derive(Debug, Clone, Default, Deserialize)]
+    pub struct LoadSettings {
+        pub context_size: Option<usize>,
+        pub gpu_layers: Option<usize>,
+        /// KV cache storage types: `f32`, `f16` (default), `q8_0` or `q4_0`.
+        pub kv_cache_type_k: Option<String>,
+        pub kv_cache_type_v: Option<String>,
+        /// RoPE scaling: `none`, `linear` or `yarn`.
+        pub rope_scaling: Option<String>,
+        pub rope_freq_base: Option<f32>,
+        pub rope_freq_scale: Option<f32>,
+        pub yarn_ext_factor: Option<f32>,
+        pub yarn_attn_factor: Option<f32>,
+        pub yarn_beta_fast: Option<f32>,
+        pub yarn_beta_slow: Option<f32>,
+        /// Context the model was trained with, for YaRN.
+        pub yarn_orig_ctx: Option<usize>,
+        /// Unset enables flash attention only where it is required (quantized V cache).
+        pub flash_attn: Option<bool>,
+        pub batch_size: Option<usize>,
+        pub ubatch_size: Option<usize>,
+        pub threads: Option<usize>,
+        pub threads_batch: Option<usize>,
+        pub use_mmap: Option<bool>,
+        pub use_mlock: Option<bool>,
+        /// NUMA strategy: `disabled`, `distribute`, `isolate`, `numactl` or `mirror`.
+        pub numa: Option<String>,
+    }
+
+    impl LoadSettings {
+        /// These settings with unset fields taken from `base`.
+        pub fn or(&self, base: &LoadSettings) -> LoadSettings {
+            LoadSettings {
+                context_size: self.context_size.or(base.context_size),
+                gpu_layers: self.gpu_layers.or(base.gpu_layers),
+                kv_cache_type_k: self.kv_cache_type_k.clone().or_else(|| base.kv_cache_type_k.clone()),
+                kv_cache_type_v: self.kv_cache_type_v.clone().or_else(|| base.kv_cache_type_v.clone()),
+                rope_scaling: self.rope_scaling.clone().or_else(|| base.rope_scaling.clone()),
+                rope_freq_base: self.rope_freq_base.or(base.rope_freq_base),
+                rope_freq_scale: self.rope_freq_scale.or(base.rope_freq_scale),
+                yarn_ext_factor: self.yarn_ext_factor.or(base.yarn_ext_factor),
+                yarn_attn_factor: self.yarn_attn_factor.or(base.yarn_attn_factor),
+                yarn_beta_fast: self.yarn_beta_fast.or(base.yarn_beta_fast),
+                yarn_beta_slow: self.yarn_beta_slow.or(base.yarn_beta_slow),
+                yarn_orig_ctx: self.yarn_orig_ctx.or(base.yarn_orig_ctx),
+                flash_attn: self.flash_attn.or(base.flash_attn),
+                batch_size: self.batch_size.or(base.batch_size),
+                ubatch_size: self.ubatch_size.or(base.ubatch_size),
+                threads: self.threads.or(base.threads),
+                threads_batch: self.threads_batch.or(base.threads_batch),
+                use_mmap: self.use_mmap.or(base.use_mmap),
+                use_mlock: self.use_mlock.or(base.use_mlock),
+                numa: self.numa.clone().or_else(|| base.numa.clone()),
+            }
+        }
+    }
+
+    /// A model the server can route to, under its `models` key and any aliases.
+    #[derive(Debug, Clone, Default, Deserialize)]
+    pub struct ModelEntry {
+        /// Model file; defaults to `<model_dir>/<name>.gguf`.
+        pub path: Option<PathBuf>,
+        /// Hugging Face `tokenizer.json`; defaults to one next to the model file,
+        /// then to the vocabulary in the GGUF file.
+        pub tokenizer: Option<PathBuf>,
+        /// Chat template: `chatml`, `llama3`, `mistral`, `gemma` or Jinja source;
+        /// defaults to the one in the model file.
+        pub chat_template: Option<String>,
+        #[serde(default)]
+        pub aliases: Vec<String>,
+        /// Idle time before the model is unloaded; the top-level `keep_alive` when unset.
+        pub keep_alive: Option<KeepAlive>,
+        /// Never unload the model, whether idle or to make room for another.
+        #[serde(default)]
+        pub pinned: bool,
+        #[serde(flatten)]
+        pub load: LoadSettings,
+    }
 
     #[derive(Debug, Clone, Deserialize)]
     pub struct RunnerConfig {
         pub model_dir: PathBuf,
-        pub context_size: Option<usize>,
-        pub gpu_layers: Option<usize>,
+        /// Load settings for every model, written at the top level of the file.
+        #[serde(flatten)]
+        pub load: LoadSettings,
+        /// Models by name, with their own load settings over the top-level ones.
+        /// `*.gguf` files in `model_dir` are served under their file stem as well.
+        #[serde(default)]
+        pub models: HashMap<String, ModelEntry>,
+        /// Model used when a request names none.
+        pub default_model: Option<String>,
+        /// Memory loaded models (weights plus KV pool) may use before idle ones are unloaded.
+        pub model_memory_mb: Option<usize>,
+        /// Idle time before a model is unloaded; requests may set their own.
+        pub keep_alive: Option<KeepAlive>,
+        /// Models loaded and warmed up at startup before the server reports ready;
+        /// unset preloads the default model only.
+        pub preload: Option<Vec<String>>,
         pub scheduler_tick_ms: Option<u64>,
         pub max_batch_tokens: Option<usize>,
+        /// Sequences decoded together in one scheduler step.
+        pub max_concurrent_seqs: Option<usize>,
+        /// Requests allowed to wait for a batch slot before new ones are rejected.
+        pub queue_capacity: Option<usize>,
+        /// Size of the paged KV pool.
+        pub kv_capacity_mb: Option<usize>,
+        /// Host RAM for KV blocks spilled out of the pool; unset or 0 disables the tier.
+        pub kv_host_spill_mb: Option<usize>,
+        /// Memory-mapped file size for KV spilled out of host RAM; unset or 0 disables the tier.
+        pub kv_disk_spill_mb: Option<usize>,
+        /// Directory for the disk tier's backing files; defaults to the temp dir.
+        pub kv_disk_spill_dir: Option<PathBuf>,
+        /// Where conversation sessions are saved; unset keeps them in memory only.
+        pub session_dir: Option<PathBuf>,
+        /// Seconds a session may sit idle before its KV is parked off the device.
+        pub session_idle_secs: Option<u64>,
     }
 
     impl Default for RunnerConfig {
         fn default() -> Self {
             Self {
                 model_dir: PathBuf::from("models"),
-                context_size: Some(2048),
-                gpu_layers: None,
+                load: LoadSettings { context_size: Some(2048), ..Default::default() },
+                models: HashMap::new(),
+                default_model: None,
+                model_memory_mb: None,
+                keep_alive: Some(KeepAlive::DEFAULT),
+                preload: None,
                 scheduler_tick_ms: Some(2),
                 max_batch_tokens: Some(1024),
+                max_concurrent_seqs: Some(32),
+                queue_capacity: Some(1024),
+                kv_capacity_mb: Some(512),
+                kv_host_spill_mb: None,
+                kv_disk_spill_mb: None,
+                kv_disk_spill_dir: None,
+                session_dir: None,
+                session_idle_secs: Some(300),
             }
         }
     }
 
     impl RunnerConfig {
+        /// Config from the file at `RUNNER_CONFIG`, else from the environment.
         pub fn load() -> Self {
             if let Ok(path) = env::var("RUNNER_CONFIG") {
                 let Ok(text) = std::fs::read_to_string(path) else { return Self::default() };
-                let Ok(cfg) = serde_yaml::from_str::<RunnerConfig>(&text) else { return Self::default() };
-                return cfg;
+                return Self::from_yaml(&text).unwrap_or_default();
             }
             let mut cfg = Self::default();
             if let Ok(dir) = env::var("RUNNER_MODEL_DIR") {
                 cfg.model_dir = PathBuf::from(dir);
             }
-            if let Some(v) = env::var("RUNNER_CONTEXT_SIZE").ok().and_then(|v| v.parse().ok()) { cfg.context_size = Some(v); }
-            if let Some(v) = env::var("RUNNER_GPU_LAYERS").ok().and_then(|v| v.parse().ok()) { cfg.gpu_layers = Some(v); }
+            if let Ok(name) = env::var("RUNNER_DEFAULT_MODEL") { cfg.default_model = Some(name); }
+            if let Some(v) = env::var("RUNNER_MODEL_MEMORY_MB").ok().and_then(|v| v.parse().ok()) { cfg.model_memory_mb = Some(v); }
+            if let Some(v) = env::var("RUNNER_KEEP_ALIVE").ok().and_then(|v| v.parse().ok()) { cfg.keep_alive = Some(v); }
+            if let Ok(v) = env::var("RUNNER_PRELOAD") { cfg.preload = Some(v.split(',').map(str::trim).filter(|m| !m.is_empty()).map(String::from).collect()); }
+            let load = &mut cfg.load;
+            if let Some(v) = env::var("RUNNER_CONTEXT_SIZE").ok().and_then(|v| v.parse().ok()) { load.context_size = Some(v); }
+            if let Some(v) = env::var("RUNNER_GPU_LAYERS").ok().and_then(|v| v.parse().ok()) { load.gpu_layers = Some(v); }
+            if let Ok(t) = env::var("RUNNER_KV_TYPE_K") { load.kv_cache_type_k = Some(t); }
+            if let Ok(t) = env::var("RUNNER_KV_TYPE_V") { load.kv_cache_type_v = Some(t); }
+            if let Ok(v) = env::var("RUNNER_ROPE_SCALING") { load.rope_scaling = Some(v); }
+            if let Some(v) = env::var("RUNNER_ROPE_FREQ_BASE").ok().and_then(|v| v.parse().ok()) { load.rope_freq_base = Some(v); }
+            if let Some(v) = env::var("RUNNER_ROPE_FREQ_SCALE").ok().and_then(|v| v.parse().ok()) { load.rope_freq_scale = Some(v); }
+            if let Some(v) = env::var("RUNNER_YARN_EXT_FACTOR").ok().and_then(|v| v.parse().ok()) { load.yarn_ext_factor = Some(v); }
+            if let Some(v) = env::var("RUNNER_YARN_ATTN_FACTOR").ok().and_then(|v| v.parse().ok()) { load.yarn_attn_factor = Some(v); }
+            if let Some(v) = env::var("RUNNER_YARN_BETA_FAST").ok().and_then(|v| v.parse().ok()) { load.yarn_beta_fast = Some(v); }
+            if let Some(v) = env::var("RUNNER_YARN_BETA_SLOW").ok().and_then(|v| v.parse().ok()) { load.yarn_beta_slow = Some(v); }
+            if let Some(v) = env::var("RUNNER_YARN_ORIG_CTX").ok().and_then(|v| v.parse().ok()) { load.yarn_orig_ctx = Some(v); }
+            if let Some(v) = env::var("RUNNER_FLASH_ATTN").ok().and_then(|v| v.parse().ok()) { load.flash_attn = Some(v); }
+            if let Some(v) = env::var("RUNNER_BATCH_SIZE").ok().and_then(|v| v.parse().ok()) { load.batch_size = Some(v); }
+            if let Some(v) = env::var("RUNNER_UBATCH_SIZE").ok().and_then(|v| v.parse().ok()) { load.ubatch_size = Some(v); }
+            if let Some(v) = env::var("RUNNER_THREADS").ok().and_then(|v| v.parse().ok()) { load.threads = Some(v); }
+            if let Some(v) = env::var("RUNNER_THREADS_BATCH").ok().and_then(|v| v.parse().ok()) { load.threads_batch = Some(v); }
+            if let Some(v) = env::var("RUNNER_MMAP").ok().and_then(|v| v.parse().ok()) { load.use_mmap = Some(v); }
+            if let Some(v) = env::var("RUNNER_MLOCK").ok().and_then(|v| v.parse().ok()) { load.use_mlock = Some(v); }
+            if let Ok(v) = env::var("RUNNER_NUMA") { load.numa = Some(v); }
             if let Some(v) = env::var("RUNNER_TICK_MS").ok().and_then(|v| v.parse().ok()) { cfg.scheduler_tick_ms = Some(v); }
             if let Some(v) = env::var("RUNNER_MAX_BATCH_TOKENS").ok().and_then(|v| v.parse().ok()) { cfg.max_batch_tokens = Some(v); }
+            if let Some(v) = env::var("RUNNER_MAX_SEQS").ok().and_then(|v| v.parse().ok()) { cfg.max_concurrent_seqs = Some(v); }
+            if let Some(v) = env::var("RUNNER_QUEUE_CAPACITY").ok().and_then(|v| v.parse().ok()) { cfg.queue_capacity = Some(v); }
+            if let Some(v) = env::var("RUNNER_KV_CAPACITY_MB").ok().and_then(|v| v.parse().ok()) { cfg.kv_capacity_mb = Some(v); }
+            if let Some(v) = env::var("RUNNER_KV_HOST_SPILL_MB").ok().and_then(|v| v.parse().ok()) { cfg.kv_host_spill_mb = Some(v); }
+            if let Some(v) = env::var("RUNNER_KV_DISK_SPILL_MB").ok().and_then(|v| v.parse().ok()) { cfg.kv_disk_spill_mb = Some(v); }
+            if let Ok(dir) = env::var("RUNNER_KV_DISK_SPILL_DIR") { cfg.kv_disk_spill_dir = Some(PathBuf::from(dir)); }
+            if let Ok(dir) = env::var("RUNNER_SESSION_DIR") { cfg.session_dir = Some(PathBuf::from(dir)); }
+            if let Some(v) = env::var("RUNNER_SESSION_IDLE_SECS").ok().and_then(|v| v.parse().ok()) { cfg.session_idle_secs = Some(v); }
             cfg
+        }
+
+        /// Config from YAML text, as the `RUNNER_CONFIG` file holds it.
+        pub fn from_yaml(text: &str) -> crate::Result<Self> {
+            serde_yaml::from_str(text).map_err(|e| crate::RunnerError::Message(format!("config: {e}")))
+        }
+
+        /// Load settings for `model` (a name or path): its `models` entry over the
+        /// top-level ones, with the default context size when neither sets one.
+        pub fn load_settings(&self, model: &str) -> LoadSettings {
+            let load = self.model_entry(model).map_or_else(|| self.load.clone(), |m| m.load.or(&self.load));
+            load.or(&Self::default().load)
+        }
+
+        /// The `models` entry for `model`, matched by name, configured path, file name or file stem.
+        pub fn model_entry(&self, model: &str) -> Option<&ModelEntry> {
+            let path = Path::new(model);
+            let names = [Some(model), path.file_name().and_then(|n| n.to_str()), path.file_stem().and_then(|n| n.to_str())];
+            names.into_iter().flatten().find_map(|n| self.models.get(n))
+                .or_else(|| self.models.values().find(|m| m.path.as_deref() == Some(path)))
+        }
+
+        /// KV pool size in bytes, falling back to the default when unset.
+        pub fn kv_capacity_bytes(&self) -> usize {
+            self.kv_capacity_mb.or(Self::default().kv_capacity_mb).unwrap_or(0) * 1024 * 1024
         }
     }
 }
@@ -0,0 +1,14 @@
+use runner_common::config::RunnerConfig;
+
+#[test]
+fn model_entries_override_top_level_load_settings() {
+    let yaml = "model_dir: models\ncontext_size: 4096\nrope_freq_base: 10000.0\nmodels:\n  long:\n    context_size: 32768\n    rope_scaling: yarn\n    yarn_orig_ctx: 8192\n";
+    let cfg = RunnerConfig::from_yaml(yaml).unwrap();
+
+    let long = cfg.load_settings("/models/long.gguf");
+    assert_eq!((long.context_size, long.rope_scaling.as_deref(), long.yarn_orig_ctx), (Some(32768), Some("yarn"), Some(8192)));
+    assert_eq!(long.rope_freq_base, Some(10000.0));
+    let other = cfg.load_settings("/models/other.gguf");
+    assert_eq!((other.context_size, other.rope_scaling), (Some(4096), None));
+    assert!(RunnerConfig::from_yaml("context_size: [").is_err());
+}
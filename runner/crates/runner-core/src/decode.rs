@@ -1,16 +1,248 @@
-use runner_backend::InferenceBackend;
-use runner_common::Result;
-use crate::sampler::sample_top_k_top_p;
+use runner_backend::{ContextOverflow, ContextPolicy, ForwardOutput, InferenceBackend, SequenceState};
+use runner_common::{cancel::CancelToken, Result, RunnerError};
+use crate::sampler::{logprobs, Sampling};
+use rand::rngs::StdRng;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static NEXT_SEQ_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Fresh backend sequence id; every `Decoder` takes one unless it resumes a parked sequence.
+pub fn next_seq_id() -> u64 { NEXT_SEQ_ID.fetch_add(1, Ordering::Relaxed) }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FinishReason { Stop, Length }
+
+impl FinishReason {
+    pub fn as_str(&self) -> &'static str {
+        match self { FinishReason::Stop => "stop", FinishReason::Length => "length" }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage { pub prompt_tokens: usize, pub completion_tokens: usize }
+
+#[derive(Debug, Clone)]
+pub struct Completion { pub text: String, pub finish_reason: FinishReason, pub usage: Usage }
+
+/// Per-request decoding settings: how tokens are sampled and the strings that
+/// end the text. A stop string is not part of the output.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DecodeOptions {
+    pub sampling: Sampling,
+    pub stop: Vec<String>,
+    /// Report each token's log-probability and this many likeliest alternatives.
+    pub logprobs: Option<usize>,
+}
+
+/// Log-probability of a generated token and the likeliest alternatives at its
+/// position; `None` and empty when the backend picked it without logits.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenLogprob {
+    pub token: u32,
+    pub logprob: Option<f32>,
+    pub top: Vec<(u32, f32)>,
+}
+
+/// Per-sequence decode state: the backend-facing `SequenceState` plus the text
+/// decoded so far and how much of it was handed out, so deltas are only emitted
+/// once they are valid UTF-8. Generated tokens are also kept apart from
+/// `seq.tokens`, which a context shift may cut into.
+pub struct Decoder {
+    pub seq: SequenceState,
+    text: String,
+    emitted: usize,
+    output: Vec<u32>,
+    /// `output[prefix_offset..read_offset]` is the last chunk added to `text`,
+    /// detokenized again as context for the tokens after it.
+    prefix_offset: usize,
+    read_offset: usize,
+    /// No stop string starts in `text` before this offset.
+    scanned: usize,
+    prompt_tokens: usize,
+    n_ctx: Option<usize>,
+    context: ContextPolicy,
+    shifted: usize,
+    stop: Vec<u32>,
+    stop_text: Vec<String>,
+    longest_stop: usize,
+    sampling: Sampling,
+    rng: StdRng,
+    logprobs: Option<usize>,
+    pending: Vec<TokenLogprob>,
+}
+
+impl Decoder {
+    pub fn new(backend: &dyn InferenceBackend, prompt: &str, max_tokens: usize, cancel: &CancelToken) -> Result<Self> {
+        Ok(Self::from_tokens(backend.tokenize(prompt)?, max_tokens, cancel))
+    }
+
+    pub fn from_tokens(tokens: Vec<u32>, max_tokens: usize, cancel: &CancelToken) -> Self {
+        let prompt_len = tokens.len();
+        let id = next_seq_id();
+        let seq = SequenceState { id, tokens, prompt_len, max_new_tokens: max_tokens, cancel: cancel.clone(), ..Default::default() };
+        Self {
+            seq, text: String::new(), emitted: 0, output: Vec::new(), prefix_offset: 0, read_offset: 0, scanned: 0, prompt_tokens: prompt_len, n_ctx: None, context: ContextPolicy::default(), shifted: 0,
+            stop: Vec::new(), stop_text: Vec::new(), longest_stop: 0, sampling: Sampling::default(), rng: Sampling::default().rng(), logprobs: None, pending: Vec::new(),
+        }
+    }
+
+    /// Bound the sequence by an `n_ctx` window, handled as `context` says.
+    pub fn with_context(mut self, n_ctx: Option<usize>, context: ContextPolicy) -> Self {
+        self.n_ctx = n_ctx;
+        self.context = context;
+        self
+    }
+
+    /// End the sequence when one of `stop` (the model's EOS/EOT) comes up.
+    pub fn with_stop_tokens(mut self, stop: &[u32]) -> Self {
+        self.stop = stop.to_vec();
+        self
+    }
+
+    /// Sample and stop as the request asked.
+    pub fn with_options(mut self, options: DecodeOptions) -> Self {
+        self.rng = options.sampling.rng();
+        self.sampling = options.sampling;
+        self.stop_text = options.stop.into_iter().filter(|s| !s.is_empty()).collect();
+        self.longest_stop = self.stop_text.iter().map(String::len).max().unwrap_or(0);
+        self.logprobs = options.logprobs;
+        self
+    }
+
+    /// Log-probabilities of the tokens generated since the last call, when asked for.
+    pub fn take_logprobs(&mut self) -> Vec<TokenLogprob> { std::mem::take(&mut self.pending) }
+
+    pub fn generated(&self) -> usize { self.output.len() }
+
+    /// Tokens dropped from the sequence by context shifts so far.
+    pub fn shifted(&self) -> usize { self.shifted }
+
+    /// Make room before the next step once the sequence has outgrown its window.
+    /// Only a `Shift` policy gets here (admission caps the others), and it drops
+    /// tokens after the kept prefix both here and in the backend's KV.
+    pub fn fit_context(&mut self, backend: &dyn InferenceBackend) -> Result<()> {
+        let Some(n_ctx) = self.n_ctx else { return Ok(()) };
+        let len = self.seq.tokens.len();
+        if len <= n_ctx { return Ok(()); }
+        if self.context.overflow != ContextOverflow::Shift {
+            return Err(RunnerError::ContextLengthExceeded { prompt: self.prompt_tokens, max_tokens: self.seq.max_new_tokens, n_ctx });
+        }
+        let (keep, discard) = self.context.shift(len, n_ctx);
+        backend.shift_context(self.seq.id, keep, discard)?;
+        self.seq.tokens.drain(keep..keep + discard);
+        self.seq.prompt_len -= self.seq.prompt_len.saturating_sub(keep).min(discard);
+        let n_past = self.seq.n_past;
+        self.seq.n_past = if n_past >= keep + discard { n_past - discard } else { n_past.min(keep) };
+        self.shifted += discard;
+        Ok(())
+    }
+
+    /// True once `max_tokens` have been produced.
+    pub fn is_exhausted(&self) -> bool { self.generated() >= self.seq.max_new_tokens }
+
+    pub fn usage(&self) -> Usage { Usage { prompt_tokens: self.prompt_tokens, completion_tokens: self.generated() } }
+
+    /// Apply one forward result. Returns `Some(Stop)` at end-of-sequence.
+    pub fn advance<F: FnMut(&str)>(&mut self, backend: &dyn InferenceBackend, out: ForwardOutput, on_delta: &mut F) -> Result<Option<FinishReason>> {
+        if let Some(e) = out.error { return Err(RunnerError::Message(e)); }
+        let next = match (out.token, &out.logits) {
+            (Some(t), _) => t,
+            (None, Some(logits)) => self.sampling.sample(logits, &mut self.rng) as u32,
+            (None, None) => return Ok(Some(FinishReason::Stop)),
+        };
+        if self.stop.contains(&next) { return Ok(Some(FinishReason::Stop)); }
+        if let Some(k) = self.logprobs {
+            let (logprob, top) = out.logits.as_deref().map_or((None, Vec::new()), |l| logprobs(l, next, k));
+            self.pending.push(TokenLogprob { token: next, logprob, top });
+        }
+        self.seq.tokens.push(next);
+        self.output.push(next);
+        // multi-token characters are only added to the text once complete
+        if !self.read(backend, false)? { return Ok(None); }
+        if self.stop() { self.emit(self.text.len(), on_delta); return Ok(Some(FinishReason::Stop)); }
+        // hold back a tail that may turn out to start a stop string
+        let held = self.stop_text.iter().filter_map(|s| (1..s.len()).rev().find(|&n| s.is_char_boundary(n) && self.text.ends_with(&s[..n]))).max().unwrap_or(0);
+        self.emit(self.text.len() - held, on_delta);
+        Ok(None)
+    }
+
+    /// Flush any held-back text and build the final completion.
+    pub fn finish<F: FnMut(&str)>(mut self, backend: &dyn InferenceBackend, finish_reason: FinishReason, on_delta: &mut F) -> Result<Completion> {
+        self.read(backend, true)?;
+        self.stop();
+        self.emit(self.text.len(), on_delta);
+        let usage = self.usage();
+        Ok(Completion { text: self.text, finish_reason, usage })
+    }
+
+    /// Add the text of the tokens after `read_offset` to `text`. Only they are
+    /// detokenized, after the previous chunk's tokens, whose text is cut off
+    /// again, so a tokenizer that spells a token differently at the start of a
+    /// string still joins up. Unless `lossy`, waits while they end in an
+    /// incomplete character; returns whether the tokens were read.
+    fn read(&mut self, backend: &dyn InferenceBackend, lossy: bool) -> Result<bool> {
+        if self.read_offset == self.output.len() { return Ok(false); }
+        let prefix = backend.detokenize(&self.output[self.prefix_offset..self.read_offset])?;
+        let window = backend.detokenize(&self.output[self.prefix_offset..])?;
+        if !lossy && window.ends_with(char::REPLACEMENT_CHARACTER) { return Ok(false); }
+        match window.strip_prefix(prefix.as_str()) {
+            Some(chunk) => self.text.push_str(chunk),
+            None => self.text.push_str(&backend.detokenize(&self.output[self.read_offset..])?),
+        }
+        (self.prefix_offset, self.read_offset) = (self.read_offset, self.output.len());
+        Ok(true)
+    }
+
+    /// Cut `text` at the first stop string in it, scanning only what was added
+    /// since the last call; returns whether one was found.
+    fn stop(&mut self) -> bool {
+        let from = self.scanned;
+        let at = self.stop_text.iter().filter_map(|s| self.text[from..].find(s.as_str())).min();
+        if let Some(at) = at {
+            self.text.truncate(from + at);
+            return true;
+        }
+        // a stop string starting earlier would have ended within the text scanned so far
+        let mut scanned = self.text.len().saturating_sub(self.longest_stop.saturating_sub(1)).max(from);
+        while !self.text.is_char_boundary(scanned) { scanned -= 1; }
+        self.scanned = scanned;
+        false
+    }
+
+    /// Hand out `text` up to `end`.
+    fn emit<F: FnMut(&str)>(&mut self, end: usize, on_delta: &mut F) {
+        if end > self.emitted {
+            on_delta(&self.text[self.emitted..end]);
+            self.emitted = end;
+        }
+    }
+}
 
 pub fn generate_once(
     backend: &dyn InferenceBackend,
     prompt: &str,
     max_tokens: usize,
 ) -> Result<String> {
-    let _ = max_tokens; // TODO: use with real step loop
-    let tokens = backend.tokenize(prompt).unwrap_or_default();
-    let _ = sample_top_k_top_p::<rand::rngs::StdRng>(&[0.0_f32; 1], 0, 1.0, 1.0, None);
-    let text = backend.detokenize(&tokens).unwrap_or_else(|_| prompt.to_string());
-    Ok(text)
+    generate(backend, prompt, max_tokens, &CancelToken::new(), |_| {}).map(|c| c.text)
 }
 
+/// Single-sequence step loop: one `forward` per new token. `cancel` is checked
+/// before every step, so an abandoned request stops within one step.
+/// `on_delta` receives text as soon as it decodes to complete UTF-8.
+pub fn generate<F: FnMut(&str)>(
+    backend: &dyn InferenceBackend,
+    prompt: &str,
+    max_tokens: usize,
+    cancel: &CancelToken,
+    mut on_delta: F,
+) -> Result<Completion> {
+    let stop = backend.model().map(|m| m.special_tokens.stop()).unwrap_or_default();
+    let mut dec = Decoder::new(backend, prompt, max_tokens, cancel)?.with_stop_tokens(&stop);
+    let finish_reason = loop {
+        if dec.is_exhausted() { break FinishReason::Length; }
+        if cancel.is_cancelled() { return Err(RunnerError::Cancelled); }
+        let out = backend.forward(std::slice::from_mut(&mut dec.seq))?.pop().unwrap_or_default();
+        if let Some(reason) = dec.advance(backend, out, &mut on_delta)? { break reason; }
+    };
+    dec.finish(backend, finish_reason, &mut on_delta)
+}
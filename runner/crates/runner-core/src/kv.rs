@@ -1,63 +1,393 @@
 use std::collections::HashMap;
-use std::hash::{Hash, Hasher};
-use std::sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}};
+use std::sync::{Arc, Mutex, OnceLock, atomic::{AtomicU64, Ordering}};
+use runner_backend::{InferenceBackend, KvLayout};
+use runner_common::{Result, RunnerError};
+use crate::metrics;
+use crate::spill::{SpillKey, TieredKvStore};
 
 pub struct NaiveKvCache { pub capacity_bytes: usize }
 impl NaiveKvCache { pub fn new(capacity_bytes: usize) -> Self { Self { capacity_bytes } } }
 
+pub type BlockId = u32;
+
+/// Paged KV pool: fixed-size physical blocks handed out by id and reference
+/// counted, so forked sequences (n>1 sampling, beam search, shared prefixes)
+/// can point at the same blocks.
 pub struct PagedKvManager {
     capacity_blocks: usize,
-    used_blocks: AtomicUsize,
-    enable_spill: bool,
-    free_list: Mutex<Vec<usize>>, // simplistic free list for defrag demo
+    block_bytes: usize,
+    spill: OnceLock<Spill>,
+    pool: Mutex<Pool>,
 }
 
+/// Where cold blocks go and how their contents are read out of and back into the backend.
+struct Spill { store: Arc<TieredKvStore>, io: Arc<dyn InferenceBackend> }
+
+struct Pool { free: Vec<BlockId>, refcounts: Vec<u32> }
+
+/// A partially filled block that was shared when its owner appended to it; the
+/// backend must copy `src`'s contents into `dst` before writing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockCopy { pub src: BlockId, pub dst: BlockId }
+
 impl PagedKvManager {
     pub const TOKENS_PER_BLOCK: usize = 32;
-    pub fn new(capacity_bytes: usize) -> Arc<Self> {
-        let capacity_blocks = capacity_bytes / 4096;
-        Arc::new(Self { capacity_blocks, used_blocks: AtomicUsize::new(0), enable_spill: false, free_list: Mutex::new(Vec::new()) })
+    /// Block size used when the model's KV layout is unknown (e.g. the mock backend).
+    pub const DEFAULT_BLOCK_BYTES: usize = 4096;
+
+    pub fn new(capacity_bytes: usize) -> Arc<Self> { Self::with_block_bytes(capacity_bytes, Self::DEFAULT_BLOCK_BYTES) }
+
+    /// Pool sized for a model's real KV footprint: each block holds
+    /// `TOKENS_PER_BLOCK` tokens of K and V for every layer.
+    pub fn for_layout(capacity_bytes: usize, layout: &KvLayout) -> Arc<Self> {
+        Self::with_block_bytes(capacity_bytes, layout.bytes_per_token() * Self::TOKENS_PER_BLOCK)
+    }
+
+    pub fn with_block_bytes(capacity_bytes: usize, block_bytes: usize) -> Arc<Self> {
+        let block_bytes = block_bytes.max(1);
+        let capacity_blocks = capacity_bytes / block_bytes;
+        // popped from the back, so the lowest ids are handed out first
+        let free = (0..capacity_blocks as BlockId).rev().collect();
+        let pool = Pool { free, refcounts: vec![0; capacity_blocks] };
+        Arc::new(Self { capacity_blocks, block_bytes, spill: OnceLock::new(), pool: Mutex::new(pool) })
     }
     pub fn tokens_to_blocks(&self, tokens: usize) -> usize {
-        (tokens + Self::TOKENS_PER_BLOCK - 1) / Self::TOKENS_PER_BLOCK
+        tokens.div_ceil(Self::TOKENS_PER_BLOCK)
     }
+    /// Allocate `blocks` physical blocks up front for a new sequence.
     pub fn try_reserve(self: &Arc<Self>, blocks: usize) -> Option<Reservation> {
-        loop {
-            let used = self.used_blocks.load(Ordering::Relaxed);
-            if used + blocks > self.capacity_blocks { return None; }
-            if self.used_blocks.compare_exchange(used, used + blocks, Ordering::SeqCst, Ordering::Relaxed).is_ok() {
-                return Some(Reservation { manager: self.clone(), blocks });
+        let ids = self.alloc(blocks)?;
+        Some(BlockTable { manager: self.clone(), blocks: ids, num_tokens: 0 })
+    }
+    pub fn used_blocks(&self) -> usize { self.capacity_blocks - self.free_blocks() }
+    pub fn free_blocks(&self) -> usize { self.pool.lock().unwrap().free.len() }
+    pub fn capacity_blocks(&self) -> usize { self.capacity_blocks }
+    pub fn block_bytes(&self) -> usize { self.block_bytes }
+    pub fn used_bytes(&self) -> usize { self.used_blocks() * self.block_bytes }
+    pub fn capacity_bytes(&self) -> usize { self.capacity_blocks * self.block_bytes }
+    pub fn ref_count(&self, id: BlockId) -> u32 { self.pool.lock().unwrap().refcounts[id as usize] }
+    fn alloc(&self, n: usize) -> Option<Vec<BlockId>> {
+        let mut pool = self.pool.lock().unwrap();
+        if pool.free.len() < n { return None; }
+        let at = pool.free.len() - n;
+        let ids: Vec<BlockId> = pool.free.drain(at..).rev().collect();
+        for &id in &ids { pool.refcounts[id as usize] = 1; }
+        Some(ids)
+    }
+    pub(crate) fn retain(&self, ids: &[BlockId]) {
+        let mut pool = self.pool.lock().unwrap();
+        for &id in ids { pool.refcounts[id as usize] += 1; }
+    }
+    pub(crate) fn release(&self, ids: &[BlockId]) {
+        let mut pool = self.pool.lock().unwrap();
+        for &id in ids {
+            let rc = &mut pool.refcounts[id as usize];
+            *rc -= 1;
+            if *rc == 0 { pool.free.push(id); }
+        }
+    }
+    /// Reorder the free list so new allocations come from the lowest ids, keeping
+    /// live blocks packed toward the front of the pool.
+    pub fn defragment(&self) {
+        let mut pool = self.pool.lock().unwrap();
+        pool.free.sort_unstable_by(|a, b| b.cmp(a));
+    }
+    /// Let cold blocks leave the pool for `store`, moving their contents through
+    /// `io`. Only the first call takes effect.
+    pub fn enable_spill(&self, store: Arc<TieredKvStore>, io: Arc<dyn InferenceBackend>) {
+        let _ = self.spill.set(Spill { store, io });
+    }
+    pub fn spill_store(&self) -> Option<&Arc<TieredKvStore>> { self.spill.get().map(|s| &s.store) }
+    /// Copy `block` out to the spill tiers; the caller still releases it.
+    pub(crate) fn spill_block(&self, block: BlockId) -> Option<SpillKey> {
+        let spill = self.spill.get()?;
+        spill.store.put(spill.io.read_kv_block(block)?).ok()
+    }
+    /// Allocate a block and fill it from a spilled entry, which is consumed.
+    /// `None` if the pool is full (the entry stays put) or the entry is gone.
+    pub(crate) fn page_in(&self, key: SpillKey) -> Option<BlockId> {
+        let spill = self.spill.get()?;
+        let id = self.alloc(1)?[0];
+        match spill.store.take(key).map(|data| spill.io.write_kv_block(id, &data)) {
+            Some(Ok(())) => Some(id),
+            _ => { self.release(&[id]); None }
+        }
+    }
+    pub(crate) fn discard_spilled(&self, key: SpillKey) {
+        if let Some(store) = self.spill_store() { store.remove(key); }
+    }
+}
+
+/// A sequence's logical-to-physical block mapping. Blocks may be allocated ahead
+/// of the tokens written into them; dropping the table releases every block.
+pub struct BlockTable { manager: Arc<PagedKvManager>, blocks: Vec<BlockId>, num_tokens: usize }
+
+/// Admission-time name for a sequence's block table.
+pub type Reservation = BlockTable;
+
+impl BlockTable {
+    pub fn new(manager: &Arc<PagedKvManager>) -> Self { Self { manager: manager.clone(), blocks: Vec::new(), num_tokens: 0 } }
+    pub fn blocks(&self) -> &[BlockId] { &self.blocks }
+    pub fn num_tokens(&self) -> usize { self.num_tokens }
+    /// Blocks that hold at least one token.
+    pub fn filled_blocks(&self) -> &[BlockId] { &self.blocks[..self.manager.tokens_to_blocks(self.num_tokens)] }
+
+    /// Make room for `n` more tokens, growing the table as needed. If the last
+    /// partial block is shared with a fork it is copied first (copy-on-write).
+    pub fn append_tokens(&mut self, n: usize) -> Result<Vec<BlockCopy>> {
+        let mut copies = Vec::new();
+        let used = self.manager.tokens_to_blocks(self.num_tokens);
+        if n > 0 && !self.num_tokens.is_multiple_of(PagedKvManager::TOKENS_PER_BLOCK) {
+            let last = self.blocks[used - 1];
+            if self.manager.ref_count(last) > 1 {
+                let dst = self.manager.alloc(1).ok_or(RunnerError::KvExhausted)?[0];
+                self.manager.release(&[last]);
+                self.blocks[used - 1] = dst;
+                copies.push(BlockCopy { src: last, dst });
             }
         }
+        let need = self.manager.tokens_to_blocks(self.num_tokens + n);
+        if need > self.blocks.len() {
+            let more = self.manager.alloc(need - self.blocks.len()).ok_or(RunnerError::KvExhausted)?;
+            self.blocks.extend(more);
+        }
+        self.num_tokens += n;
+        Ok(copies)
     }
-    pub fn used_blocks(&self) -> usize { self.used_blocks.load(Ordering::Relaxed) }
-    pub fn capacity_blocks(&self) -> usize { self.capacity_blocks }
-    fn release(&self, blocks: usize) {
-        self.used_blocks.fetch_sub(blocks, Ordering::SeqCst);
-        let mut fl = self.free_list.lock().unwrap();
-        fl.push(blocks);
+
+    /// Seed this still-empty table with full blocks that already hold a cached
+    /// prefix, handing back as many of its pre-reserved blocks to the pool.
+    pub fn adopt_prefix(&mut self, shared: &[BlockId]) {
+        debug_assert_eq!(self.num_tokens, 0);
+        self.manager.retain(shared);
+        let give_back: Vec<BlockId> = self.blocks.drain(..shared.len().min(self.blocks.len())).collect();
+        self.manager.release(&give_back);
+        self.blocks.splice(0..0, shared.iter().copied());
+        self.num_tokens = shared.len() * PagedKvManager::TOKENS_PER_BLOCK;
+    }
+
+    pub fn manager(&self) -> &Arc<PagedKvManager> { &self.manager }
+
+    /// Append `other`'s still-empty blocks to this table, e.g. a fresh reservation
+    /// for the next turn of a parked sequence. Both must come from the same pool.
+    pub fn absorb(&mut self, mut other: BlockTable) {
+        assert!(Arc::ptr_eq(&self.manager, &other.manager) && other.num_tokens == 0, "absorb needs an empty table from the same pool");
+        self.blocks.append(&mut other.blocks);
+    }
+
+    /// Hand back blocks reserved past the last filled one.
+    pub fn trim(&mut self) {
+        let keep = self.manager.tokens_to_blocks(self.num_tokens);
+        if self.blocks.len() > keep {
+            let extra: Vec<BlockId> = self.blocks.drain(keep..).collect();
+            self.manager.release(&extra);
+        }
+    }
+
+    /// New table sharing every filled block with this one. Whichever side next
+    /// appends into the shared partial block gets its own copy.
+    pub fn fork(&self) -> BlockTable {
+        let shared = self.filled_blocks().to_vec();
+        self.manager.retain(&shared);
+        BlockTable { manager: self.manager.clone(), blocks: shared, num_tokens: self.num_tokens }
     }
-    pub fn defragment(&self) { let mut fl = self.free_list.lock().unwrap(); fl.clear(); }
-    pub fn enable_spill_to_host(&mut self, enable: bool) { self.enable_spill = enable; }
 }
 
-pub struct Reservation { pub(crate) manager: Arc<PagedKvManager>, pub(crate) blocks: usize }
-impl Drop for Reservation { fn drop(&mut self) { self.manager.release(self.blocks) } }
+impl Drop for BlockTable { fn drop(&mut self) { self.manager.release(&self.blocks) } }
+
+/// Blocks already holding the KV for the first `tokens` tokens of a prompt.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PrefixMatch { pub blocks: Vec<BlockId>, pub tokens: usize }
+
+/// Radix tree over token ids whose edges are one KV block's worth of tokens.
+/// Every node pins its block in the `PagedKvManager`, so a request with a
+/// matching prefix adopts those blocks and skips their prefill. Leaves no
+/// running sequence still references are evicted least-recently-used first;
+/// with spill tiers enabled they move there and are paged back in on a hit.
+pub struct PrefixCache {
+    kv: Arc<PagedKvManager>,
+    tree: Mutex<RadixTree>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    saved_tokens: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Residency { Pool(BlockId), Spilled(SpillKey) }
+
+struct RadixNode {
+    chunk: Vec<u32>,
+    at: Residency,
+    parent: usize,
+    children: HashMap<Vec<u32>, usize>,
+    last_used: u64,
+}
+
+struct RadixTree {
+    /// Slot 0 is the root; its `chunk` and `at` are unused.
+    nodes: Vec<Option<RadixNode>>,
+    free_slots: Vec<usize>,
+    clock: u64,
+}
+
+impl RadixTree {
+    const ROOT: usize = 0;
+    fn node(&self, i: usize) -> &RadixNode { self.nodes[i].as_ref().expect("live node") }
+    fn node_mut(&mut self, i: usize) -> &mut RadixNode { self.nodes[i].as_mut().expect("live node") }
+    fn live(&self) -> impl Iterator<Item = (usize, &RadixNode)> {
+        self.nodes.iter().enumerate().skip(1).filter_map(|(i, n)| n.as_ref().map(|n| (i, n)))
+    }
+
+    /// Unlink `i` and everything under it, returning where their KV lived.
+    fn remove_subtree(&mut self, i: usize) -> Vec<Residency> {
+        let node = self.nodes[i].take().expect("live node");
+        self.node_mut(node.parent).children.remove(&node.chunk);
+        let mut gone = vec![node.at];
+        let mut stack: Vec<usize> = node.children.into_values().collect();
+        while let Some(j) = stack.pop() {
+            let n = self.nodes[j].take().expect("live node");
+            gone.push(n.at);
+            stack.extend(n.children.into_values());
+            self.free_slots.push(j);
+        }
+        self.free_slots.push(i);
+        gone
+    }
+}
 
-#[derive(Default)]
-pub struct PrefixCache { counts: Mutex<HashMap<u64, usize>>, tokens: Mutex<HashMap<u64, Vec<u32>>> }
 impl PrefixCache {
-    pub fn new() -> Arc<Self> { Arc::new(Self::default()) }
-    pub fn hash_prefix(&self, text: &str) -> u64 {
-        use std::collections::hash_map::DefaultHasher;
-        let mut hasher = DefaultHasher::new();
-        let slice = if text.len() > 256 { &text[..256] } else { text };
-        slice.hash(&mut hasher);
-        hasher.finish()
+    pub fn new(kv: &Arc<PagedKvManager>) -> Arc<Self> {
+        let root = RadixNode { chunk: Vec::new(), at: Residency::Pool(0), parent: RadixTree::ROOT, children: HashMap::new(), last_used: 0 };
+        let tree = RadixTree { nodes: vec![Some(root)], free_slots: Vec::new(), clock: 0 };
+        Arc::new(Self { kv: kv.clone(), tree: Mutex::new(tree), hits: AtomicU64::new(0), misses: AtomicU64::new(0), saved_tokens: AtomicU64::new(0) })
     }
-    pub fn note(&self, h: u64) { let mut g = self.counts.lock().unwrap(); *g.entry(h).or_insert(0) += 1; }
-    pub fn is_common(&self, h: u64) -> bool { let g = self.counts.lock().unwrap(); g.get(&h).copied().unwrap_or(0) >= 2 }
-    pub fn put_tokens(&self, h: u64, toks: Vec<u32>) { let mut t = self.tokens.lock().unwrap(); t.insert(h, toks); }
-    pub fn get_tokens(&self, h: u64) -> Option<Vec<u32>> { let t = self.tokens.lock().unwrap(); t.get(&h).cloned() }
+
+    /// Longest cached block-aligned prefix of `tokens`, always leaving at least
+    /// one token to prefill so the backend produces fresh logits. Spilled blocks
+    /// on the path are paged back in; the match stops at one that cannot be.
+    pub fn lookup(&self, tokens: &[u32]) -> PrefixMatch {
+        let t = PagedKvManager::TOKENS_PER_BLOCK;
+        let max_blocks = tokens.len().saturating_sub(1) / t;
+        let mut tree = self.tree.lock().unwrap();
+        tree.clock += 1;
+        let now = tree.clock;
+        let mut at = RadixTree::ROOT;
+        let mut blocks = Vec::new();
+        for chunk in tokens.chunks_exact(t).take(max_blocks) {
+            let Some(&child) = tree.node(at).children.get(chunk) else { break };
+            let block = match tree.node(child).at {
+                Residency::Pool(block) => block,
+                Residency::Spilled(key) => match self.kv.page_in(key) {
+                    Some(block) => block,
+                    None if self.kv.spill_store().is_some_and(|s| s.tier_of(key).is_some()) => break,
+                    None => {
+                        // the spilled copy is gone; nothing under it is reachable any more
+                        self.forget(tree.remove_subtree(child));
+                        break;
+                    }
+                },
+            };
+            let node = tree.node_mut(child);
+            node.at = Residency::Pool(block);
+            node.last_used = now;
+            blocks.push(block);
+            at = child;
+        }
+        let tokens = blocks.len() * t;
+        PrefixMatch { blocks, tokens }
+    }
+
+    /// Count an admitted request's lookup towards the hit/miss/saved metrics.
+    pub fn record(&self, m: &PrefixMatch) {
+        if m.tokens > 0 {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            self.saved_tokens.fetch_add(m.tokens as u64, Ordering::Relaxed);
+            metrics::PREFIX_HITS.inc();
+            metrics::PREFIX_SAVED_TOKENS.inc_by(m.tokens as u64);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            metrics::PREFIX_MISSES.inc();
+        }
+    }
+
+    /// Cache the full blocks of a finished sequence; `blocks[i]` must hold
+    /// `tokens[i * TOKENS_PER_BLOCK..]`. Chunks already in the pool are left as
+    /// is; spilled ones take the fresh block instead of being paged in.
+    pub fn insert(&self, tokens: &[u32], blocks: &[BlockId]) {
+        let t = PagedKvManager::TOKENS_PER_BLOCK;
+        let mut tree = self.tree.lock().unwrap();
+        tree.clock += 1;
+        let now = tree.clock;
+        let mut at = RadixTree::ROOT;
+        for (chunk, &block) in tokens.chunks_exact(t).zip(blocks) {
+            if let Some(&child) = tree.node(at).children.get(chunk) {
+                let node = tree.node_mut(child);
+                if let Residency::Spilled(key) = node.at {
+                    self.kv.retain(&[block]);
+                    self.kv.discard_spilled(key);
+                    node.at = Residency::Pool(block);
+                }
+                node.last_used = now;
+                at = child;
+                continue;
+            }
+            self.kv.retain(&[block]);
+            let node = RadixNode { chunk: chunk.to_vec(), at: Residency::Pool(block), parent: at, children: HashMap::new(), last_used: now };
+            let slot = match tree.free_slots.pop() {
+                Some(i) => { tree.nodes[i] = Some(node); i }
+                None => { tree.nodes.push(Some(node)); tree.nodes.len() - 1 }
+            };
+            tree.node_mut(at).children.insert(chunk.to_vec(), slot);
+            at = slot;
+        }
+    }
+
+    /// Free up to `blocks` pool blocks from least-recently-used nodes that only
+    /// the cache still holds and that have nothing in the pool below them. Each
+    /// is spilled when a tier takes it, otherwise dropped with its subtree.
+    /// Returns how many blocks went back to the pool.
+    pub fn evict(&self, blocks: usize) -> usize {
+        let mut tree = self.tree.lock().unwrap();
+        let mut freed = 0;
+        while freed < blocks {
+            let victim = tree.live()
+                .filter_map(|(i, n)| match n.at { Residency::Pool(b) => Some((i, b, n)), Residency::Spilled(_) => None })
+                .filter(|(_, b, n)| self.kv.ref_count(*b) == 1
+                    && n.children.values().all(|&c| matches!(tree.node(c).at, Residency::Spilled(_))))
+                .min_by_key(|(_, _, n)| n.last_used)
+                .map(|(i, b, _)| (i, b));
+            let Some((i, block)) = victim else { break };
+            match self.kv.spill_block(block) {
+                Some(key) => { tree.node_mut(i).at = Residency::Spilled(key); self.kv.release(&[block]); }
+                None => self.forget(tree.remove_subtree(i)),
+            }
+            freed += 1;
+        }
+        freed
+    }
+
+    fn forget(&self, gone: Vec<Residency>) {
+        for at in gone {
+            match at {
+                Residency::Pool(block) => self.kv.release(&[block]),
+                Residency::Spilled(key) => self.kv.discard_spilled(key),
+            }
+        }
+    }
+
+    /// Blocks currently pinned in the pool by the cache.
+    pub fn cached_blocks(&self) -> usize { self.tree.lock().unwrap().live().filter(|(_, n)| matches!(n.at, Residency::Pool(_))).count() }
+    /// Cached blocks living in a spill tier.
+    pub fn spilled_blocks(&self) -> usize { self.tree.lock().unwrap().live().filter(|(_, n)| matches!(n.at, Residency::Spilled(_))).count() }
+    pub fn hits(&self) -> u64 { self.hits.load(Ordering::Relaxed) }
+    pub fn misses(&self) -> u64 { self.misses.load(Ordering::Relaxed) }
+    pub fn saved_tokens(&self) -> u64 { self.saved_tokens.load(Ordering::Relaxed) }
 }
 
+impl Drop for PrefixCache {
+    fn drop(&mut self) {
+        let tree = self.tree.get_mut().unwrap();
+        let gone: Vec<Residency> = tree.nodes.iter().skip(1).flatten().map(|n| n.at).collect();
+        self.forget(gone);
+    }
+}
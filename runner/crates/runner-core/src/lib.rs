@@ -1,11 +1,15 @@
 pub mod decode;
 pub mod scheduler;
 pub mod kv;
+pub mod metrics;
+pub mod spill;
 pub mod sampler;
+pub mod session;
+pub mod sim;
 
+#[derive(Default)]
 pub struct Scheduler;
 
 impl Scheduler {
     pub fn new() -> Self { Self }
 }
-
@@ -0,0 +1,20 @@
+//! Prometheus counters bumped where the events happen, so their totals carry
+//! on across model loads; the API exports them from the default registry.
+
+use once_cell::sync::Lazy;
+use prometheus::{IntCounter, IntCounterVec};
+
+pub static PREFIX_HITS: Lazy<IntCounter> = Lazy::new(|| prometheus::register_int_counter!("runner_prefix_cache_hits_total", "Admitted requests that reused a cached prefix").expect("counter"));
+pub static PREFIX_MISSES: Lazy<IntCounter> = Lazy::new(|| prometheus::register_int_counter!("runner_prefix_cache_misses_total", "Admitted requests with no cached prefix").expect("counter"));
+pub static PREFIX_SAVED_TOKENS: Lazy<IntCounter> = Lazy::new(|| prometheus::register_int_counter!("runner_prefix_cache_saved_tokens_total", "Prompt tokens whose prefill was skipped").expect("counter"));
+pub static KV_TIER_SPILLS: Lazy<IntCounterVec> = Lazy::new(|| prometheus::register_int_counter_vec!("runner_kv_tier_spills_total", "KV entries written into a spill tier", &["tier"]).expect("counter"));
+pub static KV_TIER_RESTORES: Lazy<IntCounterVec> = Lazy::new(|| prometheus::register_int_counter_vec!("runner_kv_tier_restores_total", "KV entries paged back in from a spill tier", &["tier"]).expect("counter"));
+
+/// Register the counters so they are exported before their first event.
+pub fn init() {
+    Lazy::force(&PREFIX_HITS);
+    Lazy::force(&PREFIX_MISSES);
+    Lazy::force(&PREFIX_SAVED_TOKENS);
+    Lazy::force(&KV_TIER_SPILLS);
+    Lazy::force(&KV_TIER_RESTORES);
+}
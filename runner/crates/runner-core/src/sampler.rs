@@ -1,5 +1,43 @@
 use rand::prelude::*;
 
+/// How one request picks its next token. A `temperature` of 0 always takes the
+/// most likely token; `top_k` 0 and `top_p` 1 leave the distribution whole.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sampling {
+    pub temperature: f32,
+    pub top_p: f32,
+    pub top_k: usize,
+    /// Seeds the request's generator, so the same request samples the same tokens.
+    pub seed: Option<u64>,
+}
+
+impl Default for Sampling {
+    fn default() -> Self { Self { temperature: 1.0, top_p: 1.0, top_k: 0, seed: None } }
+}
+
+impl Sampling {
+    pub fn rng(&self) -> StdRng {
+        match self.seed { Some(s) => SeedableRng::seed_from_u64(s), None => StdRng::from_entropy() }
+    }
+
+    pub fn sample<R: Rng + ?Sized>(&self, logits: &[f32], rng: &mut R) -> usize {
+        if self.temperature <= 0.0 {
+            return logits.iter().enumerate().max_by(|a, b| a.1.total_cmp(b.1)).map_or(0, |(i, _)| i);
+        }
+        sample_with(logits, self.top_k, self.top_p, self.temperature, rng)
+    }
+}
+
+/// Log-softmax of `logits` at `token`, and the `k` likeliest tokens with theirs.
+pub fn logprobs(logits: &[f32], token: u32, k: usize) -> (Option<f32>, Vec<(u32, f32)>) {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let lse = max + logits.iter().map(|l| (l - max).exp()).sum::<f32>().ln();
+    let mut top: Vec<(u32, f32)> = logits.iter().enumerate().map(|(i, &l)| (i as u32, l - lse)).collect();
+    top.sort_by(|a, b| b.1.total_cmp(&a.1));
+    top.truncate(k);
+    (logits.get(token as usize).map(|l| l - lse), top)
+}
+
 pub fn sample_top_k_top_p<R: Rng + ?Sized>(
     logits: &[f32],
     top_k: usize,
@@ -7,7 +45,11 @@ pub fn sample_top_k_top_p<R: Rng + ?Sized>(
     temperature: f32,
     seed: Option<u64>,
 ) -> usize {
-    let mut rng: StdRng = match seed { Some(s) => SeedableRng::seed_from_u64(s), None => StdRng::from_entropy() };
+    let mut rng = Sampling { seed, ..Sampling::default() }.rng();
+    sample_with(logits, top_k, top_p, temperature, &mut rng)
+}
+
+fn sample_with<R: Rng + ?Sized>(logits: &[f32], top_k: usize, top_p: f32, temperature: f32, rng: &mut R) -> usize {
     if logits.is_empty() { return 0; }
     let mut pairs: Vec<(usize, f32)> = logits.iter().enumerate().map(|(i, &l)| (i, l / temperature.max(1e-4))).collect();
     pairs.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
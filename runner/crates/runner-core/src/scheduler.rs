@@ -1,72 +1,416 @@
-use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
-use tokio::sync::{mpsc, oneshot};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}};
+use tokio::sync::mpsc;
 use tokio::time::{self, Duration};
-use runner_backend::InferenceBackend;
-use crate::kv::{PagedKvManager, Reservation, PrefixCache};
+use runner_backend::{ContextPolicy, InferenceBackend, SequenceState};
+use runner_common::{cancel::CancelToken, config::RunnerConfig};
+use crate::decode::{DecodeOptions, Decoder, FinishReason, TokenLogprob, Usage};
+use crate::kv::{BlockTable, PagedKvManager, Reservation, PrefixCache};
+
+/// Lifecycle of one request as seen by its submitter.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GenerationEvent {
+    Queued { position: usize },
+    Started,
+    Token(String),
+    /// Sent after each generated token's text when the request asked for logprobs.
+    Logprob(TokenLogprob),
+    Finished { finish_reason: FinishReason, usage: Usage },
+    Error(String),
+}
+
+pub type EventStream = mpsc::UnboundedReceiver<GenerationEvent>;
 
 pub struct Request {
-    pub prompt: String,
-    pub respond: oneshot::Sender<String>,
+    /// Tokenized prompt; `submit` tokenizes up front so admission sees the real length.
+    pub prompt: Vec<u32>,
+    pub events: mpsc::UnboundedSender<GenerationEvent>,
     pub max_tokens: usize,
     pub reservation: Option<Reservation>,
+    pub cancel: CancelToken,
+    /// Sequence to continue instead of starting fresh; `prompt` extends its tokens.
+    pub resume: Option<Parked>,
+    /// Where the finished sequence is parked so a later turn can resume it.
+    pub park: Option<ParkingSlot>,
+    /// How the sequence is kept within the backend's context window.
+    pub context: ContextPolicy,
+    pub options: DecodeOptions,
+}
+
+impl Request {
+    pub fn new(prompt: Vec<u32>, max_tokens: usize, reservation: Option<Reservation>, cancel: CancelToken) -> (Self, EventStream) {
+        let (events, rx) = mpsc::unbounded_channel();
+        (Self { prompt, events, max_tokens, reservation, cancel, resume: None, park: None, context: ContextPolicy::default(), options: DecodeOptions::default() }, rx)
+    }
+}
+
+/// A finished sequence kept between turns: its backend id, every token so far,
+/// how many of them the backend holds KV for, and the blocks backing them.
+pub struct Parked { pub seq_id: u64, pub tokens: Vec<u32>, pub n_past: usize, pub table: Option<BlockTable> }
+
+/// Filled with the `Parked` sequence before the request's `Finished` event is sent.
+pub type ParkingSlot = Arc<Mutex<Option<Parked>>>;
+
+#[derive(Debug, Clone, Copy)]
+pub struct SchedulerConfig {
+    /// How often an idle scheduler polls for new requests.
+    pub tick: Duration,
+    /// Sequences decoded together in one step.
+    pub max_seqs: usize,
+    /// Tokens evaluated per step: one per decoding sequence plus each newly
+    /// admitted prompt. A prompt larger than the budget still runs alone.
+    pub max_batch_tokens: usize,
+    /// Requests allowed to wait for a batch slot before `submit` rejects.
+    pub queue_capacity: usize,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self { Self::from_runner(&RunnerConfig::default()) }
+}
+
+impl SchedulerConfig {
+    /// Values from `RunnerConfig`; unset fields take `RunnerConfig::default()`.
+    pub fn from_runner(cfg: &RunnerConfig) -> Self {
+        let d = RunnerConfig::default();
+        let pick = |v: Option<usize>, dv: Option<usize>| v.or(dv).unwrap_or(1).max(1);
+        Self {
+            tick: Duration::from_millis(cfg.scheduler_tick_ms.or(d.scheduler_tick_ms).unwrap_or(2).max(1)),
+            max_seqs: pick(cfg.max_concurrent_seqs, d.max_concurrent_seqs),
+            max_batch_tokens: pick(cfg.max_batch_tokens, d.max_batch_tokens),
+            queue_capacity: pick(cfg.queue_capacity, d.queue_capacity),
+        }
+    }
+}
+
+struct Running { req: Request, dec: Decoder }
+
+impl Running {
+    /// Grow the sequence's block table to cover `len` tokens, evicting cached
+    /// prefixes once if the pool is full. Tables only ever share full blocks, so
+    /// there are no copy-on-write copies to hand to the backend. A shifted
+    /// sequence reuses the positions it freed and does not grow.
+    fn grow_to(&mut self, len: usize, prefix: &PrefixCache) -> runner_common::Result<()> {
+        let Some(table) = self.req.reservation.as_mut() else { return Ok(()) };
+        let n = len.saturating_sub(table.num_tokens());
+        if n == 0 { return Ok(()); }
+        if table.append_tokens(n).is_err() {
+            prefix.evict(n.div_ceil(PagedKvManager::TOKENS_PER_BLOCK) + 1);
+            table.append_tokens(n)?;
+        }
+        Ok(())
+    }
+}
+
+/// Clock-free continuous-batching engine. Each `step` admits waiting requests into
+/// free batch slots and advances every running sequence by one token. The tokio
+/// driver in `SchedulerV1::start` and the `sim` harness both drive it.
+pub struct SchedulerCore {
+    backend: Arc<dyn InferenceBackend>,
+    prefix: Arc<PrefixCache>,
+    cfg: SchedulerConfig,
+    waiting: VecDeque<Request>,
+    running: Vec<Running>,
+    /// The loaded model's end-of-sequence tokens.
+    stop: Vec<u32>,
+    /// The backend can skip the prefill of a cached prefix; see `InferenceBackend::paged_kv`.
+    reuse_prefix: bool,
+}
+
+impl SchedulerCore {
+    pub fn new(backend: Arc<dyn InferenceBackend>, prefix: Arc<PrefixCache>, mut cfg: SchedulerConfig) -> Self {
+        // more running sequences than the backend has slots for would evict each other's KV every step
+        if let Some(n) = backend.max_sequences() { cfg.max_seqs = cfg.max_seqs.min(n).max(1); }
+        let stop = backend.model().map(|m| m.special_tokens.stop()).unwrap_or_default();
+        let reuse_prefix = backend.paged_kv();
+        Self { backend, prefix, cfg, waiting: VecDeque::new(), running: Vec::new(), stop, reuse_prefix }
+    }
+
+    pub fn push(&mut self, req: Request) { self.waiting.push_back(req); }
+    pub fn waiting(&self) -> usize { self.waiting.len() }
+    pub fn running(&self) -> usize { self.running.len() }
+    pub fn is_idle(&self) -> bool { self.waiting.is_empty() && self.running.is_empty() }
+
+    /// Run one scheduling step; returns the number of sequences advanced.
+    pub fn step(&mut self) -> usize {
+        // client gone: dropping the request frees its blocks, and the backend its KV, within this step
+        let backend = self.backend.clone();
+        self.waiting.retain(|r| !r.cancel.is_cancelled());
+        self.running.retain(|r| {
+            let cancelled = r.req.cancel.is_cancelled();
+            if cancelled { backend.release_sequence(r.dec.seq.id); }
+            !cancelled
+        });
+        self.admit();
+
+        let mut batch: Vec<SequenceState> = self.running.iter_mut().map(|r| std::mem::take(&mut r.dec.seq)).collect();
+        if batch.is_empty() { return 0; }
+        let outs = backend.forward(&mut batch);
+        for (r, seq) in self.running.iter_mut().zip(batch) { r.dec.seq = seq; }
+        let outs = match outs {
+            Ok(outs) => outs,
+            Err(e) => {
+                for r in self.running.drain(..) {
+                    backend.release_sequence(r.dec.seq.id);
+                    let _ = r.req.events.send(GenerationEvent::Error(e.to_string()));
+                }
+                return 0;
+            }
+        };
+
+        let advanced = self.running.len();
+        let mut outs = outs.into_iter();
+        let mut still_running = Vec::with_capacity(advanced);
+        for mut r in self.running.drain(..) {
+            let out = outs.next().unwrap_or_default();
+            let events = r.req.events.clone();
+            let mut on_delta = |delta: &str| { let _ = events.send(GenerationEvent::Token(delta.to_string())); };
+            let outcome = match r.dec.advance(backend.as_ref(), out, &mut on_delta) {
+                // a token was appended: shift if it overflowed the window, then account for it
+                Ok(None) => r.dec.fit_context(backend.as_ref()).and_then(|_| r.grow_to(r.dec.seq.tokens.len(), &self.prefix)).map(|_| None),
+                other => other,
+            };
+            for lp in r.dec.take_logprobs() { let _ = events.send(GenerationEvent::Logprob(lp)); }
+            let reason = match outcome {
+                Ok(Some(reason)) => reason,
+                Ok(None) if r.dec.is_exhausted() => FinishReason::Length,
+                Ok(None) => { still_running.push(r); continue; }
+                Err(e) => {
+                    backend.release_sequence(r.dec.seq.id);
+                    let _ = r.req.events.send(GenerationEvent::Error(e.to_string()));
+                    continue;
+                }
+            };
+            Self::retire(backend.as_ref(), &self.prefix, r, reason);
+        }
+        self.running = still_running;
+        advanced
+    }
+
+    fn admit(&mut self) {
+        // each running sequence decodes one token this step
+        let mut budget = self.cfg.max_batch_tokens.saturating_sub(self.running.len());
+        while self.running.len() < self.cfg.max_seqs {
+            let Some(mut req) = self.waiting.pop_front() else { break };
+            let resume = req.resume.take();
+            // only fresh prompts that reserved KV can adopt cached blocks, and
+            // only on a backend that reads its KV from them
+            let cached = match (&resume, &req.reservation) {
+                (None, Some(_)) if self.reuse_prefix => self.prefix.lookup(&req.prompt),
+                _ => Default::default(),
+            };
+            let skip = resume.as_ref().map_or(cached.tokens, |p| p.n_past);
+            let prefill = (req.prompt.len() - skip).max(1);
+            if prefill > budget && !self.running.is_empty() {
+                // wait for a lighter step
+                req.resume = resume;
+                self.waiting.push_front(req);
+                break;
+            }
+            budget = budget.saturating_sub(prefill);
+            let mut dec = Decoder::from_tokens(std::mem::take(&mut req.prompt), req.max_tokens, &req.cancel)
+                .with_context(self.backend.context_size(), req.context)
+                .with_stop_tokens(&self.stop)
+                .with_options(std::mem::take(&mut req.options));
+            dec.seq.n_past = skip;
+            match resume {
+                Some(p) => {
+                    debug_assert!(dec.seq.tokens.starts_with(&p.tokens));
+                    dec.seq.id = p.seq_id;
+                    req.reservation = match (p.table, req.reservation.take()) {
+                        (Some(mut table), Some(extra)) if Arc::ptr_eq(table.manager(), extra.manager()) => { table.absorb(extra); Some(table) }
+                        (Some(table), None) => Some(table),
+                        // parked before a model swap: its blocks belong to the old pool
+                        (_, extra) => extra,
+                    };
+                }
+                None => {
+                    if self.reuse_prefix { self.prefix.record(&cached); }
+                    if let Some(table) = req.reservation.as_mut() { table.adopt_prefix(&cached.blocks); }
+                }
+            }
+            let mut r = Running { req, dec };
+            if let Err(e) = r.grow_to(r.dec.seq.prompt_len, &self.prefix) {
+                let _ = r.req.events.send(GenerationEvent::Error(e.to_string()));
+                continue;
+            }
+            let _ = r.req.events.send(GenerationEvent::Started);
+            if r.dec.is_exhausted() { Self::retire(self.backend.as_ref(), &self.prefix, r, FinishReason::Length) } else { self.running.push(r) }
+        }
+    }
+
+    fn retire(backend: &dyn InferenceBackend, prefix: &PrefixCache, r: Running, reason: FinishReason) {
+        let Running { mut req, dec } = r;
+        let seq_id = dec.seq.id;
+        // blocks up to `n_past` hold computed KV and stay useful to later prompts,
+        // unless a shift re-positioned it (a fresh prefill would not match) or
+        // the backend keeps its KV somewhere else
+        if let Some(table) = req.reservation.as_ref().filter(|_| dec.shifted() == 0 && backend.paged_kv()) { prefix.insert(&dec.seq.tokens[..dec.seq.n_past], table.blocks()); }
+        if let Some(slot) = &req.park {
+            let mut table = req.reservation.take();
+            if let Some(t) = table.as_mut() { t.trim(); }
+            *slot.lock().unwrap() = Some(Parked { seq_id: dec.seq.id, tokens: dec.seq.tokens.clone(), n_past: dec.seq.n_past, table });
+        }
+        let events = req.events.clone();
+        let mut on_delta = |delta: &str| { let _ = events.send(GenerationEvent::Token(delta.to_string())); };
+        let event = match dec.finish(backend, reason, &mut on_delta) {
+            Ok(c) => GenerationEvent::Finished { finish_reason: c.finish_reason, usage: c.usage },
+            Err(e) => GenerationEvent::Error(e.to_string()),
+        };
+        // a parked sequence keeps its backend KV for the next turn
+        if req.park.is_none() { backend.release_sequence(seq_id); }
+        drop(req.reservation);
+        let _ = req.events.send(event);
+    }
 }
 
 #[derive(Clone)]
 pub struct Handle {
     pub(crate) tx: mpsc::Sender<Request>,
-    pub queue_depth: Arc<AtomicUsize>,
+    /// Requests the scheduler held waiting for a batch slot at its last tick.
+    waiting: Arc<AtomicUsize>,
     pub last_batch_size: Arc<AtomicUsize>,
     pub kv: Arc<PagedKvManager>,
     pub prefix: Arc<PrefixCache>,
+    pub backend: Arc<dyn InferenceBackend>,
+}
+
+impl Handle {
+    /// Requests waiting for a batch slot: those the scheduler holds plus those
+    /// submitted since it last took new ones.
+    pub fn queue_depth(&self) -> usize {
+        self.waiting.load(Ordering::Relaxed) + self.tx.max_capacity() - self.tx.capacity()
+    }
 }
 
 pub struct SchedulerV1;
 
 impl SchedulerV1 {
     pub fn start(backend: Arc<dyn InferenceBackend>, kv: Arc<PagedKvManager>, prefix: Arc<PrefixCache>) -> Handle {
-        let (tx, mut rx) = mpsc::channel::<Request>(1024);
-        let queue_depth = Arc::new(AtomicUsize::new(0));
+        Self::start_with(backend, kv, prefix, SchedulerConfig::default())
+    }
+
+    pub fn start_with(backend: Arc<dyn InferenceBackend>, kv: Arc<PagedKvManager>, prefix: Arc<PrefixCache>, cfg: SchedulerConfig) -> Handle {
+        let (tx, mut rx) = mpsc::channel::<Request>(cfg.queue_capacity);
+        let waiting = Arc::new(AtomicUsize::new(0));
         let last_batch_size = Arc::new(AtomicUsize::new(0));
-        let qd = queue_depth.clone();
+        let w = waiting.clone();
         let lbs = last_batch_size.clone();
-        let kv_bg = kv.clone();
+        let (pc, be) = (prefix.clone(), backend.clone());
         tokio::spawn(async move {
-            let mut ticker = time::interval(Duration::from_millis(2));
+            let mut core = SchedulerCore::new(be, pc, cfg);
+            let mut ticker = time::interval(cfg.tick);
+            ticker.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
             loop {
-                ticker.tick().await;
-                let mut batch: Vec<Request> = Vec::with_capacity(32);
-                while let Ok(req) = rx.try_recv() { batch.push(req); if batch.len() >= 32 { break; } }
-                qd.store(rx.len(), Ordering::Relaxed);
-                if batch.is_empty() { continue; }
-                lbs.store(batch.len(), Ordering::Relaxed);
-                for req in batch {
-                    let backend_ref = backend.clone();
-                    let _kv = kv_bg.clone();
-                    tokio::spawn(async move {
-                        let text = super::decode::generate_once(backend_ref.as_ref(), &req.prompt, req.max_tokens);
-                        let _ = req.respond.send(text.unwrap_or_default());
-                        drop(req.reservation);
-                    });
+                // only wait out the tick when idle; running sequences step back to back
+                if core.is_idle() { ticker.tick().await; } else { tokio::task::yield_now().await; }
+                // leave the rest in the channel so the queue never holds more than queue_capacity
+                while core.waiting() < cfg.queue_capacity {
+                    let Ok(req) = rx.try_recv() else { break };
+                    core.push(req);
+                }
+                if core.is_idle() {
+                    // every Handle dropped (e.g. model swapped out): stop ticking
+                    if rx.is_closed() && rx.is_empty() { break; }
+                    continue;
                 }
+                lbs.store(core.step(), Ordering::Relaxed);
+                w.store(core.waiting(), Ordering::Relaxed);
             }
         });
-        Handle { tx, queue_depth, last_batch_size, kv, prefix }
-    }
-
-    pub async fn enqueue(handle: &Handle, prompt: String, max_tokens: usize) -> String {
-        let est_prompt_tokens = std::cmp::max(1, prompt.len() / 4);
-        let prefix_hash = handle.prefix.hash_prefix(&prompt);
-        handle.prefix.note(prefix_hash);
-        let mut total_tokens = est_prompt_tokens + max_tokens;
-        if handle.prefix.is_common(prefix_hash) { total_tokens = total_tokens.saturating_sub(32); }
-        let predicted_blocks = handle.kv.tokens_to_blocks(total_tokens);
-        let reservation = handle.kv.try_reserve(predicted_blocks);
-        if reservation.is_none() {
-            return String::from("SERVER_BUSY: insufficient KV capacity");
+        Handle { tx, waiting, last_batch_size, kv, prefix, backend }
+    }
+
+    /// KV admission: reserve blocks for `prompt_tokens` plus `max_tokens`, evicting
+    /// idle cached prefixes if needed, or `None` when still full. Blocks a cached
+    /// prefix covers are handed back at admission.
+    pub fn reserve(kv: &Arc<PagedKvManager>, prefix: &PrefixCache, prompt_tokens: usize, max_tokens: usize) -> Option<Reservation> {
+        let blocks = kv.tokens_to_blocks(prompt_tokens.max(1) + max_tokens);
+        kv.try_reserve(blocks).or_else(|| {
+            prefix.evict(blocks.saturating_sub(kv.free_blocks()));
+            kv.try_reserve(blocks)
+        })
+    }
+
+    /// Admit a request and return its event stream. Rejections (tokenizer error,
+    /// no KV capacity, full queue) arrive as a single `Error` event.
+    pub fn submit(handle: &Handle, prompt: String, max_tokens: usize, cancel: CancelToken) -> EventStream {
+        match handle.backend.tokenize(&prompt) {
+            Ok(tokens) => Self::submit_tokens(handle, tokens, max_tokens, ContextPolicy::default(), DecodeOptions::default(), cancel, None, None),
+            Err(e) => {
+                let (req, rx) = Request::new(Vec::new(), max_tokens, None, cancel);
+                let _ = req.events.send(GenerationEvent::Error(e.to_string()));
+                rx
+            }
         }
-        let (tx, rx) = oneshot::channel();
-        let _ = handle.tx.send(Request { prompt, respond: tx, max_tokens, reservation }).await;
-        rx.await.unwrap_or_default()
     }
-}
 
+    /// Submit an already tokenized prompt, fitted into the backend's context
+    /// window as `context` says and decoded with `options`.
+    pub fn submit_with(handle: &Handle, tokens: Vec<u32>, max_tokens: usize, context: ContextPolicy, options: DecodeOptions, cancel: CancelToken) -> EventStream {
+        Self::submit_tokens(handle, tokens, max_tokens, context, options, cancel, None, None)
+    }
+
+    /// Submit one turn of a conversation. `tokens` is the whole conversation so
+    /// far and must extend `resume`'s tokens, of which only the uncached tail is
+    /// prefilled. The finished sequence is parked in the returned slot.
+    pub fn submit_turn(handle: &Handle, resume: Option<Parked>, tokens: Vec<u32>, max_tokens: usize, cancel: CancelToken) -> (EventStream, ParkingSlot) {
+        let slot = ParkingSlot::default();
+        (Self::submit_tokens(handle, tokens, max_tokens, ContextPolicy::default(), DecodeOptions::default(), cancel, resume, Some(slot.clone())), slot)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn submit_tokens(handle: &Handle, tokens: Vec<u32>, max_tokens: usize, context: ContextPolicy, options: DecodeOptions, cancel: CancelToken, resume: Option<Parked>, park: Option<ParkingSlot>) -> EventStream {
+        let (max_tokens, window) = match handle.backend.context_size() {
+            Some(n_ctx) => match context.fit(tokens.len(), max_tokens, n_ctx) {
+                Ok(max_tokens) => (max_tokens, n_ctx),
+                Err(e) => {
+                    let (req, rx) = Request::new(Vec::new(), max_tokens, None, cancel);
+                    let _ = req.events.send(GenerationEvent::Error(e.to_string()));
+                    return rx;
+                }
+            },
+            None => (max_tokens, usize::MAX),
+        };
+        if handle.queue_depth() >= handle.tx.max_capacity() {
+            let (req, rx) = Request::new(Vec::new(), max_tokens, None, cancel);
+            let _ = req.events.send(GenerationEvent::Error("SERVER_BUSY: queue full".into()));
+            return rx;
+        }
+        // a resumed sequence already holds blocks for the tokens it has seen, and a
+        // shifting one never needs more than the window
+        let held = resume.as_ref().and_then(|p| p.table.as_ref()).map_or(0, |t| t.num_tokens());
+        let generated = max_tokens.min(window.saturating_sub(tokens.len()));
+        let reservation = Self::reserve(&handle.kv, &handle.prefix, tokens.len().saturating_sub(held), generated);
+        let busy = reservation.is_none();
+        let (mut req, rx) = Request::new(tokens, max_tokens, reservation, cancel);
+        req.resume = resume;
+        req.park = park;
+        req.context = context;
+        req.options = options;
+        let events = req.events.clone();
+        if busy {
+            let _ = events.send(GenerationEvent::Error("SERVER_BUSY: insufficient KV capacity".into()));
+            return rx;
+        }
+        let _ = events.send(GenerationEvent::Queued { position: handle.queue_depth() });
+        if handle.tx.try_send(req).is_err() {
+            let _ = events.send(GenerationEvent::Error("SERVER_BUSY: queue full".into()));
+        }
+        rx
+    }
+
+    /// Convenience wrapper that waits for the whole completion.
+    pub async fn enqueue(handle: &Handle, prompt: String, max_tokens: usize, cancel: CancelToken) -> String {
+        let mut rx = Self::submit(handle, prompt, max_tokens, cancel);
+        let mut text = String::new();
+        while let Some(ev) = rx.recv().await {
+            match ev {
+                GenerationEvent::Token(t) => text.push_str(&t),
+                GenerationEvent::Finished { .. } => break,
+                GenerationEvent::Error(e) => return e,
+                _ => {}
+            }
+        }
+        text
+    }
+}
@@ -0,0 +1,225 @@
+//! Conversation sessions that keep their sequence, and with it the KV, alive
+//! between turns so each turn only prefills what was added since the last one.
+//! A session records its chat messages, which the caller renders into each
+//! turn's prompt; the sequence is resumed when that prompt extends it.
+//! Idle sessions are parked: the backend's sequence state is written next to the
+//! session's history under `dir`, which is also how sessions survive a restart.
+//! A session stays on the model it was created with, whose vocabulary its token
+//! history is in.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use runner_backend::InferenceBackend;
+use runner_common::{Result, RunnerError};
+use crate::decode::next_seq_id;
+use crate::scheduler::{Handle, Parked};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionError { NotFound, Busy }
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct SessionInfo {
+    pub id: String,
+    /// Model the session runs on.
+    pub model: String,
+    pub turns: usize,
+    /// Tokens the session's sequence has seen: the last turn's prompt and reply.
+    pub tokens: usize,
+    /// Tokens whose KV is kept, on the device or parked on disk.
+    pub cached_tokens: usize,
+    pub resident: bool,
+}
+
+/// On-disk form of a session; `<id>.kv` next to it holds `kv_tokens` tokens of backend state.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SavedSession { model: String, messages: Vec<serde_json::Value>, tokens: Vec<u32>, turns: usize, kv_tokens: usize }
+
+struct Session {
+    model: String,
+    messages: Vec<serde_json::Value>,
+    tokens: Vec<u32>,
+    turns: usize,
+    parked: Option<Parked>,
+    kv_tokens: usize,
+    busy: bool,
+    last_used: Instant,
+}
+
+impl Session {
+    fn info(&self, id: &str) -> SessionInfo {
+        let cached_tokens = self.parked.as_ref().map_or(self.kv_tokens, |p| p.n_past);
+        SessionInfo { id: id.to_string(), model: self.model.clone(), turns: self.turns, tokens: self.tokens.len(), cached_tokens, resident: self.parked.is_some() }
+    }
+}
+
+pub struct SessionStore {
+    dir: Option<PathBuf>,
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+/// A session checked out for one generation turn. Dropping it without `finish`
+/// (failed or abandoned turn) keeps the session's history but not its KV.
+pub struct Turn {
+    store: Arc<SessionStore>,
+    id: String,
+    /// The conversation so far, to render the prompt from.
+    pub messages: Vec<serde_json::Value>,
+    pub resume: Option<Parked>,
+}
+
+impl Turn {
+    /// The sequence to resume for `prompt`, when `prompt` extends the tokens it
+    /// holds. Otherwise (the template rendered earlier turns differently, or the
+    /// reply did not tokenize back the same) it is released and the prompt is
+    /// prefilled in full.
+    pub fn resume_for(&mut self, prompt: &[u32], backend: &dyn InferenceBackend) -> Option<Parked> {
+        let parked = self.resume.take()?;
+        if prompt.starts_with(&parked.tokens) { return Some(parked); }
+        backend.release_sequence(parked.seq_id);
+        None
+    }
+
+    /// Make `parked`, the sequence the turn finished with, the session's new
+    /// state, and `reply` the conversation's next message.
+    pub fn finish(mut self, parked: Parked, reply: serde_json::Value) { self.store.end_turn(&std::mem::take(&mut self.id), Some((parked, reply))); }
+}
+
+impl Drop for Turn {
+    fn drop(&mut self) { if !self.id.is_empty() { self.store.end_turn(&self.id, None); } }
+}
+
+impl SessionStore {
+    /// Store persisting under `dir` (created if missing), loading sessions saved there.
+    pub fn open(dir: Option<PathBuf>) -> Result<Arc<Self>> {
+        let mut sessions = HashMap::new();
+        if let Some(dir) = &dir {
+            let io = |e: std::io::Error| RunnerError::Message(format!("session dir {}: {e}", dir.display()));
+            std::fs::create_dir_all(dir).map_err(io)?;
+            for entry in std::fs::read_dir(dir).map_err(io)?.flatten() {
+                let path = entry.path();
+                let Some(id) = path.file_stem().and_then(|s| s.to_str()).filter(|s| is_session_id(s)) else { continue };
+                if path.extension().is_none_or(|e| e != "json") { continue; }
+                let Ok(saved) = std::fs::read(&path).map(|b| serde_json::from_slice::<SavedSession>(&b)) else { continue };
+                let Ok(saved) = saved else { continue };
+                let s = Session { model: saved.model, messages: saved.messages, tokens: saved.tokens, turns: saved.turns, parked: None, kv_tokens: saved.kv_tokens, busy: false, last_used: Instant::now() };
+                sessions.insert(id.to_string(), s);
+            }
+        }
+        Ok(Arc::new(Self { dir, sessions: Mutex::new(sessions) }))
+    }
+
+    pub fn len(&self) -> usize { self.sessions.lock().unwrap().len() }
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    /// New session on `model` starting with `messages` (e.g. the system prompt).
+    pub fn create(&self, model: &str, messages: Vec<serde_json::Value>) -> SessionInfo {
+        let id = format!("sess_{:016x}", rand::random::<u64>());
+        let s = Session { model: model.to_string(), messages, tokens: Vec::new(), turns: 0, parked: None, kv_tokens: 0, busy: false, last_used: Instant::now() };
+        let info = s.info(&id);
+        self.save(&id, &s);
+        self.sessions.lock().unwrap().insert(id, s);
+        info
+    }
+
+    pub fn info(&self, id: &str) -> Option<SessionInfo> { self.sessions.lock().unwrap().get(id).map(|s| s.info(id)) }
+
+    /// Add a message that is not generated (e.g. the user's) to the conversation.
+    pub fn append(&self, id: &str, message: serde_json::Value) -> std::result::Result<SessionInfo, SessionError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let s = sessions.get_mut(id).ok_or(SessionError::NotFound)?;
+        if s.busy { return Err(SessionError::Busy); }
+        s.messages.push(message);
+        s.turns += 1;
+        s.last_used = Instant::now();
+        self.save(id, s);
+        Ok(s.info(id))
+    }
+
+    /// Check the session out for a generation turn on `handle`. A sequence still
+    /// resident on the same pool is resumed as is; one parked on disk is restored
+    /// into the backend first. Anything else starts over.
+    pub fn begin_turn(self: &Arc<Self>, id: &str, handle: &Handle) -> std::result::Result<Turn, SessionError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let s = sessions.get_mut(id).ok_or(SessionError::NotFound)?;
+        if s.busy { return Err(SessionError::Busy); }
+        s.busy = true;
+        s.last_used = Instant::now();
+        // a model swap since the last turn leaves the sequence in the old pool and backend
+        let resume = s.parked.take().filter(|p| p.table.as_ref().is_none_or(|t| Arc::ptr_eq(t.manager(), &handle.kv)));
+        let resume = resume.or_else(|| self.restore(id, s, handle));
+        Ok(Turn { store: self.clone(), id: id.to_string(), messages: s.messages.clone(), resume })
+    }
+
+    fn restore(&self, id: &str, s: &Session, handle: &Handle) -> Option<Parked> {
+        if s.kv_tokens == 0 { return None; }
+        let data = std::fs::read(self.dir.as_ref()?.join(format!("{id}.kv"))).ok()?;
+        let seq_id = next_seq_id();
+        handle.backend.restore_sequence(seq_id, &data).ok()?;
+        let mut table = handle.kv.try_reserve(0)?;
+        if table.append_tokens(s.kv_tokens).is_err() {
+            handle.backend.release_sequence(seq_id);
+            return None;
+        }
+        Some(Parked { seq_id, tokens: s.tokens.clone(), n_past: s.kv_tokens, table: Some(table) })
+    }
+
+    fn end_turn(&self, id: &str, finished: Option<(Parked, serde_json::Value)>) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let Some(s) = sessions.get_mut(id) else { return };
+        s.busy = false;
+        s.last_used = Instant::now();
+        if let Some((p, reply)) = finished {
+            s.messages.push(reply);
+            s.tokens = p.tokens.clone();
+            s.turns += 1;
+            s.parked = Some(p);
+            // the turn moved on from whatever state was parked on disk
+            if s.kv_tokens > 0 { s.kv_tokens = 0; self.remove_file(id, "kv"); }
+            self.save(id, s);
+        }
+    }
+
+    /// Forget a session, its KV and its files. `backend` is its model's, when loaded.
+    pub fn delete(&self, id: &str, backend: Option<&dyn InferenceBackend>) -> bool {
+        let Some(s) = self.sessions.lock().unwrap().remove(id) else { return false };
+        if let (Some(p), Some(backend)) = (s.parked, backend) { backend.release_sequence(p.seq_id); }
+        self.remove_file(id, "json");
+        self.remove_file(id, "kv");
+        true
+    }
+
+    /// Take `model`'s sessions idle for longer than `idle` off the device: their
+    /// sequence state is saved to disk (when there is a `dir`) and released in
+    /// `backend`, and their KV blocks go back to the pool. Returns how many were parked.
+    pub fn park_idle(&self, model: &str, backend: &dyn InferenceBackend, idle: Duration) -> usize {
+        let mut sessions = self.sessions.lock().unwrap();
+        let mut parked = 0;
+        for (id, s) in sessions.iter_mut() {
+            if s.model != model || s.busy || s.last_used.elapsed() < idle { continue; }
+            let Some(p) = s.parked.take() else { continue };
+            if let (Some(dir), Some(data)) = (&self.dir, backend.save_sequence(p.seq_id)) {
+                if std::fs::write(dir.join(format!("{id}.kv")), data).is_ok() { s.kv_tokens = p.n_past; }
+            }
+            backend.release_sequence(p.seq_id);
+            self.save(id, s);
+            parked += 1;
+        }
+        parked
+    }
+
+    fn save(&self, id: &str, s: &Session) {
+        let Some(dir) = &self.dir else { return };
+        let saved = SavedSession { model: s.model.clone(), messages: s.messages.clone(), tokens: s.tokens.clone(), turns: s.turns, kv_tokens: s.kv_tokens };
+        if let Ok(bytes) = serde_json::to_vec(&saved) { let _ = std::fs::write(dir.join(format!("{id}.json")), bytes); }
+    }
+
+    fn remove_file(&self, id: &str, ext: &str) {
+        if let Some(dir) = &self.dir { let _ = std::fs::remove_file(dir.join(format!("{id}.{ext}"))); }
+    }
+}
+
+fn is_session_id(s: &str) -> bool {
+    s.strip_prefix("sess_").is_some_and(|hex| !hex.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit()))
+}
@@ -0,0 +1,212 @@
+//! Deterministic scheduler simulation: a virtual clock, a synthetic cost-model
+//! backend and a trace replayer that drives `SchedulerCore` without tokio timers.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, atomic::{AtomicU64, Ordering}};
+use std::time::Duration;
+use runner_backend::{ForwardOutput, InferenceBackend, KvStats, LoadParams, ModelHandle, SequenceState};
+use runner_common::{cancel::CancelToken, Result};
+use crate::kv::{PagedKvManager, PrefixCache};
+use crate::scheduler::{EventStream, GenerationEvent, Request, SchedulerConfig, SchedulerCore, SchedulerV1};
+
+/// Manually advanced monotonic clock shared by the harness and the cost model.
+#[derive(Debug, Clone, Default)]
+pub struct VirtualClock(Arc<AtomicU64>);
+
+impl VirtualClock {
+    pub fn new() -> Self { Self::default() }
+    pub fn now(&self) -> Duration { Duration::from_nanos(self.0.load(Ordering::SeqCst)) }
+    pub fn advance(&self, d: Duration) { self.0.fetch_add(d.as_nanos() as u64, Ordering::SeqCst); }
+    pub fn advance_to(&self, t: Duration) { self.0.fetch_max(t.as_nanos() as u64, Ordering::SeqCst); }
+}
+
+/// Step cost of the synthetic backend: a fixed launch overhead per `forward`,
+/// plus per prompt token prefilled and per sequence decoded in the batch.
+#[derive(Debug, Clone, Copy)]
+pub struct CostModel {
+    pub step_overhead: Duration,
+    pub prefill_per_token: Duration,
+    pub decode_per_seq: Duration,
+}
+
+impl Default for CostModel {
+    fn default() -> Self {
+        Self {
+            step_overhead: Duration::from_millis(10),
+            prefill_per_token: Duration::from_micros(100),
+            decode_per_seq: Duration::from_millis(1),
+        }
+    }
+}
+
+/// Backend that produces filler tokens until `max_new_tokens` and charges every
+/// `forward` to the virtual clock according to its `CostModel`.
+pub struct CostModelBackend { cost: CostModel, clock: VirtualClock }
+
+impl CostModelBackend {
+    pub const FILLER: u32 = b'x' as u32;
+    pub fn new(cost: CostModel, clock: VirtualClock) -> Self { Self { cost, clock } }
+}
+
+impl InferenceBackend for CostModelBackend {
+    fn load_model(&self, _path: &str, _params: LoadParams) -> Result<ModelHandle> { Ok(ModelHandle::default()) }
+    fn tokenize(&self, text: &str) -> Result<Vec<u32>> { Ok(text.bytes().map(u32::from).collect()) }
+    fn detokenize(&self, tokens: &[u32]) -> Result<String> {
+        Ok(tokens.iter().map(|&t| char::from_u32(t).unwrap_or('?')).collect())
+    }
+    fn forward(&self, requests: &mut [SequenceState]) -> Result<Vec<ForwardOutput>> {
+        let mut cost = self.cost.step_overhead;
+        let outs = requests.iter_mut().map(|seq| {
+            let pending = seq.tokens.len() - seq.n_past;
+            cost += if seq.n_past < seq.prompt_len { self.cost.prefill_per_token * pending as u32 } else { self.cost.decode_per_seq };
+            seq.n_past = seq.tokens.len();
+            ForwardOutput { token: Some(Self::FILLER), ..Default::default() }
+        }).collect();
+        self.clock.advance(cost);
+        Ok(outs)
+    }
+    fn kv_usage(&self) -> KvStats { KvStats }
+}
+
+/// One request in an arrival trace.
+#[derive(Debug, Clone)]
+pub struct Arrival {
+    pub at: Duration,
+    pub tenant: String,
+    pub prompt_tokens: usize,
+    pub max_tokens: usize,
+}
+
+/// What happened to one arrival, in virtual time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestRecord {
+    pub tenant: String,
+    pub arrival: Duration,
+    pub first_token: Option<Duration>,
+    pub finished: Option<Duration>,
+    pub completion_tokens: usize,
+    pub rejected: bool,
+}
+
+impl RequestRecord {
+    pub fn ttft(&self) -> Option<Duration> { self.first_token.map(|t| t - self.arrival) }
+    pub fn latency(&self) -> Option<Duration> { self.finished.map(|t| t - self.arrival) }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimReport {
+    pub records: Vec<RequestRecord>,
+    pub makespan: Duration,
+    pub steps: usize,
+}
+
+impl SimReport {
+    pub fn completed(&self) -> usize { self.records.iter().filter(|r| r.finished.is_some()).count() }
+    pub fn rejected(&self) -> usize { self.records.iter().filter(|r| r.rejected).count() }
+
+    /// Completion tokens per virtual second.
+    pub fn throughput(&self) -> f64 {
+        let tokens: usize = self.records.iter().map(|r| r.completion_tokens).sum();
+        tokens as f64 / self.makespan.as_secs_f64().max(1e-9)
+    }
+
+    /// Nearest-rank percentile (`p` in 0..=100) of TTFT over completed requests.
+    pub fn ttft_percentile(&self, p: f64) -> Duration {
+        let mut v: Vec<Duration> = self.records.iter().filter_map(|r| r.ttft()).collect();
+        if v.is_empty() { return Duration::ZERO; }
+        v.sort();
+        let rank = ((p / 100.0) * v.len() as f64).ceil() as usize;
+        v[rank.clamp(1, v.len()) - 1]
+    }
+
+    /// Jain's fairness index over per-tenant mean latency per output token:
+    /// 1.0 when every tenant sees the same service, 1/n when one tenant gets it all.
+    pub fn fairness(&self) -> f64 {
+        let mut per_tenant: BTreeMap<&str, (f64, usize)> = BTreeMap::new();
+        for r in &self.records {
+            let Some(lat) = r.latency() else { continue };
+            let e = per_tenant.entry(&r.tenant).or_default();
+            e.0 += lat.as_secs_f64() / r.completion_tokens.max(1) as f64;
+            e.1 += 1;
+        }
+        let xs: Vec<f64> = per_tenant.values().map(|(sum, n)| sum / *n as f64).collect();
+        if xs.is_empty() { return 1.0; }
+        let sum: f64 = xs.iter().sum();
+        let sq: f64 = xs.iter().map(|x| x * x).sum();
+        (sum * sum) / (xs.len() as f64 * sq).max(1e-18)
+    }
+}
+
+/// Replays an arrival trace through `SchedulerCore` in virtual time. The same
+/// trace and settings always yield the same report.
+#[derive(Debug, Clone)]
+pub struct Simulation {
+    pub scheduler: SchedulerConfig,
+    pub cost: CostModel,
+    pub kv_capacity_bytes: usize,
+}
+
+impl Default for Simulation {
+    fn default() -> Self { Self { scheduler: SchedulerConfig::default(), cost: CostModel::default(), kv_capacity_bytes: 512 * 1024 * 1024 } }
+}
+
+impl Simulation {
+    pub fn run(&self, trace: &[Arrival]) -> SimReport {
+        let clock = VirtualClock::new();
+        let backend = Arc::new(CostModelBackend::new(self.cost, clock.clone()));
+        let kv = PagedKvManager::new(self.kv_capacity_bytes);
+        let prefix = PrefixCache::new(&kv);
+        let mut core = SchedulerCore::new(backend, prefix.clone(), self.scheduler);
+
+        let mut order: Vec<usize> = (0..trace.len()).collect();
+        order.sort_by_key(|&i| trace[i].at);
+        let mut pending = order.into_iter().peekable();
+        let mut records: Vec<RequestRecord> = trace.iter().map(|a| RequestRecord {
+            tenant: a.tenant.clone(), arrival: a.at, first_token: None, finished: None, completion_tokens: 0, rejected: false,
+        }).collect();
+        let mut streams: Vec<(usize, EventStream)> = Vec::new();
+        let mut steps = 0;
+
+        loop {
+            while let Some(&i) = pending.peek() {
+                if trace[i].at > clock.now() { break; }
+                pending.next();
+                let a = &trace[i];
+                let prompt = vec![u32::from(b'p'); a.prompt_tokens.max(1)];
+                match SchedulerV1::reserve(&kv, &prefix, prompt.len(), a.max_tokens) {
+                    Some(reservation) => {
+                        let (req, rx) = Request::new(prompt, a.max_tokens, Some(reservation), CancelToken::new());
+                        core.push(req);
+                        streams.push((i, rx));
+                    }
+                    None => records[i].rejected = true,
+                }
+            }
+            if core.is_idle() {
+                match pending.peek() {
+                    Some(&i) => { clock.advance_to(trace[i].at); continue; }
+                    None => break,
+                }
+            }
+            let before = clock.now();
+            core.step();
+            steps += 1;
+            // an empty step still costs one scheduler tick
+            if clock.now() == before { clock.advance(self.scheduler.tick); }
+            let now = clock.now();
+            streams.retain_mut(|(i, rx)| {
+                while let Ok(ev) = rx.try_recv() {
+                    let rec = &mut records[*i];
+                    match ev {
+                        GenerationEvent::Token(_) => { rec.first_token.get_or_insert(now); }
+                        GenerationEvent::Finished { usage, .. } => { rec.finished = Some(now); rec.completion_tokens = usage.completion_tokens; return false; }
+                        GenerationEvent::Error(_) => { rec.rejected = true; return false; }
+                        GenerationEvent::Queued { .. } | GenerationEvent::Started | GenerationEvent::Logprob(_) => {}
+                    }
+                }
+                true
+            });
+        }
+        SimReport { records, makespan: clock.now(), steps }
+    }
+}
@@ -0,0 +1,203 @@
+//! Slower KV tiers behind the paged pool: host RAM first, then a memory-mapped
+//! file on local disk. Entries are opaque blobs (one block's K/V, or a whole
+//! saved sequence) addressed by a `SpillKey`; host entries that go cold are
+//! demoted to disk to make room.
+
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}};
+use memmap2::MmapMut;
+use prometheus::IntCounterVec;
+use runner_common::{config::RunnerConfig, Result, RunnerError};
+use crate::metrics;
+
+pub type SpillKey = u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tier { Host, Disk }
+
+impl Tier {
+    pub const ALL: [Tier; 2] = [Tier::Host, Tier::Disk];
+    pub fn as_str(&self) -> &'static str {
+        match self { Tier::Host => "host", Tier::Disk => "disk" }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SpillConfig {
+    pub host_capacity_bytes: usize,
+    pub disk_capacity_bytes: usize,
+    /// Where the disk tier's backing file is created; the temp dir when unset.
+    pub disk_dir: Option<PathBuf>,
+}
+
+impl SpillConfig {
+    pub fn from_runner(cfg: &RunnerConfig) -> Self {
+        Self {
+            host_capacity_bytes: cfg.kv_host_spill_mb.unwrap_or(0) * 1024 * 1024,
+            disk_capacity_bytes: cfg.kv_disk_spill_mb.unwrap_or(0) * 1024 * 1024,
+            disk_dir: cfg.kv_disk_spill_dir.clone(),
+        }
+    }
+    pub fn is_enabled(&self) -> bool { self.host_capacity_bytes > 0 || self.disk_capacity_bytes > 0 }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TierStats { pub used_bytes: usize, pub capacity_bytes: usize, pub spills: u64, pub restores: u64 }
+
+struct HostEntry { data: Vec<u8>, last_used: u64 }
+
+/// Fixed-size slots in a memory-mapped file; an entry spans as many as it needs.
+struct DiskTier {
+    path: PathBuf,
+    map: MmapMut,
+    slot_bytes: usize,
+    free: Vec<usize>,
+    entries: HashMap<SpillKey, (Vec<usize>, usize)>,
+}
+
+impl DiskTier {
+    fn create(dir: PathBuf, capacity_bytes: usize, slot_bytes: usize) -> Result<Self> {
+        static NEXT_FILE: AtomicU64 = AtomicU64::new(0);
+        let n_slots = capacity_bytes / slot_bytes;
+        let path = dir.join(format!("runner-kv-{}-{}.bin", std::process::id(), NEXT_FILE.fetch_add(1, Ordering::Relaxed)));
+        let io = |e: std::io::Error| RunnerError::Message(format!("kv disk tier {}: {e}", path.display()));
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).map_err(io)?;
+        file.set_len((n_slots * slot_bytes) as u64).map_err(io)?;
+        // SAFETY: the file was just created for this tier and nothing else maps it
+        let map = unsafe { MmapMut::map_mut(&file) }.map_err(io)?;
+        Ok(Self { map, slot_bytes, free: (0..n_slots).rev().collect(), entries: HashMap::new(), path })
+    }
+
+    fn slots_for(&self, len: usize) -> usize { len.div_ceil(self.slot_bytes).max(1) }
+    fn fits(&self, len: usize) -> bool { self.free.len() >= self.slots_for(len) }
+    fn used_bytes(&self) -> usize { self.entries.values().map(|(slots, _)| slots.len()).sum::<usize>() * self.slot_bytes }
+    fn capacity_bytes(&self) -> usize { self.map.len() }
+
+    fn write(&mut self, key: SpillKey, data: &[u8]) -> bool {
+        if !self.fits(data.len()) { return false; }
+        let at = self.free.len() - self.slots_for(data.len());
+        let slots: Vec<usize> = self.free.drain(at..).collect();
+        for (&slot, chunk) in slots.iter().zip(data.chunks(self.slot_bytes)) {
+            let off = slot * self.slot_bytes;
+            self.map[off..off + chunk.len()].copy_from_slice(chunk);
+        }
+        self.entries.insert(key, (slots, data.len()));
+        true
+    }
+
+    fn take(&mut self, key: SpillKey) -> Option<Vec<u8>> {
+        let (slots, len) = self.entries.remove(&key)?;
+        let mut data = Vec::with_capacity(len);
+        for &slot in &slots {
+            let off = slot * self.slot_bytes;
+            let n = self.slot_bytes.min(len - data.len());
+            data.extend_from_slice(&self.map[off..off + n]);
+        }
+        self.free.extend(slots);
+        Some(data)
+    }
+}
+
+impl Drop for DiskTier {
+    fn drop(&mut self) { let _ = std::fs::remove_file(&self.path); }
+}
+
+#[derive(Default)]
+struct Tiers { host: HashMap<SpillKey, HostEntry>, host_used: usize, disk: Option<DiskTier>, clock: u64 }
+
+pub struct TieredKvStore {
+    host_capacity: usize,
+    tiers: Mutex<Tiers>,
+    next_key: AtomicU64,
+    spills: [AtomicU64; 2],
+    restores: [AtomicU64; 2],
+}
+
+impl TieredKvStore {
+    /// `slot_bytes` is the disk allocation unit, normally the pool's block size.
+    pub fn new(cfg: &SpillConfig, slot_bytes: usize) -> Result<Arc<Self>> {
+        let slot_bytes = slot_bytes.max(1);
+        let disk = if cfg.disk_capacity_bytes >= slot_bytes {
+            let dir = cfg.disk_dir.clone().unwrap_or_else(std::env::temp_dir);
+            Some(DiskTier::create(dir, cfg.disk_capacity_bytes, slot_bytes)?)
+        } else { None };
+        Ok(Arc::new(Self {
+            host_capacity: cfg.host_capacity_bytes,
+            tiers: Mutex::new(Tiers { disk, ..Default::default() }),
+            next_key: AtomicU64::new(1),
+            spills: Default::default(),
+            restores: Default::default(),
+        }))
+    }
+
+    /// Store `data` in host RAM, demoting least-recently-used host entries to
+    /// disk to make room; entries larger than the host tier go straight to disk.
+    /// `KvExhausted` when neither tier can take it.
+    pub fn put(&self, data: Vec<u8>) -> Result<SpillKey> {
+        let key = self.next_key.fetch_add(1, Ordering::Relaxed);
+        let mut t = self.tiers.lock().unwrap();
+        t.clock += 1;
+        if data.len() > self.host_capacity {
+            let disk = t.disk.as_mut().ok_or(RunnerError::KvExhausted)?;
+            if !disk.write(key, &data) { return Err(RunnerError::KvExhausted); }
+            self.count(&self.spills, &metrics::KV_TIER_SPILLS, Tier::Disk);
+            return Ok(key);
+        }
+        while t.host_used + data.len() > self.host_capacity {
+            let (victim, len) = t.host.iter().min_by_key(|(_, e)| e.last_used).map(|(k, e)| (*k, e.data.len())).expect("host tier over capacity but empty");
+            if !t.disk.as_ref().is_some_and(|d| d.fits(len)) { return Err(RunnerError::KvExhausted); }
+            let entry = t.host.remove(&victim).expect("victim present");
+            t.host_used -= len;
+            t.disk.as_mut().expect("checked above").write(victim, &entry.data);
+            self.count(&self.spills, &metrics::KV_TIER_SPILLS, Tier::Disk);
+        }
+        t.host_used += data.len();
+        let last_used = t.clock;
+        t.host.insert(key, HostEntry { data, last_used });
+        self.count(&self.spills, &metrics::KV_TIER_SPILLS, Tier::Host);
+        Ok(key)
+    }
+
+    /// Remove an entry and return its bytes, from whichever tier holds it.
+    pub fn take(&self, key: SpillKey) -> Option<Vec<u8>> {
+        let mut t = self.tiers.lock().unwrap();
+        if let Some(e) = t.host.remove(&key) {
+            t.host_used -= e.data.len();
+            self.count(&self.restores, &metrics::KV_TIER_RESTORES, Tier::Host);
+            return Some(e.data);
+        }
+        let data = t.disk.as_mut()?.take(key)?;
+        self.count(&self.restores, &metrics::KV_TIER_RESTORES, Tier::Disk);
+        Some(data)
+    }
+
+    /// Drop an entry that will not be restored.
+    pub fn remove(&self, key: SpillKey) {
+        let mut t = self.tiers.lock().unwrap();
+        if let Some(e) = t.host.remove(&key) { t.host_used -= e.data.len(); return; }
+        if let Some(d) = t.disk.as_mut() { d.take(key); }
+    }
+
+    pub fn tier_of(&self, key: SpillKey) -> Option<Tier> {
+        let t = self.tiers.lock().unwrap();
+        if t.host.contains_key(&key) { return Some(Tier::Host); }
+        t.disk.as_ref().filter(|d| d.entries.contains_key(&key)).map(|_| Tier::Disk)
+    }
+
+    pub fn stats(&self, tier: Tier) -> TierStats {
+        let t = self.tiers.lock().unwrap();
+        let (used_bytes, capacity_bytes) = match tier {
+            Tier::Host => (t.host_used, self.host_capacity),
+            Tier::Disk => t.disk.as_ref().map_or((0, 0), |d| (d.used_bytes(), d.capacity_bytes())),
+        };
+        let i = tier as usize;
+        TierStats { used_bytes, capacity_bytes, spills: self.spills[i].load(Ordering::Relaxed), restores: self.restores[i].load(Ordering::Relaxed) }
+    }
+
+    fn count(&self, counters: &[AtomicU64; 2], total: &IntCounterVec, tier: Tier) {
+        counters[tier as usize].fetch_add(1, Ordering::Relaxed);
+        total.with_label_values(&[tier.as_str()]).inc();
+    }
+}
@@ -0,0 +1,72 @@
+use std::sync::{Arc, Mutex};
+use runner_backend::{mock::MockBackend, ForwardOutput, InferenceBackend, KvStats, LoadParams, ModelHandle, SequenceState};
+use runner_common::{cancel::CancelToken, Result, RunnerError};
+use runner_core::decode::generate;
+use runner_core::kv::{PagedKvManager, PrefixCache};
+use runner_core::scheduler::{Request, SchedulerConfig, SchedulerCore, SchedulerV1};
+
+/// Mock backend that records which sequences it was told to release.
+#[derive(Default)]
+struct Releases { mock: MockBackend, released: Mutex<Vec<u64>> }
+
+impl InferenceBackend for Releases {
+    fn load_model(&self, path: &str, params: LoadParams) -> Result<ModelHandle> { self.mock.load_model(path, params) }
+    fn tokenize(&self, text: &str) -> Result<Vec<u32>> { self.mock.tokenize(text) }
+    fn detokenize(&self, tokens: &[u32]) -> Result<String> { self.mock.detokenize(tokens) }
+    fn forward(&self, requests: &mut [SequenceState]) -> Result<Vec<ForwardOutput>> { self.mock.forward(requests) }
+    fn kv_usage(&self) -> KvStats { KvStats }
+    fn release_sequence(&self, seq_id: u64) { self.released.lock().unwrap().push(seq_id); }
+}
+
+#[test]
+fn decode_stops_when_cancelled() {
+    let backend = MockBackend::new();
+    let cancel = CancelToken::new();
+    cancel.cancel();
+    let res = generate(&backend, "hello", 16, &cancel, |_| {});
+    assert!(matches!(res, Err(RunnerError::Cancelled)));
+}
+
+#[tokio::test]
+async fn cancelled_request_releases_kv() {
+    let kv = PagedKvManager::new(4096 * 64);
+    let handle = SchedulerV1::start(Arc::new(MockBackend::new()), kv.clone(), PrefixCache::new(&kv));
+    let cancel = CancelToken::new();
+    cancel.cancel();
+    let text = SchedulerV1::enqueue(&handle, "hello".into(), 8, cancel).await;
+    assert!(text.is_empty());
+    assert_eq!(kv.used_blocks(), 0);
+
+    let text = SchedulerV1::enqueue(&handle, "hello".into(), 8, CancelToken::new()).await;
+    assert_eq!(text, "hello");
+}
+
+#[test]
+fn cancelling_a_running_sequence_frees_it_on_the_next_step() {
+    let backend = Arc::new(Releases::default());
+    let kv = PagedKvManager::new(4096 * 64);
+    let prefix = PrefixCache::new(&kv);
+    let mut core = SchedulerCore::new(backend.clone(), prefix.clone(), SchedulerConfig::default());
+    let submit = |core: &mut SchedulerCore, cancel: &CancelToken| {
+        let prompt = backend.tokenize("hello world").unwrap();
+        let reservation = SchedulerV1::reserve(&kv, &prefix, prompt.len(), 64);
+        let (req, events) = Request::new(prompt, 64, reservation, cancel.clone());
+        core.push(req);
+        events
+    };
+    let cancel = CancelToken::new();
+    let _events = submit(&mut core, &cancel);
+    core.step();
+    core.step();
+    assert_eq!(core.running(), 1);
+    assert!(kv.used_blocks() > 0);
+    cancel.cancel();
+    core.step();
+    assert_eq!((core.running(), kv.used_blocks()), (0, 0));
+    assert_eq!(backend.released.lock().unwrap().len(), 1);
+
+    // a sequence that finishes is released too
+    let _events = submit(&mut core, &CancelToken::new());
+    while !core.is_idle() { core.step(); }
+    assert_eq!(backend.released.lock().unwrap().len(), 2);
+}
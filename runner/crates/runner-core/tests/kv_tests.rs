@@ -1,4 +1,6 @@
-use runner_core::kv::PagedKvManager;
+use runner_backend::{KvCacheType, KvLayout};
+use runner_common::RunnerError;
+use runner_core::kv::{BlockCopy, BlockTable, PagedKvManager, PrefixCache};
 
 #[test]
 fn reservation_releases_on_drop() {
@@ -12,3 +14,152 @@ fn reservation_releases_on_drop() {
     assert_eq!(kv.used_blocks(), used0);
 }
 
+
+#[test]
+fn block_table_grows_with_tokens() {
+    let kv = PagedKvManager::new(4096 * 8);
+    let mut t = BlockTable::new(&kv);
+    assert!(t.append_tokens(40).unwrap().is_empty());
+    assert_eq!(t.blocks().len(), 2);
+    assert_eq!(t.num_tokens(), 40);
+    let ids = t.blocks().to_vec();
+    assert_ne!(ids[0], ids[1]);
+    drop(t);
+    assert_eq!(kv.used_blocks(), 0);
+}
+
+#[test]
+fn fork_shares_blocks_and_copies_partial_tail_on_write() {
+    let kv = PagedKvManager::new(4096 * 8);
+    let mut parent = BlockTable::new(&kv);
+    parent.append_tokens(40).unwrap(); // one full block + 8 tokens in the second
+    let mut child = parent.fork();
+    assert_eq!(child.blocks(), parent.blocks());
+    assert_eq!(kv.used_blocks(), 2);
+    assert!(parent.blocks().iter().all(|&b| kv.ref_count(b) == 2));
+
+    let copies = child.append_tokens(1).unwrap();
+    assert_eq!(copies, vec![BlockCopy { src: parent.blocks()[1], dst: child.blocks()[1] }]);
+    assert_eq!(child.blocks()[0], parent.blocks()[0], "full block stays shared");
+    assert_ne!(child.blocks()[1], parent.blocks()[1]);
+    assert_eq!(kv.ref_count(parent.blocks()[1]), 1);
+
+    // the parent now owns its tail alone, so writing needs no copy
+    assert!(parent.append_tokens(1).unwrap().is_empty());
+    drop(child);
+    drop(parent);
+    assert_eq!(kv.used_blocks(), 0);
+}
+
+#[test]
+fn fork_on_block_boundary_needs_no_copy() {
+    let kv = PagedKvManager::new(4096 * 8);
+    let mut parent = BlockTable::new(&kv);
+    parent.append_tokens(PagedKvManager::TOKENS_PER_BLOCK).unwrap();
+    let mut child = parent.fork();
+    assert!(child.append_tokens(1).unwrap().is_empty());
+    assert_eq!(child.blocks()[0], parent.blocks()[0]);
+    assert_eq!(child.blocks().len(), 2);
+}
+
+#[test]
+fn exhausted_pool_reports_error() {
+    let kv = PagedKvManager::new(4096 * 2);
+    let mut t = BlockTable::new(&kv);
+    assert!(t.append_tokens(64).is_ok());
+    assert!(matches!(t.append_tokens(1), Err(RunnerError::KvExhausted)));
+    assert!(kv.try_reserve(1).is_none());
+}
+
+#[test]
+fn defragment_reuses_lowest_ids_first() {
+    let kv = PagedKvManager::new(4096 * 4);
+    let a = kv.try_reserve(1).unwrap();
+    let b = kv.try_reserve(1).unwrap();
+    let c = kv.try_reserve(1).unwrap();
+    assert_eq!((a.blocks()[0], b.blocks()[0], c.blocks()[0]), (0, 1, 2));
+    drop(a);
+    drop(c);
+    kv.defragment();
+    assert_eq!(kv.try_reserve(1).unwrap().blocks()[0], 0);
+}
+
+#[test]
+fn prefix_cache_shares_full_blocks() {
+    let kv = PagedKvManager::new(4096 * 8);
+    let cache = PrefixCache::new(&kv);
+    let tokens: Vec<u32> = (0..80).collect();
+    let mut t = BlockTable::new(&kv);
+    t.append_tokens(tokens.len()).unwrap();
+    cache.insert(&tokens, t.blocks());
+    // only the two full blocks are cached; the partial third is not
+    assert_eq!(cache.cached_blocks(), 2);
+
+    let hit = cache.lookup(&tokens[..70]);
+    assert_eq!(hit.tokens, 64);
+    assert_eq!(hit.blocks, t.blocks()[..2]);
+    // a prompt that is exactly cached still leaves its last token to prefill
+    assert_eq!(cache.lookup(&tokens[..64]).tokens, 32);
+    let mut other: Vec<u32> = tokens.clone();
+    other[10] = 999;
+    assert_eq!(cache.lookup(&other).tokens, 0);
+
+    let mut adopted = kv.try_reserve(3).unwrap();
+    adopted.adopt_prefix(&hit.blocks);
+    assert_eq!(adopted.num_tokens(), 64);
+    assert_eq!(adopted.blocks().len(), 3);
+    assert_eq!(kv.ref_count(hit.blocks[0]), 3);
+    drop((t, adopted));
+    assert_eq!(kv.used_blocks(), 2);
+    drop(cache);
+    assert_eq!(kv.used_blocks(), 0);
+}
+
+#[test]
+fn prefix_cache_evicts_unreferenced_lru_leaves() {
+    let kv = PagedKvManager::new(4096 * 8);
+    let cache = PrefixCache::new(&kv);
+    let a: Vec<u32> = (0..32).collect();
+    let b: Vec<u32> = (100..132).collect();
+    let (mut ta, mut tb) = (BlockTable::new(&kv), BlockTable::new(&kv));
+    ta.append_tokens(32).unwrap();
+    tb.append_tokens(32).unwrap();
+    cache.insert(&a, ta.blocks());
+    cache.insert(&b, tb.blocks());
+    drop(ta);
+    // `b` is still referenced by a live table, so only `a` can go
+    assert_eq!(cache.evict(2), 1);
+    assert_eq!(cache.cached_blocks(), 1);
+    assert_eq!(kv.used_blocks(), 1);
+    drop(tb);
+    cache.lookup(&[b.clone(), vec![7]].concat());
+    assert_eq!(cache.evict(1), 1);
+    assert_eq!(kv.used_blocks(), 0);
+}
+
+#[test]
+fn blocks_are_sized_from_model_layout() {
+    // Llama-3-8B shape: 32 layers, 8 KV heads of 128 dims, f16
+    let layout = KvLayout { n_layers: 32, n_kv_heads: 8, head_dim: 128, type_k: KvCacheType::F16, type_v: KvCacheType::F16 };
+    assert_eq!(layout.bytes_per_token(), 128 * 1024);
+    let kv = PagedKvManager::for_layout(64 * 1024 * 1024, &layout);
+    assert_eq!(kv.block_bytes(), 4 * 1024 * 1024);
+    assert_eq!(kv.capacity_blocks(), 16);
+    let _r = kv.try_reserve(3).unwrap();
+    assert_eq!(kv.used_bytes(), 12 * 1024 * 1024);
+}
+
+#[test]
+fn quantized_kv_fits_more_blocks() {
+    let f16 = KvLayout { n_layers: 32, n_kv_heads: 8, head_dim: 128, type_k: KvCacheType::F16, type_v: KvCacheType::F16 };
+    let q8 = KvLayout { type_k: KvCacheType::Q8_0, type_v: KvCacheType::Q8_0, ..f16 };
+    let mixed = KvLayout { type_k: KvCacheType::Q8_0, type_v: KvCacheType::Q4_0, ..f16 };
+    // 1024 values per row: 32 blocks of 34 (q8_0) or 18 (q4_0) bytes
+    assert_eq!(q8.bytes_per_token(), 32 * 2 * 32 * 34);
+    assert_eq!(mixed.bytes_per_token(), 32 * 32 * (34 + 18));
+    let cap = 256 * 1024 * 1024;
+    let (a, b) = (PagedKvManager::for_layout(cap, &f16), PagedKvManager::for_layout(cap, &q8));
+    assert!(b.capacity_blocks() * 100 >= a.capacity_blocks() * 185, "{} vs {}", b.capacity_blocks(), a.capacity_blocks());
+    assert_eq!("Q4_0".parse::<KvCacheType>().unwrap(), KvCacheType::Q4_0);
+    assert!("q5_1".parse::<KvCacheType>().is_err());
+}
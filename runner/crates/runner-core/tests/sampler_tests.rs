@@ -8,3 +8,24 @@ fn sample_is_deterministic_with_seed() {
     assert_eq!(a, b);
 }
 
+#[test]
+fn sampling_settings() {
+    use runner_core::sampler::Sampling;
+    let logits = vec![0.1, 2.0, 0.3, 1.9];
+    let greedy = Sampling { temperature: 0.0, ..Sampling::default() };
+    assert_eq!(greedy.sample(&logits, &mut greedy.rng()), 1);
+    let top_k = Sampling { top_k: 1, ..Sampling::default() };
+    assert!((0..20).all(|_| top_k.sample(&logits, &mut top_k.rng()) == 1));
+    // a seed fixes the whole sequence, not just the first draw
+    let seeded = Sampling { seed: Some(7), ..Sampling::default() };
+    let draws = |mut rng: rand::rngs::StdRng| (0..16).map(|_| seeded.sample(&logits, &mut rng)).collect::<Vec<_>>();
+    assert_eq!(draws(seeded.rng()), draws(seeded.rng()));
+}
+
+#[test]
+fn logprobs_are_a_log_softmax() {
+    let (logprob, top) = runner_core::sampler::logprobs(&[0.0, 2.0_f32.ln(), 0.0], 1, 2);
+    assert!((logprob.unwrap() - 0.5_f32.ln()).abs() < 1e-6);
+    assert_eq!(top.iter().map(|t| t.0).collect::<Vec<_>>(), [1, 0]);
+    assert!((top[1].1 - 0.25_f32.ln()).abs() < 1e-6);
+}
@@ -0,0 +1,391 @@
+use std::sync::Arc;
+use runner_backend::mock::MockBackend;
+use runner_common::{cancel::CancelToken, config::RunnerConfig};
+use runner_core::decode::{DecodeOptions, FinishReason, Usage};
+use runner_core::kv::{PagedKvManager, PrefixCache};
+use runner_core::scheduler::{GenerationEvent, Handle, Request, SchedulerConfig, SchedulerCore, SchedulerV1};
+use runner_core::sampler::Sampling;
+
+fn start() -> Handle {
+    let kv = PagedKvManager::new(4096 * 64);
+    SchedulerV1::start(Arc::new(MockBackend::new()), kv.clone(), PrefixCache::new(&kv))
+}
+
+#[tokio::test]
+async fn submit_streams_lifecycle_events() {
+    let handle = start();
+    let mut rx = SchedulerV1::submit(&handle, "hello".into(), 16, CancelToken::new());
+    let mut events = Vec::new();
+    while let Some(ev) = rx.recv().await { events.push(ev); }
+
+    assert!(matches!(events[0], GenerationEvent::Queued { .. }));
+    assert_eq!(events[1], GenerationEvent::Started);
+    let text: String = events.iter().filter_map(|e| match e { GenerationEvent::Token(t) => Some(t.as_str()), _ => None }).collect();
+    assert_eq!(text, "hello");
+    assert_eq!(
+        events.last(),
+        Some(&GenerationEvent::Finished { finish_reason: FinishReason::Stop, usage: Usage { prompt_tokens: 5, completion_tokens: 5 } })
+    );
+}
+
+/// Backend that never ends a sequence on its own: every step emits `x`.
+struct Endless;
+
+impl runner_backend::InferenceBackend for Endless {
+    fn load_model(&self, _path: &str, _params: runner_backend::LoadParams) -> runner_common::Result<runner_backend::ModelHandle> { Ok(runner_backend::ModelHandle::default()) }
+    fn tokenize(&self, text: &str) -> runner_common::Result<Vec<u32>> { Ok(text.bytes().map(u32::from).collect()) }
+    fn detokenize(&self, tokens: &[u32]) -> runner_common::Result<String> { Ok(tokens.iter().map(|&t| t as u8 as char).collect()) }
+    fn forward(&self, requests: &mut [runner_backend::SequenceState]) -> runner_common::Result<Vec<runner_backend::ForwardOutput>> {
+        Ok(requests.iter_mut().map(|seq| {
+            seq.n_past = seq.tokens.len();
+            runner_backend::ForwardOutput { token: Some(u32::from(b'x')), ..Default::default() }
+        }).collect())
+    }
+    fn kv_usage(&self) -> runner_backend::KvStats { runner_backend::KvStats }
+}
+
+/// One sequence at a time on `Endless`, so later requests wait behind the first.
+fn start_single(queue_capacity: usize) -> Handle {
+    let kv = PagedKvManager::new(4096 * 256);
+    let cfg = SchedulerConfig { max_seqs: 1, queue_capacity, ..SchedulerConfig::default() };
+    SchedulerV1::start_with(Arc::new(Endless), kv.clone(), PrefixCache::new(&kv), cfg)
+}
+
+/// Submit and wait until the scheduler starts the request.
+async fn submit_started(handle: &Handle) -> runner_core::scheduler::EventStream {
+    let mut rx = SchedulerV1::submit(handle, "a".into(), 1000, CancelToken::new());
+    while let Some(ev) = rx.recv().await { if ev == GenerationEvent::Started { break; } }
+    rx
+}
+
+#[tokio::test]
+async fn queued_position_counts_the_requests_ahead() {
+    let handle = start_single(16);
+    let _running = submit_started(&handle).await;
+    let position = |mut rx: runner_core::scheduler::EventStream| match rx.try_recv() { Ok(GenerationEvent::Queued { position }) => position, ev => panic!("{ev:?}") };
+    // still in the submission channel
+    assert_eq!(position(SchedulerV1::submit(&handle, "b".into(), 1000, CancelToken::new())), 0);
+    assert_eq!(position(SchedulerV1::submit(&handle, "c".into(), 1000, CancelToken::new())), 1);
+    // once the scheduler has taken them into its own queue
+    for _ in 0..10 { tokio::task::yield_now().await; }
+    assert_eq!(position(SchedulerV1::submit(&handle, "d".into(), 1000, CancelToken::new())), 2);
+}
+
+#[tokio::test]
+async fn submissions_past_the_queue_capacity_are_rejected() {
+    let handle = start_single(2);
+    let _running = submit_started(&handle).await;
+    let first = |mut rx: runner_core::scheduler::EventStream| rx.try_recv().unwrap();
+    let _b = SchedulerV1::submit(&handle, "b".into(), 1000, CancelToken::new());
+    let _c = SchedulerV1::submit(&handle, "c".into(), 1000, CancelToken::new());
+    assert_eq!(first(SchedulerV1::submit(&handle, "d".into(), 1000, CancelToken::new())), GenerationEvent::Error("SERVER_BUSY: queue full".into()));
+    // still full once the scheduler has taken them into its own queue
+    for _ in 0..10 { tokio::task::yield_now().await; }
+    assert_eq!(handle.queue_depth(), 2);
+    assert_eq!(first(SchedulerV1::submit(&handle, "d".into(), 1000, CancelToken::new())), GenerationEvent::Error("SERVER_BUSY: queue full".into()));
+}
+
+/// Mock backend with KV room for two sequences.
+struct TwoSlots(MockBackend);
+
+impl runner_backend::InferenceBackend for TwoSlots {
+    fn load_model(&self, path: &str, params: runner_backend::LoadParams) -> runner_common::Result<runner_backend::ModelHandle> { self.0.load_model(path, params) }
+    fn tokenize(&self, text: &str) -> runner_common::Result<Vec<u32>> { self.0.tokenize(text) }
+    fn detokenize(&self, tokens: &[u32]) -> runner_common::Result<String> { self.0.detokenize(tokens) }
+    fn forward(&self, requests: &mut [runner_backend::SequenceState]) -> runner_common::Result<Vec<runner_backend::ForwardOutput>> {
+        assert!(requests.len() <= 2, "{} sequences in one batch", requests.len());
+        self.0.forward(requests)
+    }
+    fn kv_usage(&self) -> runner_backend::KvStats { runner_backend::KvStats }
+    fn max_sequences(&self) -> Option<usize> { Some(2) }
+}
+
+#[test]
+fn batches_stay_within_the_backend_sequence_limit() {
+    let kv = PagedKvManager::new(4096 * 64);
+    let cfg = SchedulerConfig { max_seqs: 32, ..SchedulerConfig::default() };
+    let mut core = SchedulerCore::new(Arc::new(TwoSlots(MockBackend::new())), PrefixCache::new(&kv), cfg);
+    let mut streams = Vec::new();
+    for _ in 0..4 {
+        let (req, events) = Request::new(b"hello".map(u32::from).to_vec(), 8, None, CancelToken::new());
+        core.push(req);
+        streams.push(events);
+    }
+    assert_eq!(core.step(), 2);
+    assert_eq!((core.running(), core.waiting()), (2, 2));
+    while !core.is_idle() { core.step(); }
+}
+
+/// Fails every step of sequences whose prompt starts with `!`, as a backend does
+/// when one sequence's decode fails but the rest of the batch went through.
+struct FailsBang(MockBackend);
+
+impl runner_backend::InferenceBackend for FailsBang {
+    fn load_model(&self, path: &str, params: runner_backend::LoadParams) -> runner_common::Result<runner_backend::ModelHandle> { self.0.load_model(path, params) }
+    fn tokenize(&self, text: &str) -> runner_common::Result<Vec<u32>> { self.0.tokenize(text) }
+    fn detokenize(&self, tokens: &[u32]) -> runner_common::Result<String> { self.0.detokenize(tokens) }
+    fn forward(&self, requests: &mut [runner_backend::SequenceState]) -> runner_common::Result<Vec<runner_backend::ForwardOutput>> {
+        let mut outs = self.0.forward(requests)?;
+        for (seq, out) in requests.iter().zip(outs.iter_mut()) {
+            if seq.tokens.first() == Some(&u32::from(b'!')) { *out = runner_backend::ForwardOutput { error: Some("decode failed".into()), ..Default::default() }; }
+        }
+        Ok(outs)
+    }
+    fn kv_usage(&self) -> runner_backend::KvStats { runner_backend::KvStats }
+}
+
+#[tokio::test]
+async fn a_failed_sequence_leaves_the_rest_of_the_batch_running() {
+    let kv = PagedKvManager::new(4096 * 64);
+    let handle = SchedulerV1::start(Arc::new(FailsBang(MockBackend::new())), kv.clone(), PrefixCache::new(&kv));
+    let mut bad = SchedulerV1::submit(&handle, "!oops".into(), 8, CancelToken::new());
+    let mut good = SchedulerV1::submit(&handle, "hello".into(), 8, CancelToken::new());
+    let mut last = None;
+    while let Some(ev) = bad.recv().await { last = Some(ev); }
+    assert!(matches!(last, Some(GenerationEvent::Error(ref e)) if e.contains("decode failed")), "{last:?}");
+    let mut text = String::new();
+    while let Some(ev) = good.recv().await {
+        match ev {
+            GenerationEvent::Token(t) => text.push_str(&t),
+            GenerationEvent::Error(e) => panic!("{e}"),
+            _ => {}
+        }
+    }
+    assert_eq!(text, "hello");
+}
+
+#[tokio::test]
+async fn max_tokens_finishes_with_length() {
+    let handle = start();
+    let mut rx = SchedulerV1::submit(&handle, "hello".into(), 2, CancelToken::new());
+    let mut last = None;
+    while let Some(ev) = rx.recv().await { last = Some(ev); }
+    assert!(matches!(last, Some(GenerationEvent::Finished { finish_reason: FinishReason::Length, .. })));
+}
+
+#[test]
+fn scheduler_config_follows_runner_config() {
+    let cfg = RunnerConfig { scheduler_tick_ms: Some(5), max_batch_tokens: Some(256), max_concurrent_seqs: Some(4), queue_capacity: None, ..RunnerConfig::default() };
+    let sc = SchedulerConfig::from_runner(&cfg);
+    assert_eq!(sc.tick, std::time::Duration::from_millis(5));
+    assert_eq!((sc.max_seqs, sc.max_batch_tokens, sc.queue_capacity), (4, 256, 1024));
+}
+
+#[tokio::test]
+async fn admission_uses_tokenized_prompt_length() {
+    // two blocks hold 64 tokens; the mock tokenizes one token per byte
+    let kv = PagedKvManager::new(4096 * 2);
+    let handle = SchedulerV1::start(Arc::new(MockBackend::new()), kv.clone(), PrefixCache::new(&kv));
+    let text = SchedulerV1::enqueue(&handle, "a".repeat(100), 1, CancelToken::new()).await;
+    assert!(text.starts_with("SERVER_BUSY"), "{text}");
+    let text = SchedulerV1::enqueue(&handle, "a".repeat(40), 1, CancelToken::new()).await;
+    assert_eq!(text, "a");
+}
+
+/// Backend with a 16-token window that always emits `x` and fails any step
+/// past the window, recording the shifts it was asked to make.
+#[derive(Default)]
+struct Windowed { shifts: std::sync::Mutex<Vec<(usize, usize)>> }
+
+impl runner_backend::InferenceBackend for Windowed {
+    fn load_model(&self, _path: &str, _params: runner_backend::LoadParams) -> runner_common::Result<runner_backend::ModelHandle> { Ok(runner_backend::ModelHandle::default()) }
+    fn tokenize(&self, text: &str) -> runner_common::Result<Vec<u32>> { Ok(text.bytes().map(u32::from).collect()) }
+    fn detokenize(&self, tokens: &[u32]) -> runner_common::Result<String> { Ok(tokens.iter().map(|&t| t as u8 as char).collect()) }
+    fn forward(&self, requests: &mut [runner_backend::SequenceState]) -> runner_common::Result<Vec<runner_backend::ForwardOutput>> {
+        requests.iter_mut().map(|seq| {
+            if seq.tokens.len() > 16 { return Err(runner_common::RunnerError::Message("past the window".into())); }
+            seq.n_past = seq.tokens.len();
+            Ok(runner_backend::ForwardOutput { token: Some(u32::from(b'x')), ..Default::default() })
+        }).collect()
+    }
+    fn kv_usage(&self) -> runner_backend::KvStats { runner_backend::KvStats }
+    fn context_size(&self) -> Option<usize> { Some(16) }
+    fn shift_context(&self, _seq_id: u64, keep: usize, discard: usize) -> runner_common::Result<()> {
+        self.shifts.lock().unwrap().push((keep, discard));
+        Ok(())
+    }
+}
+
+async fn run_windowed(backend: &Arc<Windowed>, overflow: runner_backend::ContextOverflow, max_tokens: usize) -> Vec<GenerationEvent> {
+    let kv = PagedKvManager::new(4096 * 64);
+    let handle = SchedulerV1::start(backend.clone(), kv.clone(), PrefixCache::new(&kv));
+    let context = runner_backend::ContextPolicy { overflow, keep: 4 };
+    let mut rx = SchedulerV1::submit_with(&handle, b"0123456789".map(u32::from).to_vec(), max_tokens, context, DecodeOptions::default(), CancelToken::new());
+    let mut events = Vec::new();
+    while let Some(ev) = rx.recv().await { events.push(ev); }
+    events
+}
+
+#[tokio::test]
+async fn context_overflow_policies() {
+    use runner_backend::ContextOverflow;
+    let backend = Arc::new(Windowed::default());
+
+    let events = run_windowed(&backend, ContextOverflow::Reject, 40).await;
+    assert!(matches!(&events[..], [GenerationEvent::Error(e)] if e.starts_with("context length exceeded")), "{events:?}");
+    // fits: untouched
+    let events = run_windowed(&backend, ContextOverflow::Reject, 6).await;
+    assert!(matches!(events.last(), Some(GenerationEvent::Finished { usage: Usage { completion_tokens: 6, .. }, .. })));
+
+    let events = run_windowed(&backend, ContextOverflow::Truncate, 40).await;
+    assert_eq!(events.last(), Some(&GenerationEvent::Finished { finish_reason: FinishReason::Length, usage: Usage { prompt_tokens: 10, completion_tokens: 6 } }));
+
+    let events = run_windowed(&backend, ContextOverflow::Shift, 40).await;
+    assert_eq!(events.last(), Some(&GenerationEvent::Finished { finish_reason: FinishReason::Length, usage: Usage { prompt_tokens: 10, completion_tokens: 40 } }));
+    let text: String = events.iter().filter_map(|e| match e { GenerationEvent::Token(t) => Some(t.as_str()), _ => None }).collect();
+    assert_eq!(text, "x".repeat(40));
+    let shifts = backend.shifts.lock().unwrap();
+    assert!(shifts.len() > 1 && shifts.iter().all(|&(keep, discard)| keep == 4 && discard == 6), "{shifts:?}");
+}
+
+/// Mock backend that records where each sequence's first step started, and may
+/// claim to keep its KV outside the paged blocks.
+struct FirstStep { mock: MockBackend, paged: bool, starts: std::sync::Mutex<std::collections::HashMap<u64, usize>> }
+
+impl runner_backend::InferenceBackend for FirstStep {
+    fn load_model(&self, path: &str, params: runner_backend::LoadParams) -> runner_common::Result<runner_backend::ModelHandle> { self.mock.load_model(path, params) }
+    fn tokenize(&self, text: &str) -> runner_common::Result<Vec<u32>> { self.mock.tokenize(text) }
+    fn detokenize(&self, tokens: &[u32]) -> runner_common::Result<String> { self.mock.detokenize(tokens) }
+    fn forward(&self, requests: &mut [runner_backend::SequenceState]) -> runner_common::Result<Vec<runner_backend::ForwardOutput>> {
+        let mut starts = self.starts.lock().unwrap();
+        for seq in requests.iter() { starts.entry(seq.id).or_insert(seq.n_past); }
+        self.mock.forward(requests)
+    }
+    fn kv_usage(&self) -> runner_backend::KvStats { runner_backend::KvStats }
+    fn paged_kv(&self) -> bool { self.paged }
+}
+
+#[tokio::test]
+async fn cached_prefixes_only_skip_prefill_on_paged_backends() {
+    for paged in [true, false] {
+        let backend = Arc::new(FirstStep { mock: MockBackend::new(), paged, starts: Default::default() });
+        let kv = PagedKvManager::new(4096 * 64);
+        let prefix = PrefixCache::new(&kv);
+        let handle = SchedulerV1::start(backend.clone(), kv.clone(), prefix.clone());
+        for _ in 0..2 { SchedulerV1::enqueue(&handle, "a".repeat(80), 1, CancelToken::new()).await; }
+        let mut starts: Vec<usize> = backend.starts.lock().unwrap().values().copied().collect();
+        starts.sort();
+        // two full blocks of the second prompt were cached by the first
+        let saved = if paged { 64 } else { 0 };
+        assert_eq!((starts, prefix.saved_tokens(), prefix.hits()), (vec![0, saved], saved as u64, paged as u64), "paged {paged}");
+    }
+}
+
+/// Backend that emits `x` twice and then its EOS token, 2.
+struct EndsWithEos;
+
+impl runner_backend::InferenceBackend for EndsWithEos {
+    fn load_model(&self, _path: &str, _params: runner_backend::LoadParams) -> runner_common::Result<runner_backend::ModelHandle> { Ok(self.model().unwrap()) }
+    fn tokenize(&self, text: &str) -> runner_common::Result<Vec<u32>> { Ok(text.bytes().map(u32::from).collect()) }
+    fn detokenize(&self, tokens: &[u32]) -> runner_common::Result<String> { Ok(tokens.iter().map(|&t| t as u8 as char).collect()) }
+    fn forward(&self, requests: &mut [runner_backend::SequenceState]) -> runner_common::Result<Vec<runner_backend::ForwardOutput>> {
+        Ok(requests.iter_mut().map(|seq| {
+            seq.n_past = seq.tokens.len();
+            let token = if seq.tokens.len() - seq.prompt_len < 2 { u32::from(b'x') } else { 2 };
+            runner_backend::ForwardOutput { token: Some(token), ..Default::default() }
+        }).collect())
+    }
+    fn kv_usage(&self) -> runner_backend::KvStats { runner_backend::KvStats }
+    fn model(&self) -> Option<runner_backend::ModelHandle> {
+        let special_tokens = runner_backend::SpecialTokens { eos: Some(2), ..Default::default() };
+        Some(runner_backend::ModelHandle { id: "eos".into(), special_tokens, ..Default::default() })
+    }
+}
+
+#[tokio::test]
+async fn model_eos_token_stops_generation() {
+    let kv = PagedKvManager::new(4096 * 64);
+    let handle = SchedulerV1::start(Arc::new(EndsWithEos), kv.clone(), PrefixCache::new(&kv));
+    let mut rx = SchedulerV1::submit(&handle, "hi".into(), 16, CancelToken::new());
+    let mut events = Vec::new();
+    while let Some(ev) = rx.recv().await { events.push(ev); }
+    let text: String = events.iter().filter_map(|e| match e { GenerationEvent::Token(t) => Some(t.as_str()), _ => None }).collect();
+    assert_eq!(text, "xx");
+    assert!(matches!(events.last(), Some(GenerationEvent::Finished { finish_reason: FinishReason::Stop, usage: Usage { completion_tokens: 2, .. } })), "{events:?}");
+
+    let out = runner_core::decode::generate(&EndsWithEos, "hi", 16, &CancelToken::new(), |_| {}).unwrap();
+    assert_eq!(out.text, "xx");
+}
+
+#[tokio::test]
+async fn stop_strings_end_the_text() {
+    let handle = start();
+    let run = |stop: &[&str]| {
+        let options = DecodeOptions { stop: stop.iter().map(|s| s.to_string()).collect(), ..DecodeOptions::default() };
+        SchedulerV1::submit_with(&handle, b"Hello world".map(u32::from).to_vec(), 32, Default::default(), options, CancelToken::new())
+    };
+    let events = |mut rx: runner_core::scheduler::EventStream| async move {
+        let mut events = Vec::new();
+        while let Some(ev) = rx.recv().await { events.push(ev); }
+        let text: String = events.iter().filter_map(|e| match e { GenerationEvent::Token(t) => Some(t.as_str()), _ => None }).collect();
+        (text, events.pop())
+    };
+    let (text, last) = events(run(&["xyz", "wor"])).await;
+    assert_eq!(text, "Hello ");
+    assert!(matches!(last, Some(GenerationEvent::Finished { finish_reason: FinishReason::Stop, .. })));
+    // a tail that only starts a stop string is held back, then flushed
+    let (text, _) = events(run(&["ldx"])).await;
+    assert_eq!(text, "Hello world");
+}
+
+/// Mock backend that records the most tokens it was asked to detokenize at once.
+#[derive(Default)]
+struct Widest { mock: MockBackend, widest: std::sync::atomic::AtomicUsize }
+
+impl runner_backend::InferenceBackend for Widest {
+    fn load_model(&self, path: &str, params: runner_backend::LoadParams) -> runner_common::Result<runner_backend::ModelHandle> { self.mock.load_model(path, params) }
+    fn tokenize(&self, text: &str) -> runner_common::Result<Vec<u32>> { self.mock.tokenize(text) }
+    fn detokenize(&self, tokens: &[u32]) -> runner_common::Result<String> {
+        self.widest.fetch_max(tokens.len(), std::sync::atomic::Ordering::Relaxed);
+        self.mock.detokenize(tokens)
+    }
+    fn forward(&self, requests: &mut [runner_backend::SequenceState]) -> runner_common::Result<Vec<runner_backend::ForwardOutput>> { self.mock.forward(requests) }
+    fn kv_usage(&self) -> runner_backend::KvStats { runner_backend::KvStats }
+}
+
+#[test]
+fn decoding_only_detokenizes_the_newest_tokens() {
+    // the mock echoes its prompt; `é` is two tokens
+    let backend = Widest::default();
+    let prompt = "é and more ".repeat(50);
+    let mut deltas = Vec::new();
+    let out = runner_core::decode::generate(&backend, &prompt, 1000, &CancelToken::new(), |d| deltas.push(d.to_string())).unwrap();
+    assert_eq!((out.text.as_str(), deltas.concat()), (prompt.as_str(), prompt.clone()));
+    assert!(deltas.iter().all(|d| !d.contains(char::REPLACEMENT_CHARACTER)));
+    assert!(backend.widest.load(std::sync::atomic::Ordering::Relaxed) <= 4);
+}
+
+/// Backend that leaves the choice of token to the decoder: same logits every step.
+struct Logits;
+
+impl runner_backend::InferenceBackend for Logits {
+    fn load_model(&self, _path: &str, _params: runner_backend::LoadParams) -> runner_common::Result<runner_backend::ModelHandle> { Ok(runner_backend::ModelHandle::default()) }
+    fn tokenize(&self, text: &str) -> runner_common::Result<Vec<u32>> { Ok(text.bytes().map(u32::from).collect()) }
+    fn detokenize(&self, tokens: &[u32]) -> runner_common::Result<String> { Ok(tokens.iter().map(|&t| char::from(b'a' + t as u8)).collect()) }
+    fn forward(&self, requests: &mut [runner_backend::SequenceState]) -> runner_common::Result<Vec<runner_backend::ForwardOutput>> {
+        Ok(requests.iter_mut().map(|seq| {
+            seq.n_past = seq.tokens.len();
+            runner_backend::ForwardOutput { logits: Some(vec![0.0, 1.0, 3.0, 2.0]), ..Default::default() }
+        }).collect())
+    }
+    fn kv_usage(&self) -> runner_backend::KvStats { runner_backend::KvStats }
+}
+
+#[tokio::test]
+async fn decoder_samples_logits_and_reports_logprobs() {
+    let kv = PagedKvManager::new(4096 * 64);
+    let handle = SchedulerV1::start(Arc::new(Logits), kv.clone(), PrefixCache::new(&kv));
+    let options = DecodeOptions { sampling: Sampling { temperature: 0.0, ..Sampling::default() }, logprobs: Some(2), ..DecodeOptions::default() };
+    let mut rx = SchedulerV1::submit_with(&handle, vec![0], 2, Default::default(), options, CancelToken::new());
+    let (mut text, mut logprobs) = (String::new(), Vec::new());
+    while let Some(ev) = rx.recv().await {
+        match ev {
+            GenerationEvent::Token(t) => text.push_str(&t),
+            GenerationEvent::Logprob(lp) => logprobs.push(lp),
+            _ => {}
+        }
+    }
+    assert_eq!(text, "cc");
+    assert_eq!(logprobs.len(), 2);
+    assert!(logprobs.iter().all(|lp| lp.token == 2 && lp.top.iter().map(|t| t.0).eq([2, 3]) && lp.logprob == Some(lp.top[0].1)));
+}
@@ -0,0 +1,118 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use runner_backend::{mock::MockBackend, ForwardOutput, InferenceBackend, KvStats, LoadParams, ModelHandle, SequenceState};
+use runner_common::{cancel::CancelToken, Result};
+use runner_core::kv::{PagedKvManager, PrefixCache};
+use runner_core::scheduler::{GenerationEvent, Handle, SchedulerV1};
+use runner_core::session::SessionStore;
+
+/// Mock backend that records how many tokens each step had to prefill and
+/// keeps saved sequence state in memory.
+#[derive(Default)]
+struct Recorder { mock: MockBackend, prefills: Mutex<Vec<usize>>, restored: Mutex<Vec<u64>> }
+
+impl InferenceBackend for Recorder {
+    fn load_model(&self, path: &str, params: LoadParams) -> Result<ModelHandle> { self.mock.load_model(path, params) }
+    fn tokenize(&self, text: &str) -> Result<Vec<u32>> { self.mock.tokenize(text) }
+    fn detokenize(&self, tokens: &[u32]) -> Result<String> { self.mock.detokenize(tokens) }
+    fn forward(&self, requests: &mut [SequenceState]) -> Result<Vec<ForwardOutput>> {
+        self.prefills.lock().unwrap().extend(requests.iter().map(|s| s.tokens.len() - s.n_past));
+        self.mock.forward(requests)
+    }
+    fn kv_usage(&self) -> KvStats { KvStats }
+    fn save_sequence(&self, seq_id: u64) -> Option<Vec<u8>> { Some(seq_id.to_le_bytes().to_vec()) }
+    fn restore_sequence(&self, seq_id: u64, _data: &[u8]) -> Result<()> {
+        self.restored.lock().unwrap().push(seq_id);
+        Ok(())
+    }
+}
+
+fn start(backend: &Arc<Recorder>) -> Handle {
+    let kv = PagedKvManager::new(4096 * 64);
+    SchedulerV1::start(backend.clone(), kv.clone(), PrefixCache::new(&kv))
+}
+
+fn message(role: &str, content: &str) -> serde_json::Value { serde_json::json!({"role": role, "content": content}) }
+
+/// A chat template: `role: content` lines, then the assistant's label.
+fn render(messages: &[serde_json::Value]) -> String {
+    messages.iter().map(|m| format!("{}: {}\n", m["role"].as_str().unwrap(), m["content"].as_str().unwrap())).collect::<String>() + "assistant: "
+}
+
+/// Append a user message, generate a reply and return the first step's prefill.
+async fn turn(store: &Arc<SessionStore>, handle: &Handle, backend: &Recorder, id: &str, content: &str) -> usize {
+    store.append(id, message("user", content)).unwrap();
+    let mut t = store.begin_turn(id, handle).unwrap();
+    let prompt = backend.tokenize(&render(&t.messages)).unwrap();
+    let resume = t.resume_for(&prompt, backend);
+    backend.prefills.lock().unwrap().clear();
+    let (mut events, slot) = SchedulerV1::submit_turn(handle, resume, prompt, 4, CancelToken::new());
+    let mut reply = String::new();
+    while let Some(ev) = events.recv().await {
+        assert!(!matches!(ev, GenerationEvent::Error(_)), "{ev:?}");
+        if let GenerationEvent::Token(t) = ev { reply.push_str(&t); }
+    }
+    t.finish(slot.lock().unwrap().take().expect("parked"), message("assistant", &reply));
+    backend.prefills.lock().unwrap()[0]
+}
+
+#[tokio::test]
+async fn second_turn_prefills_only_new_tokens() {
+    let backend = Arc::new(Recorder::default());
+    let handle = start(&backend);
+    let store = SessionStore::open(None).unwrap();
+    let id = store.create("mock", vec![message("system", "be brief")]).id;
+
+    assert_eq!(turn(&store, &handle, &backend, &id, "hello there").await, 46);
+    let info = store.info(&id).unwrap();
+    assert!(info.resident);
+    assert_eq!(info.cached_tokens, info.tokens - 1);
+
+    // the reply's last token was never fed back; after it only the newline
+    // closing the reply, "user: again\n" and "assistant: " are new
+    assert_eq!(turn(&store, &handle, &backend, &id, "again").await, 1 + 1 + 12 + 11);
+    assert_eq!(store.info(&id).unwrap().turns, 4);
+}
+
+#[tokio::test]
+async fn a_prompt_that_does_not_extend_the_sequence_starts_over() {
+    let backend = Arc::new(Recorder::default());
+    let handle = start(&backend);
+    let store = SessionStore::open(None).unwrap();
+    let id = store.create("mock", Vec::new()).id;
+    turn(&store, &handle, &backend, &id, "hello").await;
+    let mut t = store.begin_turn(&id, &handle).unwrap();
+    // as if the template rendered the earlier turns differently this time
+    assert!(t.resume_for(&backend.tokenize("rewritten").unwrap(), backend.as_ref()).is_none());
+    drop(t);
+    assert_eq!(handle.kv.used_blocks(), 0);
+    assert!(!store.info(&id).unwrap().resident);
+}
+
+#[tokio::test]
+async fn parked_session_restores_after_reopen() {
+    let dir = std::env::temp_dir().join(format!("runner-sessions-{}", std::process::id()));
+    let backend = Arc::new(Recorder::default());
+    let handle = start(&backend);
+    let store = SessionStore::open(Some(dir.clone())).unwrap();
+    // short enough that no whole block ends up in the prefix cache
+    let id = store.create("mock", Vec::new()).id;
+    turn(&store, &handle, &backend, &id, "one").await;
+    let before = store.info(&id).unwrap();
+
+    assert_eq!(store.park_idle("other", backend.as_ref(), Duration::ZERO), 0);
+    assert_eq!(store.park_idle("mock", backend.as_ref(), Duration::ZERO), 1);
+    assert!(!store.info(&id).unwrap().resident);
+    assert_eq!(handle.kv.used_blocks(), 0);
+    drop(store);
+
+    let store = SessionStore::open(Some(dir.clone())).unwrap();
+    let info = store.info(&id).unwrap();
+    assert_eq!((info.model, info.tokens, info.cached_tokens, info.turns), (before.model, before.tokens, before.cached_tokens, before.turns));
+    assert_eq!(turn(&store, &handle, &backend, &id, "two").await, 1 + 1 + 10 + 11);
+    assert_eq!(backend.restored.lock().unwrap().len(), 1);
+
+    assert!(store.delete(&id, Some(backend.as_ref())));
+    assert!(std::fs::read_dir(&dir).unwrap().next().is_none());
+    std::fs::remove_dir(&dir).unwrap();
+}
@@ -0,0 +1,71 @@
+use std::time::Duration;
+use runner_core::scheduler::SchedulerConfig;
+use runner_core::sim::{Arrival, Simulation};
+
+fn burst(n: usize, tenant: &str) -> Vec<Arrival> {
+    (0..n).map(|_| Arrival { at: Duration::ZERO, tenant: tenant.into(), prompt_tokens: 64, max_tokens: 32 }).collect()
+}
+
+fn steady(n: usize, every: Duration) -> Vec<Arrival> {
+    (0..n).map(|i| Arrival {
+        at: every * i as u32,
+        tenant: if i % 2 == 0 { "a".into() } else { "b".into() },
+        prompt_tokens: 128,
+        max_tokens: 32,
+    }).collect()
+}
+
+#[test]
+fn replay_is_deterministic() {
+    let sim = Simulation::default();
+    let trace = steady(20, Duration::from_millis(30));
+    assert_eq!(sim.run(&trace), sim.run(&trace));
+}
+
+#[test]
+fn continuous_batching_beats_serial_decode() {
+    let trace = burst(16, "a");
+    let serial = Simulation { scheduler: SchedulerConfig { max_seqs: 1, ..Default::default() }, ..Default::default() }.run(&trace);
+    let batched = Simulation::default().run(&trace);
+    assert_eq!(batched.completed(), 16);
+    assert!(batched.throughput() > 4.0 * serial.throughput(), "{} vs {}", batched.throughput(), serial.throughput());
+    assert!(batched.ttft_percentile(95.0) < serial.ttft_percentile(95.0) / 4);
+}
+
+#[test]
+fn steady_load_keeps_ttft_low_and_fair() {
+    let report = Simulation::default().run(&steady(40, Duration::from_millis(100)));
+    assert_eq!(report.completed(), 40);
+    assert!(report.ttft_percentile(99.0) < Duration::from_millis(100), "p99 ttft {:?}", report.ttft_percentile(99.0));
+    assert!(report.fairness() > 0.95, "fairness {}", report.fairness());
+}
+
+#[test]
+fn admission_rejects_when_kv_is_full() {
+    let sim = Simulation { kv_capacity_bytes: 4096 * 8, ..Default::default() };
+    let report = sim.run(&burst(8, "a"));
+    assert!(report.rejected() > 0);
+    assert_eq!(report.completed() + report.rejected(), 8);
+}
+
+#[test]
+fn token_budget_defers_prefill_to_later_steps() {
+    let trace: Vec<Arrival> = (0..4).map(|_| Arrival { at: Duration::ZERO, tenant: "a".into(), prompt_tokens: 64, max_tokens: 8 }).collect();
+    let sim = Simulation { scheduler: SchedulerConfig { max_batch_tokens: 200, ..Default::default() }, ..Default::default() };
+    let report = sim.run(&trace);
+    assert_eq!(report.completed(), 4);
+    let ttft: Vec<Duration> = report.records.iter().map(|r| r.ttft().unwrap()).collect();
+    // three prompts fit the first step's budget, the fourth prefills one step later
+    assert!(ttft[0] == ttft[1] && ttft[1] == ttft[2]);
+    assert!(ttft[3] > ttft[2]);
+}
+
+#[test]
+fn shared_prefix_skips_prefill() {
+    // the sim prompts are identical, so the second request reuses the first one's blocks
+    let trace: Vec<Arrival> = (0..2).map(|i| Arrival { at: Duration::from_secs(i), tenant: "a".into(), prompt_tokens: 1024, max_tokens: 4 }).collect();
+    let report = Simulation::default().run(&trace);
+    assert_eq!(report.completed(), 2);
+    let (cold, warm) = (report.records[0].ttft().unwrap(), report.records[1].ttft().unwrap());
+    assert!(warm * 4 < cold, "cold {cold:?} warm {warm:?}");
+}
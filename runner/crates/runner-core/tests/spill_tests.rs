@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use runner_backend::{ForwardOutput, InferenceBackend, KvStats, LoadParams, ModelHandle, SequenceState};
+use runner_common::{Result, RunnerError};
+use runner_core::kv::{BlockTable, PagedKvManager, PrefixCache};
+use runner_core::spill::{SpillConfig, Tier, TieredKvStore};
+
+/// Backend whose KV blocks are plain byte buffers, so spills can be checked.
+#[derive(Default)]
+struct BlockMemory(Mutex<HashMap<u32, Vec<u8>>>);
+
+impl InferenceBackend for BlockMemory {
+    fn load_model(&self, _path: &str, _params: LoadParams) -> Result<ModelHandle> { Ok(ModelHandle::default()) }
+    fn tokenize(&self, text: &str) -> Result<Vec<u32>> { Ok(text.bytes().map(u32::from).collect()) }
+    fn detokenize(&self, _tokens: &[u32]) -> Result<String> { Ok(String::new()) }
+    fn forward(&self, _requests: &mut [SequenceState]) -> Result<Vec<ForwardOutput>> { Err(RunnerError::NotImplemented) }
+    fn kv_usage(&self) -> KvStats { KvStats }
+    fn read_kv_block(&self, block: u32) -> Option<Vec<u8>> { self.0.lock().unwrap().remove(&block) }
+    fn write_kv_block(&self, block: u32, data: &[u8]) -> Result<()> {
+        self.0.lock().unwrap().insert(block, data.to_vec());
+        Ok(())
+    }
+}
+
+fn store(host_slots: usize, disk_slots: usize) -> Arc<TieredKvStore> {
+    let cfg = SpillConfig { host_capacity_bytes: host_slots * 64, disk_capacity_bytes: disk_slots * 64, disk_dir: None };
+    TieredKvStore::new(&cfg, 64).unwrap()
+}
+
+#[test]
+fn host_overflow_demotes_lru_to_disk() {
+    let s = store(2, 4);
+    let a = s.put(vec![1; 64]).unwrap();
+    let b = s.put(vec![2; 64]).unwrap();
+    let c = s.put(vec![3; 64]).unwrap();
+    assert_eq!((s.tier_of(a), s.tier_of(b), s.tier_of(c)), (Some(Tier::Disk), Some(Tier::Host), Some(Tier::Host)));
+    // larger than the whole host tier: straight to disk, across slots
+    let big = s.put((0..150).map(|i| i as u8).collect()).unwrap();
+    assert_eq!(s.tier_of(big), Some(Tier::Disk));
+    assert_eq!(s.stats(Tier::Disk).used_bytes, 4 * 64);
+
+    assert_eq!(s.take(a), Some(vec![1; 64]));
+    assert_eq!(s.take(big), Some((0..150).map(|i| i as u8).collect()));
+    assert_eq!(s.take(a), None);
+    let disk = s.stats(Tier::Disk);
+    assert_eq!((disk.used_bytes, disk.spills, disk.restores), (0, 2, 2));
+    assert_eq!(s.stats(Tier::Host).used_bytes, 128);
+}
+
+#[test]
+fn full_tiers_reject() {
+    let s = store(1, 1);
+    s.put(vec![0; 64]).unwrap();
+    s.put(vec![0; 64]).unwrap();
+    assert!(matches!(s.put(vec![0; 64]), Err(RunnerError::KvExhausted)));
+}
+
+#[test]
+fn evicted_prefix_spills_and_pages_back_in() {
+    let backend = Arc::new(BlockMemory::default());
+    let kv = PagedKvManager::new(4096 * 2);
+    kv.enable_spill(store(4, 4), backend.clone());
+    let cache = PrefixCache::new(&kv);
+
+    let tokens: Vec<u32> = (0..33).collect();
+    let mut t = BlockTable::new(&kv);
+    t.append_tokens(32).unwrap();
+    let block = t.blocks()[0];
+    backend.write_kv_block(block, &[7; 64]).unwrap();
+    cache.insert(&tokens, t.blocks());
+    drop(t);
+
+    assert_eq!(cache.evict(1), 1);
+    assert_eq!((cache.cached_blocks(), cache.spilled_blocks()), (0, 1));
+    assert_eq!(kv.used_blocks(), 0);
+
+    let hit = cache.lookup(&tokens);
+    assert_eq!(hit.tokens, 32);
+    assert_eq!(backend.0.lock().unwrap().get(&hit.blocks[0]), Some(&vec![7; 64]));
+    assert_eq!((cache.cached_blocks(), cache.spilled_blocks()), (1, 0));
+    assert_eq!(kv.spill_store().unwrap().stats(Tier::Host).restores, 1);
+}
+
+#[test]
+fn without_spill_eviction_drops() {
+    let kv = PagedKvManager::new(4096 * 2);
+    let cache = PrefixCache::new(&kv);
+    let tokens: Vec<u32> = (0..33).collect();
+    let mut t = BlockTable::new(&kv);
+    t.append_tokens(32).unwrap();
+    cache.insert(&tokens, t.blocks());
+    drop(t);
+    assert_eq!(cache.evict(1), 1);
+    assert_eq!((cache.cached_blocks(), cache.spilled_blocks()), (0, 0));
+    assert_eq!(cache.lookup(&tokens).tokens, 0);
+}